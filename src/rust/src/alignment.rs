@@ -1,4 +1,4 @@
-use crate::sequence::{Cdr3Sequence, SearchScope};
+use crate::sequence::{AmbiguousResiduePolicy, Cdr3Sequence, ResidueHandling, SearchScope};
 use std::cmp::min;
 
 /// Edit distance and alignment operations
@@ -10,6 +10,18 @@ pub enum EditOp {
     Deletion,
 }
 
+impl EditOp {
+    /// One-letter code: M(atch), S(ubstitution), I(nsertion), D(eletion)
+    pub fn code(self) -> char {
+        match self {
+            Self::Match => 'M',
+            Self::Substitution => 'S',
+            Self::Insertion => 'I',
+            Self::Deletion => 'D',
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Alignment {
     pub query: String,
@@ -28,12 +40,46 @@ impl Alignment {
             && self.deletions <= scope.deletions
             && self.edit_distance <= scope.total
     }
+
+    /// Operations as one-letter codes (see `EditOp::code`), e.g. "MMMSMMID"
+    pub fn operation_codes(&self) -> String {
+        self.operations.iter().map(|op| op.code()).collect()
+    }
 }
 
 /// Compute edit distance between two sequences
 pub fn edit_distance(seq1: &str, seq2: &str) -> usize {
-    let len1 = seq1.len();
-    let len2 = seq2.len();
+    edit_distance_by(seq1.as_bytes(), seq2.as_bytes(), usize::MAX, |a, b| a == b, &mut EditScratch::default())
+}
+
+/// `edit_distance` with ambiguous residues compared per `residues`
+/// Sequences are compared as given; gap removal is left to `ResidueHandling::prepare`.
+pub fn edit_distance_with(seq1: &str, seq2: &str, residues: &ResidueHandling) -> usize {
+    if residues.ambiguous == AmbiguousResiduePolicy::Neutral {
+        edit_distance_by(seq1.as_bytes(), seq2.as_bytes(), usize::MAX, |a, b| residues.same_residue(a, b), &mut EditScratch::default())
+    } else {
+        edit_distance(seq1, seq2)
+    }
+}
+
+/// DP rows reused across edit distance calls of one matching loop
+#[derive(Debug, Default)]
+pub struct EditScratch {
+    prev: Vec<usize>,
+    curr: Vec<usize>,
+}
+
+/// Edit distance, or some value above `limit` as soon as one DP row exceeds
+/// it (row minima never decrease, so the distance would too)
+fn edit_distance_by(
+    seq1_bytes: &[u8],
+    seq2_bytes: &[u8],
+    limit: usize,
+    same: impl Fn(u8, u8) -> bool,
+    scratch: &mut EditScratch,
+) -> usize {
+    let len1 = seq1_bytes.len();
+    let len2 = seq2_bytes.len();
     
     if len1 == 0 {
         return len2;
@@ -42,17 +88,18 @@ pub fn edit_distance(seq1: &str, seq2: &str) -> usize {
         return len1;
     }
     
-    let seq1_bytes = seq1.as_bytes();
-    let seq2_bytes = seq2.as_bytes();
-    
-    let mut prev_row: Vec<usize> = (0..=len2).collect();
-    let mut curr_row: Vec<usize> = vec![0; len2 + 1];
+    let EditScratch { prev: prev_row, curr: curr_row } = scratch;
+    prev_row.clear();
+    prev_row.extend(0..=len2);
+    curr_row.clear();
+    curr_row.resize(len2 + 1, 0);
     
     for i in 1..=len1 {
         curr_row[0] = i;
+        let mut row_min = i;
         
         for j in 1..=len2 {
-            let cost = if seq1_bytes[i - 1] == seq2_bytes[j - 1] { 0 } else { 1 };
+            let cost = if same(seq1_bytes[i - 1], seq2_bytes[j - 1]) { 0 } else { 1 };
             
             curr_row[j] = min(
                 min(
@@ -61,9 +108,13 @@ pub fn edit_distance(seq1: &str, seq2: &str) -> usize {
                 ),
                 prev_row[j - 1] + cost,    // substitution/match
             );
+            row_min = min(row_min, curr_row[j]);
+        }
+        if row_min > limit {
+            return row_min;
         }
         
-        std::mem::swap(&mut prev_row, &mut curr_row);
+        std::mem::swap(prev_row, curr_row);
     }
     
     prev_row[len2]
@@ -71,16 +122,70 @@ pub fn edit_distance(seq1: &str, seq2: &str) -> usize {
 
 /// Check if two sequences match within the given search scope using edit distance
 pub fn matches_within_scope(query: &Cdr3Sequence, target: &Cdr3Sequence, scope: &SearchScope) -> bool {
+    bytes_within_scope(query.as_bytes(), target.as_bytes(), scope, &mut EditScratch::default())
+}
+
+fn bytes_within_scope(query: &[u8], target: &[u8], scope: &SearchScope, scratch: &mut EditScratch) -> bool {
     if scope.is_exact() {
-        return query.sequence == target.sequence;
+        return query == target;
+    }
+    // Each residue of length difference costs at least one edit
+    if query.len().abs_diff(target.len()) > scope.total {
+        return false;
+    }
+    
+    // Hamming distance bounds edit distance, so equal-length hits skip the DP
+    if query.len() == target.len() && crate::simd::count_mismatches(query, target) <= scope.total {
+        return true;
     }
     
-    let distance = edit_distance(&query.sequence, &target.sequence);
+    let distance = edit_distance_by(query, target, scope.total, |a, b| a == b, scratch);
     distance <= scope.total
 }
 
+/// `matches_within_scope` over plain sequences with ambiguous residues
+/// compared per `residues`
+pub fn matches_within_scope_with(query: &str, target: &str, scope: &SearchScope, residues: &ResidueHandling) -> bool {
+    let (query, target) = (query.to_uppercase(), target.to_uppercase());
+    matches_within_scope_prepared(&query, &target, scope, residues, &mut EditScratch::default())
+}
+
+/// `matches_within_scope_with` over sequences already uppercased, such as
+/// those of `ResidueHandling::prepare_tokens`, with DP rows from `scratch`
+pub fn matches_within_scope_prepared(
+    query: &str,
+    target: &str,
+    scope: &SearchScope,
+    residues: &ResidueHandling,
+    scratch: &mut EditScratch,
+) -> bool {
+    if residues.ambiguous != AmbiguousResiduePolicy::Neutral {
+        return bytes_within_scope(query.as_bytes(), target.as_bytes(), scope, scratch);
+    }
+    // Under the neutral policy an exact scope still admits wildcard positions
+    if query.len().abs_diff(target.len()) > scope.total {
+        return false;
+    }
+    let same = |a, b| residues.same_residue(a, b);
+    edit_distance_by(query.as_bytes(), target.as_bytes(), scope.total, same, scratch) <= scope.total
+}
+
 /// Perform detailed alignment with operation tracking
 pub fn align(query: &str, target: &str) -> Alignment {
+    align_by(query, target, |a, b| a == b)
+}
+
+/// `align` with ambiguous residues compared per `residues`; a wildcard
+/// against any residue is a match
+pub fn align_with(query: &str, target: &str, residues: &ResidueHandling) -> Alignment {
+    if residues.ambiguous == AmbiguousResiduePolicy::Neutral {
+        align_by(query, target, |a, b| residues.same_residue(a, b))
+    } else {
+        align(query, target)
+    }
+}
+
+fn align_by(query: &str, target: &str, same: impl Fn(u8, u8) -> bool) -> Alignment {
     let len1 = query.len();
     let len2 = target.len();
     
@@ -101,7 +206,7 @@ pub fn align(query: &str, target: &str) -> Alignment {
     // Fill DP table
     for i in 1..=len1 {
         for j in 1..=len2 {
-            let cost = if query_bytes[i - 1] == target_bytes[j - 1] { 0 } else { 1 };
+            let cost = if same(query_bytes[i - 1], target_bytes[j - 1]) { 0 } else { 1 };
             
             dp[i][j] = min(
                 min(
@@ -123,7 +228,7 @@ pub fn align(query: &str, target: &str) -> Alignment {
     
     while i > 0 || j > 0 {
         if i > 0 && j > 0 {
-            let cost = if query_bytes[i - 1] == target_bytes[j - 1] { 0 } else { 1 };
+            let cost = if same(query_bytes[i - 1], target_bytes[j - 1]) { 0 } else { 1 };
             
             if dp[i][j] == dp[i - 1][j - 1] + cost {
                 if cost == 0 {
@@ -162,6 +267,114 @@ pub fn align(query: &str, target: &str) -> Alignment {
     }
 }
 
+/// Residues trimmed from each CDR3 end before seeding (germline "CAS..F" flanks)
+const KMER_FLANK: usize = 3;
+
+/// Seed-and-extend prefilter: only align against targets sharing enough central k-mers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KmerFilter {
+    pub k: usize,
+    pub min_shared: usize,
+}
+
+impl KmerFilter {
+    pub fn new(k: usize, min_shared: usize) -> Self {
+        Self { k: k.max(1), min_shared }
+    }
+
+    /// Distinct k-mers of the query after trimming the conserved flanks
+    /// Falls back to the full sequence when it is too short to trim
+    pub fn central_kmers<'a>(&self, query: &'a [u8]) -> Vec<&'a [u8]> {
+        let core = if query.len() >= 2 * KMER_FLANK + self.k {
+            &query[KMER_FLANK..query.len() - KMER_FLANK]
+        } else {
+            query
+        };
+        let mut kmers: Vec<&[u8]> = core.windows(self.k).collect();
+        kmers.sort_unstable();
+        kmers.dedup();
+        kmers
+    }
+
+    /// Whether `target` contains enough of the query's central k-mers
+    /// The requirement is capped at the number of query k-mers so short queries are never dropped
+    pub fn passes(&self, query_kmers: &[&[u8]], target: &[u8]) -> bool {
+        let required = self.min_shared.min(query_kmers.len());
+        if required == 0 {
+            return true;
+        }
+        let mut shared = 0;
+        for kmer in query_kmers {
+            if target.windows(self.k).any(|w| w == *kmer) {
+                shared += 1;
+                if shared >= required {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// The 20 standard amino acids used when enumerating substitutions/insertions
+const AMINO_ACIDS: &[u8; 20] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// All distinct sequences reachable from `seq` with at most `subs` substitutions
+/// and `indels` insertions/deletions, excluding `seq` itself, sorted
+/// Each sequence is expanded only from its cheapest (substitutions, indels)
+/// states, which keeps enumeration tractable for scopes up to about 2.
+pub fn enumerate_neighbors(seq: &str, subs: usize, indels: usize) -> Vec<String> {
+    use std::collections::HashMap;
+
+    let start = seq.to_uppercase().into_bytes();
+    // Pareto-minimal (substitutions, indels) used to reach each sequence
+    let mut best: HashMap<Vec<u8>, Vec<(usize, usize)>> = HashMap::new();
+    let mut stack = vec![(start.clone(), 0usize, 0usize)];
+
+    while let Some((current, s, i)) = stack.pop() {
+        let states = best.entry(current.clone()).or_default();
+        if states.iter().any(|&(bs, bi)| bs <= s && bi <= i) {
+            continue;
+        }
+        states.retain(|&(bs, bi)| !(s <= bs && i <= bi));
+        states.push((s, i));
+
+        if s < subs {
+            for pos in 0..current.len() {
+                for &aa in AMINO_ACIDS {
+                    if aa != current[pos] {
+                        let mut next = current.clone();
+                        next[pos] = aa;
+                        stack.push((next, s + 1, i));
+                    }
+                }
+            }
+        }
+        if i < indels {
+            for pos in 0..current.len() {
+                let mut next = current.clone();
+                next.remove(pos);
+                stack.push((next, s, i + 1));
+            }
+            for pos in 0..=current.len() {
+                for &aa in AMINO_ACIDS {
+                    let mut next = current.clone();
+                    next.insert(pos, aa);
+                    stack.push((next, s, i + 1));
+                }
+            }
+        }
+    }
+
+    best.remove(&start);
+    let mut neighbors: Vec<String> = best
+        .into_keys()
+        .map(|s| String::from_utf8(s).unwrap_or_default())
+        .collect();
+    neighbors.sort();
+    neighbors
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,5 +407,144 @@ mod tests {
         assert_eq!(aln.insertions, 0);
         assert_eq!(aln.deletions, 0);
         assert_eq!(aln.edit_distance, 1);
+        assert_eq!(aln.operation_codes(), "MMMMMMMMMMMMS");
+
+        let aln = align("CASSF", "CASF");
+        assert_eq!((aln.deletions, aln.edit_distance), (1, 1));
+        assert_eq!(aln.operation_codes().chars().filter(|&c| c == 'D').count(), 1);
+    }
+
+    #[test]
+    fn test_kmer_filter() {
+        let filter = KmerFilter::new(3, 2);
+        let kmers = filter.central_kmers(b"CASSLGQAYEQYF");
+        assert_eq!(kmers.first(), Some(&&b"AYE"[..]));
+        assert!(!kmers.contains(&&b"CAS"[..]));
+
+        assert!(filter.passes(&kmers, b"CASSLGQAYEQYY"));
+        assert!(!filter.passes(&kmers, b"CASRTGELFF"));
+
+        // Queries too short to yield min_shared k-mers are not filtered out
+        let short = filter.central_kmers(b"CAS");
+        assert!(filter.passes(&short, b"CASF"));
+        assert!(KmerFilter::new(3, 0).passes(&kmers, b"W"));
+    }
+
+    #[test]
+    fn test_enumerate_neighbors() {
+        // 3 positions x 19 alternatives
+        assert_eq!(enumerate_neighbors("CAS", 1, 0).len(), 57);
+
+        // Insertions at neighboring positions can yield the same sequence; each is listed once
+        let indel = enumerate_neighbors("CAS", 0, 1);
+        assert!(indel.contains(&"CA".to_string()));
+        assert!(indel.contains(&"CCAS".to_string()));
+        assert!(!indel.contains(&"CAS".to_string()));
+        let unique: std::collections::HashSet<&String> = indel.iter().collect();
+        assert_eq!(unique.len(), indel.len());
+
+        for n in enumerate_neighbors("CASF", 2, 1) {
+            assert!(edit_distance("CASF", &n) <= 3);
+        }
+        assert!(enumerate_neighbors("CAS", 0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_residues() {
+        let neutral = ResidueHandling::new(AmbiguousResiduePolicy::Neutral, ".-");
+        assert_eq!(edit_distance("CASSXGF", "CASSLGF"), 1);
+        assert_eq!(edit_distance_with("CASSXGF", "CASSLGF", &neutral), 0);
+        assert_eq!(edit_distance_with("CASSXGF", "CASSLGF", &ResidueHandling::default()), 1);
+
+        let aln = align_with("CASSXGF", "CASSLGF", &neutral);
+        assert_eq!((aln.edit_distance, aln.operation_codes().as_str()), (0, "MMMMMMM"));
+
+        assert!(matches_within_scope_with("CASSXGF", "casslgf", &SearchScope::EXACT, &neutral));
+        assert!(!matches_within_scope_with("CASSXGF", "CASSLGF", &SearchScope::EXACT, &ResidueHandling::default()));
+        assert!(!matches_within_scope_with("CASSXGF", "CASSLGFF", &SearchScope::EXACT, &neutral));
+    }
+
+    /// Random sequence over `alphabet`; small alphabets make matches,
+    /// substitutions and indels all common
+    fn random_sequence(rng: &mut crate::random::Rng, alphabet: &[u8], max_len: usize) -> String {
+        let len = rng.below(max_len + 1);
+        (0..len).map(|_| alphabet[rng.below(alphabet.len())] as char).collect()
+    }
+
+    #[test]
+    fn test_edit_distance_is_a_metric() {
+        let mut rng = crate::random::Rng::new(1);
+        for _ in 0..2000 {
+            let [a, b, c] = [(); 3].map(|_| random_sequence(&mut rng, b"ACDE", 12));
+            let ab = edit_distance(&a, &b);
+            assert_eq!(ab, edit_distance(&b, &a), "{} {}", a, b);
+            assert_eq!(ab == 0, a == b);
+            assert!(ab >= a.len().abs_diff(b.len()) && ab <= a.len().max(b.len()));
+            assert!(edit_distance(&a, &c) <= ab + edit_distance(&b, &c), "{} {} {}", a, b, c);
+        }
+    }
+
+    #[test]
+    fn test_align_agrees_with_edit_distance() {
+        let mut rng = crate::random::Rng::new(2);
+        for _ in 0..2000 {
+            let (a, b) = (random_sequence(&mut rng, b"ACDE", 12), random_sequence(&mut rng, b"ACDE", 12));
+            let aln = align(&a, &b);
+            assert_eq!(aln.edit_distance, edit_distance(&a, &b), "{} {}", a, b);
+            assert_eq!(aln.substitutions + aln.insertions + aln.deletions, aln.edit_distance);
+
+            // Deletions consume query residues, insertions target residues
+            let count = |op: EditOp| aln.operations.iter().filter(|&&o| o == op).count();
+            let matched = count(EditOp::Match);
+            assert_eq!(matched + count(EditOp::Substitution) + count(EditOp::Deletion), a.len());
+            assert_eq!(matched + count(EditOp::Substitution) + count(EditOp::Insertion), b.len());
+            assert_eq!(
+                (count(EditOp::Substitution), count(EditOp::Insertion), count(EditOp::Deletion)),
+                (aln.substitutions, aln.insertions, aln.deletions)
+            );
+
+            let swapped = align(&b, &a);
+            assert_eq!(swapped.edit_distance, aln.edit_distance);
+        }
+    }
+
+    #[test]
+    fn test_scope_checks_agree_with_counted_operations() {
+        let mut rng = crate::random::Rng::new(3);
+        let neutral = ResidueHandling::new(AmbiguousResiduePolicy::Neutral, "");
+        let mut scratch = EditScratch::default();
+        for _ in 0..2000 {
+            let (a, b) = (random_sequence(&mut rng, b"ACDE", 12), random_sequence(&mut rng, b"ACDE", 12));
+            let total = rng.below(4);
+            let indels = rng.below(total + 1);
+            let scope = SearchScope { substitutions: total, insertions: indels, deletions: indels, total };
+            let aln = align(&a, &b);
+
+            let within = matches_within_scope(&Cdr3Sequence::new(a.clone()), &Cdr3Sequence::new(b.clone()), &scope);
+            let expected = if scope.is_exact() { a == b } else { aln.edit_distance <= scope.total };
+            assert_eq!(within, expected, "{} {} {}", a, b, scope);
+            // Counted operations within every limit are within the total as well
+            assert!(!aln.within_scope(&scope) || within);
+            assert_eq!(matches_within_scope_with(&a, &b, &scope, &ResidueHandling::default()), within);
+            assert_eq!(
+                matches_within_scope_prepared(&a, &b, &scope, &ResidueHandling::default(), &mut scratch),
+                within
+            );
+            assert_eq!(matches_within_scope_with(&b, &a, &scope, &neutral), matches_within_scope_with(&a, &b, &scope, &neutral));
+        }
+    }
+
+    #[test]
+    fn test_wildcard_distances() {
+        let mut rng = crate::random::Rng::new(4);
+        let neutral = ResidueHandling::new(AmbiguousResiduePolicy::Neutral, "");
+        for _ in 0..2000 {
+            let (a, b) = (random_sequence(&mut rng, b"ACDX*", 12), random_sequence(&mut rng, b"ACDX*", 12));
+            let wild = edit_distance_with(&a, &b, &neutral);
+            assert_eq!(wild, edit_distance_with(&b, &a, &neutral), "{} {}", a, b);
+            assert!(wild <= edit_distance(&a, &b));
+            assert_eq!(edit_distance_with(&a, &a, &neutral), 0);
+            assert_eq!(align_with(&a, &b, &neutral).edit_distance, wild);
+        }
     }
 }
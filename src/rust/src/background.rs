@@ -0,0 +1,205 @@
+use crate::random::Rng;
+use crate::sequence::{segment_calls, split_allele};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+
+/// V gene, J gene and CDR3 length a background clonotype is matched on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Stratum {
+    pub v_gene: String,
+    pub j_gene: String,
+    pub length: usize,
+}
+
+impl Stratum {
+    /// Gene-level stratum of a clonotype (first call of ambiguous segments, allele dropped)
+    pub fn of(cdr3: &str, v_segment: &str, j_segment: &str) -> Self {
+        let gene = |segment: &str| {
+            segment_calls(segment)
+                .first()
+                .map(|call| split_allele(call).0.to_uppercase())
+                .unwrap_or_default()
+        };
+        Self { v_gene: gene(v_segment), j_gene: gene(j_segment), length: cdr3.len() }
+    }
+}
+
+/// Background sample drawn from a clonotype pool
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackgroundSample {
+    /// Indices into the pool, grouped by stratum in order of first query appearance
+    pub indices: Vec<usize>,
+    /// Index of the query stratum each sampled clonotype stands in for
+    /// (into `strata`)
+    pub stratum: Vec<usize>,
+    /// Whether the clonotype only matches the stratum's CDR3 length (relaxed fill)
+    pub relaxed: Vec<bool>,
+    /// Query strata in order of first appearance, with the number requested
+    pub strata: Vec<(Stratum, usize)>,
+}
+
+impl BackgroundSample {
+    /// Requested minus sampled clonotypes, summed over strata
+    pub fn shortfall(&self) -> usize {
+        let requested: usize = self.strata.iter().map(|(_, n)| n).sum();
+        requested - self.indices.len()
+    }
+}
+
+/// Reservoir key of weighted sampling without replacement (Efraimidis-Spirakis):
+/// ln(u) / w, keeping the largest keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Key(f64);
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Fixed-size weighted reservoir
+struct Reservoir {
+    size: usize,
+    heap: BinaryHeap<Reverse<(Key, usize)>>,
+}
+
+impl Reservoir {
+    fn new(size: usize) -> Self {
+        Self { size, heap: BinaryHeap::with_capacity(size) }
+    }
+
+    fn offer(&mut self, rng: &mut Rng, index: usize, weight: f64) {
+        if self.size == 0 || weight.is_nan() || weight <= 0.0 {
+            return;
+        }
+        // 1 - u is in (0, 1], so the key is finite
+        let key = Key((1.0 - rng.next_f64()).ln() / weight);
+        if self.heap.len() < self.size {
+            self.heap.push(Reverse((key, index)));
+        } else if self.heap.peek().is_some_and(|Reverse((min, _))| key > *min) {
+            self.heap.pop();
+            self.heap.push(Reverse((key, index)));
+        }
+    }
+
+    fn into_indices(self) -> Vec<usize> {
+        let mut picked: Vec<(Key, usize)> = self.heap.into_iter().map(|Reverse(entry)| entry).collect();
+        picked.sort_by(|a, b| b.cmp(a));
+        picked.into_iter().map(|(_, index)| index).collect()
+    }
+}
+
+/// Sample `per_query` pool clonotypes for every query, matched on the query's
+/// V gene, J gene and CDR3 length
+/// Pool clonotypes are drawn without replacement with probability proportional
+/// to `weight` (e.g. clone counts; zero or NaN weights are never drawn) in one
+/// stratified reservoir pass over the pool. With `relax`, strata the pool cannot
+/// fill are topped up with clonotypes of the same CDR3 length only.
+/// `query` and `pool` hold (cdr3, v_segment, j_segment).
+pub fn sample_background(
+    query: &[(&str, &str, &str)],
+    pool: &[(&str, &str, &str)],
+    weight: &[f64],
+    per_query: usize,
+    relax: bool,
+    rng: &mut Rng,
+) -> BackgroundSample {
+    let mut strata: Vec<(Stratum, usize)> = Vec::new();
+    let mut stratum_index: HashMap<Stratum, usize> = HashMap::new();
+    for &(cdr3, v, j) in query {
+        let stratum = Stratum::of(cdr3, v, j);
+        match stratum_index.get(&stratum) {
+            Some(&s) => strata[s].1 += per_query,
+            None => {
+                stratum_index.insert(stratum.clone(), strata.len());
+                strata.push((stratum, per_query));
+            }
+        }
+    }
+
+    let mut exact: Vec<Reservoir> = strata.iter().map(|(_, n)| Reservoir::new(*n)).collect();
+    // Length-only reservoirs, sized for the largest possible shortfall
+    let mut by_length: HashMap<usize, usize> = HashMap::new();
+    if relax {
+        for (stratum, n) in &strata {
+            *by_length.entry(stratum.length).or_insert(0) += n;
+        }
+    }
+    let mut loose: HashMap<usize, Reservoir> = by_length.iter().map(|(&len, &n)| (len, Reservoir::new(n))).collect();
+
+    for (i, &(cdr3, v, j)) in pool.iter().enumerate() {
+        let w = weight.get(i).copied().unwrap_or(1.0);
+        let stratum = Stratum::of(cdr3, v, j);
+        if let Some(&s) = stratum_index.get(&stratum) {
+            exact[s].offer(rng, i, w);
+        } else if let Some(reservoir) = loose.get_mut(&stratum.length) {
+            reservoir.offer(rng, i, w);
+        }
+    }
+
+    let mut sample = BackgroundSample { indices: Vec::new(), stratum: Vec::new(), relaxed: Vec::new(), strata };
+    let mut spare: HashMap<usize, Vec<usize>> =
+        loose.into_iter().map(|(len, reservoir)| (len, reservoir.into_indices())).collect();
+    for (s, reservoir) in exact.into_iter().enumerate() {
+        let picked = reservoir.into_indices();
+        let (stratum, requested) = &sample.strata[s];
+        let missing = requested - picked.len();
+        let n_exact = picked.len();
+        sample.indices.extend(picked);
+        sample.relaxed.extend(std::iter::repeat(false).take(n_exact));
+        if missing > 0 {
+            if let Some(pool) = spare.get_mut(&stratum.length) {
+                let take = missing.min(pool.len());
+                sample.indices.extend(pool.drain(..take));
+                sample.relaxed.extend(std::iter::repeat(true).take(take));
+            }
+        }
+        sample.stratum.resize(sample.indices.len(), s);
+    }
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_background() {
+        let query = [("CASSLGF", "TRBV12-3*01", "TRBJ2-7"), ("CASSQDF", "TRBV12-3", "TRBJ2-7"), ("CAVRDNF", "TRAV12-1", "TRAJ7")];
+        let pool = [
+            ("CASRPGF", "TRBV12-3", "TRBJ2-7*01"),
+            ("CASRQGF", "TRBV12-3", "TRBJ2-7"),
+            ("CASRGGF", "TRBV12-3", "TRBJ2-7"),
+            ("CASSGGF", "TRBV19", "TRBJ2-7"),
+            ("CASSLF", "TRBV12-3", "TRBJ2-7"),
+            ("CAVSDNF", "TRAV12-1", "TRAJ7"),
+        ];
+        let weight = [1.0, 1.0, 0.0, 1.0, 1.0, 1.0];
+
+        let sample = sample_background(&query, &pool, &weight, 1, false, &mut Rng::new(1));
+        assert_eq!(sample.strata.len(), 2);
+        assert_eq!(sample.strata[0].1, 2);
+        let mut first: Vec<usize> = sample.indices[..2].to_vec();
+        first.sort();
+        assert_eq!(first, [0, 1]); // index 2 has zero weight, 3 and 4 differ in V or length
+        assert_eq!(sample.indices[2], 5);
+        assert_eq!(sample.stratum, [0, 0, 1]);
+        assert_eq!(sample.shortfall(), 0);
+
+        let sample = sample_background(&query, &pool, &weight, 3, true, &mut Rng::new(1));
+        assert_eq!(sample.indices.iter().filter(|&&i| i == 3).count(), 1);
+        assert_eq!(sample.relaxed.iter().filter(|&&r| r).count(), 1);
+        assert_eq!(sample.shortfall(), 6 - 3 + 3 - 1);
+
+        let again = sample_background(&query, &pool, &weight, 3, true, &mut Rng::new(1));
+        assert_eq!(again, sample);
+    }
+}
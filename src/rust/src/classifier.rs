@@ -0,0 +1,113 @@
+use crate::tcrdist::cdr3_neighbors;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Epitope prediction for a single query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prediction {
+    /// Winning label, or None if no reference lies within the distance cutoff
+    pub label: Option<String>,
+    /// (winner weight - runner-up weight) / total weight, in [0, 1]
+    pub margin: f64,
+    /// Distance to the closest reference TCR
+    pub nearest_distance: Option<f64>,
+    /// Number of neighbors that voted
+    pub n_neighbors: usize,
+}
+
+/// Weighted k-nearest-neighbor classifier over CDR3 tcrdist
+pub struct KnnClassifier<'a> {
+    cdr3: &'a [String],
+    labels: &'a [String],
+}
+
+impl<'a> KnnClassifier<'a> {
+    /// Build from parallel slices of reference CDR3s and their epitope labels
+    pub fn new(cdr3: &'a [String], labels: &'a [String]) -> Result<Self, String> {
+        if cdr3.len() != labels.len() {
+            return Err("reference cdr3 and labels must have equal length".to_string());
+        }
+        Ok(Self { cdr3, labels })
+    }
+
+    /// Vote weight of a neighbor at the given distance
+    fn weight(distance: f64) -> f64 {
+        1.0 / (1.0 + distance)
+    }
+
+    /// Predict the label of one query from its `k` nearest references within `max_dist`
+    pub fn predict_one(&self, query: &str, k: usize, max_dist: f64) -> Prediction {
+        let mut neighbors = cdr3_neighbors(query, self.cdr3.iter().map(|s| s.as_str()), max_dist);
+        neighbors.truncate(k);
+
+        let mut votes: HashMap<&str, f64> = HashMap::new();
+        for &(idx, dist) in &neighbors {
+            *votes.entry(self.labels[idx].as_str()).or_insert(0.0) += Self::weight(dist);
+        }
+
+        // Rank labels by weight; ties broken alphabetically so results are deterministic
+        let mut ranked: Vec<(&str, f64)> = votes.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+        let total: f64 = ranked.iter().map(|(_, w)| w).sum();
+        let margin = match ranked.as_slice() {
+            [] => 0.0,
+            [_] => 1.0,
+            [first, second, ..] => (first.1 - second.1) / total,
+        };
+
+        Prediction {
+            label: ranked.first().map(|(label, _)| label.to_string()),
+            margin,
+            nearest_distance: neighbors.first().map(|&(_, d)| d),
+            n_neighbors: neighbors.len(),
+        }
+    }
+
+    /// Predict labels for many queries in parallel
+    pub fn predict(&self, queries: &[String], k: usize, max_dist: f64) -> Vec<Prediction> {
+        queries
+            .par_iter()
+            .map(|q| self.predict_one(q, k, max_dist))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_predict_majority_label() {
+        let cdr3 = strings(&["CASSLGQAYEQYF", "CASSLGQAYEQFF", "CASSIRSSYEQYF"]);
+        let labels = strings(&["GLCTLVAML", "GLCTLVAML", "NLVPMVATV"]);
+        let clf = KnnClassifier::new(&cdr3, &labels).unwrap();
+
+        let pred = clf.predict_one("CASSLGQAYEQYF", 3, 100.0);
+        assert_eq!(pred.label.as_deref(), Some("GLCTLVAML"));
+        assert_eq!(pred.nearest_distance, Some(0.0));
+        assert!(pred.margin > 0.0 && pred.margin <= 1.0);
+    }
+
+    #[test]
+    fn test_predict_no_neighbors() {
+        let cdr3 = strings(&["CASSLGQAYEQYF"]);
+        let labels = strings(&["GLCTLVAML"]);
+        let clf = KnnClassifier::new(&cdr3, &labels).unwrap();
+
+        let pred = clf.predict_one("CAVRDGGNKLTF", 5, 10.0);
+        assert_eq!(pred.label, None);
+        assert_eq!(pred.n_neighbors, 0);
+        assert_eq!(pred.margin, 0.0);
+    }
+
+    #[test]
+    fn test_mismatched_reference_lengths() {
+        let cdr3 = strings(&["CASSF"]);
+        assert!(KnnClassifier::new(&cdr3, &[]).is_err());
+    }
+}
@@ -0,0 +1,653 @@
+use crate::random::Rng;
+use crate::tcrdist::cdr3_distance;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Consensus and representative for one cluster of CDR3s
+#[derive(Debug, Clone)]
+pub struct ClusterSummary {
+    pub cluster: String,
+    pub size: usize,
+    /// Per-position majority residue over members of the modal length
+    pub consensus: String,
+    /// Fraction of modal-length members carrying the consensus residue at each position
+    pub conservation: Vec<f64>,
+    /// Index (into the input) of the member with minimum summed tcrdist to the others
+    pub medoid_index: usize,
+    pub medoid: String,
+}
+
+/// Group input indices by cluster label, keeping clusters in first-appearance order
+fn group_by_label(labels: &[String]) -> Vec<(String, Vec<usize>)> {
+    let mut order: Vec<(String, Vec<usize>)> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, label) in labels.iter().enumerate() {
+        match index.get(label.as_str()) {
+            Some(&g) => order[g].1.push(i),
+            None => {
+                index.insert(label.as_str(), order.len());
+                order.push((label.clone(), vec![i]));
+            }
+        }
+    }
+    order
+}
+
+/// Length-mode consensus sequence and per-position conservation
+/// Ties are broken towards the shorter length and the alphabetically first residue.
+pub fn consensus(seqs: &[&str]) -> (String, Vec<f64>) {
+    let mut length_counts: HashMap<usize, usize> = HashMap::new();
+    for s in seqs {
+        *length_counts.entry(s.len()).or_insert(0) += 1;
+    }
+    let mode_len = match length_counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+    {
+        Some((len, _)) => len,
+        None => return (String::new(), Vec::new()),
+    };
+
+    let modal: Vec<&[u8]> = seqs
+        .iter()
+        .filter(|s| s.len() == mode_len)
+        .map(|s| s.as_bytes())
+        .collect();
+
+    let mut consensus = String::with_capacity(mode_len);
+    let mut conservation = Vec::with_capacity(mode_len);
+    for pos in 0..mode_len {
+        let mut counts = [0usize; 256];
+        for s in &modal {
+            counts[s[pos].to_ascii_uppercase() as usize] += 1;
+        }
+        let (aa, count) = counts
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(&a.0)))
+            .map(|(aa, &count)| (aa as u8, count))
+            .unwrap_or((b'X', 0));
+        consensus.push(aa as char);
+        conservation.push(count as f64 / modal.len() as f64);
+    }
+
+    (consensus, conservation)
+}
+
+/// Index within `members` of the sequence with minimum summed CDR3 tcrdist to all others
+pub fn medoid(members: &[&str]) -> Option<usize> {
+    (0..members.len()).min_by_key(|&i| {
+        members
+            .iter()
+            .map(|other| cdr3_distance(members[i], other) as i64)
+            .sum::<i64>()
+    })
+}
+
+/// Summarize each cluster with its consensus CDR3 and medoid representative
+pub fn summarize_clusters(
+    cdr3: &[String],
+    labels: &[String],
+) -> Result<Vec<ClusterSummary>, String> {
+    if cdr3.len() != labels.len() {
+        return Err("cdr3 and cluster labels must have equal length".to_string());
+    }
+
+    let groups = group_by_label(labels);
+    Ok(groups
+        .into_par_iter()
+        .map(|(cluster, idx)| {
+            let members: Vec<&str> = idx.iter().map(|&i| cdr3[i].as_str()).collect();
+            let (consensus, conservation) = consensus(&members);
+            let local = medoid(&members).unwrap_or(0);
+            ClusterSummary {
+                cluster,
+                size: members.len(),
+                consensus,
+                conservation,
+                medoid_index: idx[local],
+                medoid: members[local].to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Cluster distance update of agglomerative clustering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    Single,
+    Complete,
+    /// UPGMA
+    Average,
+}
+
+impl Linkage {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "single" => Ok(Self::Single),
+            "complete" => Ok(Self::Complete),
+            "average" | "upgma" => Ok(Self::Average),
+            _ => Err(format!("Invalid linkage: {} (expected average, complete or single)", s)),
+        }
+    }
+
+    /// Lance-Williams distance from cluster k to the union of a and b
+    fn update(self, d_ka: f64, d_kb: f64, size_a: usize, size_b: usize) -> f64 {
+        match self {
+            Self::Single => d_ka.min(d_kb),
+            Self::Complete => d_ka.max(d_kb),
+            Self::Average => (size_a as f64 * d_ka + size_b as f64 * d_kb) / (size_a + size_b) as f64,
+        }
+    }
+}
+
+/// Agglomerative clustering tree in R's `hclust` conventions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dendrogram {
+    /// Merged pair per step: -(i + 1) for observation i, s for the cluster
+    /// formed at (1-based) step s; singletons first, then smaller ids
+    pub merge: Vec<(i64, i64)>,
+    /// Non-decreasing merge heights
+    pub height: Vec<f64>,
+    /// Leaf order for plotting (0-based)
+    pub order: Vec<usize>,
+}
+
+/// Index of pair (i, j), i < j, in a condensed distance vector (R `dist` order)
+fn condensed_index(n: usize, i: usize, j: usize) -> usize {
+    n * i - i * (i + 1) / 2 + (j - i - 1)
+}
+
+/// Condensed distances from a condensed (n(n-1)/2) or full (n x n) vector
+pub fn condensed_distances(distances: &[f64], n: usize) -> Result<Vec<f64>, String> {
+    let condensed = to_condensed(distances, n)?;
+    if condensed.iter().any(|d| !d.is_finite()) {
+        return Err("distances must be finite (no NA)".to_string());
+    }
+    Ok(condensed)
+}
+
+/// `condensed_distances` keeping non-finite (NA) distances
+fn to_condensed(distances: &[f64], n: usize) -> Result<Vec<f64>, String> {
+    let n_pairs = n * n.saturating_sub(1) / 2;
+    let condensed = if distances.len() == n_pairs {
+        distances.to_vec()
+    } else if distances.len() == n * n {
+        (0..n).flat_map(|i| (i + 1..n).map(move |j| distances[i * n + j])).collect()
+    } else {
+        return Err(format!(
+            "expected {} (condensed) or {} (full) distances for n = {}, got {}",
+            n_pairs,
+            n * n,
+            n,
+            distances.len()
+        ));
+    };
+    Ok(condensed)
+}
+
+/// Weighted mean distance between the members of two clusters (or within one)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterDistance {
+    pub cluster_a: String,
+    pub cluster_b: String,
+    /// None when no pair of members has a finite distance
+    pub distance: Option<f64>,
+    pub n_pairs: usize,
+    /// Summed pair weight `weights[i] * weights[j]`
+    pub weight: f64,
+}
+
+/// Mean distance between every pair of clusters and within each cluster
+/// (distinct members only), weighting pair (i, j) by `weights[i] * weights[j]`
+/// so expanded clones dominate. Non-finite (NA) distances are skipped; clusters
+/// are in first-appearance order with `cluster_a` <= `cluster_b`.
+pub fn weighted_cluster_distances(
+    distances: &[f64],
+    n: usize,
+    labels: &[String],
+    weights: &[f64],
+) -> Result<Vec<ClusterDistance>, String> {
+    if labels.len() != n || weights.len() != n {
+        return Err(format!(
+            "clusters and clone sizes must have one value per TCR (n = {}), got {} and {}",
+            n,
+            labels.len(),
+            weights.len()
+        ));
+    }
+    let condensed = to_condensed(distances, n)?;
+    let groups = group_by_label(labels);
+    let k = groups.len();
+    let mut group_of = vec![0usize; n];
+    for (g, (_, members)) in groups.iter().enumerate() {
+        for &i in members {
+            group_of[i] = g;
+        }
+    }
+
+    // Upper-triangle (a <= b) accumulators: weighted sum, weight, pair count
+    let mut sums = vec![(0.0f64, 0.0f64, 0usize); k * k];
+    for i in 0..n {
+        for j in i + 1..n {
+            let d = condensed[condensed_index(n, i, j)];
+            if !d.is_finite() {
+                continue;
+            }
+            let (a, b) = (group_of[i].min(group_of[j]), group_of[i].max(group_of[j]));
+            let w = weights[i] * weights[j];
+            let acc = &mut sums[a * k + b];
+            acc.0 += w * d;
+            acc.1 += w;
+            acc.2 += 1;
+        }
+    }
+
+    let mut out = Vec::with_capacity(k * (k + 1) / 2);
+    for a in 0..k {
+        for b in a..k {
+            let (sum, weight, n_pairs) = sums[a * k + b];
+            out.push(ClusterDistance {
+                cluster_a: groups[a].0.clone(),
+                cluster_b: groups[b].0.clone(),
+                distance: (weight > 0.0).then(|| sum / weight),
+                n_pairs,
+                weight,
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// Agglomerative clustering of n observations from condensed distances
+/// Uses the nearest-neighbor chain algorithm (O(n^2) time and memory, no
+/// matrix copies beyond the condensed vector), valid for all `Linkage`s.
+pub fn hierarchical(condensed: &[f64], n: usize, linkage: Linkage) -> Dendrogram {
+    let mut d = condensed.to_vec();
+    let dist = |d: &[f64], i: usize, j: usize| if i < j { d[condensed_index(n, i, j)] } else { d[condensed_index(n, j, i)] };
+    let mut size = vec![1usize; n];
+    let mut active = vec![true; n];
+    // (slot a, slot b, height); the union is kept in slot b
+    let mut steps: Vec<(usize, usize, f64)> = Vec::with_capacity(n.saturating_sub(1));
+    let mut chain: Vec<usize> = Vec::new();
+
+    while steps.len() + 1 < n {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|&a| a).unwrap());
+        }
+        let a = *chain.last().unwrap();
+        let previous = chain.len().checked_sub(2).map(|k| chain[k]);
+        // Ties go to the previous chain element so the chain always terminates
+        let mut best = previous;
+        let mut best_d = previous.map_or(f64::INFINITY, |p| dist(&d, a, p));
+        for k in (0..n).filter(|&k| active[k] && k != a) {
+            let dk = dist(&d, a, k);
+            if dk < best_d {
+                best = Some(k);
+                best_d = dk;
+            }
+        }
+        let b = best.unwrap();
+        if Some(b) != previous {
+            chain.push(b);
+            continue;
+        }
+
+        chain.truncate(chain.len() - 2);
+        for k in (0..n).filter(|&k| active[k] && k != a && k != b) {
+            let updated = linkage.update(dist(&d, k, a), dist(&d, k, b), size[a], size[b]);
+            let idx = if k < b { condensed_index(n, k, b) } else { condensed_index(n, b, k) };
+            d[idx] = updated;
+        }
+        active[a] = false;
+        size[b] += size[a];
+        steps.push((a, b, best_d));
+    }
+
+    // Order steps by height and label clusters through the merges
+    steps.sort_by(|x, y| x.2.total_cmp(&y.2));
+    let mut label: Vec<i64> = (0..n).map(|i| -(i as i64 + 1)).collect();
+    let mut parent: Vec<usize> = (0..n).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut merge = Vec::with_capacity(steps.len());
+    let mut height = Vec::with_capacity(steps.len());
+    for (s, &(a, b, h)) in steps.iter().enumerate() {
+        let (ra, rb) = (root(&mut parent, a), root(&mut parent, b));
+        let (la, lb) = (label[ra], label[rb]);
+        let pair = match (la < 0, lb < 0) {
+            (true, true) => (la.max(lb), la.min(lb)),
+            (true, false) => (la, lb),
+            (false, true) => (lb, la),
+            (false, false) => (la.min(lb), la.max(lb)),
+        };
+        merge.push(pair);
+        height.push(h);
+        parent[ra] = rb;
+        label[rb] = s as i64 + 1;
+    }
+
+    let mut order = Vec::with_capacity(n);
+    if let Some(&last) = merge.last() {
+        let mut stack = vec![last.1, last.0];
+        while let Some(node) = stack.pop() {
+            if node < 0 {
+                order.push((-node - 1) as usize);
+            } else {
+                let (l, r) = merge[node as usize - 1];
+                stack.push(r);
+                stack.push(l);
+            }
+        }
+    } else {
+        order.extend(0..n);
+    }
+    Dendrogram { merge, height, order }
+}
+
+/// Undirected weighted graph for community detection
+/// `self_loops[i]` is A_ii: twice the internal weight of an aggregated node.
+#[derive(Debug, Clone)]
+struct Graph {
+    adjacency: Vec<Vec<(usize, f64)>>,
+    self_loops: Vec<f64>,
+}
+
+impl Graph {
+    fn from_edges(n: usize, edges: &[(usize, usize, f64)]) -> Self {
+        let mut adjacency = vec![Vec::new(); n];
+        let mut self_loops = vec![0.0; n];
+        for &(i, j, w) in edges {
+            if i == j {
+                self_loops[i] += 2.0 * w;
+            } else {
+                adjacency[i].push((j, w));
+                adjacency[j].push((i, w));
+            }
+        }
+        Self { adjacency, self_loops }
+    }
+
+    fn len(&self) -> usize {
+        self.adjacency.len()
+    }
+
+    fn degrees(&self) -> Vec<f64> {
+        self.adjacency
+            .iter()
+            .zip(&self.self_loops)
+            .map(|(edges, own)| own + edges.iter().map(|e| e.1).sum::<f64>())
+            .collect()
+    }
+
+    /// Graph of communities (numbered 0..k) with summed edge weights
+    fn aggregate(&self, community: &[usize], k: usize) -> Self {
+        let mut weights: Vec<HashMap<usize, f64>> = vec![HashMap::new(); k];
+        let mut self_loops = vec![0.0; k];
+        for (i, edges) in self.adjacency.iter().enumerate() {
+            let ci = community[i];
+            self_loops[ci] += self.self_loops[i];
+            for &(j, w) in edges {
+                let cj = community[j];
+                if ci == cj {
+                    self_loops[ci] += w;
+                } else {
+                    *weights[ci].entry(cj).or_insert(0.0) += w;
+                }
+            }
+        }
+        let adjacency = weights
+            .into_iter()
+            .map(|w| {
+                let mut edges: Vec<(usize, f64)> = w.into_iter().collect();
+                edges.sort_by_key(|e| e.0);
+                edges
+            })
+            .collect();
+        Self { adjacency, self_loops }
+    }
+
+    /// Move nodes between communities while modularity improves (Louvain phase 1)
+    /// Nodes are visited in a random order; returns whether any node moved.
+    fn local_moving(&self, community: &mut [usize], resolution: f64, rng: &mut Rng) -> bool {
+        let degree = self.degrees();
+        let m2: f64 = degree.iter().sum();
+        let mut total = vec![0.0; self.len()];
+        for (i, &c) in community.iter().enumerate() {
+            total[c] += degree[i];
+        }
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        rng.shuffle(&mut order);
+
+        let mut moved_any = false;
+        let mut links: HashMap<usize, f64> = HashMap::new();
+        loop {
+            let mut moved = false;
+            for &i in &order {
+                let current = community[i];
+                links.clear();
+                links.insert(current, 0.0);
+                for &(j, w) in &self.adjacency[i] {
+                    *links.entry(community[j]).or_insert(0.0) += w;
+                }
+                total[current] -= degree[i];
+                let gain = |c: usize, w: f64| w - resolution * total[c] * degree[i] / m2;
+                let mut best = (current, gain(current, links[&current]));
+                let mut candidates: Vec<(usize, f64)> = links.iter().map(|(&c, &w)| (c, w)).collect();
+                candidates.sort_by_key(|c| c.0);
+                for (c, w) in candidates {
+                    let g = gain(c, w);
+                    if g > best.1 + 1e-12 {
+                        best = (c, g);
+                    }
+                }
+                total[best.0] += degree[i];
+                if best.0 != current {
+                    community[i] = best.0;
+                    moved = true;
+                    moved_any = true;
+                }
+            }
+            if !moved {
+                return moved_any;
+            }
+        }
+    }
+}
+
+/// Renumber labels 0..k in order of first appearance; returns k
+fn renumber(labels: &mut [usize]) -> usize {
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    for label in labels.iter_mut() {
+        let next = index.len();
+        *label = *index.entry(*label).or_insert(next);
+    }
+    index.len()
+}
+
+/// Communities of a similarity graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct Communities {
+    /// Community of each node, numbered 0.. in order of first appearance
+    pub membership: Vec<usize>,
+    pub n_communities: usize,
+    pub modularity: f64,
+}
+
+/// Newman-Girvan modularity of a partition, with a resolution parameter
+fn modularity(graph: &Graph, community: &[usize], resolution: f64) -> f64 {
+    let degree = graph.degrees();
+    let m2: f64 = degree.iter().sum();
+    if m2 <= 0.0 {
+        return 0.0;
+    }
+    let k = community.iter().max().map_or(0, |&c| c + 1);
+    let mut internal = vec![0.0; k];
+    let mut total = vec![0.0; k];
+    for (i, edges) in graph.adjacency.iter().enumerate() {
+        let c = community[i];
+        total[c] += degree[i];
+        internal[c] += graph.self_loops[i];
+        internal[c] += edges.iter().filter(|e| community[e.0] == c).map(|e| e.1).sum::<f64>();
+    }
+    internal.iter().zip(&total).map(|(inside, tot)| inside / m2 - resolution * (tot / m2).powi(2)).sum()
+}
+
+/// Split communities into their connected components within the community
+fn split_disconnected(graph: &Graph, community: &mut [usize]) {
+    let n = graph.len();
+    let mut component = vec![usize::MAX; n];
+    let mut next = 0;
+    for start in 0..n {
+        if component[start] != usize::MAX {
+            continue;
+        }
+        component[start] = next;
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            for &(j, _) in &graph.adjacency[i] {
+                if component[j] == usize::MAX && community[j] == community[start] {
+                    component[j] = next;
+                    stack.push(j);
+                }
+            }
+        }
+        next += 1;
+    }
+    community.copy_from_slice(&component);
+}
+
+/// Louvain community detection over a weighted neighbor graph of `n` nodes
+/// `edges` are (i, j, weight) with positive similarity weights (e.g. from
+/// tcrdist neighbor pairs). Local moving and aggregation repeat until no node
+/// moves; with `connected`, communities are finally split into connected parts
+/// (Louvain can merge nodes that only connect through other communities, which
+/// Leiden's refinement prevents). Node orders are drawn from `rng`, so results
+/// are reproducible for a seed. Nodes without edges form singleton communities.
+pub fn louvain(n: usize, edges: &[(usize, usize, f64)], resolution: f64, connected: bool, rng: &mut Rng) -> Communities {
+    let graph = Graph::from_edges(n, edges);
+    let mut membership: Vec<usize> = (0..n).collect();
+    let mut level = graph.clone();
+    loop {
+        let mut community: Vec<usize> = (0..level.len()).collect();
+        if !level.local_moving(&mut community, resolution, rng) {
+            break;
+        }
+        let k = renumber(&mut community);
+        for m in membership.iter_mut() {
+            *m = community[*m];
+        }
+        level = level.aggregate(&community, k);
+    }
+    if connected {
+        split_disconnected(&graph, &mut membership);
+    }
+    let n_communities = renumber(&mut membership);
+    let modularity = modularity(&graph, &membership, resolution);
+    Communities { membership, n_communities, modularity }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus_uses_modal_length() {
+        let (cons, cons_score) = consensus(&["CASSLF", "CASSLY", "CASRLF", "CASF"]);
+        assert_eq!(cons, "CASSLF");
+        assert_eq!(cons_score.len(), 6);
+        assert_eq!(cons_score[0], 1.0);
+        assert!((cons_score[3] - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_summarize_clusters() {
+        let cdr3: Vec<String> = ["CASSLF", "CASSLY", "CASSIF", "CAVRDF"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let labels: Vec<String> = ["1", "1", "1", "2"].iter().map(|s| s.to_string()).collect();
+
+        let summaries = summarize_clusters(&cdr3, &labels).unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].cluster, "1");
+        assert_eq!(summaries[0].size, 3);
+        assert_eq!(summaries[0].medoid, "CASSLF");
+        assert_eq!(summaries[1].medoid_index, 3);
+    }
+
+    #[test]
+    fn test_hierarchical() {
+        // Points on a line: 0, 1, 5, 6, 20
+        let x = [0.0f64, 1.0, 5.0, 6.0, 20.0];
+        let n = x.len();
+        let full: Vec<f64> = (0..n * n).map(|k| (x[k / n] - x[k % n]).abs()).collect();
+        let condensed = condensed_distances(&full, n).unwrap();
+        assert_eq!(condensed.len(), 10);
+        assert_eq!(condensed_distances(&condensed, n).unwrap(), condensed);
+        assert!(condensed_distances(&condensed[1..], n).is_err());
+
+        let tree = hierarchical(&condensed, n, Linkage::Single);
+        assert_eq!(tree.merge, [(-1, -2), (-3, -4), (1, 2), (-5, 3)]);
+        assert_eq!(tree.height, [1.0, 1.0, 4.0, 14.0]);
+        assert_eq!(tree.order, [4, 0, 1, 2, 3]);
+
+        let tree = hierarchical(&condensed, n, Linkage::Complete);
+        assert_eq!(tree.height, [1.0, 1.0, 6.0, 20.0]);
+        let tree = hierarchical(&condensed, n, Linkage::Average);
+        assert_eq!(tree.height[2], 5.0);
+        assert!((tree.height[3] - (20.0 + 19.0 + 15.0 + 14.0) / 4.0).abs() < 1e-9);
+
+        assert_eq!(hierarchical(&[], 1, Linkage::Average).order, [0]);
+    }
+
+    #[test]
+    fn test_louvain() {
+        // Two 4-cliques joined by one weak edge, plus an isolated node
+        let mut edges = Vec::new();
+        for block in [0, 4] {
+            for i in block..block + 4 {
+                for j in i + 1..block + 4 {
+                    edges.push((i, j, 1.0));
+                }
+            }
+        }
+        edges.push((3, 4, 0.1));
+        let result = louvain(9, &edges, 1.0, true, &mut Rng::new(1));
+        assert_eq!(result.membership, [0, 0, 0, 0, 1, 1, 1, 1, 2]);
+        assert_eq!(result.n_communities, 3);
+        assert!(result.modularity > 0.4 && result.modularity < 0.5);
+        assert_eq!(louvain(9, &edges, 1.0, true, &mut Rng::new(7)).membership, result.membership);
+
+        // Very low resolution merges the cliques
+        assert_eq!(louvain(9, &edges, 0.01, true, &mut Rng::new(1)).n_communities, 2);
+        assert_eq!(louvain(3, &[], 1.0, true, &mut Rng::new(1)).membership, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_weighted_cluster_distances() {
+        // Clusters "a" = {0, 1}, "b" = {2}; clone sizes 1, 3, 2
+        let full = [0.0, 2.0, 10.0, 2.0, 0.0, 20.0, 10.0, 20.0, 0.0];
+        let labels: Vec<String> = ["a", "a", "b"].iter().map(|s| s.to_string()).collect();
+        let res = weighted_cluster_distances(&full, 3, &labels, &[1.0, 3.0, 2.0]).unwrap();
+
+        assert_eq!(res.len(), 3);
+        assert_eq!((res[0].cluster_a.as_str(), res[0].cluster_b.as_str()), ("a", "a"));
+        assert_eq!((res[0].distance, res[0].n_pairs, res[0].weight), (Some(2.0), 1, 3.0));
+        // (1*2*10 + 3*2*20) / (2 + 6)
+        assert_eq!(res[1].distance, Some(17.5));
+        // A singleton has no within-cluster pairs
+        assert_eq!((res[2].distance, res[2].n_pairs), (None, 0));
+
+        let mut with_na = full;
+        with_na[2] = f64::NAN;
+        let res = weighted_cluster_distances(&with_na, 3, &labels, &[1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(res[1].distance, Some(20.0));
+        assert!(weighted_cluster_distances(&full, 3, &labels[1..], &[1.0; 3]).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::expansion::benjamini_hochberg;
+use crate::matching::BatchMatches;
+use crate::sequence::Clonotype;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// One cell of the sample x epitope hit matrix
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharingCell {
+    pub sample: String,
+    pub epitope: String,
+    /// Distinct query clonotypes in the sample with at least one hit to the epitope
+    pub n_clonotypes: usize,
+    /// Summed frequency of those clonotypes (None when no frequency is known)
+    pub frequency: Option<f64>,
+    /// Number of samples with at least one hit to the epitope
+    pub n_samples: usize,
+}
+
+/// Sample x epitope hit sharing from stacked per-sample hits
+/// Each hit row gives its sample, a query identifier (unique within the sample),
+/// the hit epitope and the query frequency (NaN when unknown). A query hitting
+/// an epitope through several database entries counts once.
+/// Every sample x epitope combination is returned, epitopes ordered by the
+/// number of samples they are found in; samples listed in `samples` come first
+/// so samples without hits still get (zero) rows.
+pub fn hit_sharing(
+    sample: &[String],
+    query_id: &[String],
+    epitope: &[String],
+    frequency: &[f64],
+    samples: &[String],
+) -> Result<Vec<SharingCell>, String> {
+    let n = sample.len();
+    if query_id.len() != n || epitope.len() != n || frequency.len() != n {
+        return Err("sample, query_id, epitope and frequency must have equal length".into());
+    }
+
+    let mut sample_order: Vec<&str> = Vec::new();
+    let mut seen_samples: HashSet<&str> = HashSet::new();
+    for s in samples.iter().chain(sample) {
+        if seen_samples.insert(s.as_str()) {
+            sample_order.push(s);
+        }
+    }
+
+    // (sample, epitope) -> query -> frequency
+    let mut cells: HashMap<(&str, &str), HashMap<&str, f64>> = HashMap::new();
+    for i in 0..n {
+        if epitope[i].is_empty() {
+            continue;
+        }
+        cells
+            .entry((sample[i].as_str(), epitope[i].as_str()))
+            .or_default()
+            .entry(query_id[i].as_str())
+            .or_insert(frequency[i]);
+    }
+
+    let mut epitope_samples: HashMap<&str, usize> = HashMap::new();
+    for &(_, e) in cells.keys() {
+        *epitope_samples.entry(e).or_insert(0) += 1;
+    }
+    let mut epitopes: Vec<(&str, usize)> = epitope_samples.into_iter().collect();
+    epitopes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut table = Vec::with_capacity(epitopes.len() * sample_order.len());
+    for &(e, n_samples) in &epitopes {
+        for &s in &sample_order {
+            let queries = cells.get(&(s, e));
+            let frequency = queries.and_then(|q| {
+                let known: Vec<f64> = q.values().copied().filter(|f| !f.is_nan()).collect();
+                (!known.is_empty()).then(|| known.iter().sum())
+            });
+            table.push(SharingCell {
+                sample: s.to_string(),
+                epitope: e.to_string(),
+                n_clonotypes: queries.map_or(0, |q| q.len()),
+                frequency: frequency.or(queries.is_none().then_some(0.0)),
+                n_samples,
+            });
+        }
+    }
+    Ok(table)
+}
+
+/// Hits of one epitope in two match results (e.g. tumor vs blood)
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpitopeComparison {
+    pub epitope: String,
+    /// Hit rows in each result
+    pub hits_a: usize,
+    pub hits_b: usize,
+    /// Distinct query clonotypes with at least one hit to the epitope
+    pub clonotypes_a: usize,
+    pub clonotypes_b: usize,
+    /// Hit clonotypes of the epitope found in both results
+    pub shared_clonotypes: usize,
+    /// Odds of a clonotype hitting the epitope in a over b, with 0.5 added to
+    /// every cell of the 2x2 table when one is empty
+    pub odds_ratio: f64,
+    /// Two-sided Fisher exact test of hit vs other clonotypes in a vs b
+    pub p_value: f64,
+    /// Benjamini-Hochberg adjusted `p_value` over the epitopes compared
+    pub q_value: f64,
+}
+
+/// Query clonotype as compared between results: CDR3 and V/J genes, allele
+/// and formatting of the segment names ignored
+fn clonotype_key(clonotype: &Clonotype) -> (String, String, String) {
+    (clonotype.cdr3_aa.sequence.clone(), clonotype.v_normalized(), clonotype.j_normalized())
+}
+
+/// Distinct clonotypes of a result, and the hit clonotypes and hit rows per epitope
+#[allow(clippy::type_complexity)]
+fn epitope_clonotypes(batch: &BatchMatches) -> (usize, HashMap<&str, (HashSet<(String, String, String)>, usize)>) {
+    let mut clonotypes = HashSet::new();
+    let mut epitopes: HashMap<&str, (HashSet<(String, String, String)>, usize)> = HashMap::new();
+    for (i, query) in batch.queries.iter().enumerate() {
+        let key = clonotype_key(query);
+        for hit in batch.query_hits(i) {
+            let entry = epitopes.entry(hit.db_entry.antigen_epitope.as_str()).or_default();
+            entry.0.insert(key.clone());
+            entry.1 += 1;
+        }
+        clonotypes.insert(key);
+    }
+    (clonotypes.len(), epitopes)
+}
+
+/// Per-epitope hit overlap of two match results with Fisher-exact enrichment
+/// Clonotypes are distinct (CDR3, V gene, J gene) queries; each epitope hit in
+/// either result gets a 2x2 table of its hit clonotypes vs the other
+/// clonotypes of a and b. Rows are ordered by p-value, then epitope.
+pub fn compare_annotations(a: &BatchMatches, b: &BatchMatches) -> Vec<EpitopeComparison> {
+    let (n_a, hits_a) = epitope_clonotypes(a);
+    let (n_b, hits_b) = epitope_clonotypes(b);
+    let empty = (HashSet::new(), 0);
+    let epitopes: BTreeMap<&str, ()> = hits_a.keys().chain(hits_b.keys()).map(|&e| (e, ())).collect();
+    let ln_factorials = ln_factorials(n_a + n_b);
+
+    let mut rows: Vec<EpitopeComparison> = epitopes
+        .keys()
+        .map(|&epitope| {
+            let (set_a, rows_a) = hits_a.get(epitope).unwrap_or(&empty);
+            let (set_b, rows_b) = hits_b.get(epitope).unwrap_or(&empty);
+            let (x_a, x_b) = (set_a.len(), set_b.len());
+            let table = [x_a, n_a - x_a, x_b, n_b - x_b];
+            EpitopeComparison {
+                epitope: epitope.to_string(),
+                hits_a: *rows_a,
+                hits_b: *rows_b,
+                clonotypes_a: x_a,
+                clonotypes_b: x_b,
+                shared_clonotypes: set_a.intersection(set_b).count(),
+                odds_ratio: odds_ratio(table),
+                p_value: fisher_exact(table, &ln_factorials),
+                q_value: f64::NAN,
+            }
+        })
+        .collect();
+    let q_values = benjamini_hochberg(&rows.iter().map(|r| r.p_value).collect::<Vec<_>>());
+    for (row, q) in rows.iter_mut().zip(q_values) {
+        row.q_value = q;
+    }
+    rows.sort_by(|x, y| x.p_value.total_cmp(&y.p_value).then_with(|| x.epitope.cmp(&y.epitope)));
+    rows
+}
+
+/// ln(k!) for k in 0..=n
+fn ln_factorials(n: usize) -> Vec<f64> {
+    let mut table = Vec::with_capacity(n + 1);
+    table.push(0.0);
+    for k in 1..=n {
+        table.push(table[k - 1] + (k as f64).ln());
+    }
+    table
+}
+
+/// Odds ratio of the 2x2 table [a, b; c, d], Haldane-corrected when a cell is 0
+fn odds_ratio([a, b, c, d]: [usize; 4]) -> f64 {
+    let [a, b, c, d] = [a, b, c, d].map(|x| x as f64);
+    if a == 0.0 || b == 0.0 || c == 0.0 || d == 0.0 {
+        ((a + 0.5) * (d + 0.5)) / ((b + 0.5) * (c + 0.5))
+    } else {
+        (a * d) / (b * c)
+    }
+}
+
+/// Two-sided Fisher exact p-value of the 2x2 table [a, b; c, d]: the summed
+/// probability of tables with the same margins no more likely than the observed
+/// one (with R's 1e-7 relative tolerance). `ln_factorials` covers the table total.
+pub fn fisher_exact([a, b, c, d]: [usize; 4], ln_factorials: &[f64]) -> f64 {
+    let (row1, col1, n) = (a + b, a + c, a + b + c + d);
+    let ln_f = |k: usize| ln_factorials[k];
+    let fixed = ln_f(row1) + ln_f(n - row1) + ln_f(col1) + ln_f(n - col1) - ln_f(n);
+    let ln_p = |x: usize| fixed - ln_f(x) - ln_f(row1 - x) - ln_f(col1 - x) - ln_f(n - row1 - col1 + x);
+    let observed = ln_p(a);
+    let low = (row1 + col1).saturating_sub(n);
+    let p: f64 = (low..=row1.min(col1))
+        .map(ln_p)
+        .filter(|&lp| lp <= observed + 1e-7_f64.ln_1p())
+        .map(f64::exp)
+        .sum();
+    p.min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hit_sharing() {
+        let sample = strings(&["s1", "s1", "s1", "s2"]);
+        let query = strings(&["1", "1", "2", "1"]);
+        let epitope = strings(&["GLCTLVAML", "GLCTLVAML", "NLVPMVATV", "GLCTLVAML"]);
+        let frequency = vec![0.1, 0.1, 0.05, f64::NAN];
+
+        let table = hit_sharing(&sample, &query, &epitope, &frequency, &strings(&["s0"])).unwrap();
+        assert_eq!(table.len(), 6);
+        assert_eq!(table[0].sample, "s0");
+        assert_eq!((table[0].n_clonotypes, table[0].frequency), (0, Some(0.0)));
+        assert_eq!(table[1].epitope, "GLCTLVAML");
+        assert_eq!((table[1].n_clonotypes, table[1].frequency, table[1].n_samples), (1, Some(0.1), 2));
+        assert_eq!((table[2].n_clonotypes, table[2].frequency), (1, None));
+        assert_eq!((table[4].epitope.as_str(), table[4].n_samples), ("NLVPMVATV", 1));
+    }
+
+    #[test]
+    fn test_fisher_exact() {
+        let ln_f = ln_factorials(40);
+        // R: fisher.test(matrix(c(3, 1, 1, 3), 2))$p.value
+        assert!((fisher_exact([3, 1, 1, 3], &ln_f) - 0.4857142857142857).abs() < 1e-12);
+        // R: fisher.test(matrix(c(10, 2, 3, 15), 2))$p.value
+        assert!((fisher_exact([10, 2, 3, 15], &ln_f) - 0.0005367241).abs() < 1e-9);
+        assert_eq!(fisher_exact([0, 5, 0, 5], &ln_f), 1.0);
+        assert_eq!(odds_ratio([2, 1, 1, 2]), 4.0);
+        assert_eq!(odds_ratio([1, 0, 0, 1]), 9.0);
+    }
+
+    #[test]
+    fn test_compare_annotations() {
+        use crate::database::{Database, DatabaseEntry, DatabaseMetadata};
+        use crate::matching::{dedup_clonotypes, match_clonotypes_parallel, MatchConfig};
+
+        let entry = |cdr3: &str, epitope: &str| DatabaseEntry {
+            v_segment: "TRBV19".to_string(),
+            antigen_species: "InfluenzaA".to_string(),
+            ..DatabaseEntry::test(cdr3, epitope)
+        };
+        let database = Database {
+            entries: vec![
+                entry("CASSAF", "GIL"),
+                entry("CASSAF", "GIL"),
+                entry("CASSBF", "GIL"),
+                entry("CASSDF", "NLV"),
+                entry("CASSGF", "NLV"),
+            ],
+            metadata: DatabaseMetadata::default(),
+        };
+        let batch = |cdr3s: &[&str]| {
+            let queries: Vec<Clonotype> = cdr3s
+                .iter()
+                .map(|c| Clonotype::new(c.to_string(), "TRBV19".into(), "TRBJ2-7".into(), 1, 0.0))
+                .collect();
+            let (unique, mapping) = dedup_clonotypes(&queries);
+            let hits = match_clonotypes_parallel(&unique, &database, &MatchConfig::default());
+            BatchMatches::new(queries, mapping, hits)
+        };
+        let a = batch(&["CASSAF", "CASSBF", "CASSCF", "CASSDF", "CASSAF"]);
+        let b = batch(&["CASSAF", "CASSEF", "CASSFF", "CASSGF"]);
+
+        let rows = compare_annotations(&a, &b);
+        assert_eq!(rows.len(), 2);
+        let gil = rows.iter().find(|r| r.epitope == "GIL").unwrap();
+        // The repeated CASSAF query adds hit rows but not clonotypes
+        assert_eq!((gil.hits_a, gil.hits_b, gil.clonotypes_a, gil.clonotypes_b, gil.shared_clonotypes), (5, 2, 2, 1, 1));
+        assert!((gil.p_value - 1.0).abs() < 1e-12);
+        assert_eq!(gil.odds_ratio, 3.0);
+        let nlv = rows.iter().find(|r| r.epitope == "NLV").unwrap();
+        assert_eq!((nlv.clonotypes_a, nlv.clonotypes_b, nlv.shared_clonotypes), (1, 1, 0));
+        assert!(rows.iter().all(|r| r.q_value >= r.p_value));
+    }
+}
@@ -1,16 +1,45 @@
 #![allow(dead_code)]
 use crate::error::{Result, VdjMatchError};
+use crate::sequence::{canonical_segment, Cdr3Tokens};
 // use crate::sequence::Clonotype;
-use csv::ReaderBuilder;
-use flate2::read::GzDecoder;
+use csv::{ReaderBuilder, WriterBuilder};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
+/// PubMed id of a VDJdb reference ("PMID:28636592", "pmid 28636592" or a bare number)
+pub fn parse_pmid(reference: &str) -> Option<u32> {
+    let reference = reference.trim();
+    let id = match reference.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("PMID") => reference[4..].trim_start_matches([':', ' ']),
+        _ => reference,
+    };
+    id.parse().ok()
+}
+
+/// Year embedded in a non-PubMed reference, e.g. a dated DOI
+/// ("10.1101/2020.05.04.20085779") or URL: the first run of four digits
+/// between 1900 and 2099
+pub fn reference_year(reference: &str) -> Option<u16> {
+    if parse_pmid(reference).is_some() {
+        return None;
+    }
+    reference
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|run| run.len() == 4)
+        .filter_map(|run| run.parse::<u16>().ok())
+        .find(|year| (1900..2100).contains(year))
+}
+
+/// Study key of a reference: trimmed and case-insensitive, None when blank
+fn study_key(reference: Option<&str>) -> Option<String> {
+    reference.map(str::trim).filter(|r| !r.is_empty()).map(str::to_uppercase)
+}
+
 /// VDJdb database entry
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DatabaseEntry {
     pub cdr3: String,
     pub v_segment: String,
@@ -26,21 +55,89 @@ pub struct DatabaseEntry {
     pub meta: Option<String>,
     pub cdr3_fix: Option<String>,
     pub vdjdb_score: u8,
+    /// 1-based data row of the entry in the source file (0 when unknown)
+    #[serde(default)]
+    pub row_id: usize,
+    /// D segment, for databases that record one (VDJdb does not)
+    #[serde(default)]
+    pub d_segment: Option<String>,
+    /// VDJdb complex id shared by the chains of one receptor ("0" when unpaired)
+    #[serde(default)]
+    pub complex_id: Option<String>,
+    /// MHC alleles presenting the epitope ("HLA-A*02:01", "B2M")
+    #[serde(default)]
+    pub mhc_a: Option<String>,
+    #[serde(default)]
+    pub mhc_b: Option<String>,
+    /// PubMed id parsed from `reference_id`
+    #[serde(default)]
+    pub pmid: Option<u32>,
+    /// Publication year, from the reference or `Database::with_publication_years`
+    #[serde(default)]
+    pub publication_year: Option<u16>,
+    /// Records of the entry's reference in the loaded release (0 without a reference)
+    #[serde(default)]
+    pub study_records: usize,
+    /// `cdr3` as the matching kernels read it, built with the entry
+    #[serde(skip)]
+    pub cdr3_tokens: Cdr3Tokens,
 }
 
 impl DatabaseEntry {
     /// Check if this entry matches the given filters
+    /// Case and surrounding whitespace are ignored in species and gene filters
     pub fn matches_species(&self, species: &str) -> bool {
-        self.species.eq_ignore_ascii_case(species)
+        self.species.trim().eq_ignore_ascii_case(species.trim())
     }
     
     pub fn matches_gene(&self, gene: &str) -> bool {
-        self.gene.eq_ignore_ascii_case(gene)
+        self.gene.trim().eq_ignore_ascii_case(gene.trim())
     }
     
     pub fn matches_vdjdb_score(&self, min_score: u8) -> bool {
         self.vdjdb_score >= min_score
     }
+
+    /// Whether the entry is restricted by the given MHC class ("MHCI", "I", "1", "MHCII", ...)
+    /// Entries without an MHC class never match a class restriction.
+    pub fn matches_mhc_class(&self, mhc_class: &str) -> bool {
+        normalize_mhc_class(mhc_class).is_some_and(|wanted| self.has_mhc_class(wanted))
+    }
+
+    /// `matches_mhc_class` for a class already passed through `normalize_mhc_class`
+    pub fn has_mhc_class(&self, mhc_class: &str) -> bool {
+        self.mhc_class.as_deref().and_then(normalize_mhc_class) == Some(mhc_class)
+    }
+
+    /// Human TRB entry (TRBV12-3/TRBJ2-7, EBV, score 1) for tests; set other
+    /// fields with `..DatabaseEntry::test(cdr3, epitope)` so the CDR3 tokens
+    /// stay in step with `cdr3`
+    #[cfg(test)]
+    pub(crate) fn test(cdr3: &str, epitope: &str) -> Self {
+        Self {
+            cdr3: cdr3.to_string(),
+            v_segment: "TRBV12-3".to_string(),
+            j_segment: "TRBJ2-7".to_string(),
+            species: "HomoSapiens".to_string(),
+            gene: "TRB".to_string(),
+            antigen_epitope: epitope.to_string(),
+            antigen_species: "EBV".to_string(),
+            vdjdb_score: 1,
+            cdr3_tokens: Cdr3Tokens::new(cdr3),
+            ..Self::default()
+        }
+    }
+}
+
+/// Canonical "MHCI"/"MHCII" for the spellings found in VDJdb and user input
+pub fn normalize_mhc_class(mhc_class: &str) -> Option<&'static str> {
+    let class = mhc_class.trim().to_uppercase().replace(['-', ' ', '_'], "");
+    let class = class.trim_start_matches("MHC").trim_start_matches("CLASS");
+    match class {
+        "I" | "1" => Some("MHCI"),
+        "II" | "2" => Some("MHCII"),
+        _ => None,
+    }
 }
 
 /// VDJdb database manager
@@ -49,88 +146,258 @@ pub struct Database {
     pub metadata: DatabaseMetadata,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DatabaseMetadata {
     pub columns: Vec<String>,
     pub version: Option<String>,
+    /// File the entries were originally loaded from
+    pub source_path: Option<String>,
+    /// UTC time (ISO 8601) the source file was loaded
+    pub loaded_at: Option<String>,
+    /// Filters applied since loading, in order ("species=HomoSapiens", "min_epitope_size=10", ...)
+    pub filters: Vec<String>,
+    /// Rows skipped by a lenient load (not kept by `save`)
+    pub load_report: LoadReport,
+    /// (field, column) pairs for fields read from differently named columns,
+    /// whether given as a column mapping or found through an alias
+    pub col_map: Vec<(String, String)>,
+    /// VDJdb release layout recognised from the header
+    pub profile: crate::schema::SchemaProfile,
+}
+
+/// How `Database::load_with_options` reads a TSV
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// (field, column) pairs naming the column that holds a field, see
+    /// `schema::resolve_columns`
+    pub col_map: Vec<(String, String)>,
+    /// Skip malformed rows, keeping this many diagnostics; None fails on them
+    pub lenient: Option<usize>,
+}
+
+/// A source row that could not be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadDiagnostic {
+    /// 1-based data row, as in `DatabaseEntry::row_id`
+    pub row: usize,
+    /// Line of the file (header is line 1), when known
+    pub line: Option<u64>,
+    pub reason: String,
+}
+
+/// Something suspicious about a table that still loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadWarning {
+    /// Condition code, raised in R with class "vdjmatchR_<code>"
+    pub code: &'static str,
+    /// Header name of the column concerned, if any
+    pub column: Option<String>,
+    pub message: String,
+}
+
+/// Outcome of a load: rows skipped by a lenient load and warnings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadReport {
+    pub n_skipped: usize,
+    /// The first skipped rows, at most the `max_diagnostics` asked for
+    pub diagnostics: Vec<LoadDiagnostic>,
+    pub warnings: Vec<LoadWarning>,
+}
+
+/// Separates filter steps in the save file header; filter expressions may
+/// contain any printable character
+const FILTER_SEPARATOR: &str = "\u{1f}";
+
+/// VDJdb release date ("2024-06-13") from the database file name or, for files
+/// unpacked from a release archive, its directory name
+fn version_from_path(path: &Path) -> Option<String> {
+    lazy_static::lazy_static! {
+        static ref RELEASE_DATE: regex::Regex = regex::Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
+    }
+    [path.file_name(), path.parent().and_then(|d| d.file_name())]
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.to_str())
+        .find_map(|name| RELEASE_DATE.find(name).map(|m| m.as_str().to_string()))
+}
+
+/// Short description of why a row failed to parse
+fn malformed_row_reason(e: &csv::Error) -> String {
+    match e.kind() {
+        csv::ErrorKind::UnequalLengths { expected_len, len, .. } => {
+            format!("expected {} fields, found {}", expected_len, len)
+        }
+        csv::ErrorKind::Utf8 { err, .. } => format!("invalid UTF-8 in field {}", err.field() + 1),
+        _ => e.to_string(),
+    }
+}
+
+/// File name extensions a VDJdb table is looked up under, in order of preference
+/// zstd files are left out: they are recognised but cannot be read.
+pub const DATABASE_EXTENSIONS: &[&str] = &["txt", "tsv", "txt.gz", "tsv.gz"];
+
+/// First existing `<stem>.<ext>` in `dir` over `DATABASE_EXTENSIONS`
+pub fn find_database_file(dir: &Path, stem: &str) -> Option<PathBuf> {
+    DATABASE_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", stem, ext)))
+        .find(|path| path.is_file())
 }
 
 impl Database {
     /// Load database from file
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_options(path, &LoadOptions::default())
+    }
+
+    /// Load database from file, skipping malformed rows (ragged rows, invalid
+    /// UTF-8) instead of failing; the first `max_diagnostics` of them are
+    /// described in `metadata.load_report`
+    pub fn load_from_file_lenient<P: AsRef<Path>>(path: P, max_diagnostics: usize) -> Result<Self> {
+        Self::load_with_options(path, &LoadOptions { lenient: Some(max_diagnostics), ..Default::default() })
+    }
+
+    /// Load database from file with column mapping and malformed-row handling
+    /// Columns are resolved with `schema::resolve_columns`, so a file without a
+    /// CDR3, V, J or epitope column fails instead of loading empty entries.
+    /// An empty file and a header repeating a column that is read into a field
+    /// are errors; repeated unused columns and a table without data rows load
+    /// with a warning in `metadata.load_report`.
+    pub fn load_with_options<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self> {
         let p = path.as_ref();
-        let file = File::open(p)
-            .map_err(|e| VdjMatchError::DatabaseNotFound(e.to_string()))?;
-
-        // Support gzip-compressed TSVs by checking the file extension.
-        let is_gz = p
-            .extension()
-            .and_then(|s| s.to_str())
-            .map(|s| s.eq_ignore_ascii_case("gz"))
-            .unwrap_or(false);
-
-        let reader: Box<dyn Read> = if is_gz {
-            Box::new(GzDecoder::new(file))
-        } else {
-            Box::new(file)
-        };
+        let lenient = options.lenient;
+        // Compression is sniffed from the content, so a gzipped file named
+        // "vdjdb.txt" loads and a plain file named ".gz" is read as text.
+        let reader = crate::io::open_reader(p).map_err(|e| match e {
+            VdjMatchError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                VdjMatchError::DatabaseNotFound { path: p.display().to_string(), reason: e.to_string() }
+            }
+            e => e,
+        })?;
+
+        let mut reader = crate::io::tsv_reader_builder().from_reader(reader);
 
-        let mut reader = ReaderBuilder::new()
-            .delimiter(b'\t')
-            .from_reader(BufReader::new(reader));
-        
         let headers = reader.headers()?;
         let columns: Vec<String> = headers.iter().map(|s| s.to_string()).collect();
+        if columns.iter().all(|c| c.trim().is_empty()) {
+            return Err(VdjMatchError::EmptyFile { path: p.display().to_string() });
+        }
 
-        // Build column name -> index map for flexible column ordering
-        let mut col_map = HashMap::new();
-        for (i, col_name) in columns.iter().enumerate() {
-            col_map.insert(col_name.as_str(), i);
+        let mut load_report = LoadReport::default();
+        for (column, positions) in crate::schema::duplicate_columns(&columns) {
+            if crate::schema::is_field_column(&column, &options.col_map) {
+                return Err(VdjMatchError::DuplicateColumns { column, positions });
+            }
+            let positions: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+            load_report.warnings.push(LoadWarning {
+                code: "duplicate_columns",
+                message: format!(
+                    "{}: column \"{}\" appears {} times (columns {}); it is not read",
+                    p.display(),
+                    column,
+                    positions.len(),
+                    positions.join(", ")
+                ),
+                column: Some(column),
+            });
         }
 
-        // Required column names
-        let gene_idx = col_map.get("gene").copied();
-        let cdr3_idx = col_map.get("cdr3").copied();
-        let species_idx = col_map.get("species").copied();
-        let v_segm_idx = col_map.get("v.segm").copied();
-        let j_segm_idx = col_map.get("j.segm").copied();
-        let antigen_epitope_idx = col_map.get("antigen.epitope").copied();
-        let antigen_gene_idx = col_map.get("antigen.gene").copied();
-        let antigen_species_idx = col_map.get("antigen.species").copied();
-        let mhc_class_idx = col_map.get("mhc.class").copied();
-        let reference_id_idx = col_map.get("reference.id").copied();
-        let vdjdb_score_idx = col_map.get("vdjdb.score").copied();
-        let method_idx = col_map.get("method").copied();
-        let meta_idx = col_map.get("meta").copied();
-        let cdr3fix_idx = col_map.get("cdr3fix").copied();
+        let index = crate::schema::resolve_columns(&columns, &options.col_map)?;
+        let col_map = index.renamed(&columns);
+        let profile = crate::schema::SchemaProfile::detect(&columns);
+        let gene_idx = index.get("gene");
+        let cdr3_idx = index.get("cdr3");
+        let species_idx = index.get("species");
+        let v_segm_idx = index.get("v.segm");
+        let j_segm_idx = index.get("j.segm");
+        let antigen_epitope_idx = index.get("antigen.epitope");
+        let antigen_gene_idx = index.get("antigen.gene");
+        let antigen_species_idx = index.get("antigen.species");
+        let mhc_class_idx = index.get("mhc.class");
+        let reference_id_idx = index.get("reference.id");
+        let vdjdb_score_idx = index.get("vdjdb.score");
+        let method_idx = index.get("method");
+        let meta_idx = index.get("meta");
+        let cdr3fix_idx = index.get("cdr3fix");
+        let d_segm_idx = index.get("d.segm");
+        let complex_id_idx = index.get("complex.id");
+        let mhc_a_idx = index.get("mhc.a");
+        let mhc_b_idx = index.get("mhc.b");
+        // Early releases spread method, meta and cdr3fix over one column per
+        // key; they are put back together as the JSON later releases store
+        let flattened = |field: &str, idx: Option<usize>| match idx {
+            Some(_) => Vec::new(),
+            None => crate::schema::flattened_columns(&columns, field),
+        };
+        let method_keys = flattened("method", method_idx);
+        let meta_keys = flattened("meta", meta_idx);
+        let cdr3fix_keys = flattened("cdr3fix", cdr3fix_idx);
 
         let mut entries = Vec::new();
 
-        for result in reader.records() {
-            let record = result?;
+        for (row, result) in reader.records().enumerate() {
+            let record = match (result, lenient) {
+                (Ok(record), _) => record,
+                (Err(e), Some(max_diagnostics)) if !e.is_io_error() => {
+                    load_report.n_skipped += 1;
+                    if load_report.diagnostics.len() < max_diagnostics {
+                        load_report.diagnostics.push(LoadDiagnostic {
+                            row: row + 1,
+                            line: e.position().map(|pos| pos.line()),
+                            reason: malformed_row_reason(&e),
+                        });
+                    }
+                    continue;
+                }
+                (Err(e), _) => return Err(e.into()),
+            };
 
+            // Spreadsheets pad values with spaces, including non-breaking
+            // ones the reader's ASCII trimming keeps
+            let field = |idx: Option<usize>| idx.and_then(|i| record.get(i)).map(str::trim);
+            let json_field = |idx: Option<usize>, keys: &[(String, usize)]| match idx {
+                Some(_) => field(idx).map(|s| s.to_string()),
+                None if keys.is_empty() => None,
+                None => Some(crate::schema::flattened_json(
+                    keys.iter().map(|(key, i)| (key.as_str(), field(Some(*i)).unwrap_or(""))),
+                )),
+            };
             // Parse record into DatabaseEntry using column names
+            let cdr3 = field(cdr3_idx).unwrap_or("");
             let entry = DatabaseEntry {
-                gene: gene_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                cdr3: cdr3_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                v_segment: v_segm_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                j_segment: j_segm_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                species: species_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                antigen_epitope: antigen_epitope_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                antigen_gene: antigen_gene_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                antigen_species: antigen_species_idx.and_then(|i| record.get(i)).unwrap_or("").to_string(),
-                mhc_class: mhc_class_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                reference_id: reference_id_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                method: method_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                meta: meta_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                cdr3_fix: cdr3fix_idx.and_then(|i| record.get(i).map(|s| s.to_string())),
-                vdjdb_score: vdjdb_score_idx
-                    .and_then(|i| record.get(i))
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(0),
+                gene: canonical_segment(field(gene_idx).unwrap_or("")),
+                cdr3: cdr3.to_string(),
+                v_segment: canonical_segment(field(v_segm_idx).unwrap_or("")),
+                j_segment: canonical_segment(field(j_segm_idx).unwrap_or("")),
+                species: field(species_idx).unwrap_or("").to_string(),
+                antigen_epitope: field(antigen_epitope_idx).unwrap_or("").to_string(),
+                antigen_gene: field(antigen_gene_idx).map(|s| s.to_string()),
+                antigen_species: field(antigen_species_idx).unwrap_or("").to_string(),
+                mhc_class: field(mhc_class_idx).map(|s| s.to_string()),
+                reference_id: field(reference_id_idx).map(|s| s.to_string()),
+                method: json_field(method_idx, &method_keys),
+                meta: json_field(meta_idx, &meta_keys),
+                cdr3_fix: json_field(cdr3fix_idx, &cdr3fix_keys),
+                vdjdb_score: field(vdjdb_score_idx).and_then(|s| s.parse().ok()).unwrap_or(0),
+                row_id: row + 1,
+                d_segment: field(d_segm_idx).and_then(crate::sequence::parse_d_segment).map(|d| canonical_segment(&d)),
+                complex_id: field(complex_id_idx).map(|s| s.to_string()),
+                mhc_a: field(mhc_a_idx).map(|s| s.to_string()),
+                mhc_b: field(mhc_b_idx).map(|s| s.to_string()),
+                pmid: None,
+                publication_year: None,
+                study_records: 0,
+                cdr3_tokens: Cdr3Tokens::new(cdr3),
             };
             entries.push(entry);
         }
+        if entries.is_empty() {
+            let message = match load_report.n_skipped {
+                0 => format!("{}: header only, no data rows; the database is empty", p.display()),
+                n => format!("{}: all {} data row(s) are malformed; the database is empty", p.display(), n),
+            };
+            load_report.warnings.push(LoadWarning { code: "no_data_rows", column: None, message });
+        }
 
         // eprintln!("DEBUG: Loaded {} entries from database", entries.len());
         // if !entries.is_empty() {
@@ -141,13 +408,39 @@ impl Database {
         //     }
         // }
 
-        Ok(Self {
+        let mut database = Self {
             entries,
             metadata: DatabaseMetadata {
                 columns,
-                version: None,
+                version: version_from_path(p),
+                source_path: Some(p.display().to_string()),
+                loaded_at: Some(crate::utils::utc_timestamp()),
+                filters: Vec::new(),
+                load_report,
+                col_map,
+                profile,
             },
-        })
+        };
+        database.annotate_provenance();
+        Ok(database)
+    }
+
+    /// Derive the provenance fields of every entry from its reference: PMID,
+    /// the year of dated non-PubMed references and the number of records
+    /// sharing the reference
+    pub fn annotate_provenance(&mut self) {
+        let mut study_records: HashMap<String, usize> = HashMap::new();
+        for entry in &self.entries {
+            if let Some(key) = study_key(entry.reference_id.as_deref()) {
+                *study_records.entry(key).or_insert(0) += 1;
+            }
+        }
+        for entry in &mut self.entries {
+            let reference = entry.reference_id.as_deref();
+            entry.pmid = reference.and_then(parse_pmid);
+            entry.publication_year = reference.and_then(reference_year);
+            entry.study_records = study_key(reference).map_or(0, |key| study_records[&key]);
+        }
     }
     
     /// Filter database entries by criteria
@@ -157,6 +450,9 @@ impl Database {
         gene: Option<&str>,
         min_vdjdb_score: u8,
     ) -> Self {
+        // Synonyms ("human", "Homo sapiens") select the VDJdb species name
+        let species = species.map(|s| crate::reference::canonical_species(s).unwrap_or_else(|| s.to_string()));
+        let species = species.as_deref();
         // eprintln!("DEBUG: Filtering {} entries", self.entries.len());
         // eprintln!("DEBUG: species filter={:?}, gene filter={:?}", species, gene);
         // if let Some(first) = self.entries.first() {
@@ -191,11 +487,67 @@ impl Database {
         //               i+1, entry.gene, entry.species, entry.cdr3);
         // }
 
-        Self {
-            entries: filtered_entries,
-            metadata: self.metadata.clone(),
+        let mut steps = Vec::new();
+        steps.extend(species.map(|s| format!("species={}", s)));
+        steps.extend(gene.map(|g| format!("gene={}", g)));
+        if min_vdjdb_score > 0 {
+            steps.push(format!("min_vdjdb_score={}", min_vdjdb_score));
         }
+        self.derive(filtered_entries, steps)
+    }
 
+    /// Keep entries passing a filter expression ("__species__=='HomoSapiens'",
+    /// "__antigen.epitope__=~'^GLC'")
+    pub fn filter_expression(&self, expr: &str) -> std::result::Result<Self, String> {
+        let filter = crate::filtering::parse_filter_expression(expr)?;
+        let entries = self.entries.iter().filter(|e| filter.matches(e)).cloned().collect();
+        Ok(self.derive(entries, vec![format!("expr={}", expr)]))
+    }
+
+    /// Re-run one recorded filter step (an entry of `metadata.filters`)
+    pub fn apply_filter_step(&self, step: &str) -> std::result::Result<Self, String> {
+        let (key, value) = step
+            .split_once('=')
+            .ok_or_else(|| format!("invalid filter step '{}'", step))?;
+        let number = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid value in filter step '{}'", step))
+        };
+        match key {
+            "species" => Ok(self.filter(Some(value), None, 0)),
+            "gene" => Ok(self.filter(None, Some(value), 0)),
+            "min_vdjdb_score" => Ok(self.filter(None, None, number(value)?.min(u8::MAX as usize) as u8)),
+            "min_epitope_size" => Ok(self.filter_by_epitope_size(number(value)?)),
+            "expr" => self.filter_expression(value),
+            "mhc_allele" => self.filter_mhc_allele(value).map_err(|e| e.to_string()),
+            "max_study_records" => Ok(self.filter_by_study_size(number(value)?)),
+            "min_publication_year" => {
+                let (year, keep_unknown) = match value.split_once(',') {
+                    Some((year, "keep_unknown")) => (year, true),
+                    _ => (value, false),
+                };
+                Ok(self.filter_by_publication_year(number(year)?.min(u16::MAX as usize) as u16, keep_unknown))
+            }
+            "rows" => Err("row subsets depend on row positions and cannot be replayed".into()),
+            _ => Err(format!("unknown filter step '{}'", step)),
+        }
+    }
+
+    /// Re-run a filter history, e.g. one recorded on an older database release
+    pub fn apply_filter_steps(&self, steps: &[String]) -> std::result::Result<Self, String> {
+        let mut database = self.derive(self.entries.clone(), Vec::new());
+        for step in steps {
+            database = database.apply_filter_step(step)?;
+        }
+        Ok(database)
+    }
+
+    /// A database holding `entries` selected from this one by the filter `steps`
+    pub fn derive(&self, entries: Vec<DatabaseEntry>, steps: Vec<String>) -> Self {
+        let mut metadata = self.metadata.clone();
+        metadata.filters.extend(steps);
+        Self { entries, metadata }
     }
     
     /// Filter by epitope size (minimum number of unique CDR3 per epitope)
@@ -225,10 +577,190 @@ impl Database {
         //               i+1, entry.gene, entry.species, entry.cdr3);
         // }
 
-        Self {
-            entries: filtered_entries,
-            metadata: self.metadata.clone(),
+        self.derive(filtered_entries, vec![format!("min_epitope_size={}", min_size)])
+    }
+    
+    /// Keep entries from references with at most `max_records` records in the
+    /// loaded release, dropping large bulk studies; entries without a
+    /// reference are kept
+    pub fn filter_by_study_size(&self, max_records: usize) -> Self {
+        let entries = self.entries.iter().filter(|e| e.study_records <= max_records).cloned().collect();
+        self.derive(entries, vec![format!("max_study_records={}", max_records)])
+    }
+
+    /// Keep entries published in or after `min_year`; entries of unknown
+    /// year are kept only with `keep_unknown`
+    pub fn filter_by_publication_year(&self, min_year: u16, keep_unknown: bool) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|e| e.publication_year.map_or(keep_unknown, |year| year >= min_year))
+            .cloned()
+            .collect();
+        let step = if keep_unknown {
+            format!("min_publication_year={},keep_unknown", min_year)
+        } else {
+            format!("min_publication_year={}", min_year)
+        };
+        self.derive(entries, vec![step])
+    }
+
+    /// A copy with publication years set from a PMID table (VDJdb has no year
+    /// column); entries whose PMID is not in `years` keep their year
+    pub fn with_publication_years(&self, years: &HashMap<u32, u16>) -> Self {
+        let mut database = self.derive(self.entries.clone(), Vec::new());
+        for entry in &mut database.entries {
+            if let Some(&year) = entry.pmid.and_then(|pmid| years.get(&pmid)) {
+                entry.publication_year = Some(year);
+            }
+        }
+        database
+    }
+
+    /// Whether the source table has the columns a feature needs
+    pub fn supports(&self, capability: crate::schema::Capability) -> bool {
+        capability.supported(&self.metadata.columns, self.metadata.profile)
+    }
+
+    /// Fail early, suggesting the fat database, when a feature's columns are missing
+    pub fn require(&self, capability: crate::schema::Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(VdjMatchError::Unsupported(capability.unsupported_reason(self.metadata.profile)))
+        }
+    }
+
+    /// Key/value pairs of each entry's JSON method field (empty when the
+    /// field is blank or not a flat JSON object)
+    pub fn methods(&self) -> Result<Vec<Vec<(String, String)>>> {
+        self.require(crate::schema::Capability::Method)?;
+        Ok(self
+            .entries
+            .iter()
+            .map(|e| e.method.as_deref().and_then(crate::schema::parse_flat_json).unwrap_or_default())
+            .collect())
+    }
+
+    /// (alpha, beta) entry indices recorded in the same complex, for every
+    /// TRA/TRB combination sharing a complex id other than "0"
+    pub fn complex_pairs(&self) -> Result<Vec<(usize, usize)>> {
+        self.require(crate::schema::Capability::ComplexPairing)?;
+        let mut chains: std::collections::BTreeMap<&str, (Vec<usize>, Vec<usize>)> = Default::default();
+        for (i, entry) in self.entries.iter().enumerate() {
+            let Some(id) = entry.complex_id.as_deref().map(str::trim).filter(|id| !id.is_empty() && *id != "0") else {
+                continue;
+            };
+            let (alpha, beta) = chains.entry(id).or_default();
+            if entry.matches_gene("TRA") {
+                alpha.push(i);
+            } else if entry.matches_gene("TRB") {
+                beta.push(i);
+            }
         }
+        let mut pairs: Vec<(usize, usize)> = chains
+            .values()
+            .flat_map(|(alpha, beta)| alpha.iter().flat_map(move |&a| beta.iter().map(move |&b| (a, b))))
+            .collect();
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    /// Keep entries presented by an MHC allele, matched on `mhc.a` or `mhc.b`
+    /// at the resolution given ("HLA-A*02" keeps "HLA-A*02:01")
+    pub fn filter_mhc_allele(&self, allele: &str) -> Result<Self> {
+        self.require(crate::schema::Capability::MhcAlleles)?;
+        let wanted = allele.trim();
+        let matches = |mhc: &Option<String>| {
+            mhc.as_deref().is_some_and(|m| {
+                let m = m.trim();
+                m.eq_ignore_ascii_case(wanted)
+                    || (m.len() > wanted.len()
+                        && m.get(..wanted.len()).is_some_and(|p| p.eq_ignore_ascii_case(wanted))
+                        && matches!(m.as_bytes()[wanted.len()], b':' | b'*'))
+            })
+        };
+        let entries = self.entries.iter().filter(|e| matches(&e.mhc_a) || matches(&e.mhc_b)).cloned().collect();
+        Ok(self.derive(entries, vec![format!("mhc_allele={}", wanted)]))
+    }
+
+    /// Save entries and metadata in vdjmatchR's own TSV format (gzip-compressed
+    /// when the path ends in ".gz") for reloading with `load_saved`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        crate::utils::write_to_path(path, |out| {
+            let version = self.metadata.version.as_deref().unwrap_or("");
+            let columns = self.metadata.columns.join(",");
+            let source_path = self.metadata.source_path.as_deref().unwrap_or("");
+            let loaded_at = self.metadata.loaded_at.as_deref().unwrap_or("");
+            let filters = self.metadata.filters.join(FILTER_SEPARATOR);
+            let col_map: Vec<String> = self.metadata.col_map.iter().map(|(f, c)| format!("{}={}", f, c)).collect();
+            let col_map = col_map.join(FILTER_SEPARATOR);
+            crate::utils::write_save_header(
+                out,
+                "database",
+                &[version, &columns, source_path, loaded_at, &filters, &col_map, self.metadata.profile.as_str()],
+            )?;
+
+            let mut writer = WriterBuilder::new().delimiter(b'\t').from_writer(out);
+            for entry in &self.entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Load a database written by `save`
+    pub fn load_saved<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = crate::utils::open_reader(path)?;
+        let header = crate::utils::read_save_header(&mut reader, "database")?;
+        let field = |i: usize| header.get(i).filter(|v| !v.is_empty()).cloned();
+        let version = field(0);
+        let columns: Vec<String> = header
+            .get(1)
+            .map(|c| c.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect())
+            .unwrap_or_default();
+        // Files saved before profiles were recorded are profiled from their columns
+        let profile = field(6)
+            .and_then(|p| crate::schema::SchemaProfile::parse(&p))
+            .unwrap_or_else(|| crate::schema::SchemaProfile::detect(&columns));
+
+        let mut reader = ReaderBuilder::new().delimiter(b'\t').from_reader(reader);
+        let mut entries = reader
+            .deserialize()
+            .collect::<std::result::Result<Vec<DatabaseEntry>, _>>()?;
+        for entry in &mut entries {
+            entry.cdr3_tokens = Cdr3Tokens::new(&entry.cdr3);
+        }
+        // Files saved before provenance was recorded derive it from the saved entries
+        let annotate = entries.iter().all(|e| e.study_records == 0);
+
+        let mut database = Self {
+            entries,
+            metadata: DatabaseMetadata {
+                columns,
+                version,
+                source_path: field(2),
+                loaded_at: field(3),
+                filters: field(4)
+                    .map(|f| f.split(FILTER_SEPARATOR).map(|s| s.to_string()).collect())
+                    .unwrap_or_default(),
+                load_report: LoadReport::default(),
+                col_map: field(5)
+                    .map(|m| {
+                        m.split(FILTER_SEPARATOR)
+                            .filter_map(|pair| pair.split_once('='))
+                            .map(|(f, c)| (f.to_string(), c.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                profile,
+            },
+        };
+        if annotate {
+            database.annotate_provenance();
+        }
+        Ok(database)
     }
     
     pub fn len(&self) -> usize {
@@ -261,18 +793,14 @@ impl DatabaseManager {
     pub fn ensure_database_exists(&self, use_fat_db: bool) -> Result<PathBuf> {
         std::fs::create_dir_all(&self.home_dir)?;
         
-        let db_file = if use_fat_db {
-            self.home_dir.join("vdjdb.txt")
-        } else {
-            self.home_dir.join("vdjdb.slim.txt")
-        };
-        
-        if !db_file.exists() {
-            eprintln!("Database not found. Downloading...");
-            self.download_database(use_fat_db)?;
+        let stem = if use_fat_db { "vdjdb" } else { "vdjdb.slim" };
+        if let Some(existing) = find_database_file(&self.home_dir, stem) {
+            return Ok(existing);
         }
-        
-        Ok(db_file)
+
+        eprintln!("Database not found. Downloading...");
+        self.download_database(use_fat_db)?;
+        Ok(self.home_dir.join(format!("{}.txt", stem)))
     }
     
     fn download_database(&self, use_fat_db: bool) -> Result<()> {
@@ -322,3 +850,307 @@ impl Default for DatabaseManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load_roundtrip() {
+        let entry = DatabaseEntry {
+            mhc_class: Some("MHCI".to_string()),
+            reference_id: Some("PMID:12345".to_string()),
+            meta: Some("{\"cell.subset\": \"CD8\"}".to_string()),
+            vdjdb_score: 3,
+            row_id: 7,
+            ..DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML")
+        };
+        let database = Database {
+            entries: vec![entry],
+            metadata: DatabaseMetadata {
+                columns: vec!["gene".to_string(), "cdr3".to_string()],
+                version: Some("2024-06-13".to_string()),
+                source_path: Some("/data/vdjdb-2024-06-13/vdjdb.slim.txt".to_string()),
+                loaded_at: Some("2024-07-01T12:00:00Z".to_string()),
+                filters: vec![],
+                col_map: vec![("cdr3".to_string(), "junction_aa".to_string())],
+                profile: crate::schema::SchemaProfile::Slim,
+                ..Default::default()
+            },
+        };
+        let database = database.filter(Some("HomoSapiens"), None, 2).filter_by_epitope_size(1);
+        assert_eq!(database.metadata.filters, ["species=HomoSapiens", "min_vdjdb_score=2", "min_epitope_size=1"]);
+
+        let path = std::env::temp_dir().join(format!("vdjmatchR-db-{}.tsv.gz", std::process::id()));
+        database.save(&path).unwrap();
+        let loaded = Database::load_saved(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.entries[0].meta, database.entries[0].meta);
+        assert_eq!(loaded.entries[0].antigen_gene, None);
+        assert_eq!(loaded.entries[0].vdjdb_score, 3);
+        assert!(loaded.entries[0].matches_mhc_class("class I"));
+        assert!(!loaded.entries[0].matches_mhc_class("MHCII"));
+        assert_eq!(loaded.metadata.version.as_deref(), Some("2024-06-13"));
+        assert_eq!(loaded.metadata.columns, database.metadata.columns);
+        assert_eq!(loaded.entries[0].row_id, 7);
+        assert_eq!(loaded.entries[0].cdr3_tokens, database.entries[0].cdr3_tokens);
+        assert_eq!(loaded.metadata.source_path, database.metadata.source_path);
+        assert_eq!(loaded.metadata.loaded_at, database.metadata.loaded_at);
+        assert_eq!(loaded.metadata.filters, database.metadata.filters);
+        assert_eq!(loaded.metadata.col_map, database.metadata.col_map);
+        assert_eq!(loaded.metadata.profile, crate::schema::SchemaProfile::Slim);
+    }
+
+    #[test]
+    fn test_replay_filters() {
+        let entry = |species: &str, epitope: &str, score: u8| DatabaseEntry {
+            v_segment: String::new(),
+            j_segment: String::new(),
+            species: species.to_string(),
+            antigen_species: String::new(),
+            vdjdb_score: score,
+            ..DatabaseEntry::test("CASSLGQAYEQYF", epitope)
+        };
+        let old = Database {
+            entries: vec![entry("HomoSapiens", "GLCTLVAML", 1), entry("MusMusculus", "SSLENFRAYV", 2)],
+            metadata: DatabaseMetadata::default(),
+        };
+        let filtered = old
+            .filter(Some("HomoSapiens"), None, 0)
+            .filter_expression("__antigen.epitope__=~'^GLC|^NLV'")
+            .unwrap();
+        let history = filtered.metadata.filters.clone();
+        assert_eq!(history, ["species=HomoSapiens", "expr=__antigen.epitope__=~'^GLC|^NLV'"]);
+        let mouse = old.filter(Some("mouse"), None, 0);
+        assert_eq!((mouse.entries.len(), mouse.metadata.filters[0].as_str()), (1, "species=MusMusculus"));
+
+        let new = Database {
+            entries: vec![
+                entry("HomoSapiens", "GLCTLVAML", 1),
+                entry("HomoSapiens", "NLVPMVATV", 3),
+                entry("HomoSapiens", "YVLDHLIVV", 3),
+                entry("MusMusculus", "SSLENFRAYV", 2),
+            ],
+            metadata: DatabaseMetadata::default(),
+        };
+        let replayed = new.apply_filter_steps(&history).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed.metadata.filters, history);
+        assert!(new.apply_filter_steps(&["rows=2".to_string()]).is_err());
+        assert!(new.apply_filter_steps(&["colour=red".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_provenance_filters() {
+        assert_eq!(parse_pmid("PMID:28636592"), Some(28636592));
+        assert_eq!(parse_pmid(" pmid 28636592"), Some(28636592));
+        assert_eq!(parse_pmid("https://www.10xgenomics.com/datasets"), None);
+        assert_eq!(reference_year("https://doi.org/10.1101/2020.05.04.20085779"), Some(2020));
+        assert_eq!(reference_year("PMID:20101234"), None);
+
+        let path = std::env::temp_dir().join(format!("vdjmatchR-provenance-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "gene\tcdr3\tv.segm\tj.segm\tspecies\tantigen.epitope\treference.id\n\
+             TRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tGLCTLVAML\tPMID:111\n\
+             TRB\tCASSIRSSYEQYF\tTRBV19\tTRBJ2-7\tHomoSapiens\tGILGFVFTL\tPMID:222\n\
+             TRB\tCASSIRSAYEQYF\tTRBV19\tTRBJ2-7\tHomoSapiens\tGILGFVFTL\tpmid:222\n\
+             TRB\tCASSPGQGYEQYF\tTRBV7-9\tTRBJ2-7\tHomoSapiens\tYLQPRTFLL\t10.1101/2021.02.01.429023\n\
+             TRB\tCASSLAPGATNEKLFF\tTRBV7-9\tTRBJ1-4\tHomoSapiens\tNLVPMVATV\t\n",
+        )
+        .unwrap();
+        let db = Database::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let records: Vec<usize> = db.entries.iter().map(|e| e.study_records).collect();
+        assert_eq!(records, [1, 2, 2, 1, 0]);
+        assert_eq!(db.entries[1].pmid, Some(222));
+        assert_eq!(db.filter_by_study_size(1).len(), 3);
+
+        let years = HashMap::from([(222, 2018)]);
+        let dated = db.with_publication_years(&years);
+        assert!(dated.metadata.filters.is_empty());
+        let recent = dated.filter_by_publication_year(2019, false);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent.entries[0].antigen_epitope, "YLQPRTFLL");
+        let with_unknown = dated.filter_by_publication_year(2018, true);
+        assert_eq!(with_unknown.len(), 5);
+        assert_eq!(with_unknown.metadata.filters, ["min_publication_year=2018,keep_unknown"]);
+        assert_eq!(dated.apply_filter_steps(&with_unknown.metadata.filters).unwrap().len(), 5);
+        assert_eq!(dated.apply_filter_step("max_study_records=1").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_load_compressed_by_content() {
+        let dir = std::env::temp_dir().join(format!("vdjmatchR-sniff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let table = "gene\tcdr3\tv.segm\tj.segm\tspecies\tmhc.class\tantigen.epitope\tantigen.species\tvdjdb.score\n\
+                     TRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tMHCI\tGLCTLVAML\tEBV\t2\n";
+        // gzip content without a ".gz" name
+        crate::io::write_to_path(dir.join("vdjdb.slim.tsv.gz"), |out| Ok(out.write_all(table.as_bytes())?)).unwrap();
+        std::fs::rename(dir.join("vdjdb.slim.tsv.gz"), dir.join("vdjdb.slim.tsv")).unwrap();
+
+        let found = find_database_file(&dir, "vdjdb.slim").unwrap();
+        assert!(found.ends_with("vdjdb.slim.tsv"));
+        let db = Database::load_from_file(&found).unwrap();
+        assert_eq!(db.entries.len(), 1);
+        assert_eq!(db.entries[0].antigen_epitope, "GLCTLVAML");
+        assert!(find_database_file(&dir, "vdjdb").is_none());
+        // A zstd file this build cannot read is not picked up
+        std::fs::write(dir.join("vdjdb.txt.zst"), [0x28, 0xb5, 0x2f, 0xfd]).unwrap();
+        assert!(find_database_file(&dir, "vdjdb").is_none());
+        assert!(matches!(Database::load_from_file(dir.join("missing.txt")), Err(VdjMatchError::DatabaseNotFound { .. })));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_lenient_load() {
+        let path = std::env::temp_dir().join(format!("vdjmatchR-ragged-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "gene\tcdr3\tv.segm\tj.segm\tspecies\tantigen.epitope\n\
+             TRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tGLCTLVAML\n\
+             TRB\tCASSIRSSYEQYF\tTRBV19\n\
+             TRA\tCAVRDF\tTRAV12-1\tTRAJ7\tHomoSapiens\tNLVPMVATV\textra\n\
+             TRB\tCASSPGQGYEQYF\tTRBV7-9\tTRBJ2-7\tHomoSapiens\tNLVPMVATV\n",
+        )
+        .unwrap();
+
+        assert!(Database::load_from_file(&path).is_err());
+        let db = Database::load_from_file_lenient(&path, 1).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(db.entries.len(), 2);
+        assert_eq!(db.entries[1].row_id, 4);
+        let report = &db.metadata.load_report;
+        assert_eq!(report.n_skipped, 2);
+        assert_eq!(
+            report.diagnostics,
+            vec![LoadDiagnostic { row: 2, line: Some(3), reason: "expected 6 fields, found 3".to_string() }]
+        );
+    }
+
+    #[test]
+    fn test_load_empty_and_duplicate_columns() {
+        let path = std::env::temp_dir().join(format!("vdjmatchR-load-checks-{}.tsv", std::process::id()));
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            Database::load_from_file(&path)
+        };
+
+        let err = load("").err().unwrap();
+        assert_eq!(err.code(), "empty_file");
+
+        let db = load("gene\tcdr3\tv.segm\tj.segm\tspecies\tantigen.epitope\n").unwrap();
+        assert!(db.entries.is_empty());
+        assert_eq!(db.metadata.load_report.warnings.len(), 1);
+        assert_eq!(db.metadata.load_report.warnings[0].code, "no_data_rows");
+
+        let err = load("cdr3\tv.segm\tj.segm\tantigen.epitope\tcdr3\nCASSF\tTRBV19\tTRBJ2-7\tGILGFVFTL\tCASSL\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), "duplicate_columns");
+        assert!(err.to_string().contains("columns 1, 5"), "{}", err);
+
+        let db = load("cdr3\tnote\tv.segm\tj.segm\tantigen.epitope\tnote\nCASSF\ta\tTRBV19\tTRBJ2-7\tGILGFVFTL\tb\n")
+            .unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(db.entries.len(), 1);
+        let warnings = &db.metadata.load_report.warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].code, warnings[0].column.as_deref()), ("duplicate_columns", Some("note")));
+        assert!(warnings[0].message.contains("columns 2, 6"));
+    }
+
+    #[test]
+    fn test_load_messy_tsv() {
+        // Excel export: byte order mark, CRLF line ends, padded header and
+        // gene names, a non-breaking space and a quoted field holding a tab
+        // and a doubled quote
+        let path = std::env::temp_dir().join(format!("vdjmatchR-messy-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "\u{feff}gene\tcdr3 \tv.segm\tj.segm\tspecies\tantigen.epitope\tmeta\r\n\
+             TRB\tCASSLGQAYEQYF\tTRBV12-3 \t TRBJ2-7\tHomoSapiens\tGLCTLVAML\t\"{\"\"note\"\": \"\"a\tb\"\"}\"\r\n\
+             trb\tCASSIRSSYEQYF\ttrbv19\u{a0}\tTRBJ2-7\thomosapiens\tGILGFVFTL\t\r\n",
+        )
+        .unwrap();
+        let db = Database::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(db.metadata.columns[0], "gene");
+        assert_eq!(db.metadata.columns[1], "cdr3");
+        assert_eq!(db.entries.len(), 2);
+        assert_eq!(db.entries[0].v_segment, "TRBV12-3");
+        assert_eq!(db.entries[0].j_segment, "TRBJ2-7");
+        assert_eq!(db.entries[0].meta.as_deref(), Some("{\"note\": \"a\tb\"}"));
+        assert_eq!(db.entries[1].v_segment, "TRBV19");
+        assert_eq!(db.entries[1].gene, "TRB");
+        assert_eq!(db.entries[1].antigen_epitope, "GILGFVFTL");
+        // Species and gene filters ignore case and padding
+        assert_eq!(db.filter(Some(" HomoSapiens "), Some("trb "), 0).entries.len(), 2);
+    }
+
+    #[test]
+    fn test_load_flattened_release() {
+        let path = std::env::temp_dir().join(format!("vdjmatchR-flat-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "gene\tcdr3\tv.segm\tj.segm\tspecies\tantigen.epitope\tmethod.identification\tmethod.frequency\tmeta.cell.subset\tvdjdb.score\n\
+             TRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tGLCTLVAML\ttetramer-sort\t3/10\tCD8\t2\n",
+        )
+        .unwrap();
+        let db = Database::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(db.metadata.profile, crate::schema::SchemaProfile::Flattened);
+        let entry = &db.entries[0];
+        assert_eq!(entry.method.as_deref(), Some("{\"identification\": \"tetramer-sort\", \"frequency\": \"3/10\"}"));
+        assert_eq!(entry.meta.as_deref(), Some("{\"cell.subset\": \"CD8\"}"));
+        assert_eq!(entry.cdr3_fix, None);
+    }
+
+    #[test]
+    fn test_fat_only_features() {
+        let path = std::env::temp_dir().join(format!("vdjmatchR-fat-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "complex.id\tgene\tcdr3\tv.segm\tj.segm\tspecies\tmhc.a\tmhc.b\tantigen.epitope\tvdjdb.score\tmethod\tmeta\tweb.method\n\
+             1\tTRA\tCAVRDF\tTRAV12-1\tTRAJ7\tHomoSapiens\tHLA-A*02:01\tB2M\tGLCTLVAML\t2\t{\"identification\": \"tetramer-sort\"}\t{}\tsort\n\
+             1\tTRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tHLA-A*02:01\tB2M\tGLCTLVAML\t2\t\t{}\tsort\n\
+             0\tTRB\tCASSPGQGYEQYF\tTRBV7-9\tTRBJ2-7\tHomoSapiens\tHLA-A*02\tB2M\tNLVPMVATV\t2\t\t{}\tsort\n\
+             2\tTRB\tCASSIRSSYEQYF\tTRBV19\tTRBJ2-7\tHomoSapiens\tHLA-B*08:01\tB2M\tRAKFKQLL\t2\t\t{}\tsort\n",
+        )
+        .unwrap();
+        let db = Database::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(db.metadata.profile.variant(), "fat");
+        assert_eq!(db.methods().unwrap()[0], [("identification".to_string(), "tetramer-sort".to_string())]);
+        assert!(db.methods().unwrap()[1].is_empty());
+        assert_eq!(db.complex_pairs().unwrap(), [(0, 1)]);
+        let a02 = db.filter_mhc_allele("HLA-A*02").unwrap();
+        assert_eq!(a02.entries.len(), 3);
+        assert_eq!(db.filter_mhc_allele("hla-a*02:01").unwrap().entries.len(), 2);
+        assert_eq!(db.filter_mhc_allele("HLA-A*0").unwrap().entries.len(), 0);
+        assert_eq!(a02.metadata.filters, ["mhc_allele=HLA-A*02"]);
+        assert_eq!(db.apply_filter_steps(&a02.metadata.filters).unwrap().entries.len(), 3);
+
+        let mut slim = db.derive(db.entries.clone(), vec![]);
+        slim.metadata.columns.retain(|c| c != "method" && c != "meta" && !c.starts_with("web."));
+        slim.metadata.profile = crate::schema::SchemaProfile::detect(&slim.metadata.columns);
+        assert_eq!(slim.metadata.profile, crate::schema::SchemaProfile::Slim);
+        assert!(matches!(slim.methods(), Err(VdjMatchError::Unsupported(_))));
+        let err = slim.complex_pairs().unwrap_err().to_string();
+        assert!(err.contains("complex ids") && err.contains("use_fat_db = TRUE"), "{}", err);
+        assert!(slim.filter_mhc_allele("HLA-A*02").is_ok());
+    }
+
+    #[test]
+    fn test_version_from_path() {
+        assert_eq!(version_from_path(Path::new("/x/vdjdb-2024-06-13/vdjdb.txt")).as_deref(), Some("2024-06-13"));
+        assert_eq!(version_from_path(Path::new("vdjdb-2024-06-13.slim.txt.gz")).as_deref(), Some("2024-06-13"));
+        assert_eq!(version_from_path(Path::new("vdjdb.slim.txt")), None);
+    }
+}
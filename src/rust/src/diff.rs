@@ -0,0 +1,170 @@
+use crate::database::{Database, DatabaseEntry};
+use std::collections::{HashMap, HashSet};
+
+/// Identity of a database record across releases: CDR3, V, J, epitope and
+/// reference, compared case-insensitively without surrounding whitespace
+pub type RecordKey = (String, String, String, String, String);
+
+pub fn record_key(entry: &DatabaseEntry) -> RecordKey {
+    key_of(
+        &entry.cdr3,
+        &entry.v_segment,
+        &entry.j_segment,
+        &entry.antigen_epitope,
+        entry.reference_id.as_deref().unwrap_or(""),
+    )
+}
+
+pub fn key_of(cdr3: &str, v: &str, j: &str, epitope: &str, reference: &str) -> RecordKey {
+    let norm = |s: &str| s.trim().to_uppercase();
+    (norm(cdr3), norm(v), norm(j), norm(epitope), norm(reference))
+}
+
+/// How a record differs between two releases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// Same key, different annotation (see `EntryDiff::fields`)
+    Changed,
+}
+
+impl Change {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+/// A record added, removed or changed between releases
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryDiff {
+    pub change: Change,
+    /// Index of the record in the old release (None when added)
+    pub old: Option<usize>,
+    /// Index of the record in the new release (None when removed)
+    pub new: Option<usize>,
+    /// Annotation fields that differ, for changed records
+    pub fields: Vec<&'static str>,
+}
+
+/// Annotation fields compared for records present in both releases
+const COMPARED_FIELDS: &[&str] = &[
+    "species",
+    "gene",
+    "antigen.gene",
+    "antigen.species",
+    "mhc.class",
+    "mhc.a",
+    "mhc.b",
+    "vdjdb.score",
+    "d.segm",
+    "method",
+    "meta",
+    "cdr3fix",
+];
+
+fn field_value(entry: &DatabaseEntry, field: &str) -> String {
+    let opt = |v: &Option<String>| v.as_deref().unwrap_or("").trim().to_string();
+    match field {
+        "species" => entry.species.trim().to_string(),
+        "gene" => entry.gene.trim().to_string(),
+        "antigen.gene" => opt(&entry.antigen_gene),
+        "antigen.species" => entry.antigen_species.trim().to_string(),
+        "mhc.class" => opt(&entry.mhc_class),
+        "mhc.a" => opt(&entry.mhc_a),
+        "mhc.b" => opt(&entry.mhc_b),
+        "vdjdb.score" => entry.vdjdb_score.to_string(),
+        "d.segm" => opt(&entry.d_segment),
+        "method" => opt(&entry.method),
+        "meta" => opt(&entry.meta),
+        "cdr3fix" => opt(&entry.cdr3_fix),
+        _ => String::new(),
+    }
+}
+
+/// Records added, removed or changed from `old` to `new`, old-release order
+/// first (removed and changed), then added records in new-release order
+/// Records sharing a key (e.g. one per identification method) are paired up
+/// in file order; unpaired ones count as added or removed.
+pub fn database_diff(old: &Database, new: &Database) -> Vec<EntryDiff> {
+    let mut new_by_key: HashMap<RecordKey, Vec<usize>> = HashMap::new();
+    for (i, entry) in new.entries.iter().enumerate().rev() {
+        new_by_key.entry(record_key(entry)).or_default().push(i);
+    }
+
+    let mut diffs = Vec::new();
+    let mut paired: HashSet<usize> = HashSet::new();
+    for (i, entry) in old.entries.iter().enumerate() {
+        match new_by_key.get_mut(&record_key(entry)).and_then(Vec::pop) {
+            Some(k) => {
+                paired.insert(k);
+                let other = &new.entries[k];
+                let fields: Vec<&'static str> = COMPARED_FIELDS
+                    .iter()
+                    .copied()
+                    .filter(|f| field_value(entry, f) != field_value(other, f))
+                    .collect();
+                if !fields.is_empty() {
+                    diffs.push(EntryDiff { change: Change::Changed, old: Some(i), new: Some(k), fields });
+                }
+            }
+            None => diffs.push(EntryDiff { change: Change::Removed, old: Some(i), new: None, fields: Vec::new() }),
+        }
+    }
+    diffs.extend(
+        (0..new.entries.len())
+            .filter(|k| !paired.contains(k))
+            .map(|k| EntryDiff { change: Change::Added, old: None, new: Some(k), fields: Vec::new() }),
+    );
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseMetadata;
+
+    fn entry(cdr3: &str, epitope: &str, reference: &str, score: u8) -> DatabaseEntry {
+        DatabaseEntry {
+            mhc_class: Some("MHCI".into()),
+            reference_id: Some(reference.into()),
+            vdjdb_score: score,
+            ..DatabaseEntry::test(cdr3, epitope)
+        }
+    }
+
+    #[test]
+    fn test_database_diff() {
+        let db = |entries| Database { entries, metadata: DatabaseMetadata::default() };
+        let old = db(vec![
+            entry("CASSLGQAYEQYF", "GLCTLVAML", "PMID:1", 1),
+            entry("CASSIRSSYEQYF", "GILGFVFTL", "PMID:2", 2),
+            entry("CASSIRSSYEQYF", "GILGFVFTL", "PMID:2", 2),
+            entry("CAVRDGYNF", "NLVPMVATV", "PMID:3", 0),
+        ]);
+        let new = db(vec![
+            entry("CASSIRSSYEQYF", "GILGFVFTL", "PMID:2", 2),
+            entry("cassLGQAYEQYF ", "GLCTLVAML", "PMID:1", 3),
+            entry("CASSPGQGYEQYF", "GLCTLVAML", "PMID:4", 1),
+        ]);
+
+        let diffs = database_diff(&old, &new);
+        let summary: Vec<(&str, Option<usize>, Option<usize>)> =
+            diffs.iter().map(|d| (d.change.as_str(), d.old, d.new)).collect();
+        assert_eq!(
+            summary,
+            [
+                ("changed", Some(0), Some(1)),
+                ("removed", Some(2), None),
+                ("removed", Some(3), None),
+                ("added", None, Some(2)),
+            ]
+        );
+        assert_eq!(diffs[0].fields, ["vdjdb.score"]);
+        assert!(database_diff(&old, &old).is_empty());
+    }
+}
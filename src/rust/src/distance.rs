@@ -0,0 +1,136 @@
+use crate::alignment::edit_distance;
+use crate::simd::count_mismatches;
+use crate::tcrdist::{align_encoded, encode_sequence};
+use rayon::prelude::*;
+
+/// Distance between two CDR3s for `cdr3_distance_matrix`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cdr3Metric {
+    /// Levenshtein distance
+    Edit,
+    /// Mismatch count; undefined for CDR3s of different length
+    Hamming,
+    /// BLOSUM62 mismatch cost, max(0, 4 - BLOSUM62), with gap cost 8 (the
+    /// tcrdist CDR3 alignment without its x3 weight)
+    Blosum,
+}
+
+impl Cdr3Metric {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "edit" | "levenshtein" => Ok(Self::Edit),
+            "hamming" => Ok(Self::Hamming),
+            "blosum" => Ok(Self::Blosum),
+            _ => Err(format!("Invalid CDR3 metric: {} (expected edit, hamming or blosum)", s)),
+        }
+    }
+
+    /// Cheapest distance two CDR3s `len_diff` residues apart in length can have,
+    /// used to skip pairs that cannot be within a radius
+    fn min_distance(self, len_diff: usize) -> f64 {
+        match self {
+            Self::Edit => len_diff as f64,
+            Self::Hamming if len_diff > 0 => f64::INFINITY,
+            Self::Hamming => 0.0,
+            Self::Blosum => (8 * len_diff) as f64,
+        }
+    }
+}
+
+/// CDR3s prepared once for repeated pairwise distances
+struct Prepared {
+    metric: Cdr3Metric,
+    seqs: Vec<String>,
+    encoded: Vec<Vec<u8>>,
+}
+
+impl Prepared {
+    fn new(cdr3s: &[String], metric: Cdr3Metric) -> Self {
+        let seqs: Vec<String> = cdr3s.iter().map(|s| s.trim().to_uppercase()).collect();
+        let encoded = match metric {
+            Cdr3Metric::Blosum => seqs.iter().map(|s| encode_sequence(s)).collect(),
+            _ => Vec::new(),
+        };
+        Self { metric, seqs, encoded }
+    }
+
+    fn distance(&self, i: usize, j: usize) -> Option<f64> {
+        let (a, b) = (&self.seqs[i], &self.seqs[j]);
+        match self.metric {
+            Cdr3Metric::Edit => Some(edit_distance(a, b) as f64),
+            Cdr3Metric::Hamming => (a.len() == b.len()).then(|| count_mismatches(a.as_bytes(), b.as_bytes()) as f64),
+            Cdr3Metric::Blosum => Some(align_encoded(&self.encoded[i], &self.encoded[j], 8) as f64),
+        }
+    }
+}
+
+/// All-vs-all CDR3 distances as a row-major (symmetric) n x n matrix
+/// Only the upper triangle is computed, in parallel. Hamming distances between
+/// CDR3s of different length are None.
+pub fn cdr3_distance_matrix(cdr3s: &[String], metric: Cdr3Metric) -> Vec<Option<f64>> {
+    let n = cdr3s.len();
+    let prepared = Prepared::new(cdr3s, metric);
+    let upper: Vec<Vec<Option<f64>>> =
+        (0..n).into_par_iter().map(|i| (i..n).map(|j| prepared.distance(i, j)).collect()).collect();
+
+    let mut matrix = vec![None; n * n];
+    for (i, row) in upper.into_iter().enumerate() {
+        for (offset, dist) in row.into_iter().enumerate() {
+            let j = i + offset;
+            matrix[i * n + j] = dist;
+            matrix[j * n + i] = dist;
+        }
+    }
+    matrix
+}
+
+/// Pairs (i, j), i < j, of CDR3s within `radius`, with their distance
+/// Pairs whose length difference alone exceeds the radius are skipped unaligned.
+pub fn cdr3_pairs_within(cdr3s: &[String], metric: Cdr3Metric, radius: f64) -> Vec<(usize, usize, f64)> {
+    let n = cdr3s.len();
+    let prepared = Prepared::new(cdr3s, metric);
+    (0..n)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let prepared = &prepared;
+            (i + 1..n).filter_map(move |j| {
+                let len_diff = prepared.seqs[i].len().abs_diff(prepared.seqs[j].len());
+                if metric.min_distance(len_diff) > radius {
+                    return None;
+                }
+                prepared.distance(i, j).filter(|&d| d <= radius).map(|d| (i, j, d))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cdr3_distances() {
+        let cdr3s: Vec<String> = ["CASSLGF", "CASSLGY", "CASSGF", "cassLGF "].iter().map(|s| s.to_string()).collect();
+
+        let edit = cdr3_distance_matrix(&cdr3s, Cdr3Metric::Edit);
+        assert_eq!(edit[1], Some(1.0));
+        assert_eq!(edit[2], Some(1.0));
+        assert_eq!(edit[3], Some(0.0));
+        assert_eq!(edit[4], edit[1]);
+
+        let hamming = cdr3_distance_matrix(&cdr3s, Cdr3Metric::Hamming);
+        assert_eq!((hamming[1], hamming[2]), (Some(1.0), None));
+
+        let blosum = cdr3_distance_matrix(&cdr3s, Cdr3Metric::Blosum);
+        assert_eq!(blosum[3], Some(0.0));
+        assert_eq!(blosum[2], Some(8.0));
+        assert!(blosum[1].unwrap() > 0.0);
+
+        let pairs = cdr3_pairs_within(&cdr3s, Cdr3Metric::Edit, 0.0);
+        assert_eq!(pairs, [(0, 3, 0.0)]);
+        let pairs = cdr3_pairs_within(&cdr3s, Cdr3Metric::Hamming, 1.0);
+        assert_eq!(pairs.len(), 3);
+        assert!(pairs.iter().all(|&(i, j, _)| i < j && i != 2 && j != 2));
+        assert!(Cdr3Metric::parse("cosine").is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::alignment::edit_distance;
+use crate::database::{Database, DatabaseEntry};
+use crate::random::Rng;
+use crate::sequence::{split_allele, SearchScope};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Sequence diversity of the database records of one epitope and chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpitopeDiversity {
+    pub epitope: String,
+    /// Receptor chain (`gene` column: TRA, TRB, ...)
+    pub gene: String,
+    pub n_records: usize,
+    pub n_unique_cdr3: usize,
+    /// Distinct CDR3s per CDR3 length
+    pub length_counts: BTreeMap<usize, usize>,
+    pub mean_length: f64,
+    /// Sample standard deviation of the distinct CDR3 lengths (None for one CDR3)
+    pub sd_length: Option<f64>,
+    /// Mean edit distance between distinct CDR3s (None for one CDR3)
+    pub mean_edit_distance: Option<f64>,
+    /// CDR3 pairs the mean is over
+    pub n_pairs: usize,
+    /// Whether every pair was compared rather than a random subsample
+    pub exhaustive: bool,
+    /// Distinct V genes (alleles merged) among records with a V call
+    pub n_v_genes: usize,
+    /// Shannon entropy (nats) of V gene usage over records
+    pub v_shannon: f64,
+    /// `v_shannon` over its maximum ln(n_v_genes) (None for fewer than two genes)
+    pub v_evenness: Option<f64>,
+}
+
+/// Diversity of each (epitope, chain) with at least `min_size` distinct CDR3s,
+/// largest first. Mean edit distances are over all pairs of distinct CDR3s, or
+/// over `max_pairs` random pairs when there are more.
+pub fn epitope_diversity(db: &Database, min_size: usize, max_pairs: usize, rng: &mut Rng) -> Vec<EpitopeDiversity> {
+    let mut groups: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (i, entry) in db.entries.iter().enumerate() {
+        if !entry.cdr3.is_empty() {
+            groups.entry((entry.antigen_epitope.as_str(), entry.gene.as_str())).or_default().push(i);
+        }
+    }
+
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .map(|(key, rows)| {
+            let mut cdr3s: Vec<&str> = rows.iter().map(|&i| db.entries[i].cdr3.as_str()).collect();
+            cdr3s.sort_unstable();
+            cdr3s.dedup();
+            (key, cdr3s, rows)
+        })
+        .filter(|(_, cdr3s, _)| cdr3s.len() >= min_size.max(1))
+        .collect();
+    groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+
+    // Draw one seed per group up front so results do not depend on scheduling
+    let seeds: Vec<u64> = groups.iter().map(|_| rng.next_u64()).collect();
+    groups
+        .into_par_iter()
+        .zip(seeds)
+        .map(|(((epitope, gene), cdr3s, rows), seed)| {
+            let mut length_counts = BTreeMap::new();
+            for s in &cdr3s {
+                *length_counts.entry(s.len()).or_insert(0) += 1;
+            }
+            let lengths: Vec<f64> = cdr3s.iter().map(|s| s.len() as f64).collect();
+            let n = lengths.len() as f64;
+            let mean_length = lengths.iter().sum::<f64>() / n;
+            let sd_length = (lengths.len() > 1)
+                .then(|| (lengths.iter().map(|l| (l - mean_length).powi(2)).sum::<f64>() / (n - 1.0)).sqrt());
+
+            let (mean_edit_distance, n_pairs, exhaustive) = mean_pairwise_distance(&cdr3s, max_pairs, &mut Rng::new(seed));
+
+            let mut v_counts: HashMap<String, usize> = HashMap::new();
+            for &i in &rows {
+                let v = split_allele(db.entries[i].v_segment.trim()).0;
+                if !v.is_empty() {
+                    *v_counts.entry(v.to_uppercase()).or_insert(0) += 1;
+                }
+            }
+            let n_v: usize = v_counts.values().sum();
+            let v_shannon = -v_counts
+                .values()
+                .map(|&c| {
+                    let p = c as f64 / n_v as f64;
+                    p * p.ln()
+                })
+                .sum::<f64>();
+            let v_evenness = (v_counts.len() > 1).then(|| v_shannon / (v_counts.len() as f64).ln());
+
+            EpitopeDiversity {
+                epitope: epitope.to_string(),
+                gene: gene.to_string(),
+                n_records: rows.len(),
+                n_unique_cdr3: cdr3s.len(),
+                length_counts,
+                mean_length,
+                sd_length,
+                mean_edit_distance,
+                n_pairs,
+                exhaustive,
+                n_v_genes: v_counts.len(),
+                v_shannon,
+                v_evenness,
+            }
+        })
+        .collect()
+}
+
+/// Mean edit distance over all pairs of `seqs`, or `max_pairs` random pairs
+/// when there are more; returns (mean, pairs compared, exhaustive)
+fn mean_pairwise_distance(seqs: &[&str], max_pairs: usize, rng: &mut Rng) -> (Option<f64>, usize, bool) {
+    let n = seqs.len();
+    let total = n * n.saturating_sub(1) / 2;
+    if total == 0 || max_pairs == 0 {
+        return (None, 0, total == 0);
+    }
+    let (sum, pairs, exhaustive) = if total <= max_pairs {
+        let sum: usize = (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))).map(|(i, j)| edit_distance(seqs[i], seqs[j])).sum();
+        (sum, total, true)
+    } else {
+        let sum: usize = (0..max_pairs)
+            .map(|_| {
+                let i = rng.below(n);
+                let j = (i + 1 + rng.below(n - 1)) % n;
+                edit_distance(seqs[i], seqs[j])
+            })
+            .sum();
+        (sum, max_pairs, false)
+    };
+    (Some(sum as f64 / pairs as f64), pairs, exhaustive)
+}
+
+/// CDR3 pairs compared per epitope for adaptive scopes
+const ADAPTIVE_MAX_PAIRS: usize = 2000;
+const ADAPTIVE_SEED: u64 = 0xAD4E;
+
+/// Mean CDR3 edit distance by epitope, then chain
+pub type EpitopeDistances = HashMap<String, HashMap<String, f64>>;
+
+/// Mean edit distance between the distinct CDR3s of each (epitope, chain)
+/// with at least two of them, over a fixed random subsample of pairs
+pub fn mean_cdr3_distances(db: &Database) -> EpitopeDistances {
+    let mut distances = EpitopeDistances::new();
+    for d in epitope_diversity(db, 2, ADAPTIVE_MAX_PAIRS, &mut Rng::new(ADAPTIVE_SEED)) {
+        if let Some(mean) = d.mean_edit_distance {
+            distances.entry(d.epitope).or_default().insert(d.gene, mean);
+        }
+    }
+    distances
+}
+
+/// What an adaptive search scope scales with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdaptiveBasis {
+    /// Mean pairwise CDR3 edit distance of the database entry's epitope
+    Diversity,
+    /// Length of the query CDR3
+    Length,
+}
+
+/// Search scope set per database entry instead of globally
+/// ("adaptive[:key=value,...]"): the allowed edit distance is `fraction` of
+/// the basis, rounded down and clamped to `min_total..=max_total`, with at
+/// most `max_indels` insertions and deletions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveScopeParams {
+    pub basis: AdaptiveBasis,
+    pub fraction: f64,
+    pub min_total: usize,
+    pub max_total: usize,
+    pub max_indels: usize,
+}
+
+impl AdaptiveScopeParams {
+    /// Parse "adaptive" with optional `by` ("diversity" or "length"),
+    /// `fraction`, `min`, `max` and `indels` settings; None for other scopes
+    pub fn parse(scope: &str) -> Result<Option<Self>, String> {
+        let scope = scope.trim();
+        let Some(rest) = scope.strip_prefix("adaptive") else {
+            return Ok(None);
+        };
+        let settings = match rest.strip_prefix(':') {
+            Some(settings) => settings,
+            None if rest.is_empty() => "",
+            None => return Ok(None),
+        };
+
+        let mut params = Self {
+            basis: AdaptiveBasis::Diversity,
+            fraction: f64::NAN,
+            min_total: 0,
+            max_total: 3,
+            max_indels: 1,
+        };
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("adaptive scope settings are key=value pairs, got '{}'", setting))?;
+            let (key, value) = (key.trim(), value.trim());
+            let count = || value.parse::<usize>().map_err(|_| format!("adaptive scope '{}' must be a non-negative integer, got '{}'", key, value));
+            match key {
+                "by" => {
+                    params.basis = match value {
+                        "diversity" => AdaptiveBasis::Diversity,
+                        "length" => AdaptiveBasis::Length,
+                        _ => return Err(format!("adaptive scope 'by' must be \"diversity\" or \"length\", got '{}'", value)),
+                    }
+                }
+                "fraction" => {
+                    params.fraction = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|f| f.is_finite() && *f >= 0.0)
+                        .ok_or_else(|| format!("adaptive scope 'fraction' must be a non-negative number, got '{}'", value))?
+                }
+                "min" => params.min_total = count()?,
+                "max" => params.max_total = count()?,
+                "indels" => params.max_indels = count()?,
+                _ => return Err(format!("unknown adaptive scope setting '{}' (use by, fraction, min, max or indels)", key)),
+            }
+        }
+        if params.min_total > params.max_total {
+            return Err(format!("adaptive scope 'min' ({}) exceeds 'max' ({})", params.min_total, params.max_total));
+        }
+        if params.fraction.is_nan() {
+            params.fraction = match params.basis {
+                AdaptiveBasis::Diversity => 0.25,
+                AdaptiveBasis::Length => 0.1,
+            };
+        }
+        Ok(Some(params))
+    }
+
+    /// The widest scope any entry can get
+    pub fn widest(&self) -> SearchScope {
+        self.scope_with_total(self.max_total)
+    }
+
+    fn scope_for_basis(&self, basis: Option<f64>) -> SearchScope {
+        let total = basis.map_or(self.min_total, |b| (self.fraction * b).floor() as usize);
+        self.scope_with_total(total.clamp(self.min_total, self.max_total))
+    }
+
+    fn scope_with_total(&self, total: usize) -> SearchScope {
+        let indels = self.max_indels.min(total);
+        SearchScope { substitutions: total, insertions: indels, deletions: indels, total }
+    }
+}
+
+/// Adaptive scope with the epitope diversities of one database
+#[derive(Debug, Clone)]
+pub struct AdaptiveScope {
+    pub params: AdaptiveScopeParams,
+    /// `mean_cdr3_distances` of the database (empty for the length basis)
+    mean_distances: Arc<EpitopeDistances>,
+}
+
+impl AdaptiveScope {
+    pub fn new(params: AdaptiveScopeParams, mean_distances: Arc<EpitopeDistances>) -> Self {
+        Self { params, mean_distances }
+    }
+
+    /// Scope for matching a query CDR3 of `query_len` residues against `entry`
+    /// Entries of epitopes with a single distinct CDR3 get the minimum scope.
+    pub fn scope_for(&self, query_len: usize, entry: &DatabaseEntry) -> SearchScope {
+        let basis = match self.params.basis {
+            AdaptiveBasis::Length => Some(query_len as f64),
+            AdaptiveBasis::Diversity => self
+                .mean_distances
+                .get(&entry.antigen_epitope)
+                .and_then(|by_gene| by_gene.get(&entry.gene))
+                .copied(),
+        };
+        self.params.scope_for_basis(basis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseEntry;
+
+    fn entry(cdr3: &str, v: &str, epitope: &str) -> DatabaseEntry {
+        DatabaseEntry {
+            v_segment: v.to_string(),
+            ..DatabaseEntry::test(cdr3, epitope)
+        }
+    }
+
+    #[test]
+    fn test_epitope_diversity() {
+        let db = Database {
+            entries: vec![
+                entry("CASSLF", "TRBV19*01", "A"),
+                entry("CASSLF", "TRBV19*02", "A"),
+                entry("CASSQF", "TRBV19*01", "A"),
+                entry("CASSQEF", "TRBV7-9*01", "A"),
+                entry("CAVRDF", "TRBV2*01", "B"),
+            ],
+            metadata: crate::database::DatabaseMetadata::default(),
+        };
+        let stats = epitope_diversity(&db, 1, 100, &mut Rng::new(1));
+        assert_eq!(stats.len(), 2);
+        let a = &stats[0];
+        assert_eq!((a.epitope.as_str(), a.n_records, a.n_unique_cdr3), ("A", 4, 3));
+        assert_eq!(a.length_counts, BTreeMap::from([(6, 2), (7, 1)]));
+        // CASSLF-CASSQF 1, CASSLF-CASSQEF 2, CASSQEF-CASSQF 1
+        assert_eq!((a.mean_edit_distance, a.n_pairs, a.exhaustive), (Some(4.0 / 3.0), 3, true));
+        assert_eq!(a.n_v_genes, 2);
+        let expected = -(0.75f64 * 0.75f64.ln() + 0.25 * 0.25f64.ln());
+        assert!((a.v_shannon - expected).abs() < 1e-12);
+        assert!((a.v_evenness.unwrap() - expected / 2f64.ln()).abs() < 1e-12);
+
+        let b = &stats[1];
+        assert_eq!((b.mean_edit_distance, b.sd_length, b.v_shannon, b.v_evenness), (None, None, 0.0, None));
+        assert_eq!(epitope_diversity(&db, 2, 100, &mut Rng::new(1)).len(), 1);
+
+        // Subsampled pairs never compare a CDR3 with itself
+        let sampled = epitope_diversity(&db, 2, 2, &mut Rng::new(1));
+        assert_eq!((sampled[0].n_pairs, sampled[0].exhaustive), (2, false));
+        assert!(sampled[0].mean_edit_distance.unwrap() >= 1.0);
+    }
+
+    #[test]
+    fn test_adaptive_scope() {
+        assert_eq!(AdaptiveScopeParams::parse("1,0,0,1"), Ok(None));
+        let params = AdaptiveScopeParams::parse("adaptive").unwrap().unwrap();
+        assert_eq!((params.basis, params.fraction, params.min_total, params.max_total), (AdaptiveBasis::Diversity, 0.25, 0, 3));
+        let params = AdaptiveScopeParams::parse(" adaptive:by=length, max=2,indels=0 ").unwrap().unwrap();
+        assert_eq!((params.basis, params.fraction, params.max_total, params.max_indels), (AdaptiveBasis::Length, 0.1, 2, 0));
+        assert!(AdaptiveScopeParams::parse("adaptive:by=width").is_err());
+        assert!(AdaptiveScopeParams::parse("adaptive:fraction=-1").is_err());
+        assert!(AdaptiveScopeParams::parse("adaptive:min=3,max=1").is_err());
+        assert!(AdaptiveScopeParams::parse("adaptive:radius=2").is_err());
+
+        // A diverse epitope tolerates more edits than a convergent one
+        let mut entries: Vec<DatabaseEntry> =
+            ["CASSLAPGATNEKLFF", "CSARDRTGNGYTF", "CAWSVQGTEAFF", "CASRPGLAGGRPEQYF"]
+                .iter()
+                .map(|c| entry(c, "TRBV19*01", "DIVERSE"))
+                .collect();
+        entries.extend(["CASSIRSSYEQYF", "CASSIRSAYEQYF"].iter().map(|c| entry(c, "TRBV19*01", "CONVERGENT")));
+        let db = Database { entries, metadata: crate::database::DatabaseMetadata::default() };
+        let distances = mean_cdr3_distances(&db);
+        assert_eq!(distances["CONVERGENT"]["TRB"], 1.0);
+        assert!(distances["DIVERSE"]["TRB"] >= 8.0);
+
+        let adaptive = AdaptiveScope::new(AdaptiveScopeParams::parse("adaptive").unwrap().unwrap(), Arc::new(distances));
+        assert_eq!(adaptive.scope_for(13, &db.entries[0]).total, 2);
+        assert_eq!(adaptive.scope_for(13, &db.entries[4]).total, 0);
+        assert_eq!(adaptive.scope_for(13, &entry("CASSF", "", "UNSEEN")).total, 0);
+        let scope = adaptive.scope_for(13, &db.entries[0]);
+        assert_eq!((scope.substitutions, scope.insertions, scope.deletions), (2, 1, 1));
+
+        let by_length = AdaptiveScope::new(AdaptiveScopeParams::parse("adaptive:by=length,fraction=0.2").unwrap().unwrap(), Arc::default());
+        assert_eq!(by_length.scope_for(9, &db.entries[0]).total, 1);
+        assert_eq!(by_length.scope_for(20, &db.entries[0]).total, 3);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::random::Rng;
+use rayon::prelude::*;
+
+/// Low-dimensional coordinates of n points
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embedding {
+    /// Row-major n x dims coordinates
+    pub coordinates: Vec<f64>,
+    pub dims: usize,
+    /// Eigenvalues of the (landmark) Gram matrix, one per dimension; 0 for
+    /// dimensions without a positive eigenvalue
+    pub eigenvalues: Vec<f64>,
+    /// Indices of the landmark points (all points for classical MDS)
+    pub landmarks: Vec<usize>,
+}
+
+/// Power iterations per eigenvector before giving up on convergence
+const MAX_POWER_ITERATIONS: usize = 1000;
+
+/// Leading `k` eigenpairs of a symmetric m x m matrix (row-major) by power
+/// iteration with deflation, largest eigenvalue first
+/// Power iteration finds the eigenvalue of largest magnitude, and Gram matrices
+/// of non-Euclidean distances such as tcrdist have large negative ones, so the
+/// spectrum is first shifted by a Gershgorin bound to make it non-negative.
+/// Start vectors come from `rng`, so results are reproducible for a seed.
+fn top_eigenpairs(matrix: &[f64], m: usize, k: usize, rng: &mut Rng) -> Vec<(f64, Vec<f64>)> {
+    let shift = (0..m)
+        .map(|i| {
+            let row = &matrix[i * m..(i + 1) * m];
+            row.iter().map(|x| x.abs()).sum::<f64>() - row[i].abs() - row[i]
+        })
+        .fold(0.0, f64::max);
+    let mut a = matrix.to_vec();
+    for i in 0..m {
+        a[i * m + i] += shift;
+    }
+    let mut pairs = Vec::with_capacity(k);
+    for _ in 0..k.min(m) {
+        let mut v: Vec<f64> = (0..m).map(|_| rng.next_f64() - 0.5).collect();
+        normalize(&mut v);
+        let mut lambda = 0.0;
+        for _ in 0..MAX_POWER_ITERATIONS {
+            let mut w: Vec<f64> = (0..m).into_par_iter().map(|i| dot(&a[i * m..(i + 1) * m], &v)).collect();
+            let next_lambda = dot(&v, &w);
+            if normalize(&mut w) == 0.0 {
+                lambda = 0.0;
+                break;
+            }
+            let change = v.iter().zip(&w).map(|(x, y)| (x - y).abs()).fold(0.0, f64::max);
+            v = w;
+            let converged = (next_lambda - lambda).abs() <= 1e-10 * next_lambda.abs().max(1.0) && change < 1e-8;
+            lambda = next_lambda;
+            if converged {
+                break;
+            }
+        }
+        // Deflate: A -= lambda v v^T, leaving the pair at 0, below the rest of the shifted spectrum
+        for i in 0..m {
+            for j in 0..m {
+                a[i * m + j] -= lambda * v[i] * v[j];
+            }
+        }
+        pairs.push((lambda - shift, v));
+    }
+    pairs
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Scale to unit length; returns the original norm
+fn normalize(v: &mut [f64]) -> f64 {
+    let norm = dot(v, v).sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    norm
+}
+
+/// Double-centered Gram matrix -1/2 H D^2 H of an m x m distance matrix
+fn gram_matrix(distances: &[f64], m: usize) -> (Vec<f64>, Vec<f64>) {
+    let squared: Vec<f64> = distances.iter().map(|d| d * d).collect();
+    let row_mean: Vec<f64> = (0..m).map(|i| squared[i * m..(i + 1) * m].iter().sum::<f64>() / m as f64).collect();
+    let grand_mean = row_mean.iter().sum::<f64>() / m.max(1) as f64;
+    let gram = (0..m * m)
+        .map(|k| {
+            let (i, j) = (k / m, k % m);
+            -0.5 * (squared[k] - row_mean[i] - row_mean[j] + grand_mean)
+        })
+        .collect();
+    (gram, row_mean)
+}
+
+/// Classical (Torgerson) MDS of a full n x n distance matrix (row-major)
+pub fn classical_mds(distances: &[f64], n: usize, dims: usize, rng: &mut Rng) -> Embedding {
+    let (gram, _) = gram_matrix(distances, n);
+    let pairs = top_eigenpairs(&gram, n, dims, rng);
+    let mut coordinates = vec![0.0; n * dims];
+    let mut eigenvalues = vec![0.0; dims];
+    for (d, (lambda, v)) in pairs.iter().enumerate() {
+        if *lambda <= 0.0 {
+            continue;
+        }
+        eigenvalues[d] = *lambda;
+        for i in 0..n {
+            coordinates[i * dims + d] = v[i] * lambda.sqrt();
+        }
+    }
+    Embedding { coordinates, dims, eigenvalues, landmarks: (0..n).collect() }
+}
+
+/// Landmark MDS (de Silva & Tenenbaum 2004) of n points given a distance function
+/// Picks `n_landmarks` spread-out landmarks by max-min selection from a random
+/// start, embeds them by classical MDS and places every point by distance-based
+/// triangulation against the landmarks, so only n x n_landmarks distances are
+/// computed. With n <= n_landmarks this is classical MDS.
+pub fn landmark_mds<F>(n: usize, distance: F, n_landmarks: usize, dims: usize, rng: &mut Rng) -> Embedding
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let n_landmarks = n_landmarks.clamp(1, n.max(1));
+    if n == 0 {
+        return Embedding { coordinates: Vec::new(), dims, eigenvalues: vec![0.0; dims], landmarks: Vec::new() };
+    }
+
+    // Max-min landmark selection, keeping each landmark's distances to all points
+    let mut landmarks = vec![rng.below(n)];
+    let mut is_landmark = vec![false; n];
+    is_landmark[landmarks[0]] = true;
+    let mut to_landmark: Vec<Vec<f64>> = Vec::with_capacity(n_landmarks);
+    let mut nearest = vec![f64::INFINITY; n];
+    while to_landmark.len() < n_landmarks {
+        let l = *landmarks.last().unwrap();
+        let row: Vec<f64> = (0..n).into_par_iter().map(|i| distance(l, i)).collect();
+        for (near, d) in nearest.iter_mut().zip(&row) {
+            *near = near.min(*d);
+        }
+        to_landmark.push(row);
+        if to_landmark.len() == n_landmarks {
+            break;
+        }
+        let (next, _) = nearest
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !is_landmark[*i])
+            .fold((usize::MAX, f64::NEG_INFINITY), |best, (i, &d)| if d > best.1 { (i, d) } else { best });
+        if next == usize::MAX {
+            break;
+        }
+        is_landmark[next] = true;
+        landmarks.push(next);
+    }
+
+    let m = landmarks.len();
+    let landmark_distances: Vec<f64> = (0..m * m).map(|k| to_landmark[k / m][landmarks[k % m]]).collect();
+    let (gram, mean_squared) = gram_matrix(&landmark_distances, m);
+    let pairs = top_eigenpairs(&gram, m, dims, rng);
+
+    let mut coordinates = vec![0.0; n * dims];
+    let mut eigenvalues = vec![0.0; dims];
+    for (d, (lambda, v)) in pairs.iter().enumerate() {
+        if *lambda <= 0.0 {
+            continue;
+        }
+        eigenvalues[d] = *lambda;
+        let scale = -0.5 / lambda.sqrt();
+        let column: Vec<f64> = (0..n)
+            .into_par_iter()
+            .map(|i| {
+                let projection: f64 =
+                    (0..m).map(|l| v[l] * (to_landmark[l][i] * to_landmark[l][i] - mean_squared[l])).sum();
+                scale * projection
+            })
+            .collect();
+        for (i, x) in column.into_iter().enumerate() {
+            coordinates[i * dims + d] = x;
+        }
+    }
+    Embedding { coordinates, dims, eigenvalues, landmarks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mds_recovers_planar_distances() {
+        let points = [(0.0, 0.0), (3.0, 0.0), (0.0, 4.0), (3.0, 4.0), (1.0, 1.0), (10.0, 2.0)];
+        let n = points.len();
+        let dist = |i: usize, j: usize| {
+            let (a, b): ((f64, f64), (f64, f64)) = (points[i], points[j]);
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        };
+        let full: Vec<f64> = (0..n * n).map(|k| dist(k / n, k % n)).collect();
+        let embedded_dist = |e: &Embedding, i: usize, j: usize| {
+            let (a, b) = (&e.coordinates[i * 2..i * 2 + 2], &e.coordinates[j * 2..j * 2 + 2]);
+            ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+        };
+
+        let classical = classical_mds(&full, n, 2, &mut Rng::new(1));
+        let landmark = landmark_mds(n, dist, 4, 2, &mut Rng::new(1));
+        assert_eq!(landmark.landmarks.len(), 4);
+        for i in 0..n {
+            for j in 0..n {
+                assert!((embedded_dist(&classical, i, j) - dist(i, j)).abs() < 1e-6);
+                assert!((embedded_dist(&landmark, i, j) - dist(i, j)).abs() < 1e-6);
+            }
+        }
+        assert!(classical.eigenvalues[0] >= classical.eigenvalues[1]);
+
+        // A third dimension has no positive eigenvalue for planar points
+        let three = classical_mds(&full, n, 3, &mut Rng::new(1));
+        assert!(three.eigenvalues[2] < 1e-6);
+        assert!(landmark_mds(0, dist, 4, 2, &mut Rng::new(1)).coordinates.is_empty());
+    }
+
+    #[test]
+    fn test_mds_of_non_euclidean_distances() {
+        // Six points on a cycle at distances 1, 2, 1 (a metric, but not Euclidean):
+        // the Gram eigenvalues are 2 (four times) and -2.5, which dominates in magnitude
+        let n: usize = 6;
+        let step = [0.0, 1.0, 2.0, 1.0];
+        let full: Vec<f64> = (0..n * n)
+            .map(|k| {
+                let d = (k / n).abs_diff(k % n);
+                step[d.min(n - d)]
+            })
+            .collect();
+
+        let classical = classical_mds(&full, n, 4, &mut Rng::new(1));
+        let landmark = landmark_mds(n, |i, j| full[i * n + j], n, 4, &mut Rng::new(1));
+        for embedding in [&classical, &landmark] {
+            for d in 0..4 {
+                assert!((embedding.eigenvalues[d] - 2.0).abs() < 1e-6, "{:?}", embedding.eigenvalues);
+                assert!((0..n).any(|i| embedding.coordinates[i * 4 + d].abs() > 0.1));
+            }
+        }
+        // Then the centering direction at 0; the negative eigenvalue comes last and gets no coordinates
+        let all = classical_mds(&full, n, 6, &mut Rng::new(1));
+        assert!(all.eigenvalues[4].abs() < 1e-6);
+        assert_eq!(all.eigenvalues[5], 0.0);
+        assert!((0..n).all(|i| all.coordinates[i * 6 + 5] == 0.0));
+    }
+}
@@ -4,33 +4,183 @@ use thiserror::Error;
 pub enum VdjMatchError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    
+
     #[error("CSV parsing error: {0}")]
     Csv(#[from] csv::Error),
-    
-    #[error("Database not found: {0}")]
-    DatabaseNotFound(String),
-    
+
+    #[error("Database not found: {path}: {reason}")]
+    DatabaseNotFound { path: String, reason: String },
+
     #[error("Invalid search scope: {0}")]
     InvalidSearchScope(String),
-    
+
     #[error("Invalid filter expression: {0}")]
     InvalidFilter(String),
-    
+
     #[error("Sequence error: {0}")]
     Sequence(String),
-    
+
     #[error("Alignment error: {0}")]
     Alignment(String),
-    
+
     #[error("Invalid configuration: {0}")]
     Configuration(String),
-    
+
+    #[error("Invalid file format: {0}")]
+    InvalidFormat(String),
+
+    #[error(
+        "Invalid file format: missing required column(s): {} (found: {}); rename them or pass a column mapping",
+        missing.join(", "),
+        found.join(", ")
+    )]
+    MissingColumns { missing: Vec<String>, found: Vec<String> },
+
+    #[error("Invalid file format: {path} is empty (no header line)")]
+    EmptyFile { path: String },
+
+    #[error(
+        "Invalid file format: column \"{column}\" appears {} times (columns {}); remove or rename the duplicates",
+        positions.len(),
+        positions.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    DuplicateColumns { column: String, positions: Vec<usize> },
+
+    #[error("{handle} handle is closed and can no longer be used")]
+    HandleClosed { handle: &'static str },
+
+    #[error("Not available for this database: {0}")]
+    Unsupported(String),
+
     #[error("Network error: {0}")]
     Network(#[from] reqwest::Error),
-    
+
     #[error("Regex error: {0}")]
     Regex(#[from] regex::Error),
 }
 
 pub type Result<T> = std::result::Result<T, VdjMatchError>;
+
+/// Marks an error message carrying a condition code and fields for R
+const CONDITION_MARK: &str = "\u{1e}vdjmatchR";
+const RECORD_SEPARATOR: char = '\u{1e}';
+const FIELD_SEPARATOR: char = '\u{1f}';
+
+impl VdjMatchError {
+    /// Condition code, raised in R with class "vdjmatchR_<code>"
+    pub fn code(&self) -> &'static str {
+        match self {
+            VdjMatchError::Io(_) => "io",
+            VdjMatchError::Csv(_) => "parse",
+            VdjMatchError::DatabaseNotFound { .. } => "database_not_found",
+            VdjMatchError::InvalidSearchScope(_) => "invalid_scope",
+            VdjMatchError::InvalidFilter(_) => "invalid_filter",
+            VdjMatchError::Sequence(_) => "sequence",
+            VdjMatchError::Alignment(_) => "alignment",
+            VdjMatchError::Configuration(_) => "configuration",
+            VdjMatchError::InvalidFormat(_) => "invalid_format",
+            VdjMatchError::MissingColumns { .. } => "missing_columns",
+            VdjMatchError::EmptyFile { .. } => "empty_file",
+            VdjMatchError::DuplicateColumns { .. } => "duplicate_columns",
+            VdjMatchError::HandleClosed { .. } => "handle_closed",
+            VdjMatchError::Unsupported(_) => "unsupported",
+            VdjMatchError::Network(_) => "network",
+            VdjMatchError::Regex(_) => "regex",
+        }
+    }
+
+    /// Fields describing where the error occurred: `path`, `row` (1-based
+    /// data row), `line` (file line, header is line 1) and `column`
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            VdjMatchError::DatabaseNotFound { path, .. } => vec![("path", path.clone())],
+            VdjMatchError::MissingColumns { missing, .. } => vec![("column", missing.join(","))],
+            VdjMatchError::EmptyFile { path } => vec![("path", path.clone())],
+            VdjMatchError::DuplicateColumns { column, .. } => vec![("column", column.clone())],
+            VdjMatchError::Csv(e) => {
+                let mut fields = Vec::new();
+                if let Some(pos) = e.position() {
+                    // The header is record 0, so the record number is the data row
+                    fields.push(("row", pos.record().to_string()));
+                    fields.push(("line", pos.line().to_string()));
+                }
+                let field = match e.kind() {
+                    csv::ErrorKind::Utf8 { err, .. } => Some(err.field()),
+                    csv::ErrorKind::Deserialize { err, .. } => err.field().map(|f| f as usize),
+                    _ => None,
+                };
+                fields.extend(field.map(|f| ("column", (f + 1).to_string())));
+                fields
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Message for an R error, with the code and fields encoded for
+    /// `vdjmatchR_condition()` to raise as a classed condition
+    pub fn to_r_message(&self) -> String {
+        encode_condition(self.code(), &self.fields(), &self.to_string())
+    }
+
+    /// Like `to_r_message`, with `context` ("Failed to save database to x")
+    /// before the message
+    pub fn to_r_message_with(&self, context: &str) -> String {
+        encode_condition(self.code(), &self.fields(), &format!("{}: {}", context, self))
+    }
+}
+
+/// Encode an error for R: mark, code, `key=value` fields and the message,
+/// separated by ASCII record/unit separators that do not occur in messages
+pub fn encode_condition(code: &str, fields: &[(&str, String)], message: &str) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value.replace([RECORD_SEPARATOR, FIELD_SEPARATOR], " ")))
+        .collect();
+    format!(
+        "{}{}{}{}{}{}",
+        CONDITION_MARK,
+        RECORD_SEPARATOR,
+        code,
+        RECORD_SEPARATOR,
+        fields.join(&FIELD_SEPARATOR.to_string()),
+        RECORD_SEPARATOR
+    ) + message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition_fields() {
+        let err = VdjMatchError::DatabaseNotFound { path: "/x/vdjdb.txt".into(), reason: "No such file".into() };
+        assert_eq!(err.code(), "database_not_found");
+        assert_eq!(err.to_string(), "Database not found: /x/vdjdb.txt: No such file");
+        assert_eq!(
+            err.to_r_message(),
+            "\u{1e}vdjmatchR\u{1e}database_not_found\u{1e}path=/x/vdjdb.txt\u{1e}Database not found: /x/vdjdb.txt: No such file"
+        );
+
+        let data = "gene\tcdr3\nTRB\tCASSF\nTRB\n";
+        let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_reader(data.as_bytes());
+        let err: VdjMatchError = reader.records().nth(1).unwrap().unwrap_err().into();
+        assert_eq!(err.code(), "parse");
+        assert_eq!(err.fields(), [("row", "2".to_string()), ("line", "3".to_string())]);
+
+        let err = VdjMatchError::MissingColumns { missing: vec!["v.segm".into(), "j.segm".into()], found: vec!["cdr3".into()] };
+        assert_eq!(err.fields(), [("column", "v.segm,j.segm".to_string())]);
+        assert!(err.to_r_message_with("Failed to load x").ends_with("\u{1e}Failed to load x: Invalid file format: missing required column(s): v.segm, j.segm (found: cdr3); rename them or pass a column mapping"));
+
+        let err = VdjMatchError::DuplicateColumns { column: "cdr3".into(), positions: vec![2, 7] };
+        assert_eq!(err.code(), "duplicate_columns");
+        assert_eq!(err.fields(), [("column", "cdr3".to_string())]);
+        assert_eq!(
+            err.to_string(),
+            "Invalid file format: column \"cdr3\" appears 2 times (columns 2, 7); remove or rename the duplicates"
+        );
+
+        let err = VdjMatchError::HandleClosed { handle: "RMatchResult" };
+        assert_eq!(err.code(), "handle_closed");
+        assert_eq!(err.to_string(), "RMatchResult handle is closed and can no longer be used");
+    }
+}
@@ -0,0 +1,300 @@
+use crate::sequence::Clonotype;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+const AMINO_ACIDS: &[u8; 20] = b"ACDEFGHIKLMNPQRSTVWY";
+
+fn residue_index(aa: u8) -> Option<usize> {
+    AMINO_ACIDS.iter().position(|&x| x == aa.to_ascii_uppercase())
+}
+
+/// Length-stratified, position-independent CDR3 generation model
+/// P(seq) = P(length) * prod_i f_{length,i}(seq_i), fitted with a pseudocount.
+/// A lightweight stand-in for a full recombination model: good enough to
+/// calibrate neighbor counts against the repertoire's own composition.
+#[derive(Debug, Clone)]
+pub struct PositionModel {
+    length_prob: HashMap<usize, f64>,
+    frequencies: HashMap<usize, Vec<[f64; 20]>>,
+}
+
+impl PositionModel {
+    pub fn fit(seqs: &[&str], pseudocount: f64) -> Self {
+        let mut counts: HashMap<usize, Vec<[f64; 20]>> = HashMap::new();
+        let mut length_counts: HashMap<usize, f64> = HashMap::new();
+        let mut total = 0.0;
+        for s in seqs {
+            let bytes = s.as_bytes();
+            let positions = counts.entry(bytes.len()).or_insert_with(|| vec![[0.0; 20]; bytes.len()]);
+            for (pos, &aa) in bytes.iter().enumerate() {
+                if let Some(r) = residue_index(aa) {
+                    positions[pos][r] += 1.0;
+                }
+            }
+            *length_counts.entry(bytes.len()).or_insert(0.0) += 1.0;
+            total += 1.0;
+        }
+
+        let frequencies = counts
+            .into_iter()
+            .map(|(len, positions)| {
+                let normalized = positions
+                    .into_iter()
+                    .map(|c| {
+                        let sum: f64 = c.iter().sum::<f64>() + 20.0 * pseudocount;
+                        let mut f = [0.0; 20];
+                        for r in 0..20 {
+                            f[r] = (c[r] + pseudocount) / sum;
+                        }
+                        f
+                    })
+                    .collect();
+                (len, normalized)
+            })
+            .collect();
+        let length_prob = length_counts.into_iter().map(|(len, c)| (len, c / total)).collect();
+        Self { length_prob, frequencies }
+    }
+
+    /// Generation probability of `seq`; 0 for lengths or residues never seen in fitting
+    pub fn probability(&self, seq: &str) -> f64 {
+        let (Some(&p_len), Some(freqs)) = (self.length_prob.get(&seq.len()), self.frequencies.get(&seq.len())) else {
+            return 0.0;
+        };
+        seq.bytes().zip(freqs).fold(p_len, |p, (aa, f)| match residue_index(aa) {
+            Some(r) => p * f[r],
+            None => 0.0,
+        })
+    }
+
+    /// Summed probability of all single-substitution neighbors of `seq`
+    /// Closed form: P(seq) * sum_i (1 - f_i(a_i)) / f_i(a_i).
+    pub fn neighbor_probability(&self, seq: &str) -> f64 {
+        let p = self.probability(seq);
+        if p == 0.0 {
+            return 0.0;
+        }
+        let freqs = &self.frequencies[&seq.len()];
+        let ratio: f64 = seq
+            .bytes()
+            .zip(freqs)
+            .map(|(aa, f)| {
+                let fa = f[residue_index(aa).unwrap_or(0)];
+                (1.0 - fa) / fa
+            })
+            .sum();
+        p * ratio
+    }
+}
+
+/// Per-clonotype ALICE statistics
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliceResult {
+    /// Distinct CDR3s in the sample one substitution away with the same V and J
+    pub n_neighbors: usize,
+    /// Expected neighbor count under the generation model
+    pub expected: f64,
+    pub p_value: f64,
+    /// Benjamini-Hochberg adjusted p-value over distinct clonotypes
+    pub q_value: f64,
+}
+
+/// Poisson upper tail P(X >= k) for rate `lambda`
+pub fn poisson_upper_tail(k: usize, lambda: f64) -> f64 {
+    if k == 0 {
+        return 1.0;
+    }
+    if lambda <= 0.0 {
+        return 0.0;
+    }
+    let ln_fact: f64 = (1..=k).map(|i| (i as f64).ln()).sum();
+    let mut term = (-lambda + k as f64 * lambda.ln() - ln_fact).exp();
+    let mut sum = 0.0;
+    let mut i = k;
+    loop {
+        sum += term;
+        i += 1;
+        term *= lambda / i as f64;
+        if (i as f64 > lambda && term < sum * 1e-16) || term == 0.0 {
+            break;
+        }
+    }
+    sum.min(1.0)
+}
+
+/// Benjamini-Hochberg adjusted p-values, in input order
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| p_values[b].total_cmp(&p_values[a]));
+    let mut adjusted = vec![0.0; n];
+    let mut running = 1.0f64;
+    for (rank, &i) in order.iter().enumerate() {
+        let k = (n - rank) as f64;
+        running = running.min(p_values[i] * n as f64 / k);
+        adjusted[i] = running;
+    }
+    adjusted
+}
+
+/// ALICE-style antigen-driven expansion test (Pogorelyy et al. 2019)
+/// For each clonotype, counts distinct same-V/J CDR3s in the sample at one
+/// substitution and compares with the Poisson expectation
+/// `(N_vj - 1) * sum P(neighbor)` under a `PositionModel` fitted on
+/// `background` (or on the sample's distinct CDR3s when empty).
+/// Results are returned per input row; duplicate rows share their statistics.
+pub fn alice(cdr3: &[String], v_segment: &[String], j_segment: &[String], background: &[String]) -> Result<Vec<AliceResult>, String> {
+    let n = cdr3.len();
+    if v_segment.len() != n || j_segment.len() != n {
+        return Err("cdr3, v_segment and j_segment must have equal length".into());
+    }
+
+    // Distinct clonotypes and their V/J groups
+    let mut clonotype_index: HashMap<(&str, &str, &str), usize> = HashMap::new();
+    let mut clonotypes: Vec<(&str, &str, &str)> = Vec::new();
+    let row_clonotype: Vec<usize> = (0..n)
+        .map(|i| {
+            let key = (cdr3[i].as_str(), v_segment[i].as_str(), j_segment[i].as_str());
+            *clonotype_index.entry(key).or_insert_with(|| {
+                clonotypes.push(key);
+                clonotypes.len() - 1
+            })
+        })
+        .collect();
+    let mut groups: HashMap<(&str, &str), HashSet<&[u8]>> = HashMap::new();
+    for &(c, v, j) in &clonotypes {
+        groups.entry((v, j)).or_default().insert(c.as_bytes());
+    }
+
+    let model = if background.is_empty() {
+        let distinct: HashSet<&str> = clonotypes.iter().map(|c| c.0).collect();
+        PositionModel::fit(&distinct.into_iter().collect::<Vec<_>>(), 1.0)
+    } else {
+        PositionModel::fit(&background.iter().map(|s| s.as_str()).collect::<Vec<_>>(), 1.0)
+    };
+
+    let stats: Vec<(usize, f64, f64)> = clonotypes
+        .par_iter()
+        .map(|&(c, v, j)| {
+            let group = &groups[&(v, j)];
+            let mut candidate = c.as_bytes().to_vec();
+            let mut n_neighbors = 0;
+            for pos in 0..candidate.len() {
+                let original = candidate[pos];
+                for &aa in AMINO_ACIDS {
+                    if aa != original {
+                        candidate[pos] = aa;
+                        n_neighbors += group.contains(candidate.as_slice()) as usize;
+                    }
+                }
+                candidate[pos] = original;
+            }
+            let expected = (group.len() - 1) as f64 * model.neighbor_probability(c);
+            (n_neighbors, expected, poisson_upper_tail(n_neighbors, expected))
+        })
+        .collect();
+
+    let q_values = benjamini_hochberg(&stats.iter().map(|s| s.2).collect::<Vec<_>>());
+    Ok(row_clonotype
+        .into_iter()
+        .map(|c| AliceResult {
+            n_neighbors: stats[c].0,
+            expected: stats[c].1,
+            p_value: stats[c].2,
+            q_value: q_values[c],
+        })
+        .collect())
+}
+
+/// Convergent recombination: for each row, the number of distinct nucleotide
+/// CDR3s in the same sample that encode its amino acid CDR3
+/// Rows without a nucleotide sequence count towards nothing but still get their
+/// group's count (0 when no row of the group has one). An empty `sample`
+/// slice treats all rows as one sample.
+pub fn convergence_counts(cdr3_aa: &[String], cdr3_nt: &[String], sample: &[String]) -> Result<Vec<usize>, String> {
+    let n = cdr3_aa.len();
+    if cdr3_nt.len() != n || !(sample.is_empty() || sample.len() == n) {
+        return Err("cdr3_aa, cdr3_nt and sample must have equal length".into());
+    }
+    let sample_of = |i: usize| sample.get(i).map(|s| s.as_str()).unwrap_or("");
+
+    let mut variants: HashMap<(&str, String), HashSet<String>> = HashMap::new();
+    for i in 0..n {
+        let group = variants.entry((sample_of(i), cdr3_aa[i].trim().to_uppercase())).or_default();
+        let nt = cdr3_nt[i].trim();
+        if !nt.is_empty() {
+            group.insert(nt.to_uppercase());
+        }
+    }
+    Ok((0..n)
+        .map(|i| variants[&(sample_of(i), cdr3_aa[i].trim().to_uppercase())].len())
+        .collect())
+}
+
+/// `convergence_counts` over loaded clonotypes, grouped by `sample_id`
+pub fn clonotype_convergence(clonotypes: &[Clonotype]) -> Vec<usize> {
+    let field = |f: fn(&Clonotype) -> Option<&str>| -> Vec<String> {
+        clonotypes.iter().map(|c| f(c).unwrap_or("").to_string()).collect()
+    };
+    let cdr3_aa = field(|c| Some(c.cdr3_aa.sequence.as_str()));
+    let cdr3_nt = field(|c| c.cdr3_nt.as_deref());
+    let sample = field(|c| c.sample_id.as_deref());
+    convergence_counts(&cdr3_aa, &cdr3_nt, &sample).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_poisson_and_bh() {
+        assert_eq!(poisson_upper_tail(0, 2.0), 1.0);
+        assert!((poisson_upper_tail(1, 2.0) - (1.0 - (-2.0f64).exp())).abs() < 1e-12);
+        assert!(poisson_upper_tail(30, 0.5) < 1e-30);
+
+        let q = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.5]);
+        assert!((q[0] - 0.04).abs() < 1e-12);
+        assert!((q[1] - 0.04 * 4.0 / 3.0).abs() < 1e-12);
+        assert_eq!(q[1], q[2]);
+        assert!((q[3] - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_alice_flags_neighbor_cluster() {
+        // A tight family of CASSLGQ?YEQYF variants plus unrelated clonotypes
+        let mut cdr3 = strings(&["CASSLGQAYEQYF", "CASSLGQTYEQYF", "CASSLGQSYEQYF", "CASSLGQGYEQYF"]);
+        cdr3.extend(strings(&["CASRPDRGNTEAFF", "CSARDLLNYGYTF", "CASSPTGGYNEQFF", "CAWSVRGTEAFF"]));
+        cdr3.push("CASSLGQAYEQYF".into());
+        let v = vec!["TRBV7-9".to_string(); cdr3.len()];
+        let j = vec!["TRBJ2-7".to_string(); cdr3.len()];
+
+        let res = alice(&cdr3, &v, &j, &[]).unwrap();
+        assert_eq!(res.len(), cdr3.len());
+        assert_eq!(res[0].n_neighbors, 3);
+        assert_eq!(res[0], res[8]);
+        assert_eq!(res[4].n_neighbors, 0);
+        assert_eq!(res[4].p_value, 1.0);
+        assert!(res[0].p_value < res[4].p_value);
+        assert!(res[0].expected > 0.0);
+
+        // Different J: no neighbors across V/J groups
+        let mut j2 = j.clone();
+        j2[1] = "TRBJ1-1".into();
+        assert_eq!(alice(&cdr3, &v, &j2, &[]).unwrap()[0].n_neighbors, 2);
+    }
+
+    #[test]
+    fn test_convergence_counts() {
+        let aa = strings(&["CASSF", "CASSF", "cassf", "CASSF", "CASRF"]);
+        let nt = strings(&["TGTGCCAGCAGCTTT", "TGCGCCAGCAGCTTC", "tgtgccagcagcttt", "TGTGCTTCTTCTTTT", ""]);
+        assert_eq!(convergence_counts(&aa, &nt, &[]).unwrap(), vec![3, 3, 3, 3, 0]);
+
+        let sample = strings(&["s1", "s1", "s1", "s2", "s2"]);
+        assert_eq!(convergence_counts(&aa, &nt, &sample).unwrap(), vec![2, 2, 2, 1, 0]);
+        assert!(convergence_counts(&aa, &nt[..2], &[]).is_err());
+    }
+}
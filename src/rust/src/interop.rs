@@ -0,0 +1,332 @@
+use crate::database::Database;
+use crate::matching::{dedup_clonotypes, match_clonotypes_parallel, ClonotypeMatch, MatchConfig};
+use crate::sequence::Clonotype;
+use std::collections::HashMap;
+
+/// One row of an immunarch repertoire table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImmunarchRow {
+    pub clones: usize,
+    pub proportion: f64,
+    pub cdr3_nt: String,
+    pub cdr3_aa: String,
+    pub v_name: String,
+    pub d_name: String,
+    pub j_name: String,
+}
+
+/// Clonotypes in immunarch's layout (`Clones`, `Proportion`, `CDR3.nt`, `CDR3.aa`,
+/// `V.name`, `D.name`, `J.name`)
+/// Rows with identical sequences and genes are merged and sorted by decreasing
+/// clone count. Proportions are counts over the total; when no counts are known
+/// the input frequencies are summed and used instead.
+pub fn immunarch_table(clonotypes: &[Clonotype]) -> Vec<ImmunarchRow> {
+    let mut rows: Vec<ImmunarchRow> = Vec::new();
+    let mut index: HashMap<(String, String, String, String, String), usize> = HashMap::new();
+    for c in clonotypes {
+        let key = (
+            c.cdr3_nt.clone().unwrap_or_default(),
+            c.cdr3_aa.sequence.clone(),
+            c.v_segment.clone(),
+            c.d_segment.clone().unwrap_or_default(),
+            c.j_segment.clone(),
+        );
+        match index.get(&key) {
+            Some(&i) => {
+                rows[i].clones += c.count;
+                rows[i].proportion += c.frequency;
+            }
+            None => {
+                index.insert(key.clone(), rows.len());
+                let (cdr3_nt, cdr3_aa, v_name, d_name, j_name) = key;
+                rows.push(ImmunarchRow {
+                    clones: c.count,
+                    proportion: c.frequency,
+                    cdr3_nt,
+                    cdr3_aa,
+                    v_name,
+                    d_name,
+                    j_name,
+                });
+            }
+        }
+    }
+
+    let total: usize = rows.iter().map(|r| r.clones).sum();
+    if total > 0 {
+        for row in &mut rows {
+            row.proportion = row.clones as f64 / total as f64;
+        }
+    }
+    rows.sort_by(|a, b| b.clones.cmp(&a.clones).then(b.proportion.total_cmp(&a.proportion)));
+    rows
+}
+
+/// One annotated contig of a 10x `filtered_contig_annotations.csv` table
+#[derive(Debug, Clone, Default)]
+pub struct Contig {
+    pub barcode: String,
+    pub chain: String,
+    pub cdr3_aa: String,
+    pub cdr3_nt: String,
+    pub v_gene: String,
+    pub d_gene: String,
+    pub j_gene: String,
+    pub c_gene: String,
+}
+
+impl Contig {
+    /// Whether the contig is the first chain of a pair (TRA/TRG, or IGK/IGL light chains)
+    fn is_first_chain(&self) -> bool {
+        matches!(self.chain.to_uppercase().as_str(), "TRA" | "TRG" | "IGK" | "IGL")
+    }
+}
+
+/// Contigs of one cell, split into the first (TRA/TRG) and second (TRB/TRD) chain
+#[derive(Debug, Clone)]
+pub struct CellContigs<'a> {
+    pub barcode: &'a str,
+    pub first: Vec<&'a Contig>,
+    pub second: Vec<&'a Contig>,
+}
+
+/// Group contigs by barcode, in order of first appearance
+/// Contigs without a CDR3 (non-productive or unannotated) are skipped.
+pub fn group_by_cell(contigs: &[Contig]) -> Vec<CellContigs<'_>> {
+    let mut cells: Vec<CellContigs> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for contig in contigs {
+        let i = *index.entry(contig.barcode.as_str()).or_insert_with(|| {
+            cells.push(CellContigs { barcode: &contig.barcode, first: Vec::new(), second: Vec::new() });
+            cells.len() - 1
+        });
+        if contig.cdr3_aa.is_empty() {
+            continue;
+        }
+        if contig.is_first_chain() {
+            cells[i].first.push(contig);
+        } else {
+            cells[i].second.push(contig);
+        }
+    }
+    cells
+}
+
+/// Per-cell clonotype in scRepertoire's `combineTCR()` conventions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScRepertoireCell {
+    pub barcode: String,
+    /// Genes per chain ("TRAV12-1.TRAJ20.TRAC_TRBV6-2.TRBJ2-7.TRBD1.TRBC2")
+    pub ct_gene: String,
+    /// CDR3 nucleotides per chain ("TGTGCC..._TGTGCC...")
+    pub ct_nt: String,
+    /// CDR3 amino acids per chain ("CAVRDSNYQLIW_CASSLGQAYEQYF")
+    pub ct_aa: String,
+    /// Genes and CDR3 nucleotides per chain
+    pub ct_strict: String,
+}
+
+/// Collapse 10x contigs into one scRepertoire-style row per barcode
+/// Chains are ordered alpha (gamma) then beta (delta) and joined with "_"; a
+/// missing chain is "NA" and multiple contigs of one chain are joined with ";".
+/// Genes are V.J.C for the first chain and V.J.D.C for the second, with "NA"
+/// for genes that are not annotated.
+pub fn screpertoire_cells(contigs: &[Contig]) -> Vec<ScRepertoireCell> {
+    let or_na = |s: &str| if s.is_empty() { "NA".to_string() } else { s.to_string() };
+    let genes = |c: &Contig, with_d: bool| {
+        let mut parts = vec![or_na(&c.v_gene), or_na(&c.j_gene)];
+        if with_d {
+            parts.push(or_na(&c.d_gene));
+        }
+        parts.push(or_na(&c.c_gene));
+        parts.join(".")
+    };
+    let chain_field = |contigs: &[&Contig], field: &dyn Fn(&Contig) -> String| {
+        if contigs.is_empty() {
+            "NA".to_string()
+        } else {
+            contigs.iter().map(|c| field(c)).collect::<Vec<_>>().join(";")
+        }
+    };
+
+    group_by_cell(contigs)
+        .into_iter()
+        .filter(|cell| !cell.first.is_empty() || !cell.second.is_empty())
+        .map(|cell| {
+            let pair = |field: &dyn Fn(&Contig) -> String, second: &dyn Fn(&Contig) -> String| {
+                format!("{}_{}", chain_field(&cell.first, field), chain_field(&cell.second, second))
+            };
+            ScRepertoireCell {
+                barcode: cell.barcode.to_string(),
+                ct_gene: pair(&|c| genes(c, false), &|c| genes(c, true)),
+                ct_nt: pair(&|c| or_na(&c.cdr3_nt), &|c| or_na(&c.cdr3_nt)),
+                ct_aa: pair(&|c| c.cdr3_aa.clone(), &|c| c.cdr3_aa.clone()),
+                ct_strict: pair(
+                    &|c| format!("{};{}", genes(c, false), or_na(&c.cdr3_nt)),
+                    &|c| format!("{};{}", genes(c, true), or_na(&c.cdr3_nt)),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Best database annotation of one cell
+#[derive(Debug, Clone)]
+pub struct CellAnnotation {
+    pub barcode: String,
+    /// Best-scoring hit to the cell's best-supported epitope
+    pub best: Option<ClonotypeMatch>,
+    /// Whether both chains of the cell hit the best epitope
+    pub paired: bool,
+    /// Distinct epitopes hit by any chain of the cell
+    pub n_epitopes: usize,
+}
+
+/// Match every productive contig and pick one epitope per cell
+/// Each unique (CDR3, V, J) is matched once. An epitope hit by both chains of a
+/// cell beats one hit by a single chain; ties go to the higher summed best
+/// score per chain. Every barcode of `contigs` gets a row, in order of first
+/// appearance.
+pub fn annotate_cells(contigs: &[Contig], database: &Database, config: &MatchConfig) -> Vec<CellAnnotation> {
+    let cells = group_by_cell(contigs);
+    let clonotypes: Vec<Clonotype> = cells
+        .iter()
+        .flat_map(|cell| cell.first.iter().chain(&cell.second))
+        .map(|c| Clonotype::new(c.cdr3_aa.clone(), c.v_gene.clone(), c.j_gene.clone(), 1, 0.0))
+        .collect();
+    let (unique, mapping) = dedup_clonotypes(&clonotypes);
+    let unique_hits = match_clonotypes_parallel(&unique, database, config);
+
+    let mut next = 0;
+    cells
+        .iter()
+        .map(|cell| {
+            let mut chain_hits = |n: usize| {
+                let hits: Vec<&ClonotypeMatch> =
+                    mapping[next..next + n].iter().flat_map(|&u| &unique_hits[u]).collect();
+                next += n;
+                hits
+            };
+            let first = chain_hits(cell.first.len());
+            let second = chain_hits(cell.second.len());
+
+            // epitope -> (best score on first chain, best score on second chain)
+            let mut support: HashMap<&str, (Option<f64>, Option<f64>)> = HashMap::new();
+            for (hits, on_first) in [(&first, true), (&second, false)] {
+                for m in hits.iter() {
+                    let slot = support.entry(m.db_entry.antigen_epitope.as_str()).or_default();
+                    let best = if on_first { &mut slot.0 } else { &mut slot.1 };
+                    *best = Some(best.map_or(m.score, |s: f64| s.max(m.score)));
+                }
+            }
+            let rank = |(a, b): &(Option<f64>, Option<f64>)| {
+                (a.is_some() as u8 + b.is_some() as u8, a.unwrap_or(0.0) + b.unwrap_or(0.0))
+            };
+            let best_epitope = support
+                .iter()
+                .max_by(|x, y| {
+                    let (rx, ry) = (rank(x.1), rank(y.1));
+                    rx.0.cmp(&ry.0).then(rx.1.total_cmp(&ry.1)).then(y.0.cmp(x.0))
+                })
+                .map(|(e, s)| (*e, *s));
+
+            let best = best_epitope.and_then(|(epitope, _)| {
+                first
+                    .iter()
+                    .chain(&second)
+                    .filter(|m| m.db_entry.antigen_epitope == epitope)
+                    .max_by(|a, b| a.score.total_cmp(&b.score))
+            });
+            CellAnnotation {
+                barcode: cell.barcode.to_string(),
+                best: best.map(|m| (*m).clone()),
+                paired: best_epitope.is_some_and(|(_, (a, b))| a.is_some() && b.is_some()),
+                n_epitopes: support.len(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_immunarch_table() {
+        let mut a = Clonotype::new("CASSLF".into(), "TRBV5-1".into(), "TRBJ2-7".into(), 2, 0.0);
+        a.cdr3_nt = Some("TGTGCC".into());
+        let b = Clonotype::new("CASRDF".into(), "TRBV6-2".into(), "TRBJ1-1".into(), 5, 0.0);
+        let rows = immunarch_table(&[a.clone(), b, a]);
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].cdr3_aa.as_str(), rows[0].clones), ("CASRDF", 5));
+        assert_eq!((rows[1].clones, rows[1].cdr3_nt.as_str()), (4, "TGTGCC"));
+        assert!((rows[1].proportion - 4.0 / 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_screpertoire_cells() {
+        let contig = |barcode: &str, chain: &str, cdr3: &str, v: &str| Contig {
+            barcode: barcode.into(),
+            chain: chain.into(),
+            cdr3_aa: cdr3.into(),
+            v_gene: v.into(),
+            j_gene: "J".into(),
+            ..Default::default()
+        };
+        let contigs = vec![
+            contig("AAAC-1", "TRB", "CASSLF", "TRBV5-1"),
+            contig("AAAC-1", "TRA", "CAVRDF", "TRAV12-1"),
+            contig("AAAC-1", "TRA", "CAASGF", "TRAV1-2"),
+            contig("GGGT-1", "TRB", "CASRDF", "TRBV6-2"),
+            contig("TTTT-1", "TRB", "", "TRBV6-2"),
+        ];
+        let cells = screpertoire_cells(&contigs);
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[0].ct_aa, "CAVRDF;CAASGF_CASSLF");
+        assert_eq!(cells[0].ct_gene, "TRAV12-1.J.NA;TRAV1-2.J.NA_TRBV5-1.J.NA.NA");
+        assert_eq!(cells[1].ct_aa, "NA_CASRDF");
+        assert_eq!(cells[1].ct_strict, "NA_TRBV6-2.J.NA.NA;NA");
+    }
+
+    #[test]
+    fn test_annotate_cells() {
+        use crate::database::{DatabaseEntry, DatabaseMetadata};
+        let entry = |cdr3: &str, gene: &str, epitope: &str| DatabaseEntry {
+            v_segment: String::new(),
+            j_segment: String::new(),
+            gene: gene.into(),
+            antigen_species: String::new(),
+            ..DatabaseEntry::test(cdr3, epitope)
+        };
+        let database = Database {
+            entries: vec![
+                entry("CASSLF", "TRB", "NLVPMVATV"),
+                entry("CASSLF", "TRB", "GLCTLVAML"),
+                entry("CAVRDF", "TRA", "GLCTLVAML"),
+            ],
+            metadata: DatabaseMetadata::default(),
+        };
+        let contig = |barcode: &str, chain: &str, cdr3: &str| Contig {
+            barcode: barcode.into(),
+            chain: chain.into(),
+            cdr3_aa: cdr3.into(),
+            ..Default::default()
+        };
+        let contigs = vec![
+            contig("AAAC-1", "TRB", "CASSLF"),
+            contig("AAAC-1", "TRA", "CAVRDF"),
+            contig("GGGT-1", "TRB", "CASSLF"),
+            contig("TTTT-1", "TRB", "CASRDF"),
+        ];
+
+        let cells = annotate_cells(&contigs, &database, &MatchConfig::default());
+        assert_eq!(cells.len(), 3);
+        let best = cells[0].best.as_ref().unwrap();
+        assert_eq!(best.db_entry.antigen_epitope, "GLCTLVAML");
+        assert!(cells[0].paired);
+        assert_eq!(cells[0].n_epitopes, 2);
+        assert!(!cells[1].paired);
+        assert_eq!(cells[1].best.as_ref().unwrap().db_entry.antigen_epitope, "GLCTLVAML");
+        assert!(cells[2].best.is_none());
+    }
+}
@@ -0,0 +1,263 @@
+//! Binary database cache: a loaded (and possibly filtered) database in a
+//! compact, uncompressed layout that reopens without TSV parsing.
+use crate::database::{Database, DatabaseEntry, DatabaseMetadata};
+use crate::error::{Result, VdjMatchError};
+use crate::sequence::Cdr3Tokens;
+use std::io::Write;
+use std::path::Path;
+
+const MAGIC: &[u8] = b"VDJMATCHR-DBCACHE";
+const FORMAT_VERSION: u32 = 1;
+/// Length marking an absent string or number
+const NONE: u64 = u64::MAX;
+
+struct Encoder<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl Encoder<'_> {
+    fn number(&mut self, value: u64) -> Result<()> {
+        self.out.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn string(&mut self, value: &str) -> Result<()> {
+        self.number(value.len() as u64)?;
+        self.out.write_all(value.as_bytes())?;
+        Ok(())
+    }
+
+    fn opt_string(&mut self, value: &Option<String>) -> Result<()> {
+        match value {
+            Some(s) => self.string(s),
+            None => self.number(NONE),
+        }
+    }
+
+    fn opt_number(&mut self, value: Option<u64>) -> Result<()> {
+        self.number(value.unwrap_or(NONE))
+    }
+
+    fn strings<'s>(&mut self, values: impl ExactSizeIterator<Item = &'s str>) -> Result<()> {
+        self.number(values.len() as u64)?;
+        values.into_iter().try_for_each(|v| self.string(v))
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or_else(|| VdjMatchError::InvalidFormat("truncated database cache".into()))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+    }
+
+    fn opt_number(&mut self) -> Result<Option<u64>> {
+        Ok(Some(self.number()?).filter(|&n| n != NONE))
+    }
+
+    fn opt_string(&mut self) -> Result<Option<String>> {
+        match self.opt_number()? {
+            None => Ok(None),
+            Some(len) => {
+                let bytes = self.take(usize::try_from(len).unwrap_or(usize::MAX))?;
+                let s = std::str::from_utf8(bytes)
+                    .map_err(|_| VdjMatchError::InvalidFormat("database cache holds invalid UTF-8".into()))?;
+                Ok(Some(s.to_string()))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(self.opt_string()?.unwrap_or_default())
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let n = self.number()?;
+        (0..n).map(|_| self.string()).collect()
+    }
+}
+
+/// Write `database` as a binary cache for `read_cache`
+pub fn write_cache<P: AsRef<Path>>(database: &Database, path: P) -> Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    let mut enc = Encoder { out: &mut file };
+    let metadata = &database.metadata;
+    enc.opt_string(&metadata.version)?;
+    enc.strings(metadata.columns.iter().map(String::as_str))?;
+    enc.opt_string(&metadata.source_path)?;
+    enc.opt_string(&metadata.loaded_at)?;
+    enc.strings(metadata.filters.iter().map(String::as_str))?;
+    enc.strings(metadata.col_map.iter().map(|(f, _)| f.as_str()))?;
+    enc.strings(metadata.col_map.iter().map(|(_, c)| c.as_str()))?;
+    enc.string(metadata.profile.as_str())?;
+
+    enc.number(database.entries.len() as u64)?;
+    for e in &database.entries {
+        enc.string(&e.cdr3)?;
+        enc.string(&e.v_segment)?;
+        enc.string(&e.j_segment)?;
+        enc.string(&e.species)?;
+        enc.string(&e.gene)?;
+        enc.opt_string(&e.mhc_class)?;
+        enc.string(&e.antigen_epitope)?;
+        enc.opt_string(&e.antigen_gene)?;
+        enc.string(&e.antigen_species)?;
+        enc.opt_string(&e.reference_id)?;
+        enc.opt_string(&e.method)?;
+        enc.opt_string(&e.meta)?;
+        enc.opt_string(&e.cdr3_fix)?;
+        enc.number(e.vdjdb_score as u64)?;
+        enc.number(e.row_id as u64)?;
+        enc.opt_string(&e.d_segment)?;
+        enc.opt_string(&e.complex_id)?;
+        enc.opt_string(&e.mhc_a)?;
+        enc.opt_string(&e.mhc_b)?;
+        enc.opt_number(e.pmid.map(u64::from))?;
+        enc.opt_number(e.publication_year.map(u64::from))?;
+        enc.number(e.study_records as u64)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn narrow<T: TryFrom<u64>>(value: Option<u64>, what: &str) -> Result<Option<T>> {
+    value
+        .map(|v| T::try_from(v).map_err(|_| VdjMatchError::InvalidFormat(format!("invalid {} in database cache", what))))
+        .transpose()
+}
+
+/// Decode a database from the bytes of a cache file
+pub fn decode_cache(bytes: &[u8]) -> Result<Database> {
+    let mut dec = Decoder { bytes, pos: 0 };
+    if dec.take(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(VdjMatchError::InvalidFormat("not a vdjmatchR database cache".into()));
+    }
+    let version = u32::from_le_bytes(dec.take(4)?.try_into().expect("4 bytes"));
+    if version != FORMAT_VERSION {
+        return Err(VdjMatchError::InvalidFormat(format!(
+            "database cache format {} is not supported (expected {}); recreate it with db$save_cache()",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    let version = dec.opt_string()?;
+    let columns = dec.strings()?;
+    let source_path = dec.opt_string()?;
+    let loaded_at = dec.opt_string()?;
+    let filters = dec.strings()?;
+    let col_fields = dec.strings()?;
+    let col_names = dec.strings()?;
+    let profile = dec.string()?;
+    let profile = crate::schema::SchemaProfile::parse(&profile)
+        .unwrap_or_else(|| crate::schema::SchemaProfile::detect(&columns));
+
+    let n = dec.number()?;
+    let mut entries = Vec::with_capacity(n.min(bytes.len() as u64) as usize);
+    for _ in 0..n {
+        let cdr3 = dec.string()?;
+        entries.push(DatabaseEntry {
+            cdr3_tokens: Cdr3Tokens::new(&cdr3),
+            cdr3,
+            v_segment: dec.string()?,
+            j_segment: dec.string()?,
+            species: dec.string()?,
+            gene: dec.string()?,
+            mhc_class: dec.opt_string()?,
+            antigen_epitope: dec.string()?,
+            antigen_gene: dec.opt_string()?,
+            antigen_species: dec.string()?,
+            reference_id: dec.opt_string()?,
+            method: dec.opt_string()?,
+            meta: dec.opt_string()?,
+            cdr3_fix: dec.opt_string()?,
+            vdjdb_score: narrow(Some(dec.number()?), "VDJdb score")?.unwrap_or(0),
+            row_id: dec.number()? as usize,
+            d_segment: dec.opt_string()?,
+            complex_id: dec.opt_string()?,
+            mhc_a: dec.opt_string()?,
+            mhc_b: dec.opt_string()?,
+            pmid: narrow(dec.opt_number()?, "PMID")?,
+            publication_year: narrow(dec.opt_number()?, "publication year")?,
+            study_records: dec.number()? as usize,
+        });
+    }
+
+    Ok(Database {
+        entries,
+        metadata: DatabaseMetadata {
+            columns,
+            version,
+            source_path,
+            loaded_at,
+            filters,
+            col_map: col_fields.into_iter().zip(col_names).collect(),
+            profile,
+            ..Default::default()
+        },
+    })
+}
+
+/// Load a database written by `write_cache`
+pub fn read_cache<P: AsRef<Path>>(path: P) -> Result<Database> {
+    let p = path.as_ref();
+    let bytes = std::fs::read(p).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            VdjMatchError::DatabaseNotFound { path: p.display().to_string(), reason: e.to_string() }
+        }
+        _ => e.into(),
+    })?;
+    decode_cache(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("vdjmatchR-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tsv = dir.join("vdjdb.slim.txt");
+        std::fs::write(
+            &tsv,
+            "gene\tcdr3\tv.segm\tj.segm\tspecies\tantigen.epitope\tantigen.species\treference.id\tvdjdb.score\n\
+             TRB\tCASSLGQAYEQYF\tTRBV12-3\tTRBJ2-7\tHomoSapiens\tGLCTLVAML\tEBV\tPMID:111\t2\n\
+             TRA\tCAVRDGYNF\tTRAV12-1\tTRAJ7\tHomoSapiens\tNLVPMVATV\tCMV\t\t0\n",
+        )
+        .unwrap();
+        let database = Database::load_from_file(&tsv).unwrap().filter(None, None, 0);
+
+        let path = dir.join("vdjdb.cache");
+        write_cache(&database, &path).unwrap();
+        let loaded = read_cache(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.entries[0].cdr3, "CASSLGQAYEQYF");
+        assert_eq!(loaded.entries[0].pmid, Some(111));
+        assert_eq!(loaded.entries[0].study_records, 1);
+        assert_eq!(loaded.entries[1].reference_id.as_deref(), Some(""));
+        assert_eq!(loaded.entries[1].row_id, 2);
+        assert_eq!(loaded.metadata.source_path, database.metadata.source_path);
+        assert_eq!(loaded.metadata.profile, database.metadata.profile);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(decode_cache(&bytes[..bytes.len() - 3]).is_err());
+        assert!(matches!(read_cache(&tsv), Err(VdjMatchError::InvalidFormat(_))));
+        assert!(matches!(read_cache(dir.join("missing.cache")), Err(VdjMatchError::DatabaseNotFound { .. })));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
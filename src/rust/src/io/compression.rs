@@ -0,0 +1,117 @@
+use crate::error::{Result, VdjMatchError};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of an input or output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    /// Recognised so it can be reported clearly; zstd reading and writing were
+    /// descoped, as no zstd codec is available to this build
+    Zstd,
+}
+
+impl Compression {
+    /// Compression implied by the leading bytes of a file
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Compression implied by the extension of an output path (".gz", ".zst")
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|s| s.to_str()).map(|s| s.to_ascii_lowercase()) {
+            Some(ext) if ext == "gz" || ext == "gzip" => Compression::Gzip,
+            Some(ext) if ext == "zst" || ext == "zstd" => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+fn zstd_unsupported(path: &Path) -> VdjMatchError {
+    VdjMatchError::InvalidFormat(format!(
+        "{} is zstd-compressed, which this build cannot read or write; decompress it with `zstd -d` or use gzip",
+        path.display()
+    ))
+}
+
+/// Open `path` for buffered reading, decompressing gzip content
+/// Compression is taken from the magic bytes, so the file name does not matter.
+pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>> {
+    let path = path.as_ref();
+    let mut file = BufReader::new(File::open(path)?);
+    match Compression::detect(file.fill_buf()?) {
+        Compression::None => Ok(Box::new(file)),
+        // Multi-member so concatenated gzip files (`cat a.gz b.gz`) read in full
+        Compression::Gzip => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+        Compression::Zstd => Err(zstd_unsupported(path)),
+    }
+}
+
+/// Create `path` and pass a writer to `write`, compressing as the extension asks
+/// The gzip stream is finished explicitly so write errors are not lost on drop.
+pub fn write_to_path<P: AsRef<Path>, T>(
+    path: P,
+    write: impl FnOnce(&mut dyn Write) -> Result<T>,
+) -> Result<T> {
+    let path = path.as_ref();
+    let compression = Compression::from_path(path);
+    if compression == Compression::Zstd {
+        return Err(zstd_unsupported(path));
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+
+    if compression == Compression::Gzip {
+        let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+        let value = write(&mut encoder)?;
+        encoder.finish()?.flush()?;
+        Ok(value)
+    } else {
+        let value = write(&mut file)?;
+        file.flush()?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_compression_by_content() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8]), Compression::Gzip);
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd]), Compression::Zstd);
+        assert_eq!(Compression::detect(b"count\tfreq"), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+        assert_eq!(Compression::from_path(Path::new("x.tsv.GZ")), Compression::Gzip);
+        assert_eq!(Compression::from_path(Path::new("x.zst")), Compression::Zstd);
+
+        // gzip content under a plain name is still decompressed
+        let dir = std::env::temp_dir();
+        let gz = dir.join(format!("vdjmatchR-compression-{}.gz", std::process::id()));
+        let plain = dir.join(format!("vdjmatchR-compression-{}.txt", std::process::id()));
+        write_to_path(&gz, |out| Ok(out.write_all(b"a\tb\n")?)).unwrap();
+        std::fs::rename(&gz, &plain).unwrap();
+        let mut text = String::new();
+        open_reader(&plain).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "a\tb\n");
+
+        std::fs::write(&plain, ZSTD_MAGIC).unwrap();
+        assert!(matches!(open_reader(&plain), Err(VdjMatchError::InvalidFormat(_))));
+        std::fs::remove_file(&plain).ok();
+        assert!(write_to_path(dir.join("x.tsv.zst"), |_| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+//! File input/output shared by the loaders and writers
+pub mod cache;
+pub mod compression;
+pub mod writers;
+
+pub use compression::{open_reader, write_to_path, Compression};
+
+/// Reader for hand-made and spreadsheet-edited TSVs: headers and fields are
+/// trimmed, a field may be quoted (a quote inside doubled, `""`) to hold tabs
+/// or line breaks, lines may end in LF or CRLF, and a UTF-8 byte order mark
+/// is skipped
+pub fn tsv_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .delimiter(b'\t')
+        .trim(csv::Trim::All)
+        .quoting(true)
+        .double_quote(true)
+        .terminator(csv::Terminator::CRLF);
+    builder
+}
@@ -0,0 +1,235 @@
+use crate::error::Result;
+use crate::matching::{BatchMatches, ClonotypeMatch};
+use crate::network::HitNetwork;
+use crate::report::{AnnotationReport, ReportFormat};
+use crate::sequence::infer_chain;
+use std::path::Path;
+
+/// Required AIRR Rearrangement fields followed by the optional ones we fill
+const AIRR_FIELDS: &[&str] = &[
+    "sequence_id",
+    "sequence",
+    "rev_comp",
+    "productive",
+    "v_call",
+    "d_call",
+    "j_call",
+    "sequence_alignment",
+    "germline_alignment",
+    "junction",
+    "junction_aa",
+    "v_cigar",
+    "d_cigar",
+    "j_cigar",
+    "locus",
+    "duplicate_count",
+];
+
+/// vdjmatchR extension fields describing the best database hit of each rearrangement
+const VDJDB_FIELDS: &[&str] = &[
+    "vdjdb_epitope",
+    "vdjdb_antigen_gene",
+    "vdjdb_antigen_species",
+    "vdjdb_mhc_class",
+    "vdjdb_cdr3",
+    "vdjdb_v_call",
+    "vdjdb_j_call",
+    "vdjdb_score",
+    "vdjdb_match_score",
+    "vdjdb_specificity",
+    "vdjdb_reference_id",
+    "vdjdb_row",
+    "vdjdb_n_hits",
+    "vdjdb_epitopes",
+];
+
+/// Write queries of a batch match as an AIRR Rearrangement TSV (gzip-compressed
+/// when the path ends in ".gz"), one row per query
+/// The query CDR3 is written as `junction_aa`; alignment fields that are not
+/// known are left empty. `vdjdb_*` fields describe the query's best-scoring hit
+/// (empty without hits); `vdjdb_epitopes` lists all distinct hit epitopes.
+/// `sequence_ids` defaults to the 1-based query index.
+pub fn write_airr<P: AsRef<Path>>(path: P, batch: &BatchMatches, sequence_ids: Option<&[String]>) -> Result<()> {
+    crate::utils::write_to_path(path, |out| {
+        let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_writer(out);
+        writer.write_record(AIRR_FIELDS.iter().chain(VDJDB_FIELDS))?;
+
+        for (i, query) in batch.queries.iter().enumerate() {
+            let hits = batch.query_hits(i);
+            let best = hits.iter().max_by(|a, b| a.score.total_cmp(&b.score).then(b.db_entry.row_id.cmp(&a.db_entry.row_id)));
+            let sequence_id = sequence_ids
+                .and_then(|ids| ids.get(i).cloned())
+                .unwrap_or_else(|| (i + 1).to_string());
+            let locus = infer_chain(&query.v_segment, &query.j_segment).0.unwrap_or("");
+
+            let mut record = vec![
+                sequence_id,
+                String::new(),
+                "F".to_string(),
+                "T".to_string(),
+                query.v_segment.clone(),
+                query.d_segment.clone().unwrap_or_default(),
+                query.j_segment.clone(),
+                String::new(),
+                String::new(),
+                query.cdr3_nt.clone().unwrap_or_default(),
+                query.cdr3_aa.sequence.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                locus.to_string(),
+                query.count.to_string(),
+            ];
+            record.extend(vdjdb_fields(best, hits));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+/// Write a hit network as a TSV edge list (gzip-compressed when the path ends
+/// in ".gz") with node labels and the target epitope, the layout Cytoscape's
+/// "Import Network from File" and `igraph::graph_from_data_frame()` read
+pub fn write_network<P: AsRef<Path>>(path: P, network: &HitNetwork) -> Result<()> {
+    let nodes: std::collections::HashMap<&str, &crate::network::NetworkNode> =
+        network.nodes.iter().map(|n| (n.id.as_str(), n)).collect();
+    crate::utils::write_to_path(path, |out| {
+        let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_writer(out);
+        writer.write_record([
+            "source", "target", "weight", "edit_distance", "n_hits", "source_label", "target_label", "target_type",
+            "epitope", "source_copies",
+        ])?;
+        for edge in &network.edges {
+            let (source, target) = (nodes[edge.source.as_str()], nodes[edge.target.as_str()]);
+            writer.write_record([
+                edge.source.as_str(),
+                edge.target.as_str(),
+                &edge.weight.to_string(),
+                &edge.edit_distance.to_string(),
+                &edge.n_hits.to_string(),
+                &source.label,
+                &target.label,
+                target.kind,
+                &target.epitope,
+                &source.copies.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+/// Write an annotation report as JSON or self-contained HTML (gzip-compressed
+/// when the path ends in ".gz")
+pub fn write_report<P: AsRef<Path>>(path: P, report: &AnnotationReport, format: ReportFormat) -> Result<()> {
+    crate::utils::write_to_path(path, |out| {
+        out.write_all(report.render(format).as_bytes())?;
+        Ok(())
+    })
+}
+
+fn vdjdb_fields(best: Option<&ClonotypeMatch>, hits: &[ClonotypeMatch]) -> Vec<String> {
+    let Some(m) = best else {
+        let mut fields = vec![String::new(); VDJDB_FIELDS.len()];
+        fields[VDJDB_FIELDS.len() - 2] = "0".to_string();
+        return fields;
+    };
+    let mut epitopes: Vec<&str> = hits.iter().map(|h| h.db_entry.antigen_epitope.as_str()).collect();
+    epitopes.sort_unstable();
+    epitopes.dedup();
+    let e = &m.db_entry;
+    vec![
+        e.antigen_epitope.clone(),
+        e.antigen_gene.clone().unwrap_or_default(),
+        e.antigen_species.clone(),
+        e.mhc_class.clone().unwrap_or_default(),
+        e.cdr3.clone(),
+        e.v_segment.clone(),
+        e.j_segment.clone(),
+        e.vdjdb_score.to_string(),
+        m.score.to_string(),
+        m.specificity.to_string(),
+        e.reference_id.clone().unwrap_or_default(),
+        if e.row_id > 0 { e.row_id.to_string() } else { String::new() },
+        hits.len().to_string(),
+        epitopes.join(","),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseEntry, DatabaseMetadata};
+    use crate::matching::{match_clonotypes_parallel, MatchConfig};
+    use crate::sequence::Clonotype;
+
+    #[test]
+    fn test_write_airr() {
+        let entry = |epitope: &str, row_id: usize| DatabaseEntry {
+            mhc_class: Some("MHCI".into()),
+            vdjdb_score: 2,
+            row_id,
+            ..DatabaseEntry::test("CASSLGQAYEQYF", epitope)
+        };
+        let database = Database {
+            entries: vec![entry("GLCTLVAML", 4), entry("NLVPMVATV", 9)],
+            metadata: DatabaseMetadata::default(),
+        };
+        let queries = vec![
+            Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3".into(), "TRBJ2-7".into(), 3, 0.0),
+            Clonotype::new("CAVRDF".into(), "TRAV12-1".into(), "".into(), 1, 0.0),
+        ];
+        let hits = match_clonotypes_parallel(&queries, &database, &MatchConfig::default());
+        let batch = BatchMatches::new(queries, vec![0, 1], hits);
+
+        let path = std::env::temp_dir().join(format!("vdjmatchR-airr-{}.tsv.gz", std::process::id()));
+        write_airr(&path, &batch, Some(&["cell1".to_string(), "cell2".to_string()])).unwrap();
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut crate::utils::open_reader(&path).unwrap(), &mut text).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let lines: Vec<Vec<&str>> = text.lines().map(|l| l.split('\t').collect()).collect();
+        let column = |name: &str| lines[0].iter().position(|h| *h == name).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1][column("sequence_id")], "cell1");
+        assert_eq!(lines[1][column("junction_aa")], "CASSLGQAYEQYF");
+        assert_eq!(lines[1][column("locus")], "TRB");
+        assert_eq!(lines[1][column("duplicate_count")], "3");
+        assert_eq!(lines[1][column("vdjdb_epitope")], "GLCTLVAML");
+        assert_eq!(lines[1][column("vdjdb_row")], "4");
+        assert_eq!(lines[1][column("vdjdb_epitopes")], "GLCTLVAML,NLVPMVATV");
+        assert_eq!(lines[2][column("vdjdb_epitope")], "");
+        assert_eq!(lines[2][column("vdjdb_n_hits")], "0");
+        assert_eq!(lines[2][column("locus")], "TRA");
+    }
+
+    #[test]
+    fn test_write_network() {
+        use crate::network::{NetworkEdge, NetworkNode};
+        let node = |id: &str, kind: &'static str, label: &str, copies: usize| NetworkNode {
+            id: id.into(),
+            kind,
+            label: label.into(),
+            epitope: if kind == "query" { String::new() } else { "GLCTLVAML".into() },
+            copies,
+        };
+        let network = HitNetwork {
+            nodes: vec![node("Q1", "query", "CASSLGQAYEQYF", 2), node("E:GLCTLVAML", "epitope", "GLCTLVAML", 0)],
+            edges: vec![NetworkEdge {
+                source: "Q1".into(),
+                target: "E:GLCTLVAML".into(),
+                weight: 1.0,
+                edit_distance: 0,
+                n_hits: 3,
+            }],
+        };
+        let path = std::env::temp_dir().join(format!("vdjmatchR-network-{}.tsv", std::process::id()));
+        write_network(&path, &network).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("source\ttarget\tweight"));
+        assert_eq!(lines[1], "Q1\tE:GLCTLVAML\t1\t0\t3\tCASSLGQAYEQYF\tGLCTLVAML\tepitope\tGLCTLVAML\t2");
+    }
+}
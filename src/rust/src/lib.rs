@@ -2,122 +2,593 @@
 
 // Reuse core modules ported from vdjmatch-rs
 pub mod alignment;
+pub mod background;
+pub mod classifier;
+pub mod clustering;
+pub mod cohort;
 pub mod database;
+pub mod diff;
+pub mod diversity;
+pub mod distance;
+pub mod embedding;
 pub mod error;
+pub mod expansion;
 pub mod filtering;
+pub mod interop;
+pub mod io;
 pub mod matching;
+pub mod motif;
+pub mod network;
+pub mod ontology;
+pub mod random;
+pub mod reference;
+pub mod report;
+pub mod schema;
 pub mod scoring;
 pub mod sequence;
+pub mod simd;
+pub mod species;
+pub mod splits;
 pub mod tcrdist;
 pub mod utils;
+pub mod validation;
 
 use extendr_api::prelude::*;
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::Path;
 
+/// Extract a readable message from a caught panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run `f`, returning the panic message instead of unwinding further.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> std::result::Result<T, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(payload.as_ref()))
+}
+
+/// Result of an entry point. extendr raises a returned `Err` in R only as
+/// "user function panicked", so an `Err` is returned to R instead, as an
+/// `extendr_error` condition holding the encoded message (the shape of
+/// extendr-api's `result_condition` feature), which the R layer raises with
+/// `vdjmatchR_condition()`.
+pub struct RResult<T>(pub Result<T>);
+
+impl<T: Into<Robj>> From<RResult<T>> for Robj {
+    fn from(result: RResult<T>) -> Self {
+        match result.0 {
+            Ok(value) => value.into(),
+            Err(e) => list!(message = "extendr_err", value = e.to_string())
+                .set_class(["extendr_error", "error", "condition"])
+                .expect("failed to set the class of an R condition"),
+        }
+    }
+}
+
+/// Run an entry point body, converting any Rust panic into an R error that
+/// carries the panic message instead of taking down the R session.
+fn guard<T>(context: &str, f: impl FnOnce() -> Result<T>) -> RResult<T> {
+    RResult(catch_panic(f).unwrap_or_else(|msg| {
+        Err(extendr_api::error::Error::Other(error::encode_condition(
+            "internal",
+            &[],
+            &format!("internal error in {context}: {msg}"),
+        )))
+    }))
+}
+
+/// `value` with `warnings` (encoded like errors) attached for the R layer to
+/// raise as classed conditions
+fn with_warnings(value: Robj, warnings: Vec<String>) -> Result<Robj> {
+    if warnings.is_empty() {
+        return Ok(value);
+    }
+    value.set_attrib("vdjmatchR_warnings", warnings)
+}
+
+/// R error for a backend error, keeping its condition code and fields
+fn r_error(e: error::VdjMatchError) -> extendr_api::error::Error {
+    extendr_api::error::Error::Other(e.to_r_message())
+}
+
+/// `r_error` with `context` ("Failed to save database to x") before the message
+fn r_error_with(context: &str, e: error::VdjMatchError) -> extendr_api::error::Error {
+    extendr_api::error::Error::Other(e.to_r_message_with(context))
+}
+
 #[extendr]
 pub struct RDatabase {
-    inner: database::Database,
+    /// Shared with handles made by `clone_handle`; None once closed
+    inner: Option<std::sync::Arc<database::Database>>,
+    /// Null score distribution for hit p-values, built on first use
+    score_null: std::sync::OnceLock<scoring::ScoreNull>,
+    /// Record and distinct-CDR3 counts per epitope, built on first use
+    epitope_stats: std::sync::OnceLock<std::collections::HashMap<String, matching::EpitopeStats>>,
+    /// Mean CDR3 edit distance per epitope and chain for adaptive scopes, built on first use
+    cdr3_distances: std::sync::OnceLock<std::sync::Arc<diversity::EpitopeDistances>>,
+    /// Entry positions by CDR3 for exact-scope `has_match`, built on first use
+    cdr3_index: std::sync::OnceLock<std::sync::Arc<matching::Cdr3Index>>,
+}
+
+impl From<database::Database> for RDatabase {
+    fn from(inner: database::Database) -> Self {
+        Self {
+            inner: Some(std::sync::Arc::new(inner)),
+            score_null: std::sync::OnceLock::new(),
+            epitope_stats: std::sync::OnceLock::new(),
+            cdr3_distances: std::sync::OnceLock::new(),
+            cdr3_index: std::sync::OnceLock::new(),
+        }
+    }
+}
+
+impl RDatabase {
+    /// The database, or a "handle_closed" error after `close()`
+    fn db(&self) -> Result<&database::Database> {
+        self.inner
+            .as_deref()
+            .ok_or_else(|| r_error(error::VdjMatchError::HandleClosed { handle: "RDatabase" }))
+    }
+
+    fn score_null(&self) -> Result<&scoring::ScoreNull> {
+        let db = self.db()?;
+        Ok(self.score_null.get_or_init(|| matching::score_null(db)))
+    }
+
+    fn epitope_stats(&self) -> Result<&std::collections::HashMap<String, matching::EpitopeStats>> {
+        let db = self.db()?;
+        Ok(self.epitope_stats.get_or_init(|| matching::epitope_stats(db)))
+    }
+
+    fn cdr3_distances(&self) -> Result<std::sync::Arc<diversity::EpitopeDistances>> {
+        let db = self.db()?;
+        Ok(self
+            .cdr3_distances
+            .get_or_init(|| std::sync::Arc::new(diversity::mean_cdr3_distances(db)))
+            .clone())
+    }
+
+    fn cdr3_index(&self) -> Result<std::sync::Arc<matching::Cdr3Index>> {
+        let db = self.db()?;
+        Ok(self.cdr3_index.get_or_init(|| std::sync::Arc::new(matching::cdr3_index(db))).clone())
+    }
 }
 
 #[extendr]
 impl RDatabase {
-    pub fn new_from_file(path: &str) -> Result<Self> {
-        match database::Database::load_from_file(path) {
-            Ok(db) => Ok(Self { inner: db }),
-            Err(e) => Err(extendr_api::error::Error::Other(e.to_string())),
-        }
+    pub fn new_from_file(path: &str) -> RResult<Self> {
+        guard("RDatabase$new_from_file", || {
+            match database::Database::load_from_file(path) {
+                Ok(db) => Ok(db.into()),
+                Err(e) => Err(r_error(e)),
+            }
+        })
     }
 
-    pub fn new_from_vdjdb(_use_fat_db: bool) -> Result<Self> {
-        Err(extendr_api::error::Error::Other(
+    pub fn new_from_vdjdb(_use_fat_db: bool) -> RResult<Self> {
+        RResult(Err(extendr_api::error::Error::Other(
             "new_from_vdjdb is disabled. Provide an explicit file path via new_from_file().".into(),
-        ))
+        )))
     }
 
-    pub fn len(&self) -> i32 {
-        self.inner.len() as i32
+    pub fn len(&self) -> RResult<i32> {
+        guard("RDatabase$len", || Ok(self.db()?.len() as i32))
     }
 
     /// Return a filtered copy of the database. Use NULL for no filter.
-    pub fn filter(&self, species: Option<String>, gene: Option<String>, min_vdjdb_score: i32) -> Self {
-        let filtered = self.inner.filter(
-            species.as_deref(),
-            gene.as_deref(),
-            min_vdjdb_score as u8,
-        );
-        Self { inner: filtered }
+    pub fn filter(&self, species: Option<String>, gene: Option<String>, min_vdjdb_score: i32) -> RResult<Self> {
+        guard("RDatabase$filter", || {
+            let filtered = self.db()?.filter(
+                species.as_deref(),
+                gene.as_deref(),
+                min_vdjdb_score as u8,
+            );
+            Ok(filtered.into())
+        })
     }
 
     /// Filter by minimum epitope size (unique CDR3s per epitope)
-    pub fn filter_by_epitope_size(&self, min_size: i32) -> Self {
-        let filtered = self.inner.filter_by_epitope_size(min_size as usize);
-        Self { inner: filtered }
+    pub fn filter_by_epitope_size(&self, min_size: i32) -> RResult<Self> {
+        guard("RDatabase$filter_by_epitope_size", || {
+            let filtered = self.db()?.filter_by_epitope_size(min_size as usize);
+            Ok(filtered.into())
+        })
+    }
+
+    /// Return a filtered copy keeping entries that pass a filter expression such as
+    /// "__species__=='HomoSapiens'" or "__antigen.epitope__=~'^GLC'"
+    pub fn filter_expr(&self, expr: &str) -> RResult<Self> {
+        guard("RDatabase$filter_expr", || {
+            self.db()?
+                .filter_expression(expr)
+                .map(Self::from)
+                .map_err(extendr_api::error::Error::Other)
+        })
+    }
+
+    /// Filters applied since loading, in order, as replayable steps
+    pub fn filter_history(&self) -> RResult<Vec<String>> {
+        guard("RDatabase$filter_history", || Ok(self.db()?.metadata.filters.clone()))
+    }
+
+    /// Return a copy holding only the given (1-based) rows, in that order
+    pub fn subset(&self, rows: Vec<i32>) -> RResult<Self> {
+        guard("RDatabase$subset", || {
+            let db = self.db()?;
+            let n = db.entries.len();
+            let entries = rows
+                .iter()
+                .map(|&r| {
+                    if r >= 1 && (r as usize) <= n {
+                        Ok(db.entries[r as usize - 1].clone())
+                    } else {
+                        Err(extendr_api::error::Error::Other(format!("row {} out of range 1..{}", r, n)))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(db.derive(entries, vec![format!("rows={}", rows.len())]).into())
+        })
+    }
+
+    /// Source file, VDJdb version (from the file or directory name), release
+    /// layout recognised from the header ("standard", "slim", "legacy",
+    /// "flattened" or "custom"), variant ("fat", "slim" or "custom"), the
+    /// fat-only features the source columns support, load time (UTC), number of rows, rows per species and gene, number of unique
+    /// epitopes and the filters applied since loading
+    pub fn info(&self) -> RResult<List> {
+        guard("RDatabase$info", || {
+            let db = self.db()?;
+            let metadata = &db.metadata;
+            let field = |value: &Option<String>| value.as_deref().map_or(Rstr::na(), Rstr::from);
+            let entries = &db.entries;
+            let epitopes: std::collections::HashSet<&str> =
+                entries.iter().map(|e| e.antigen_epitope.as_str()).collect();
+            Ok(list!(
+                source_path = field(&metadata.source_path),
+                version = field(&metadata.version),
+                profile = metadata.profile.as_str(),
+                variant = metadata.profile.variant(),
+                capabilities = capability_flags(db)?,
+                loaded_at = field(&metadata.loaded_at),
+                n_rows = entries.len() as i32,
+                species = named_counts(entries.iter().map(|e| e.species.as_str()))?,
+                gene = named_counts(entries.iter().map(|e| e.gene.as_str()))?,
+                n_epitopes = epitopes.len() as i32,
+                filters = metadata.filters.clone(),
+                n_skipped = metadata.load_report.n_skipped as i32,
+                col_map = named_strings(&metadata.col_map)?
+            ))
+        })
+    }
+
+    /// Method field parsed into one column per key (identification,
+    /// frequency, singlecell, sequencing, verification, ...) with the entry's
+    /// db_row. Fails for databases without a method column, such as slim VDJdb.
+    pub fn methods(&self) -> RResult<List> {
+        guard("RDatabase$methods", || {
+            let db = self.db()?;
+            let methods = db.methods().map_err(r_error)?;
+            let mut keys: Vec<&str> = Vec::new();
+            for (key, _) in methods.iter().flatten() {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key);
+                }
+            }
+            let mut names = vec!["db_row"];
+            names.extend(&keys);
+            let mut columns: Vec<Robj> = vec![db.entries.iter().map(db_row_id).collect::<Vec<_>>().into()];
+            for key in &keys {
+                let values: Vec<Option<&str>> = methods
+                    .iter()
+                    .map(|pairs| pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()))
+                    .collect();
+                columns.push(values.into());
+            }
+            let columns: List = List::from_values(columns).set_attrib("names", names)?.try_into()?;
+            as_data_frame(columns, 1, methods.len())
+        })
+    }
+
+    /// Alpha and beta chains recorded in the same VDJdb complex, one row per
+    /// pair. Fails for databases without usable complex ids, such as slim VDJdb.
+    pub fn complex_pairs(&self) -> RResult<List> {
+        guard("RDatabase$complex_pairs", || {
+            let db = self.db()?;
+            let pairs = db.complex_pairs().map_err(r_error)?;
+            let entries = &db.entries;
+            let alpha = |f: fn(&database::DatabaseEntry) -> String| pairs.iter().map(|&(a, _)| f(&entries[a])).collect::<Vec<_>>();
+            let beta = |f: fn(&database::DatabaseEntry) -> String| pairs.iter().map(|&(_, b)| f(&entries[b])).collect::<Vec<_>>();
+            let columns = list!(
+                complex_id = alpha(|e| e.complex_id.clone().unwrap_or_default()),
+                cdr3_alpha = alpha(|e| e.cdr3.clone()),
+                v_alpha = alpha(|e| e.v_segment.clone()),
+                j_alpha = alpha(|e| e.j_segment.clone()),
+                cdr3_beta = beta(|e| e.cdr3.clone()),
+                v_beta = beta(|e| e.v_segment.clone()),
+                j_beta = beta(|e| e.j_segment.clone()),
+                antigen_epitope = beta(|e| e.antigen_epitope.clone()),
+                mhc_a = beta(|e| e.mhc_a.clone().unwrap_or_default()),
+                db_row_alpha = pairs.iter().map(|&(a, _)| db_row_id(&entries[a])).collect::<Vec<_>>(),
+                db_row_beta = pairs.iter().map(|&(_, b)| db_row_id(&entries[b])).collect::<Vec<_>>()
+            );
+            as_data_frame(columns, 1, pairs.len())
+        })
+    }
+
+    /// Return a filtered copy keeping entries presented by an MHC allele
+    /// ("HLA-A*02:01", or "HLA-A*02" for the allele group). Fails for
+    /// databases without mhc.a/mhc.b columns.
+    pub fn filter_mhc_allele(&self, allele: &str) -> RResult<Self> {
+        guard("RDatabase$filter_mhc_allele", || {
+            self.db()?
+                .filter_mhc_allele(allele)
+                .map(Self::from)
+                .map_err(r_error)
+        })
+    }
+
+    /// Malformed rows skipped by a lenient load: data row, file line and reason
+    pub fn load_diagnostics(&self) -> RResult<List> {
+        guard("RDatabase$load_diagnostics", || {
+            let diagnostics = &self.db()?.metadata.load_report.diagnostics;
+            let columns = list!(
+                row = diagnostics.iter().map(|d| d.row as i32).collect::<Vec<_>>(),
+                line = diagnostics.iter().map(|d| d.line.map(|l| l as i32)).collect::<Vec<_>>(),
+                reason = diagnostics.iter().map(|d| d.reason.clone()).collect::<Vec<_>>()
+            );
+            as_data_frame(columns, 1, diagnostics.len())
+        })
+    }
+
+    /// Distinct species of the database entries, sorted
+    pub fn species(&self) -> RResult<Vec<String>> {
+        guard("RDatabase$species", || {
+            let species: std::collections::BTreeSet<&str> =
+                self.db()?.entries.iter().map(|e| e.species.as_str()).collect();
+            Ok(species.into_iter().map(String::from).collect())
+        })
+    }
+
+    /// Save the database (e.g. after filtering) for reloading with `db_load()`.
+    /// Paths ending in ".gz" are gzip-compressed.
+    pub fn save(&self, path: &str) -> RResult<()> {
+        guard("RDatabase$save", || {
+            self.db()?
+                .save(path)
+                .map_err(|e| r_error_with(&format!("Failed to save database to {}", path), e))
+        })
     }
 
     /// Convert database to column vectors for R data.frame/data.table
-    pub fn to_columns(&self) -> List {
-        let n = self.inner.entries.len();
+    pub fn to_columns(&self) -> RResult<List> {
+        guard("RDatabase$to_columns", || Ok(entry_columns(&self.db()?.entries)))
+    }
 
-        let mut gene = Vec::with_capacity(n);
-        let mut cdr3 = Vec::with_capacity(n);
-        let mut v_segment = Vec::with_capacity(n);
-        let mut j_segment = Vec::with_capacity(n);
-        let mut species = Vec::with_capacity(n);
-        let mut antigen_epitope = Vec::with_capacity(n);
-        let mut antigen_gene = Vec::with_capacity(n);
-        let mut antigen_species = Vec::with_capacity(n);
-        let mut mhc_class = Vec::with_capacity(n);
-        let mut reference_id = Vec::with_capacity(n);
-        let mut vdjdb_score = Vec::with_capacity(n);
+    /// Call `f` on consecutive chunks of at most `chunk_size` entries, each a
+    /// data.frame with the `to_columns()` columns and the handle's row numbers as
+    /// row names, decoding one chunk at a time; returns `f`'s results, one per chunk.
+    pub fn scan(&self, f: Function, #[default = "10000L"] chunk_size: i32) -> RResult<List> {
+        guard("RDatabase$scan", || {
+            if chunk_size <= 0 {
+                return Err(extendr_api::error::Error::Other(format!(
+                    "chunk_size must be positive, got {}",
+                    chunk_size
+                )));
+            }
+            let size = chunk_size as usize;
+            let mut results = Vec::new();
+            for (k, chunk) in self.db()?.entries.chunks(size).enumerate() {
+                let first_row = k * size + 1;
+                let df = as_data_frame(entry_columns(chunk), first_row, chunk.len())?;
+                let result = f.call(pairlist!(df)).map_err(|e| {
+                    extendr_api::error::Error::Other(format!(
+                        "f failed on rows {}-{}: {}",
+                        first_row,
+                        first_row + chunk.len() - 1,
+                        e
+                    ))
+                })?;
+                results.push(result);
+            }
+            Ok(List::from_values(results))
+        })
+    }
 
-        for entry in &self.inner.entries {
-            gene.push(entry.gene.clone());
-            cdr3.push(entry.cdr3.clone());
-            v_segment.push(entry.v_segment.clone());
-            j_segment.push(entry.j_segment.clone());
-            species.push(entry.species.clone());
-            antigen_epitope.push(entry.antigen_epitope.clone());
-            antigen_gene.push(entry.antigen_gene.clone().unwrap_or_default());
-            antigen_species.push(entry.antigen_species.clone());
-            mhc_class.push(entry.mhc_class.clone().unwrap_or_default());
-            reference_id.push(entry.reference_id.clone().unwrap_or_default());
-            vdjdb_score.push(entry.vdjdb_score as i32);
-        }
+    /// Return a filtered copy keeping entries from references with at most
+    /// `max_records` records in the loaded release (entries without a
+    /// reference are kept)
+    pub fn filter_by_study_size(&self, max_records: i32) -> RResult<Self> {
+        guard("RDatabase$filter_by_study_size", || {
+            Ok(self.db()?.filter_by_study_size(max_records.max(0) as usize).into())
+        })
+    }
 
-        list!(
-            gene = gene,
-            cdr3 = cdr3,
-            v_segment = v_segment,
-            j_segment = j_segment,
-            species = species,
-            antigen_epitope = antigen_epitope,
-            antigen_gene = antigen_gene,
-            antigen_species = antigen_species,
-            mhc_class = mhc_class,
-            reference_id = reference_id,
-            vdjdb_score = vdjdb_score
-        )
+    /// Return a filtered copy keeping entries published in or after `min_year`;
+    /// entries of unknown year are kept only with `keep_unknown`
+    pub fn filter_by_publication_year(&self, min_year: i32, keep_unknown: bool) -> RResult<Self> {
+        guard("RDatabase$filter_by_publication_year", || {
+            Ok(self.db()?.filter_by_publication_year(min_year.clamp(0, u16::MAX as i32) as u16, keep_unknown).into())
+        })
+    }
+
+    /// Return a copy with publication years set by PMID ("PMID:28636592" or
+    /// "28636592"); NA years and unparseable PMIDs are ignored
+    pub fn with_publication_years(&self, pmid: Vec<String>, year: Vec<i32>) -> RResult<Self> {
+        guard("RDatabase$with_publication_years", || {
+            if pmid.len() != year.len() {
+                return Err(extendr_api::error::Error::Other("pmid and year must have equal length".into()));
+            }
+            let years: std::collections::HashMap<u32, u16> = pmid
+                .iter()
+                .zip(&year)
+                .filter(|(_, &y)| y != i32::MIN && (0..=u16::MAX as i32).contains(&y))
+                .filter_map(|(p, &y)| database::parse_pmid(p).map(|p| (p, y as u16)))
+                .collect();
+            Ok(self.db()?.with_publication_years(&years).into())
+        })
+    }
+
+    /// Write the database as a binary cache for `db_open_cache()`, which
+    /// reopens it without parsing the TSV (filters and provenance are kept)
+    pub fn save_cache(&self, path: &str) -> RResult<()> {
+        guard("RDatabase$save_cache", || {
+            io::cache::write_cache(self.db()?, path)
+                .map_err(|e| r_error_with(&format!("Failed to write database cache {}", path), e))
+        })
+    }
+
+    /// A second handle on the same in-memory database: entries are shared,
+    /// not copied, and the p-value, epitope and distance tables already built
+    /// are carried over
+    pub fn clone_handle(&self) -> RResult<Self> {
+        guard("RDatabase$clone_handle", || {
+            self.db()?;
+            Ok(Self {
+                inner: self.inner.clone(),
+                score_null: self.score_null.clone(),
+                epitope_stats: self.epitope_stats.clone(),
+                cdr3_distances: self.cdr3_distances.clone(),
+                cdr3_index: self.cdr3_index.clone(),
+            })
+        })
+    }
+
+    /// Drop this handle's database and cached tables now instead of when R
+    /// collects the handle; any later use is a "handle_closed" error. Entries
+    /// shared with `clone_handle()` copies are freed when the last is closed.
+    pub fn close(&mut self) {
+        self.inner = None;
+        self.score_null = std::sync::OnceLock::new();
+        self.epitope_stats = std::sync::OnceLock::new();
+        self.cdr3_distances = std::sync::OnceLock::new();
+        self.cdr3_index = std::sync::OnceLock::new();
+    }
+
+    /// Whether `close()` was called
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    /// One row per reference: `reference_id`, `pmid` and `publication_year`
+    /// (NA when unknown) and `n_records` in the loaded release, largest first
+    pub fn studies(&self) -> RResult<List> {
+        guard("RDatabase$studies", || {
+            let mut studies: Vec<&database::DatabaseEntry> = Vec::new();
+            let mut seen = std::collections::HashSet::new();
+            for entry in &self.db()?.entries {
+                let reference = entry.reference_id.as_deref().unwrap_or("").trim();
+                if !reference.is_empty() && seen.insert(reference.to_uppercase()) {
+                    studies.push(entry);
+                }
+            }
+            studies.sort_by_key(|e| std::cmp::Reverse(e.study_records));
+            let columns = list!(
+                reference_id = studies.iter().map(|e| e.reference_id.as_deref().unwrap_or("").trim().to_string()).collect::<Vec<_>>(),
+                pmid = studies.iter().map(|e| e.pmid.map(|p| p as f64)).collect::<Vec<_>>(),
+                publication_year = studies.iter().map(|e| e.publication_year.map(i32::from)).collect::<Vec<_>>(),
+                n_records = studies.iter().map(|e| e.study_records as i32).collect::<Vec<_>>()
+            );
+            as_data_frame(columns, 1, studies.len())
+        })
     }
 }
 
 /// Open a VDJdb TSV/TSV.GZ via the Rust backend.
+/// Gzip is recognised from the file content whatever the name; zstd files are
+/// rejected (this build has no zstd codec), so decompress them with `zstd -d` first.
+/// With `lenient = TRUE`, malformed rows (wrong number of fields, invalid UTF-8)
+/// are skipped instead of failing the load; the first `max_diagnostics` of them
+/// are listed by `db$load_diagnostics()`.
+/// Columns are found by their VDJdb names, case-insensitively or by common
+/// aliases (`cdr3_aa`, `v_call`, `epitope`, ...); `col_map` names the column for
+/// a field explicitly, e.g. `list(cdr3 = "junction_aa", v_segment = "v_call")`.
+/// Loading fails listing any missing cdr3, V, J or epitope column. The columns
+/// read under another name are reported by `db$info()$col_map`.
+/// The VDJdb release layout is recognised from the header and reported by
+/// `db$info()$profile`; early releases that spread method/meta over
+/// `method.*`/`meta.*` columns are read back into the JSON `method`/`meta` fields.
+/// An empty file, or a header naming a column that is read more than once, fails
+/// (conditions `vdjmatchR_empty_file`, `vdjmatchR_duplicate_columns`); other
+/// repeated columns and a table without data rows load with a warning.
+/// Spreadsheet exports read as written: fields are trimmed, quoted fields may
+/// hold tabs, and CRLF line ends and a byte order mark are accepted.
+/// `db$close()` frees the database without waiting for garbage collection;
+/// later use of the handle fails with a `vdjmatchR_handle_closed` condition.
 /// @export
 #[extendr]
-pub fn vdjdb_open_file(path: &str) -> Result<RDatabase> {
-    if path.trim().is_empty() {
-        return Err(extendr_api::error::Error::Other("path must be a non-empty string".into()));
-    }
-    if !Path::new(path).exists() {
-        return Err(extendr_api::error::Error::Other(format!("VDJdb file not found: {path}")));
+pub fn vdjdb_open_file(
+    path: &str,
+    #[default = "FALSE"] lenient: bool,
+    #[default = "20L"] max_diagnostics: i32,
+    #[default = "NULL"] col_map: Nullable<List>,
+) -> RResult<Robj> {
+    guard("vdjdb_open_file", || {
+        if path.trim().is_empty() {
+            return Err(extendr_api::error::Error::Other("path must be a non-empty string".into()));
+        }
+        if !Path::new(path).exists() {
+            return Err(r_error(error::VdjMatchError::DatabaseNotFound {
+                path: path.to_string(),
+                reason: "no such file".to_string(),
+            }));
+        }
+        let options = database::LoadOptions {
+            col_map: match col_map {
+                Nullable::NotNull(map) => column_mapping(&map)?,
+                Nullable::Null => Vec::new(),
+            },
+            lenient: lenient.then_some(max_diagnostics.max(0) as usize),
+        };
+        let db = database::Database::load_with_options(path, &options)
+            .map_err(r_error)?;
+        let n_skipped = db.metadata.load_report.n_skipped;
+        if n_skipped > 0 {
+            reprintln!("Skipped {} malformed row(s) of {}; see db$load_diagnostics()", n_skipped, path);
+        }
+        let warnings = db
+            .metadata
+            .load_report
+            .warnings
+            .iter()
+            .map(|warning| {
+                let mut fields = vec![("path", path.to_string())];
+                fields.extend(warning.column.clone().map(|c| ("column", c)));
+                error::encode_condition(warning.code, &fields, &warning.message)
+            })
+            .collect();
+        with_warnings(RDatabase::from(db).into(), warnings)
+    })
+}
+
+/// (field, column) pairs from a named list such as
+/// `list(cdr3 = "junction_aa", v_segment = "v_call")`
+fn column_mapping(map: &List) -> Result<Vec<(String, String)>> {
+    let names: Vec<String> = map.names().map(|n| n.map(String::from).collect()).unwrap_or_default();
+    if names.len() != map.len() || names.iter().any(|n| n.is_empty()) {
+        return Err(extendr_api::error::Error::Other("col_map must be named, e.g. list(cdr3 = \"junction_aa\")".into()));
     }
-    RDatabase::new_from_file(path)
+    names
+        .into_iter()
+        .zip(map.values())
+        .map(|(field, column)| match column.as_str() {
+            Some(column) => Ok((field, column.to_string())),
+            None => Err(extendr_api::error::Error::Other(format!("col_map${} must be a single column name", field))),
+        })
+        .collect()
 }
 
 /// Number of rows stored in the in-memory VDJdb handle.
 /// @export
 #[extendr]
-pub fn vdjdb_len(db: &RDatabase) -> i32 {
-    db.len()
+pub fn vdjdb_len(db: &RDatabase) -> RResult<i32> {
+    guard("vdjdb_len", || db.len().0)
 }
 
 /// Filter database entries by species, gene, and minimum VDJdb score.
@@ -128,22 +599,60 @@ pub fn filter_db(
     species: Nullable<String>,
     gene: Nullable<String>,
     min_vdjdb_score: i32,
-) -> RDatabase {
-    let species_string = species.into_option().filter(|s| !s.trim().is_empty());
-    let gene_string = gene.into_option().filter(|s| !s.trim().is_empty());
-    db.filter(species_string, gene_string, min_vdjdb_score)
+) -> RResult<RDatabase> {
+    guard("filter_db", || {
+        let species_string = species.into_option().filter(|s| !s.trim().is_empty());
+        let gene_string = gene.into_option().filter(|s| !s.trim().is_empty());
+        db.filter(species_string, gene_string, min_vdjdb_score).0
+    })
 }
 
 /// Filter by minimum epitope size (unique CDR3 per epitope).
 /// @export
 #[extendr]
-pub fn filter_db_by_epitope_size(db: &RDatabase, min_size: i32) -> RDatabase {
-    db.filter_by_epitope_size(min_size)
+pub fn filter_db_by_epitope_size(db: &RDatabase, min_size: i32) -> RResult<RDatabase> {
+    guard("filter_db_by_epitope_size", || db.filter_by_epitope_size(min_size).0)
+}
+
+/// Re-apply a filter history from `db$filter_history()` (e.g. recorded on an older
+/// VDJdb release) to another database, so an analysis can be repeated on a new release.
+/// @export
+#[extendr]
+pub fn reapply_filters(db: &RDatabase, history: Vec<String>) -> RResult<RDatabase> {
+    guard("reapply_filters", || {
+        db.db()?
+            .apply_filter_steps(&history)
+            .map(RDatabase::from)
+            .map_err(extendr_api::error::Error::Other)
+    })
 }
 
 /// Match a single clonotype against the database.
 /// Returns a list of columns (vector-of-equal-length) suitable for as.data.frame in R.
+/// `kmer_min_shared` > 0 skips alignment against entries sharing fewer central `kmer_size`-mers.
+/// `exhaustive_search`: 0 = first hit only, 1 = all hits, 2 = all hits with full vdjmatch rescoring.
+/// `allele_mode`: "ignore" alleles, "prefer" same-allele hits (differing alleles score 0.5),
+/// or "require" the query's allele.
+/// `restrict_chain` only matches database rows of the chain (TRA/TRB/...) implied by the query's V/J.
+/// `mhc_class` ("MHCI"/"MHCII", NULL for any) only matches rows restricted by that class.
+/// `missing_segments`: "exclude" database rows lacking the query's V/J segment, or
+/// "allow_as_partial" to keep them with a 0.5 segment score (flagged in `db_segment_missing`).
+/// `score_normalization` sets the `normalized_score` column: the hit's substitution score
+/// "none" (raw), per query residue ("length"), as a fraction of the query's self score
+/// ("max"), or as a z-score against same-length database CDR3s ("zscore").
+/// `p_values` adds `p_value`, the chance of an equal-or-better CDR3 score against a
+/// random database CDR3 of the query's length, and its BH-adjusted `q_value`.
+/// `ambiguous_residues` ("penalize", "neutral", "error" or "skip") handles X/`*` in
+/// query and database CDR3s; characters in `gap_chars` are removed before comparison.
+/// `simple_score` sets the CDR3 score of hits: 1 - edit distance / longer length
+/// ("max_length"), identity excluding the terminal anchor residues ("trimmed_identity"),
+/// or 1 / (1 + edit distance) ("absolute").
+/// `v_match_level` sets how closely V segments must agree: same gene and allele
+/// ("allele"), same gene ("gene", alleles per `allele_mode`), same IMGT family
+/// ("family": "TRBV6" matches "TRBV6-2" and "TRBV6-3", scored 0.5 across genes),
+/// or not compared ("none").
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 pub fn match_tcr(
     db: &RDatabase,
     cdr3: &str,
@@ -151,128 +660,366 @@ pub fn match_tcr(
     j_segment: &str,
     scope: &str,
     top_n: i32,
-) -> List {
-    let clonotype = sequence::Clonotype::new(
-        cdr3.to_string(),
-        v_segment.to_string(),
-        j_segment.to_string(),
-        1,
-        0.0,
-    );
+    #[default = "0L"] kmer_min_shared: i32,
+    #[default = "3L"] kmer_size: i32,
+    #[default = "1L"] exhaustive_search: i32,
+    #[default = "\"ignore\""] allele_mode: &str,
+    #[default = "TRUE"] restrict_chain: bool,
+    #[default = "NULL"] mhc_class: Option<String>,
+    #[default = "\"exclude\""] missing_segments: &str,
+    #[default = "\"none\""] score_normalization: &str,
+    #[default = "FALSE"] p_values: bool,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+    #[default = "\"max_length\""] simple_score: &str,
+    #[default = "\"gene\""] v_match_level: &str,
+) -> RResult<List> {
+    guard("match_tcr", || {
+        let clonotype = sequence::Clonotype::new(
+            cdr3.to_string(),
+            v_segment.to_string(),
+            j_segment.to_string(),
+            1,
+            0.0,
+        );
 
-    // Parse scope, default to exact on failure.
-    let search_scope = sequence::SearchScope::parse(scope).unwrap_or(sequence::SearchScope::EXACT);
+        let mut config = match_config(
+            db, scope, top_n, kmer_min_shared, kmer_size, exhaustive_search, allele_mode, restrict_chain, mhc_class,
+            missing_segments, ambiguous_residues, gap_chars,
+        )?;
+        config.score_normalization = parse_score_normalization(score_normalization)?;
+        config.simple_score = parse_simple_score(simple_score)?;
+        config.v_match_level = parse_v_match_level(v_match_level)?;
+        check_residues(&config.residues, "query", [cdr3])?;
 
-    let mut config = matching::MatchConfig::default();
-    config.search_scope = search_scope;
-    config.match_v = !v_segment.is_empty();
-    config.match_j = !j_segment.is_empty();
-    if top_n > 0 { config.top_n_hits = Some(top_n as usize); }
+        let mut matches = matching::match_clonotype(&clonotype, db.db()?, &config);
+        if p_values {
+            matching::assign_p_values(std::slice::from_mut(&mut matches), db.score_null()?, &config.residues);
+        }
+        matching::assign_epitope_stats(std::slice::from_mut(&mut matches), db.epitope_stats()?);
 
-    let matches = matching::match_clonotype(&clonotype, &db.inner, &config);
-
-    let n = matches.len();
-    let mut cdr3_db = Vec::with_capacity(n);
-    let mut v_db = Vec::with_capacity(n);
-    let mut j_db = Vec::with_capacity(n);
-    let mut species = Vec::with_capacity(n);
-    let mut gene = Vec::with_capacity(n);
-    let mut epitope = Vec::with_capacity(n);
-    let mut antigen_gene = Vec::with_capacity(n);
-    let mut antigen_species = Vec::with_capacity(n);
-    let mut mhc_class = Vec::with_capacity(n);
-    let mut reference_id = Vec::with_capacity(n);
-    let mut vdjdb_score = Vec::with_capacity(n);
-    let mut score = Vec::with_capacity(n);
-    let mut cdr3_score = Vec::with_capacity(n);
-    let mut v_score = Vec::with_capacity(n);
-    let mut j_score = Vec::with_capacity(n);
-    let mut edit_distance = Vec::with_capacity(n);
-
-    for m in matches.into_iter() {
-        cdr3_db.push(m.db_entry.cdr3);
-        v_db.push(m.db_entry.v_segment);
-        j_db.push(m.db_entry.j_segment);
-        species.push(m.db_entry.species);
-        gene.push(m.db_entry.gene);
-        epitope.push(m.db_entry.antigen_epitope.clone());
-        antigen_gene.push(m.db_entry.antigen_gene.unwrap_or_default());
-        antigen_species.push(m.db_entry.antigen_species);
-        mhc_class.push(m.db_entry.mhc_class.unwrap_or_default());
-        reference_id.push(m.db_entry.reference_id.unwrap_or_default());
-        vdjdb_score.push(m.db_entry.vdjdb_score as i32);
-        score.push(m.score);
-        cdr3_score.push(m.cdr3_alignment_score);
-        v_score.push(m.v_score);
-        j_score.push(m.j_score);
-        edit_distance.push(m.edit_distance as i32);
+        let n = matches.len();
+        let mut cdr3_db = Vec::with_capacity(n);
+        let mut v_db = Vec::with_capacity(n);
+        let mut j_db = Vec::with_capacity(n);
+        let mut species = Vec::with_capacity(n);
+        let mut gene = Vec::with_capacity(n);
+        let mut epitope = Vec::with_capacity(n);
+        let mut antigen_gene = Vec::with_capacity(n);
+        let mut antigen_species = Vec::with_capacity(n);
+        let mut mhc_class = Vec::with_capacity(n);
+        let mut reference_id = Vec::with_capacity(n);
+        let mut vdjdb_score = Vec::with_capacity(n);
+        let mut db_row = Vec::with_capacity(n);
+        let mut score = Vec::with_capacity(n);
+        let mut cdr3_score = Vec::with_capacity(n);
+        let mut v_score = Vec::with_capacity(n);
+        let mut j_score = Vec::with_capacity(n);
+        let mut edit_distance = Vec::with_capacity(n);
+        let mut query_cdr3_length = Vec::with_capacity(n);
+        let mut cdr3_db_length = Vec::with_capacity(n);
+        let mut cdr3_length_diff = Vec::with_capacity(n);
+        let mut specificity = Vec::with_capacity(n);
+        let mut matched_v = Vec::with_capacity(n);
+        let mut matched_j = Vec::with_capacity(n);
+        let mut normalized_score = Vec::with_capacity(n);
+        let mut p_value = Vec::with_capacity(n);
+        let mut q_value = Vec::with_capacity(n);
+        let mut epitope_records = Vec::with_capacity(n);
+        let mut epitope_unique_cdr3 = Vec::with_capacity(n);
+        let mut db_segment_missing = Vec::with_capacity(n);
+
+        for m in matches.into_iter() {
+            let (query_length, db_length) = m.cdr3_lengths();
+            query_cdr3_length.push(query_length as i32);
+            cdr3_db_length.push(db_length as i32);
+            cdr3_length_diff.push(m.cdr3_length_diff() as i32);
+            db_row.push(db_row_id(&m.db_entry));
+            db_segment_missing.push(segment_missing(&m.db_entry));
+            cdr3_db.push(m.db_entry.cdr3);
+            v_db.push(m.db_entry.v_segment);
+            j_db.push(m.db_entry.j_segment);
+            species.push(m.db_entry.species);
+            gene.push(m.db_entry.gene);
+            epitope.push(m.db_entry.antigen_epitope.clone());
+            antigen_gene.push(m.db_entry.antigen_gene.unwrap_or_default());
+            antigen_species.push(m.db_entry.antigen_species);
+            mhc_class.push(m.db_entry.mhc_class.unwrap_or_default());
+            reference_id.push(m.db_entry.reference_id.unwrap_or_default());
+            vdjdb_score.push(m.db_entry.vdjdb_score as i32);
+            score.push(m.score);
+            cdr3_score.push(m.cdr3_alignment_score);
+            v_score.push(m.v_score);
+            j_score.push(m.j_score);
+            edit_distance.push(m.edit_distance as i32);
+            specificity.push(m.specificity);
+            matched_v.push(m.matched_v);
+            matched_j.push(m.matched_j);
+            normalized_score.push(m.normalized_score);
+            p_value.push(m.p_value);
+            q_value.push(m.q_value);
+            epitope_records.push(m.epitope_records.map(|c| c as i32));
+            epitope_unique_cdr3.push(m.epitope_unique_cdr3.map(|c| c as i32));
+        }
+
+        with_db_provenance(list!(
+            cdr3_db = cdr3_db,
+            v_db = v_db,
+            j_db = j_db,
+            species = species,
+            gene = gene,
+            antigen_epitope = epitope,
+            antigen_gene = antigen_gene,
+            antigen_species = antigen_species,
+            mhc_class = mhc_class,
+            reference_id = reference_id,
+            vdjdb_score = vdjdb_score,
+            db_row = db_row,
+            score = score,
+            cdr3_score = cdr3_score,
+            v_score = v_score,
+            j_score = j_score,
+            edit_distance = edit_distance,
+            query_cdr3_length = query_cdr3_length,
+            cdr3_db_length = cdr3_db_length,
+            cdr3_length_diff = cdr3_length_diff,
+            specificity = specificity,
+            matched_v = matched_v,
+            matched_j = matched_j,
+            normalized_score = normalized_score,
+            p_value = p_value,
+            q_value = q_value,
+            epitope_records = epitope_records,
+            epitope_unique_cdr3 = epitope_unique_cdr3,
+            db_segment_missing = db_segment_missing
+        ), &db.db()?.metadata)
+    })
+}
+
+/// Named integer vector of value counts, sorted by name
+fn named_counts<'a>(values: impl Iterator<Item = &'a str>) -> Result<Robj> {
+    let mut counts: std::collections::BTreeMap<&str, i32> = std::collections::BTreeMap::new();
+    for v in values {
+        *counts.entry(v).or_insert(0) += 1;
     }
+    let names: Vec<&str> = counts.keys().copied().collect();
+    Robj::from(counts.into_values().collect::<Vec<i32>>()).set_attrib("names", names)
+}
 
-    list!(
-        cdr3_db = cdr3_db,
-        v_db = v_db,
-        j_db = j_db,
-        species = species,
-        gene = gene,
-        antigen_epitope = epitope,
-        antigen_gene = antigen_gene,
-        antigen_species = antigen_species,
-        mhc_class = mhc_class,
-        reference_id = reference_id,
-        vdjdb_score = vdjdb_score,
-        score = score,
-        cdr3_score = cdr3_score,
-        v_score = v_score,
-        j_score = j_score,
-        edit_distance = edit_distance
-    )
+/// Named character vector from (name, value) pairs
+fn named_strings(pairs: &[(String, String)]) -> Result<Robj> {
+    let names: Vec<&str> = pairs.iter().map(|(name, _)| name.as_str()).collect();
+    let values: Vec<&str> = pairs.iter().map(|(_, value)| value.as_str()).collect();
+    Robj::from(values).set_attrib("names", names)
 }
 
-/// Batch match: vectors of cdr3/v/j; returns stacked results with query metadata.
-/// Uses parallel processing via Rayon for improved performance.
-#[extendr]
-pub fn match_tcr_many(
+/// Named logical vector of the fat-only features a database supports
+fn capability_flags(db: &database::Database) -> Result<Robj> {
+    let names: Vec<&str> = schema::CAPABILITIES.iter().map(|c| c.as_str()).collect();
+    let flags: Vec<bool> = schema::CAPABILITIES.iter().map(|&c| db.supports(c)).collect();
+    Robj::from(flags).set_attrib("names", names)
+}
+
+/// Source file row of a database entry for R (NA when unknown)
+fn db_row_id(entry: &database::DatabaseEntry) -> Option<i32> {
+    (entry.row_id > 0).then_some(entry.row_id as i32)
+}
+
+/// Whether a database entry lacks its V or J segment (CDR3-only record)
+fn segment_missing(entry: &database::DatabaseEntry) -> bool {
+    entry.v_segment.is_empty() || entry.j_segment.is_empty()
+}
+
+/// Attach the provenance of the database a result was matched against
+fn with_db_provenance(columns: List, metadata: &database::DatabaseMetadata) -> Result<List> {
+    let attr = |value: &Option<String>| value.as_deref().map_or(Rstr::na(), Rstr::from);
+    columns
+        .set_attrib("db_source", attr(&metadata.source_path))?
+        .set_attrib("db_version", attr(&metadata.version))?
+        .set_attrib("db_loaded_at", attr(&metadata.loaded_at))?
+        .try_into()
+}
+
+/// Matching configuration shared by the `match_tcr*` entry points
+#[allow(clippy::too_many_arguments)]
+fn match_config(
     db: &RDatabase,
-    cdr3: Vec<String>,
-    v_segment: Vec<String>,
-    j_segment: Vec<String>,
     scope: &str,
     top_n: i32,
-) -> Result<List> {
+    kmer_min_shared: i32,
+    kmer_size: i32,
+    exhaustive_search: i32,
+    allele_mode: &str,
+    restrict_chain: bool,
+    mhc_class: Option<String>,
+    missing_segments: &str,
+    ambiguous_residues: &str,
+    gap_chars: &str,
+) -> Result<matching::MatchConfig> {
+    if !(0..=2).contains(&exhaustive_search) {
+        return Err(extendr_api::error::Error::Other(format!(
+            "exhaustive_search must be 0 (first hit), 1 (all hits) or 2 (all hits, full rescoring), got {}",
+            exhaustive_search
+        )));
+    }
+
+    let mut config = matching::MatchConfig::default();
+    let adaptive = diversity::AdaptiveScopeParams::parse(scope)
+        .map_err(|e| r_error(error::VdjMatchError::InvalidSearchScope(e)))?;
+    if let Some(params) = adaptive {
+        config.search_scope = params.widest();
+        let distances = match params.basis {
+            diversity::AdaptiveBasis::Diversity => db.cdr3_distances()?,
+            diversity::AdaptiveBasis::Length => Default::default(),
+        };
+        config.adaptive_scope = Some(diversity::AdaptiveScope::new(params, distances));
+    } else if let Some(scopes) =
+        sequence::ChainScopes::parse(scope).map_err(|e| r_error(error::VdjMatchError::InvalidSearchScope(e)))?
+    {
+        config.search_scope = scopes.widest();
+        config.chain_scopes = Some(scopes);
+    } else {
+        // Parse scope, default to exact on failure.
+        config.search_scope = sequence::SearchScope::parse(scope).unwrap_or(sequence::SearchScope::EXACT);
+    }
+    config.match_v = true;  // Matching logic handles empty segments
+    config.match_j = true;  // Matching logic handles empty segments
+    config.exhaustive_search = exhaustive_search as u8;
+    config.allele_mode = sequence::AlleleMode::parse(allele_mode).map_err(extendr_api::error::Error::Other)?;
+    config.restrict_chain = restrict_chain;
+    config.missing_segments =
+        sequence::MissingSegmentPolicy::parse(missing_segments).map_err(extendr_api::error::Error::Other)?;
+    if let Some(class) = mhc_class {
+        if database::normalize_mhc_class(&class).is_none() {
+            return Err(extendr_api::error::Error::Other(format!(
+                "mhc_class must be \"MHCI\" or \"MHCII\", got \"{}\"",
+                class
+            )));
+        }
+        config.mhc_class = Some(class);
+    }
+    config.residues = parse_residues(ambiguous_residues, gap_chars)?;
+    if top_n > 0 { config.top_n_hits = Some(top_n as usize); }
+    if kmer_min_shared > 0 {
+        config.kmer_filter = Some(alignment::KmerFilter::new(kmer_size.max(1) as usize, kmer_min_shared as usize));
+    }
+    Ok(config)
+}
+
+/// `ambiguous_residues`/`gap_chars` arguments: the X/`*` policy ("penalize",
+/// "neutral", "error" or "skip") and the characters stripped before comparison
+fn parse_residues(ambiguous_residues: &str, gap_chars: &str) -> Result<sequence::ResidueHandling> {
+    let policy = sequence::AmbiguousResiduePolicy::parse(ambiguous_residues).map_err(extendr_api::error::Error::Other)?;
+    Ok(sequence::ResidueHandling::new(policy, gap_chars))
+}
+
+/// Under the "error" policy, fail on the first sequence with an ambiguous
+/// residue, naming it by its 1-based position among `what`
+fn check_residues<'a>(
+    residues: &sequence::ResidueHandling,
+    what: &str,
+    seqs: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    for (i, seq) in seqs.into_iter().enumerate() {
+        if let Err(msg) = residues.prepare(&seq.to_uppercase()) {
+            return Err(r_error(error::VdjMatchError::Sequence(format!("{} {}: {}", what, i + 1, msg))));
+        }
+    }
+    Ok(())
+}
+
+/// `score_normalization` argument of the `match_tcr*` entry points
+fn parse_score_normalization(mode: &str) -> Result<scoring::ScoreNormalization> {
+    scoring::ScoreNormalization::parse(mode).map_err(extendr_api::error::Error::Other)
+}
+
+/// `simple_score` argument of the `match_tcr*` entry points
+fn parse_simple_score(metric: &str) -> Result<scoring::SimpleScore> {
+    scoring::SimpleScore::parse(metric).map_err(extendr_api::error::Error::Other)
+}
+
+/// `v_match_level` argument of the `match_tcr*` entry points
+fn parse_v_match_level(level: &str) -> Result<sequence::VMatchLevel> {
+    sequence::VMatchLevel::parse(level).map_err(extendr_api::error::Error::Other)
+}
+
+/// Run batch matching shared by `match_tcr_many` and `match_tcr_many_lazy`
+/// With `p_values`, hits get p/q-values against the database's score null.
+#[allow(clippy::too_many_arguments)]
+fn batch_match(
+    context: &str,
+    db: &RDatabase,
+    cdr3: &[String],
+    v_segment: &[String],
+    j_segment: &[String],
+    d_segment: Option<&[String]>,
+    config: &matching::MatchConfig,
+    p_values: bool,
+) -> Result<matching::BatchMatches> {
+    use rayon::prelude::*;
+
     if !(cdr3.len() == v_segment.len() && v_segment.len() == j_segment.len()) {
         return Err(extendr_api::error::Error::Other("cdr3, v_segment, j_segment must have equal length".into()));
     }
-
-    let search_scope = sequence::SearchScope::parse(scope).unwrap_or(sequence::SearchScope::EXACT);
+    if d_segment.is_some_and(|d| d.len() != cdr3.len()) {
+        return Err(extendr_api::error::Error::Other("d_segment must have the same length as cdr3".into()));
+    }
+    check_residues(&config.residues, "query", cdr3.iter().map(String::as_str))?;
 
     // Build clonotypes for parallel matching
     let clonotypes: Vec<sequence::Clonotype> = cdr3
         .iter()
         .zip(v_segment.iter().zip(j_segment.iter()))
-        .map(|(cdr3i, (vi, ji))| {
-            sequence::Clonotype::new(cdr3i.clone(), vi.clone(), ji.clone(), 1, 0.0)
+        .enumerate()
+        .map(|(i, (cdr3i, (vi, ji)))| {
+            let mut clonotype = sequence::Clonotype::new(cdr3i.clone(), vi.clone(), ji.clone(), 1, 0.0);
+            clonotype.d_segment = d_segment.and_then(|d| sequence::parse_d_segment(&d[i]));
+            clonotype
         })
         .collect();
 
-    // Configure matching
-    let mut config = matching::MatchConfig::default();
-    config.search_scope = search_scope;
-    config.match_v = true;  // Matching logic handles empty segments
-    config.match_j = true;  // Matching logic handles empty segments
-    if top_n > 0 { config.top_n_hits = Some(top_n as usize); }
+    // Single-cell inputs repeat the same clonotype once per cell: match each
+    // unique (cdr3, v, j) once and fan hits back out to the original rows
+    let (unique, mapping) = matching::dedup_clonotypes(&clonotypes);
 
-    // Use parallel matching
-    let all_matches = matching::match_clonotypes_parallel(&clonotypes, &db.inner, &config);
+    // Use sharded parallel matching; on a panic, re-run queries individually
+    // so the failure is reported with the offending (1-based) query index
+    let database = db.db()?;
+    let mut unique_matches = catch_panic(|| {
+        matching::match_clonotypes_parallel(&unique, database, config)
+    })
+    .map_err(|msg| {
+        let culprit = clonotypes.par_iter().enumerate().find_map_first(|(i, clonotype)| {
+            catch_panic(|| matching::match_clonotype(clonotype, database, config))
+                .err()
+                .map(|msg| format!("query {} (cdr3 '{}'): {}", i + 1, clonotype.cdr3_aa, msg))
+        });
+        extendr_api::error::Error::Other(format!(
+            "internal error in {}, {}",
+            context,
+            culprit.unwrap_or(msg)
+        ))
+    })?;
+
+    if p_values {
+        matching::assign_p_values(&mut unique_matches, db.score_null()?, &config.residues);
+    }
+    matching::assign_epitope_stats(&mut unique_matches, db.epitope_stats()?);
+    Ok(matching::BatchMatches::new(clonotypes, mapping, unique_matches))
+}
 
-    // Flatten results
+/// Stacked query metadata and hit columns for rows `range` of a batch result
+fn batch_columns(batch: &matching::BatchMatches, range: std::ops::Range<usize>) -> List {
     let mut all_query_index: Vec<i32> = Vec::new();
     let mut all_query_cdr3: Vec<String> = Vec::new();
     let mut all_query_v: Vec<String> = Vec::new();
     let mut all_query_j: Vec<String> = Vec::new();
+    let mut all_query_d: Vec<String> = Vec::new();
 
     let mut cdr3_db = Vec::new();
     let mut v_db = Vec::new();
     let mut j_db = Vec::new();
+    let mut d_db = Vec::new();
     let mut species = Vec::new();
     let mut gene = Vec::new();
     let mut epitope = Vec::new();
@@ -281,47 +1028,76 @@ pub fn match_tcr_many(
     let mut mhc_class = Vec::new();
     let mut reference_id = Vec::new();
     let mut vdjdb_score = Vec::new();
+    let mut db_row = Vec::new();
     let mut score = Vec::new();
     let mut cdr3_score = Vec::new();
     let mut v_score = Vec::new();
     let mut j_score = Vec::new();
     let mut edit_distance = Vec::new();
+    let mut query_cdr3_length = Vec::new();
+    let mut cdr3_db_length = Vec::new();
+    let mut cdr3_length_diff = Vec::new();
+    let mut specificity = Vec::new();
+    let mut matched_v = Vec::new();
+    let mut matched_j = Vec::new();
+    let mut normalized_score = Vec::new();
+    let mut p_value = Vec::new();
+    let mut q_value = Vec::new();
+    let mut epitope_records = Vec::new();
+    let mut epitope_unique_cdr3 = Vec::new();
+    let mut db_segment_missing = Vec::new();
 
-    for (i, matches) in all_matches.into_iter().enumerate() {
-        let clonotype = &clonotypes[i];
-        for m in matches.into_iter() {
-            all_query_index.push((i as i32) + 1); // 1-based index for R
-            all_query_cdr3.push(clonotype.cdr3_aa.sequence.clone());
-            all_query_v.push(clonotype.v_segment.clone());
-            all_query_j.push(clonotype.j_segment.clone());
-
-            cdr3_db.push(m.db_entry.cdr3);
-            v_db.push(m.db_entry.v_segment);
-            j_db.push(m.db_entry.j_segment);
-            species.push(m.db_entry.species);
-            gene.push(m.db_entry.gene);
-            epitope.push(m.db_entry.antigen_epitope.clone());
-            antigen_gene.push(m.db_entry.antigen_gene.unwrap_or_default());
-            antigen_species.push(m.db_entry.antigen_species);
-            mhc_class.push(m.db_entry.mhc_class.unwrap_or_default());
-            reference_id.push(m.db_entry.reference_id.unwrap_or_default());
-            vdjdb_score.push(m.db_entry.vdjdb_score as i32);
-            score.push(m.score);
-            cdr3_score.push(m.cdr3_alignment_score);
-            v_score.push(m.v_score);
-            j_score.push(m.j_score);
-            edit_distance.push(m.edit_distance as i32);
-        }
-    }
+    for (i, m) in batch.rows(range) {
+        let clonotype = &batch.queries[i];
+        all_query_index.push((i as i32) + 1); // 1-based index for R
+        all_query_cdr3.push(clonotype.cdr3_aa.sequence.clone());
+        all_query_v.push(clonotype.v_segment.clone());
+        all_query_j.push(clonotype.j_segment.clone());
+        all_query_d.push(clonotype.d_segment.clone().unwrap_or_default());
 
-    Ok(list!(
+        cdr3_db.push(m.db_entry.cdr3.clone());
+        v_db.push(m.db_entry.v_segment.clone());
+        j_db.push(m.db_entry.j_segment.clone());
+        d_db.push(m.db_entry.d_segment.clone().unwrap_or_default());
+        species.push(m.db_entry.species.clone());
+        gene.push(m.db_entry.gene.clone());
+        epitope.push(m.db_entry.antigen_epitope.clone());
+        antigen_gene.push(m.db_entry.antigen_gene.clone().unwrap_or_default());
+        antigen_species.push(m.db_entry.antigen_species.clone());
+        mhc_class.push(m.db_entry.mhc_class.clone().unwrap_or_default());
+        reference_id.push(m.db_entry.reference_id.clone().unwrap_or_default());
+        vdjdb_score.push(m.db_entry.vdjdb_score as i32);
+        db_row.push(db_row_id(&m.db_entry));
+        score.push(m.score);
+        cdr3_score.push(m.cdr3_alignment_score);
+        v_score.push(m.v_score);
+        j_score.push(m.j_score);
+        edit_distance.push(m.edit_distance as i32);
+        let (query_length, db_length) = m.cdr3_lengths();
+        query_cdr3_length.push(query_length as i32);
+        cdr3_db_length.push(db_length as i32);
+        cdr3_length_diff.push(m.cdr3_length_diff() as i32);
+        specificity.push(m.specificity);
+        matched_v.push(m.matched_v.clone());
+        matched_j.push(m.matched_j.clone());
+        normalized_score.push(m.normalized_score);
+        p_value.push(m.p_value);
+        q_value.push(m.q_value);
+        epitope_records.push(m.epitope_records.map(|c| c as i32));
+        epitope_unique_cdr3.push(m.epitope_unique_cdr3.map(|c| c as i32));
+        db_segment_missing.push(segment_missing(&m.db_entry));
+    }
+
+    list!(
         query_index = all_query_index,
         query_cdr3 = all_query_cdr3,
         query_v = all_query_v,
         query_j = all_query_j,
+        query_d = all_query_d,
         cdr3_db = cdr3_db,
         v_db = v_db,
         j_db = j_db,
+        d_db = d_db,
         species = species,
         gene = gene,
         antigen_epitope = epitope,
@@ -330,66 +1106,606 @@ pub fn match_tcr_many(
         mhc_class = mhc_class,
         reference_id = reference_id,
         vdjdb_score = vdjdb_score,
+        db_row = db_row,
         score = score,
         cdr3_score = cdr3_score,
         v_score = v_score,
         j_score = j_score,
-        edit_distance = edit_distance
-    ))
+        edit_distance = edit_distance,
+        query_cdr3_length = query_cdr3_length,
+        cdr3_db_length = cdr3_db_length,
+        cdr3_length_diff = cdr3_length_diff,
+        specificity = specificity,
+        matched_v = matched_v,
+        matched_j = matched_j,
+        normalized_score = normalized_score,
+        p_value = p_value,
+        q_value = q_value,
+        epitope_records = epitope_records,
+        epitope_unique_cdr3 = epitope_unique_cdr3,
+        db_segment_missing = db_segment_missing
+    )
+}
+
+/// Batch match: vectors of cdr3/v/j; returns stacked results with query metadata.
+/// Uses parallel processing via Rayon for improved performance.
+/// Identical (cdr3, v, j) queries are matched once; the unique count is
+/// returned in the "n_unique_queries" attribute.
+/// `kmer_min_shared` > 0 enables the k-mer prefilter and `exhaustive_search`
+/// selects first-hit/all-hit modes, `allele_mode` allele handling,
+/// `restrict_chain`/`mhc_class` restrictions, `missing_segments`, `score_normalization`,
+/// `p_values`, `simple_score` and `v_match_level` as in `match_tcr` (q-values adjust over
+/// all hits of the batch).
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn match_tcr_many(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    scope: &str,
+    top_n: i32,
+    #[default = "0L"] kmer_min_shared: i32,
+    #[default = "3L"] kmer_size: i32,
+    #[default = "1L"] exhaustive_search: i32,
+    #[default = "\"ignore\""] allele_mode: &str,
+    #[default = "TRUE"] restrict_chain: bool,
+    #[default = "NULL"] mhc_class: Option<String>,
+    #[default = "\"exclude\""] missing_segments: &str,
+    #[default = "NULL"] d_segment: Nullable<Vec<String>>,
+    #[default = "FALSE"] match_d: bool,
+    #[default = "\"none\""] score_normalization: &str,
+    #[default = "FALSE"] p_values: bool,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+    #[default = "\"max_length\""] simple_score: &str,
+    #[default = "\"gene\""] v_match_level: &str,
+) -> RResult<List> {
+    guard("match_tcr_many", || {
+        let mut config = match_config(
+            db, scope, top_n, kmer_min_shared, kmer_size, exhaustive_search, allele_mode, restrict_chain, mhc_class,
+            missing_segments, ambiguous_residues, gap_chars,
+        )?;
+        config.match_d = match_d;
+        config.score_normalization = parse_score_normalization(score_normalization)?;
+        config.simple_score = parse_simple_score(simple_score)?;
+        config.v_match_level = parse_v_match_level(v_match_level)?;
+        let d_segment = match d_segment {
+            Nullable::NotNull(d) => Some(d),
+            Nullable::Null => None,
+        };
+        let batch = batch_match(
+            "match_tcr_many", db, &cdr3, &v_segment, &j_segment, d_segment.as_deref(), &config, p_values,
+        )?;
+
+        let columns: List = batch_columns(&batch, 0..batch.len())
+            .set_attrib("n_unique_queries", batch.n_unique() as i32)?
+            .try_into()?;
+        with_db_provenance(columns, &db.db()?.metadata)
+    })
+}
+
+/// Duplicate diagnostics for a batch of queries: `n_queries`, `n_unique`
+/// (distinct cdr3/v/j/d), `dedup_factor`, `first_query` (1-based first
+/// occurrence of each query's clonotype) and `top`, the `top` most repeated
+/// clonotypes with their `copies` and `first_query`.
+#[extendr]
+pub fn query_duplicates(
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    d_segment: Nullable<Vec<String>>,
+    top: i32,
+) -> RResult<List> {
+    guard("query_duplicates", || {
+        let n = cdr3.len();
+        let d_segment = match d_segment {
+            Nullable::NotNull(d) => Some(d),
+            Nullable::Null => None,
+        };
+        if v_segment.len() != n || j_segment.len() != n || d_segment.as_ref().is_some_and(|d| d.len() != n) {
+            return Err(extendr_api::error::Error::Other("cdr3, v_segment, j_segment and d_segment must have equal length".into()));
+        }
+        let clonotypes: Vec<sequence::Clonotype> = (0..n)
+            .map(|i| {
+                let mut clonotype =
+                    sequence::Clonotype::new(cdr3[i].clone(), v_segment[i].clone(), j_segment[i].clone(), 1, 0.0);
+                clonotype.d_segment = d_segment.as_ref().and_then(|d| sequence::parse_d_segment(&d[i]));
+                clonotype
+            })
+            .collect();
+        let (unique, mapping) = matching::dedup_clonotypes(&clonotypes);
+        let mut first = vec![0usize; unique.len()];
+        for (i, &u) in mapping.iter().enumerate().rev() {
+            first[u] = i;
+        }
+        let duplicates: Vec<(usize, usize)> =
+            matching::duplicate_queries(&clonotypes).into_iter().take(top.max(0) as usize).collect();
+        let top_field = |f: &dyn Fn(&sequence::Clonotype) -> String| {
+            duplicates.iter().map(|&(i, _)| f(&clonotypes[i])).collect::<Vec<_>>()
+        };
+        Ok(list!(
+            n_queries = n as i32,
+            n_unique = unique.len() as i32,
+            dedup_factor = n as f64 / unique.len().max(1) as f64,
+            first_query = mapping.iter().map(|&u| first[u] as i32 + 1).collect::<Vec<_>>(),
+            top = list!(
+                cdr3 = top_field(&|c| c.cdr3_aa.sequence.clone()),
+                v_segment = top_field(&|c| c.v_segment.clone()),
+                j_segment = top_field(&|c| c.j_segment.clone()),
+                d_segment = top_field(&|c| c.d_segment.clone().unwrap_or_default()),
+                copies = duplicates.iter().map(|&(_, n)| n as i32).collect::<Vec<_>>(),
+                first_query = duplicates.iter().map(|&(i, _)| i as i32 + 1).collect::<Vec<_>>()
+            )
+        ))
+    })
+}
+
+/// Records added, removed or changed from the `old` to the `new` database,
+/// keyed on cdr3/v/j/epitope/reference (case-insensitive). Columns `change`,
+/// the key fields (from the new release when present), `old_row`/`new_row`
+/// (source file rows, NA when absent or unknown) and `changed_fields`
+/// (comma-separated annotation fields of changed records).
+#[extendr]
+pub fn db_diff_table(old: &RDatabase, new: &RDatabase) -> RResult<List> {
+    guard("db_diff_table", || {
+        let (old, new) = (old.db()?, new.db()?);
+        let diffs = diff::database_diff(old, new);
+        let record = |d: &diff::EntryDiff| match d.new {
+            Some(k) => &new.entries[k],
+            None => &old.entries[d.old.unwrap()],
+        };
+        let field = |f: &dyn Fn(&database::DatabaseEntry) -> String| {
+            diffs.iter().map(|d| f(record(d))).collect::<Vec<_>>()
+        };
+        let row = |entries: &[database::DatabaseEntry], i: Option<usize>| i.and_then(|i| db_row_id(&entries[i]));
+        Ok(list!(
+            change = diffs.iter().map(|d| d.change.as_str()).collect::<Vec<_>>(),
+            cdr3 = field(&|e| e.cdr3.clone()),
+            v_segment = field(&|e| e.v_segment.clone()),
+            j_segment = field(&|e| e.j_segment.clone()),
+            antigen_epitope = field(&|e| e.antigen_epitope.clone()),
+            reference_id = field(&|e| e.reference_id.clone().unwrap_or_default()),
+            old_row = diffs.iter().map(|d| row(&old.entries, d.old)).collect::<Vec<_>>(),
+            new_row = diffs.iter().map(|d| row(&new.entries, d.new)).collect::<Vec<_>>(),
+            changed_fields = diffs.iter().map(|d| d.fields.join(",")).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Whether `db` holds a record with each (cdr3, v, j, epitope, reference) key,
+/// compared as in `db_diff_table`
+#[extendr]
+pub fn db_has_records(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    antigen_epitope: Vec<String>,
+    reference_id: Vec<String>,
+) -> RResult<Vec<bool>> {
+    guard("db_has_records", || {
+        let n = cdr3.len();
+        if [v_segment.len(), j_segment.len(), antigen_epitope.len(), reference_id.len()].iter().any(|&len| len != n) {
+            return Err(extendr_api::error::Error::Other(
+                "cdr3, v_segment, j_segment, antigen_epitope and reference_id must have equal length".into(),
+            ));
+        }
+        let keys: std::collections::HashSet<diff::RecordKey> = db.db()?.entries.iter().map(diff::record_key).collect();
+        Ok((0..n)
+            .map(|i| keys.contains(&diff::key_of(&cdr3[i], &v_segment[i], &j_segment[i], &antigen_epitope[i], &reference_id[i])))
+            .collect())
+    })
+}
+
+/// Scope tiers for `match_tcr_scan()`: the widest of `scopes` as an "s,i,d,t"
+/// string, to match with once, and for each hit `edit_distance` the 1-based
+/// index of the tightest scope it falls in (NA when none).
+#[extendr]
+pub fn scope_tiers(scopes: Vec<String>, edit_distance: Vec<i32>) -> RResult<List> {
+    guard("scope_tiers", || {
+        if scopes.is_empty() {
+            return Err(extendr_api::error::Error::Other("scopes must not be empty".into()));
+        }
+        let scopes = scopes
+            .iter()
+            .map(|s| sequence::SearchScope::parse(s.trim()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| r_error(error::VdjMatchError::InvalidSearchScope(e)))?;
+        let tier: Vec<Option<i32>> = edit_distance
+            .iter()
+            .map(|&d| matching::scope_tier(d.max(0) as usize, &scopes).map(|t| t as i32 + 1))
+            .collect();
+        Ok(list!(widest = sequence::SearchScope::widest(&scopes).to_string(), tier = tier))
+    })
+}
+
+/// Database entries as the column vectors of `RDatabase$to_columns()`
+fn entry_columns(entries: &[database::DatabaseEntry]) -> List {
+    let text = |get: fn(&database::DatabaseEntry) -> &str| entries.iter().map(|e| get(e).to_string()).collect::<Vec<_>>();
+    list!(
+        gene = text(|e| &e.gene),
+        cdr3 = text(|e| &e.cdr3),
+        v_segment = text(|e| &e.v_segment),
+        j_segment = text(|e| &e.j_segment),
+        species = text(|e| &e.species),
+        antigen_epitope = text(|e| &e.antigen_epitope),
+        antigen_gene = text(|e| e.antigen_gene.as_deref().unwrap_or_default()),
+        antigen_species = text(|e| &e.antigen_species),
+        mhc_class = text(|e| e.mhc_class.as_deref().unwrap_or_default()),
+        reference_id = text(|e| e.reference_id.as_deref().unwrap_or_default()),
+        vdjdb_score = entries.iter().map(|e| e.vdjdb_score as i32).collect::<Vec<_>>(),
+        db_row = entries.iter().map(db_row_id).collect::<Vec<_>>()
+    )
+}
+
+/// Mark a column list as an R data.frame with row names `first_row..`
+fn as_data_frame(columns: List, first_row: usize, nrow: usize) -> Result<List> {
+    let row_names: Vec<i32> = (first_row..first_row + nrow).map(|r| r as i32).collect();
+    columns
+        .set_attrib("row.names", row_names)?
+        .set_attrib("class", "data.frame")?
+        .try_into()
+}
+
+/// Batch match results kept in Rust for incremental inspection
+#[extendr]
+pub struct RMatchResult {
+    /// None once released
+    inner: Option<matching::BatchMatches>,
+    /// Database the hits came from (None for results loaded from disk)
+    source: Option<database::DatabaseMetadata>,
+}
+
+impl RMatchResult {
+    /// The hits, or a "handle_closed" error after `release()`
+    fn batch(&self) -> Result<&matching::BatchMatches> {
+        self.inner
+            .as_ref()
+            .ok_or_else(|| r_error(error::VdjMatchError::HandleClosed { handle: "RMatchResult" }))
+    }
+}
+
+#[extendr]
+impl RMatchResult {
+    /// Free the hits now instead of when R collects the handle; any later
+    /// use of the handle is a "handle_closed" error
+    pub fn release(&mut self) {
+        self.inner = None;
+        self.source = None;
+    }
+
+    /// Whether `release()` was called
+    pub fn is_released(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    /// Number of (query, hit) rows
+    pub fn nrow(&self) -> RResult<i32> {
+        guard("RMatchResult$nrow", || Ok(self.batch()?.len() as i32))
+    }
+
+    /// First `n` rows as a data.frame
+    pub fn head(&self, n: i32) -> RResult<List> {
+        self.page(0, n)
+    }
+
+    /// `n` rows after skipping `offset` rows, as a data.frame
+    pub fn page(&self, offset: i32, n: i32) -> RResult<List> {
+        guard("RMatchResult$page", || {
+            let batch = self.batch()?;
+            let start = (offset.max(0) as usize).min(batch.len());
+            let end = start.saturating_add(n.max(0) as usize).min(batch.len());
+            let df = as_data_frame(batch_columns(batch, start..end), start + 1, end - start)?;
+            match &self.source {
+                Some(metadata) => with_db_provenance(df, metadata),
+                None => Ok(df),
+            }
+        })
+    }
+
+    /// Query/hit counts and hits per epitope (most frequent first)
+    /// With `n_boot` > 0, also the fraction of queries annotated and hitting
+    /// each epitope with `conf_level` bootstrap intervals (see `annotation_fractions`).
+    pub fn summary(
+        &self,
+        #[default = "0L"] n_boot: i32,
+        #[default = "0.95"] conf_level: f64,
+        #[default = "NULL"] seed: Option<i32>,
+    ) -> RResult<List> {
+        guard("RMatchResult$summary", || {
+            let batch = self.batch()?;
+            let n_queries = batch.queries.len();
+            let n_with_hits = (0..n_queries).filter(|&i| !batch.query_hits(i).is_empty()).count();
+
+            let mut epitope_counts: std::collections::HashMap<&str, usize> = std::collections::HashMap::new();
+            for (_, m) in batch.rows(0..batch.len()) {
+                *epitope_counts.entry(m.db_entry.antigen_epitope.as_str()).or_insert(0) += 1;
+            }
+            let mut epitope_counts: Vec<(&str, usize)> = epitope_counts.into_iter().collect();
+            epitope_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+            let mut summary: Vec<(&str, Robj)> = vec![
+                ("n_queries", (n_queries as i32).into()),
+                ("n_unique_queries", (batch.n_unique() as i32).into()),
+                ("n_queries_with_hits", (n_with_hits as i32).into()),
+                ("n_hits", (batch.len() as f64).into()),
+                ("epitope", epitope_counts.iter().map(|(e, _)| e.to_string()).collect::<Vec<_>>().into()),
+                ("epitope_hits", epitope_counts.iter().map(|(_, c)| *c as i32).collect::<Vec<_>>().into()),
+            ];
+            if n_boot > 0 {
+                let mut rng = random::rng_for(seed.map(|s| s as u64));
+                let fractions = report::annotation_fractions(batch, None, n_boot as usize, conf_level, &mut rng)
+                    .map_err(extendr_api::error::Error::Other)?;
+                let by_epitope: std::collections::HashMap<&str, report::FractionEstimate> =
+                    fractions.epitopes.iter().map(|(e, f)| (e.as_str(), *f)).collect();
+                let epitope_fraction = |get: fn(&report::FractionEstimate) -> f64| {
+                    epitope_counts.iter().map(|(e, _)| get(&by_epitope[e])).collect::<Vec<_>>()
+                };
+                let a = fractions.annotated;
+                summary.extend([
+                    ("annotated_fraction", vec![a.fraction, a.lower, a.upper].into()),
+                    ("epitope_fraction", epitope_fraction(|f| f.fraction).into()),
+                    ("epitope_fraction_lower", epitope_fraction(|f| f.lower).into()),
+                    ("epitope_fraction_upper", epitope_fraction(|f| f.upper).into()),
+                ]);
+            }
+            Ok(List::from_pairs(summary))
+        })
+    }
+
+    /// Fraction of queries annotated (the "annotated" row) and hitting each
+    /// epitope, weighted by `counts` (one per query; NULL: 1 each), with
+    /// `conf_level` percentile intervals over `n_boot` resamples of the queries.
+    /// Without a `seed` the resamples are drawn from the `set_vdjmatch_seed()` stream.
+    pub fn annotation_fractions(
+        &self,
+        #[default = "NULL"] counts: Nullable<Vec<f64>>,
+        #[default = "1000L"] n_boot: i32,
+        #[default = "0.95"] conf_level: f64,
+        #[default = "NULL"] seed: Option<i32>,
+    ) -> RResult<List> {
+        guard("RMatchResult$annotation_fractions", || {
+            let counts = match counts {
+                Nullable::NotNull(counts) => Some(counts),
+                Nullable::Null => None,
+            };
+            let mut rng = random::rng_for(seed.map(|s| s as u64));
+            let fractions =
+                report::annotation_fractions(self.batch()?, counts.as_deref(), n_boot.max(0) as usize, conf_level, &mut rng)
+                    .map_err(extendr_api::error::Error::Other)?;
+            let rows: Vec<(&str, &str, report::FractionEstimate)> = std::iter::once(("annotated", "", fractions.annotated))
+                .chain(fractions.epitopes.iter().map(|(e, f)| ("epitope", e.as_str(), *f)))
+                .collect();
+            let columns = list!(
+                level = rows.iter().map(|r| r.0).collect::<Vec<_>>(),
+                epitope = rows.iter().map(|r| r.1).collect::<Vec<_>>(),
+                fraction = rows.iter().map(|r| r.2.fraction).collect::<Vec<_>>(),
+                lower = rows.iter().map(|r| r.2.lower).collect::<Vec<_>>(),
+                upper = rows.iter().map(|r| r.2.upper).collect::<Vec<_>>()
+            );
+            as_data_frame(columns, 1, rows.len())
+        })
+    }
+
+    /// All rows as a data.frame
+    pub fn to_data_frame(&self) -> RResult<List> {
+        guard("RMatchResult$to_data_frame", || self.page(0, self.batch()?.len() as i32).0)
+    }
+
+    /// A new handle keeping only the hits with `score` >= `min_score`, on one of
+    /// `epitopes` and with `edit_distance` <= `max_edit_distance` (NULL: no
+    /// limit). Queries and their `query_index` are kept, with fewer hits.
+    pub fn filter(
+        &self,
+        #[default = "NULL"] min_score: Nullable<f64>,
+        #[default = "NULL"] epitopes: Nullable<Vec<String>>,
+        #[default = "NULL"] max_edit_distance: Nullable<i32>,
+    ) -> RResult<Self> {
+        guard("RMatchResult$filter", || {
+            let max_edit_distance = match max_edit_distance {
+                Nullable::NotNull(d) if d < 0 => {
+                    return Err(extendr_api::error::Error::Other(format!(
+                        "max_edit_distance must be non-negative, got {}",
+                        d
+                    )))
+                }
+                Nullable::NotNull(d) => Some(d as usize),
+                Nullable::Null => None,
+            };
+            let filter = matching::HitFilter {
+                min_score: match min_score {
+                    Nullable::NotNull(s) => Some(s),
+                    Nullable::Null => None,
+                },
+                epitopes: match epitopes {
+                    Nullable::NotNull(e) => Some(e.into_iter().collect()),
+                    Nullable::Null => None,
+                },
+                max_edit_distance,
+            };
+            Ok(Self { inner: Some(self.batch()?.filter(&filter)), source: self.source.clone() })
+        })
+    }
+
+    /// Save the result for reloading with `match_result_load()`.
+    /// Paths ending in ".gz" are gzip-compressed.
+    pub fn save(&self, path: &str) -> RResult<()> {
+        guard("RMatchResult$save", || {
+            self.batch()?
+                .save(path)
+                .map_err(|e| r_error_with(&format!("Failed to save match result to {}", path), e))
+        })
+    }
+
+    /// Bipartite hit network: `nodes` (id, node_type, label, epitope, copies) and
+    /// `edges` (source, target, weight = best score, edit_distance, n_hits)
+    /// linking query clonotypes to the database entries or epitopes
+    /// (`target` "entry" or "epitope") they hit.
+    pub fn network(&self, target: &str) -> RResult<List> {
+        guard("RMatchResult$network", || {
+            let target = network::NetworkTarget::parse(target).map_err(extendr_api::error::Error::Other)?;
+            let net = network::hit_network(self.batch()?, target);
+            let nodes = list!(
+                id = net.nodes.iter().map(|n| n.id.as_str()).collect::<Vec<_>>(),
+                node_type = net.nodes.iter().map(|n| n.kind).collect::<Vec<_>>(),
+                label = net.nodes.iter().map(|n| n.label.as_str()).collect::<Vec<_>>(),
+                epitope = net.nodes.iter().map(|n| n.epitope.as_str()).collect::<Vec<_>>(),
+                copies = net.nodes.iter().map(|n| n.copies as i32).collect::<Vec<_>>()
+            );
+            let edges = list!(
+                source = net.edges.iter().map(|e| e.source.as_str()).collect::<Vec<_>>(),
+                target = net.edges.iter().map(|e| e.target.as_str()).collect::<Vec<_>>(),
+                weight = net.edges.iter().map(|e| e.weight).collect::<Vec<_>>(),
+                edit_distance = net.edges.iter().map(|e| e.edit_distance as i32).collect::<Vec<_>>(),
+                n_hits = net.edges.iter().map(|e| e.n_hits as i32).collect::<Vec<_>>()
+            );
+            Ok(list!(
+                nodes = as_data_frame(nodes, 1, net.nodes.len())?,
+                edges = as_data_frame(edges, 1, net.edges.len())?
+            ))
+        })
+    }
+
+    /// Write the hit network as a TSV edge list with node labels, for
+    /// Cytoscape or igraph. Paths ending in ".gz" are gzip-compressed.
+    pub fn write_network(&self, path: &str, target: &str) -> RResult<()> {
+        guard("RMatchResult$write_network", || {
+            let target = network::NetworkTarget::parse(target).map_err(extendr_api::error::Error::Other)?;
+            io::writers::write_network(path, &network::hit_network(self.batch()?, target))
+                .map_err(|e| r_error_with(&format!("Failed to write network file {}", path), e))
+        })
+    }
+
+    /// Write one AIRR Rearrangement row per query, with the best hit in
+    /// `vdjdb_*` fields. Paths ending in ".gz" are gzip-compressed.
+    pub fn write_airr(&self, path: &str, sequence_id: Nullable<Vec<String>>) -> RResult<()> {
+        guard("RMatchResult$write_airr", || {
+            let batch = self.batch()?;
+            let ids = match sequence_id {
+                Nullable::NotNull(ids) if ids.len() == batch.queries.len() => Some(ids),
+                Nullable::NotNull(ids) => {
+                    return Err(extendr_api::error::Error::Other(format!(
+                        "sequence_id has {} values for {} queries",
+                        ids.len(),
+                        batch.queries.len()
+                    )))
+                }
+                Nullable::Null => None,
+            };
+            io::writers::write_airr(path, batch, ids.as_deref())
+                .map_err(|e| r_error_with(&format!("Failed to write AIRR file {}", path), e))
+        })
+    }
+
+    /// Write an annotation report (annotation rates, the `top_n` epitopes hit
+    /// by most queries, hit score distribution, database provenance) as
+    /// `format` "json" or "html". Paths ending in ".gz" are gzip-compressed.
+    pub fn write_report(&self, path: &str, format: &str, top_n: i32) -> RResult<()> {
+        guard("RMatchResult$write_report", || {
+            let format = report::ReportFormat::parse(format).map_err(extendr_api::error::Error::Other)?;
+            let report = report::AnnotationReport::new(self.batch()?, self.source.as_ref(), top_n.max(0) as usize);
+            io::writers::write_report(path, &report, format)
+                .map_err(|e| r_error_with(&format!("Failed to write report {}", path), e))
+        })
+    }
+}
+
+/// Batch match like `match_tcr_many`, but keep the hits in Rust and return an
+/// `RMatchResult` handle for paging through them.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn match_tcr_many_lazy(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    scope: &str,
+    top_n: i32,
+    #[default = "0L"] kmer_min_shared: i32,
+    #[default = "3L"] kmer_size: i32,
+    #[default = "1L"] exhaustive_search: i32,
+    #[default = "\"ignore\""] allele_mode: &str,
+    #[default = "TRUE"] restrict_chain: bool,
+    #[default = "NULL"] mhc_class: Option<String>,
+    #[default = "\"exclude\""] missing_segments: &str,
+    #[default = "NULL"] d_segment: Nullable<Vec<String>>,
+    #[default = "FALSE"] match_d: bool,
+    #[default = "\"none\""] score_normalization: &str,
+    #[default = "FALSE"] p_values: bool,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+    #[default = "\"max_length\""] simple_score: &str,
+    #[default = "\"gene\""] v_match_level: &str,
+) -> RResult<RMatchResult> {
+    guard("match_tcr_many_lazy", || {
+        let mut config = match_config(
+            db, scope, top_n, kmer_min_shared, kmer_size, exhaustive_search, allele_mode, restrict_chain, mhc_class,
+            missing_segments, ambiguous_residues, gap_chars,
+        )?;
+        config.match_d = match_d;
+        config.score_normalization = parse_score_normalization(score_normalization)?;
+        config.simple_score = parse_simple_score(simple_score)?;
+        config.v_match_level = parse_v_match_level(v_match_level)?;
+        let d_segment = match d_segment {
+            Nullable::NotNull(d) => Some(d),
+            Nullable::Null => None,
+        };
+        let inner =
+            batch_match("match_tcr_many_lazy", db, &cdr3, &v_segment, &j_segment, d_segment.as_deref(), &config, p_values)?;
+        Ok(RMatchResult { inner: Some(inner), source: Some(db.db()?.metadata.clone()) })
+    })
 }
 
 /// Ensure VDJdb exists locally and return the path.
 #[extendr]
-pub fn vdjdb_ensure(_use_fat_db: bool) -> Result<String> {
-    Err(extendr_api::error::Error::Other(
+pub fn vdjdb_ensure(_use_fat_db: bool) -> RResult<String> {
+    RResult(Err(extendr_api::error::Error::Other(
         "Automatic download to home directory is disabled. Use vdjdb_ensure_into(dir, use_fat_db).".into(),
-    ))
+    )))
 }
 
 /// Download/update the VDJdb files (slim and fat).
 #[extendr]
-pub fn vdjdb_update() -> Result<()> {
-    Err(extendr_api::error::Error::Other(
+pub fn vdjdb_update() -> RResult<()> {
+    RResult(Err(extendr_api::error::Error::Other(
         "Automatic update to home directory is disabled. Use vdjdb_update_into(dir).".into(),
-    ))
+    )))
 }
 
 /// Ensure VDJdb exists in the specified directory and return the path.
 #[extendr]
-pub fn vdjdb_ensure_into(dir: &str, use_fat_db: bool) -> Result<String> {
-    let mgr = database::DatabaseManager::new_with_dir(dir);
-    match mgr.ensure_database_exists(use_fat_db) {
-        Ok(path) => Ok(path.to_string_lossy().to_string()),
-        Err(e) => Err(extendr_api::error::Error::Other(e.to_string())),
-    }
+pub fn vdjdb_ensure_into(dir: &str, use_fat_db: bool) -> RResult<String> {
+    guard("vdjdb_ensure_into", || {
+        let mgr = database::DatabaseManager::new_with_dir(dir);
+        match mgr.ensure_database_exists(use_fat_db) {
+            Ok(path) => Ok(path.to_string_lossy().to_string()),
+            Err(e) => Err(r_error(e)),
+        }
+    })
 }
 
 /// Download/update the VDJdb files (slim and fat) into the specified directory.
 #[extendr]
-pub fn vdjdb_update_into(dir: &str) -> Result<()> {
-    let mgr = database::DatabaseManager::new_with_dir(dir);
-    match mgr.update_database() {
-        Ok(()) => Ok(()),
-        Err(e) => Err(extendr_api::error::Error::Other(e.to_string())),
-    }
+pub fn vdjdb_update_into(dir: &str) -> RResult<()> {
+    guard("vdjdb_update_into", || {
+        let mgr = database::DatabaseManager::new_with_dir(dir);
+        match mgr.update_database() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(r_error(e)),
+        }
+    })
 }
 
-/// Calculate pairwise tcrdist distances between TCRs
-/// Returns a distance matrix (as a vector in column-major order for R)
-/// Pass empty strings for missing CDR sequences
-/// Uses parallel processing via Rayon for improved performance
-/// @export
-#[extendr]
-pub fn calculate_tcrdist(
-    cdr1_a: Vec<String>,
-    cdr2_a: Vec<String>,
-    cdr3_a: Vec<String>,
-    cdr1_b: Vec<String>,
-    cdr2_b: Vec<String>,
-    cdr3_b: Vec<String>,
-) -> Result<List> {
-    use rayon::prelude::*;
-
+/// Build TCR objects from parallel CDR vectors; empty strings mean missing
+fn build_tcrs(
+    cdr1_a: &[String],
+    cdr2_a: &[String],
+    cdr3_a: &[String],
+    cdr1_b: &[String],
+    cdr2_b: &[String],
+    cdr3_b: &[String],
+) -> Result<Vec<tcrdist::TCR>> {
     let n = cdr3_a.len();
 
     // Validate input lengths
@@ -403,8 +1719,7 @@ pub fn calculate_tcrdist(
     // Helper to convert empty string to None
     let to_opt = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
 
-    // Build TCR objects
-    let tcrs: Vec<tcrdist::TCR> = (0..n).map(|i| {
+    Ok((0..n).map(|i| {
         tcrdist::TCR::new(
             to_opt(&cdr1_a[i]),
             to_opt(&cdr2_a[i]),
@@ -413,40 +1728,194 @@ pub fn calculate_tcrdist(
             to_opt(&cdr2_b[i]),
             to_opt(&cdr3_b[i]),
         )
-    }).collect();
+    }).collect())
+}
+
+/// Calculate pairwise tcrdist distances between TCRs
+/// Returns a distance matrix (as a vector in column-major order for R)
+/// Pass empty strings for missing CDR sequences
+/// CDRs present in only one TCR are handled by `missing_policy`:
+/// "skip" (ignore), "penalize" (add `missing_penalty` each) or "na" (NA distance)
+/// Characters in `gap_chars` are removed; X and `*` follow `ambiguous_residues`:
+/// "penalize" (unknown residue), "neutral" (no cost), "error", or "skip" (the
+/// CDR counts as missing)
+/// Optional per-TCR `ids` add `id_i`/`id_j` (and `ids`) to the result and
+/// `clone_sizes` add `clone_size_i`/`clone_size_j`; with `clusters` (one label
+/// per TCR) the result also has `cluster_distances`, the clone-size-weighted
+/// mean distance between and within clusters
+/// `chain_weights` scales the alpha and beta chain distances: c(alpha = 1, beta = 1)
+/// by default, or "alpha_only"/"beta_only" to ignore the other chain
+/// `precision` "float" or "integer" returns `distance` in half the memory:
+/// "integer" as an integer vector (needs integer chain weights), "float" as
+/// single-precision values that `as.numeric()` converts back to doubles
+/// Uses parallel processing via Rayon for improved performance
+/// @export
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_tcrdist(
+    cdr1_a: Vec<String>,
+    cdr2_a: Vec<String>,
+    cdr3_a: Vec<String>,
+    cdr1_b: Vec<String>,
+    cdr2_b: Vec<String>,
+    cdr3_b: Vec<String>,
+    #[default = "\"skip\""] missing_policy: &str,
+    #[default = "24L"] missing_penalty: i32,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+    #[default = "NULL"] ids: Nullable<Vec<String>>,
+    #[default = "NULL"] clone_sizes: Nullable<Vec<f64>>,
+    #[default = "NULL"] clusters: Nullable<Vec<String>>,
+    #[default = "c(alpha = 1, beta = 1)"] chain_weights: Robj,
+    #[default = "\"double\""] precision: &str,
+) -> RResult<List> {
+    guard("calculate_tcrdist", || {
+        let policy = tcrdist::MissingPolicy::parse(missing_policy, missing_penalty)
+            .map_err(extendr_api::error::Error::Other)?;
+        let residues = parse_residues(ambiguous_residues, gap_chars)?;
+        let weights = parse_chain_weights(&chain_weights)?;
+        let precision = parse_precision(precision, weights)?;
+
+        let tcrs = build_tcrs(&cdr1_a, &cdr2_a, &cdr3_a, &cdr1_b, &cdr2_b, &cdr3_b)?;
+        let n = tcrs.len();
+
+        // Pairwise distances (encoded once, upper triangle in parallel, mirrored)
+        let distances = tcrdist::pairwise_distances_as(&tcrs, policy, &residues, weights, precision)
+            .map_err(|e| r_error(error::VdjMatchError::Sequence(e)))?;
+
+        let ids = per_tcr(ids, n, "ids")?;
+        let clone_sizes = clone_size_weights(clone_sizes, n)?;
+        let clusters = per_tcr(clusters, n, "clusters")?;
+
+        // 1-based (i, j) indices for R, in the same row-major order as `distances`
+        let i_indices: Vec<i32> = (0..n * n).map(|k| (k / n + 1) as i32).collect();
+        let j_indices: Vec<i32> = (0..n * n).map(|k| (k % n + 1) as i32).collect();
+
+        let mut columns: Vec<(&str, Robj)> = Vec::new();
+        if let Some(ids) = &ids {
+            columns.push(("id_i", (0..n * n).map(|k| ids[k / n].clone()).collect::<Vec<_>>().into()));
+            columns.push(("id_j", (0..n * n).map(|k| ids[k % n].clone()).collect::<Vec<_>>().into()));
+        }
+        if let Some(sizes) = &clone_sizes {
+            columns.push(("clone_size_i", (0..n * n).map(|k| sizes[k / n]).collect::<Vec<_>>().into()));
+            columns.push(("clone_size_j", (0..n * n).map(|k| sizes[k % n]).collect::<Vec<_>>().into()));
+        }
+        let cluster_distances = match &clusters {
+            Some(labels) => Some(cluster_distance_columns(&distances.to_f64(), n, labels, clone_sizes.as_deref())?),
+            None => None,
+        };
+
+        let mut result: Vec<(&str, Robj)> = vec![
+            ("i", i_indices.into()),
+            ("j", j_indices.into()),
+            ("distance", distance_values(distances)?),
+            ("n", (n as i32).into()),
+        ];
+        result.extend(columns);
+        if let Some(ids) = ids {
+            result.push(("ids", ids.into()));
+        }
+        if let Some(cluster_distances) = cluster_distances {
+            result.push(("cluster_distances", cluster_distances.into()));
+        }
+        Ok(List::from_pairs(result))
+    })
+}
 
-    // Calculate pairwise distances using parallel processing
-    // Each row is computed in parallel using references to avoid move issues
-    let results: Vec<_> = (0..n).into_par_iter().flat_map(|i| {
-        let tcrs_ref = &tcrs; // Capture reference, not ownership
-        (0..n).map(move |j| {
-            let dist = tcrdist::tcrdist(&tcrs_ref[i], &tcrs_ref[j]);
-            ((i + 1) as i32, (j + 1) as i32, dist) // 1-based indices for R
-        }).collect::<Vec<_>>()
-    }).collect();
+/// `precision` argument; integer distances need integer chain weights to be exact
+fn parse_precision(precision: &str, weights: tcrdist::ChainWeights) -> Result<tcrdist::DistancePrecision> {
+    let precision = tcrdist::DistancePrecision::parse(precision).map_err(extendr_api::error::Error::Other)?;
+    if precision == tcrdist::DistancePrecision::Integer && !weights.is_integral() {
+        return Err(extendr_api::error::Error::Other(
+            "precision = \"integer\" needs whole-number chain weights; use \"float\"".into(),
+        ));
+    }
+    Ok(precision)
+}
+
+/// Distances as an R vector: double, integer, or single-precision bits in an
+/// integer vector of class "vdjmatchR_float32"
+fn distance_values(distances: tcrdist::Distances) -> Result<Robj> {
+    Ok(match distances {
+        tcrdist::Distances::Double(d) => d.into(),
+        tcrdist::Distances::Integer(d) => d.into(),
+        tcrdist::Distances::Float(bits) => {
+            let values: Robj = bits.into();
+            values.set_class(&["vdjmatchR_float32"])?;
+            values
+        }
+    })
+}
+
+/// `chain_weights` argument: a numeric c(alpha, beta) (named in either order,
+/// or unnamed alpha first) or a shortcut string ("alpha_only", "beta_only")
+fn parse_chain_weights(weights: &Robj) -> Result<tcrdist::ChainWeights> {
+    if let Some(shortcut) = weights.as_str() {
+        return tcrdist::ChainWeights::parse(shortcut).map_err(extendr_api::error::Error::Other);
+    }
+    let values: Vec<f64> = match (weights.as_real_slice(), weights.as_integer_slice()) {
+        (Some(v), _) => v.to_vec(),
+        (None, Some(v)) => v.iter().map(|&x| if x == i32::MIN { f64::NAN } else { x as f64 }).collect(),
+        _ => Vec::new(),
+    };
+    if values.len() != 2 {
+        return Err(extendr_api::error::Error::Other(
+            "chain_weights must be c(alpha = , beta = ) or \"alpha_only\"/\"beta_only\"".into(),
+        ));
+    }
+    let names: Vec<String> = weights.names().map(|n| n.map(|x| x.to_lowercase()).collect()).unwrap_or_default();
+    let beta_first = names.first().is_some_and(|n| n == "beta") || names.get(1).is_some_and(|n| n == "alpha");
+    let (alpha, beta) = if beta_first { (values[1], values[0]) } else { (values[0], values[1]) };
+    tcrdist::ChainWeights::new(alpha, beta).map_err(extendr_api::error::Error::Other)
+}
 
-    // Unpack results into separate vectors
-    let mut i_indices = Vec::with_capacity(n * n);
-    let mut j_indices = Vec::with_capacity(n * n);
-    let mut distances = Vec::with_capacity(n * n);
+/// Optional per-TCR argument, which must have one value per TCR
+fn per_tcr<T>(values: Nullable<Vec<T>>, n: usize, name: &str) -> Result<Option<Vec<T>>> {
+    match values {
+        Nullable::NotNull(v) if v.len() != n => Err(extendr_api::error::Error::Other(format!(
+            "{} must have one value per TCR ({}), got {}",
+            name,
+            n,
+            v.len()
+        ))),
+        Nullable::NotNull(v) => Ok(Some(v)),
+        Nullable::Null => Ok(None),
+    }
+}
 
-    for (i_idx, j_idx, dist) in results {
-        i_indices.push(i_idx);
-        j_indices.push(j_idx);
-        distances.push(dist);
+/// `clone_sizes` argument: one finite, non-negative size per TCR
+fn clone_size_weights(clone_sizes: Nullable<Vec<f64>>, n: usize) -> Result<Option<Vec<f64>>> {
+    let sizes = per_tcr(clone_sizes, n, "clone_sizes")?;
+    if let Some(bad) = sizes.iter().flatten().position(|s| !s.is_finite() || *s < 0.0) {
+        return Err(extendr_api::error::Error::Other(format!(
+            "clone_sizes must be finite and non-negative (TCR {})",
+            bad + 1
+        )));
     }
+    Ok(sizes)
+}
 
+/// `cluster_distances` columns: clone-size-weighted (unweighted without sizes)
+/// mean distance per cluster pair
+fn cluster_distance_columns(distances: &[f64], n: usize, clusters: &[String], clone_sizes: Option<&[f64]>) -> Result<List> {
+    let ones = vec![1.0; n];
+    let rows = clustering::weighted_cluster_distances(distances, n, clusters, clone_sizes.unwrap_or(&ones))
+        .map_err(extendr_api::error::Error::Other)?;
     Ok(list!(
-        i = i_indices,
-        j = j_indices,
-        distance = distances,
-        n = n as i32
+        cluster_a = rows.iter().map(|r| r.cluster_a.clone()).collect::<Vec<_>>(),
+        cluster_b = rows.iter().map(|r| r.cluster_b.clone()).collect::<Vec<_>>(),
+        distance = rows.iter().map(|r| r.distance).collect::<Vec<_>>(),
+        n_pairs = rows.iter().map(|r| r.n_pairs as i32).collect::<Vec<_>>(),
+        weight = rows.iter().map(|r| r.weight).collect::<Vec<_>>()
     ))
 }
 
 /// Calculate tcrdist between two single TCRs
-/// Pass empty strings for missing CDR sequences
+/// Pass empty strings for missing CDR sequences; see `calculate_tcrdist` for
+/// `missing_policy` and `chain_weights`
+/// @export
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 pub fn tcrdist_single(
     cdr1_a_1: &str,
     cdr2_a_1: &str,
@@ -460,44 +1929,1855 @@ pub fn tcrdist_single(
     cdr1_b_2: &str,
     cdr2_b_2: &str,
     cdr3_b_2: &str,
-) -> f64 {
-    let to_opt = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
+    #[default = "\"skip\""] missing_policy: &str,
+    #[default = "24L"] missing_penalty: i32,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+    #[default = "c(alpha = 1, beta = 1)"] chain_weights: Robj,
+) -> RResult<Option<f64>> {
+    guard("tcrdist_single", || {
+        let policy = tcrdist::MissingPolicy::parse(missing_policy, missing_penalty)
+            .map_err(extendr_api::error::Error::Other)?;
+        let residues = parse_residues(ambiguous_residues, gap_chars)?;
+        let weights = parse_chain_weights(&chain_weights)?;
 
-    let tcr1 = tcrdist::TCR::new(
-        to_opt(cdr1_a_1),
-        to_opt(cdr2_a_1),
-        to_opt(cdr3_a_1),
-        to_opt(cdr1_b_1),
-        to_opt(cdr2_b_1),
-        to_opt(cdr3_b_1),
-    );
+        let to_opt = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
+
+        let tcr1 = tcrdist::TCR::new(
+            to_opt(cdr1_a_1),
+            to_opt(cdr2_a_1),
+            to_opt(cdr3_a_1),
+            to_opt(cdr1_b_1),
+            to_opt(cdr2_b_1),
+            to_opt(cdr3_b_1),
+        );
+
+        let tcr2 = tcrdist::TCR::new(
+            to_opt(cdr1_a_2),
+            to_opt(cdr2_a_2),
+            to_opt(cdr3_a_2),
+            to_opt(cdr1_b_2),
+            to_opt(cdr2_b_2),
+            to_opt(cdr3_b_2),
+        );
+
+        let encode = |tcr: &tcrdist::TCR, which: usize| {
+            tcrdist::EncodedTCR::with_residues(tcr, &residues)
+                .map_err(|e| r_error(error::VdjMatchError::Sequence(format!("TCR {}: {}", which, e))))
+        };
+        Ok(tcrdist::encoded_tcrdist_weighted(&encode(&tcr1, 1)?, &encode(&tcr2, 2)?, policy, weights))
+    })
+}
+
+/// Match CDR3s against the database by CDR3 tcrdist instead of edit-distance scope.
+/// Returns stacked hits within `max_dist` with query metadata, sorted by distance per query.
+/// `precision` sets the type of the `tcrdist` column as in `calculate_tcrdist`.
+#[extendr]
+pub fn match_tcr_tcrdist(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    max_dist: f64,
+    #[default = "\"double\""] precision: &str,
+) -> RResult<List> {
+    guard("match_tcr_tcrdist", || {
+        let precision = parse_precision(precision, tcrdist::ChainWeights::default())?;
+        let all_hits = tcrdist::database_neighbors(&cdr3, db.db()?, max_dist);
+
+        let mut query_index = Vec::new();
+        let mut query_cdr3 = Vec::new();
+        let mut cdr3_db = Vec::new();
+        let mut v_db = Vec::new();
+        let mut j_db = Vec::new();
+        let mut species = Vec::new();
+        let mut gene = Vec::new();
+        let mut epitope = Vec::new();
+        let mut antigen_gene = Vec::new();
+        let mut antigen_species = Vec::new();
+        let mut mhc_class = Vec::new();
+        let mut reference_id = Vec::new();
+        let mut vdjdb_score = Vec::new();
+        let mut distance = Vec::new();
+
+        for (i, hits) in all_hits.into_iter().enumerate() {
+            for (idx, dist) in hits {
+                let entry = &db.db()?.entries[idx];
+                query_index.push((i as i32) + 1); // 1-based index for R
+                query_cdr3.push(cdr3[i].clone());
+                cdr3_db.push(entry.cdr3.clone());
+                v_db.push(entry.v_segment.clone());
+                j_db.push(entry.j_segment.clone());
+                species.push(entry.species.clone());
+                gene.push(entry.gene.clone());
+                epitope.push(entry.antigen_epitope.clone());
+                antigen_gene.push(entry.antigen_gene.clone().unwrap_or_default());
+                antigen_species.push(entry.antigen_species.clone());
+                mhc_class.push(entry.mhc_class.clone().unwrap_or_default());
+                reference_id.push(entry.reference_id.clone().unwrap_or_default());
+                vdjdb_score.push(entry.vdjdb_score as i32);
+                distance.push(dist);
+            }
+        }
+
+        Ok(list!(
+            query_index = query_index,
+            query_cdr3 = query_cdr3,
+            cdr3_db = cdr3_db,
+            v_db = v_db,
+            j_db = j_db,
+            species = species,
+            gene = gene,
+            antigen_epitope = epitope,
+            antigen_gene = antigen_gene,
+            antigen_species = antigen_species,
+            mhc_class = mhc_class,
+            reference_id = reference_id,
+            vdjdb_score = vdjdb_score,
+            tcrdist = distance_values(tcrdist::Distances::new(distance.into_iter().map(Some), precision))?
+        ))
+    })
+}
+
+/// Predict query epitopes by weighted k-nearest-neighbor vote over CDR3 tcrdist
+/// against a labeled reference (parallel `ref_cdr3`/`ref_label` vectors).
+#[extendr]
+pub fn predict_specificity_knn(
+    ref_cdr3: Vec<String>,
+    ref_label: Vec<String>,
+    cdr3: Vec<String>,
+    k: i32,
+    max_dist: f64,
+) -> RResult<List> {
+    guard("predict_specificity_knn", || {
+        if k < 1 {
+            return Err(extendr_api::error::Error::Other("k must be at least 1".into()));
+        }
+        let clf = classifier::KnnClassifier::new(&ref_cdr3, &ref_label)
+            .map_err(extendr_api::error::Error::Other)?;
+        let predictions = clf.predict(&cdr3, k as usize, max_dist);
+
+        let mut label = Vec::with_capacity(predictions.len());
+        let mut margin = Vec::with_capacity(predictions.len());
+        let mut nearest_distance = Vec::with_capacity(predictions.len());
+        let mut n_neighbors = Vec::with_capacity(predictions.len());
+        for p in predictions {
+            label.push(p.label);
+            margin.push(p.margin);
+            nearest_distance.push(p.nearest_distance);
+            n_neighbors.push(p.n_neighbors as i32);
+        }
+
+        Ok(list!(
+            query_index = (1..=cdr3.len() as i32).collect::<Vec<i32>>(),
+            query_cdr3 = cdr3,
+            predicted_epitope = label,
+            vote_margin = margin,
+            nearest_distance = nearest_distance,
+            n_neighbors = n_neighbors
+        ))
+    })
+}
+
+/// Per-cluster length-mode consensus CDR3, per-position conservation and medoid
+/// (minimum summed CDR3 tcrdist) representative.
+#[extendr]
+pub fn cluster_consensus(cdr3: Vec<String>, cluster: Vec<String>) -> RResult<List> {
+    guard("cluster_consensus", || {
+        let summaries = clustering::summarize_clusters(&cdr3, &cluster)
+            .map_err(extendr_api::error::Error::Other)?;
+
+        let conservation = List::from_values(summaries.iter().map(|s| s.conservation.clone()));
+        Ok(list!(
+            cluster = summaries.iter().map(|s| s.cluster.clone()).collect::<Vec<_>>(),
+            size = summaries.iter().map(|s| s.size as i32).collect::<Vec<_>>(),
+            consensus = summaries.iter().map(|s| s.consensus.clone()).collect::<Vec<_>>(),
+            medoid = summaries.iter().map(|s| s.medoid.clone()).collect::<Vec<_>>(),
+            medoid_index = summaries.iter().map(|s| s.medoid_index as i32 + 1).collect::<Vec<_>>(),
+            conservation = conservation
+        ))
+    })
+}
+
+/// Stream all TCR pairs within `radius` to a TSV file as (i, j, distance) triplets
+/// Only i < j is written (1-based); a ".gz" path is gzip-compressed
+/// Avoids materializing the full n x n matrix for very large cohorts
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn write_tcrdist_neighbors(
+    cdr1_a: Vec<String>,
+    cdr2_a: Vec<String>,
+    cdr3_a: Vec<String>,
+    cdr1_b: Vec<String>,
+    cdr2_b: Vec<String>,
+    cdr3_b: Vec<String>,
+    radius: f64,
+    path: &str,
+) -> RResult<List> {
+    guard("write_tcrdist_neighbors", || {
+        let tcrs = build_tcrs(&cdr1_a, &cdr2_a, &cdr3_a, &cdr1_b, &cdr2_b, &cdr3_b)?;
+        let n_pairs = tcrdist::write_neighbors_to_path(&tcrs, radius, path)
+            .map_err(|e| r_error_with(&format!("Failed to write {}", path), e))?;
 
-    let tcr2 = tcrdist::TCR::new(
-        to_opt(cdr1_a_2),
-        to_opt(cdr2_a_2),
-        to_opt(cdr3_a_2),
-        to_opt(cdr1_b_2),
-        to_opt(cdr2_b_2),
-        to_opt(cdr3_b_2),
+        Ok(list!(
+            path = path,
+            n = tcrs.len() as i32,
+            n_pairs = n_pairs as f64
+        ))
+    })
+}
+
+/// Distances (NA when undefined) converted to `precision` ("double",
+/// "float" or "integer", rounding), as returned by `calculate_tcrdist`
+#[extendr]
+pub fn tcrdist_as_precision(distance: Vec<f64>, precision: &str) -> RResult<Robj> {
+    guard("tcrdist_as_precision", || {
+        let precision = tcrdist::DistancePrecision::parse(precision).map_err(extendr_api::error::Error::Other)?;
+        distance_values(tcrdist::Distances::new(distance.into_iter().map(|d| (!d.is_nan()).then_some(d)), precision))
+    })
+}
+
+/// Doubles from single-precision distances (NA stays NA)
+#[extendr]
+pub fn float32_to_double(bits: Vec<i32>) -> Vec<Option<f64>> {
+    bits.into_iter().map(tcrdist::from_float_bits).collect()
+}
+
+/// V genes and alleles with their CDR1/CDR2 as a data.frame
+fn v_gene_frame(genes: &[reference::VGene]) -> Result<List> {
+    let columns = list!(
+        name = genes.iter().map(|g| g.name.clone()).collect::<Vec<_>>(),
+        species = genes.iter().map(|g| g.species.clone()).collect::<Vec<_>>(),
+        cdr1 = genes.iter().map(|g| g.cdr1.clone()).collect::<Vec<_>>(),
+        cdr2 = genes.iter().map(|g| g.cdr2.clone()).collect::<Vec<_>>()
     );
+    as_data_frame(columns, 1, genes.len())
+}
 
-    tcrdist::tcrdist(&tcr1, &tcr2)
+/// Register the CDR1 and CDR2 (amino acids) of V genes or alleles of
+/// `species` for this session, replacing earlier registrations of the same
+/// names, and return them as a data.frame (name, species, cdr1, cdr2).
+/// @export
+#[extendr]
+pub fn register_v_gene(name: Vec<String>, cdr1: Vec<String>, cdr2: Vec<String>, #[default = "\"HomoSapiens\""] species: &str) -> RResult<List> {
+    guard("register_v_gene", || {
+        if cdr1.len() != name.len() || cdr2.len() != name.len() {
+            return Err(extendr_api::error::Error::Other("name, cdr1 and cdr2 must have the same length".into()));
+        }
+        let genes = (0..name.len())
+            .map(|i| reference::register_v_gene(&name[i], &cdr1[i], &cdr2[i], species))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(extendr_api::error::Error::Other)?;
+        v_gene_frame(&genes)
+    })
 }
 
-// Register exported functions/types with R.
-extendr_module! {
-    mod vdjmatchR;
-    impl RDatabase;
-    fn match_tcr;
-    fn match_tcr_many;
-    fn vdjdb_open_file;
-    fn vdjdb_len;
-    fn filter_db;
-    fn filter_db_by_epitope_size;
-    fn vdjdb_ensure;
-    fn vdjdb_update;
-    fn vdjdb_ensure_into;
-    fn vdjdb_update_into;
-    fn calculate_tcrdist;
-    fn tcrdist_single;
+/// CDR1/CDR2 of each V segment of `species` (Adaptive names and registered
+/// aliases accepted): the allele's registration, else the gene's; `name` is
+/// the registration used, NA when there is none.
+/// @export
+#[extendr]
+pub fn v_gene_cdrs(v_segment: Vec<String>, #[default = "\"HomoSapiens\""] species: &str) -> RResult<List> {
+    guard("v_gene_cdrs", || {
+        let genes: Vec<_> = v_segment.iter().map(|v| reference::v_gene_cdrs(v, species)).collect();
+        let field = |f: fn(&reference::VGene) -> &String| {
+            genes.iter().map(|g| g.as_ref().map_or_else(Rstr::na, |g| Rstr::from(f(g).as_str()))).collect::<Strings>()
+        };
+        let columns = list!(
+            v_segment = v_segment.clone(),
+            name = field(|g| &g.name),
+            cdr1 = field(|g| &g.cdr1),
+            cdr2 = field(|g| &g.cdr2)
+        );
+        as_data_frame(columns, 1, v_segment.len())
+    })
+}
+
+/// V genes registered with `register_v_gene()` in this session.
+/// @export
+#[extendr]
+pub fn registered_v_genes() -> RResult<List> {
+    guard("registered_v_genes", || v_gene_frame(&reference::registered_v_genes()))
+}
+
+/// Register `alias` as another name of the IMGT gene or allele `imgt`, for
+/// `imgt_gene_names()` and `v_gene_cdrs()`.
+/// @export
+#[extendr]
+pub fn register_gene_alias(alias: Vec<String>, imgt: Vec<String>) -> RResult<()> {
+    guard("register_gene_alias", || {
+        if alias.len() != imgt.len() {
+            return Err(extendr_api::error::Error::Other("alias and imgt must have the same length".into()));
+        }
+        alias
+            .iter()
+            .zip(&imgt)
+            .try_for_each(|(a, i)| reference::register_gene_alias(a, i))
+            .map_err(extendr_api::error::Error::Other)
+    })
+}
+
+/// IMGT names of gene names: registered aliases, then Adaptive ImmunoSEQ
+/// names ("TCRBV05-01*01" is "TRBV5-1*01"); other names are unchanged.
+/// @export
+#[extendr]
+pub fn imgt_gene_names(name: Vec<String>) -> Vec<String> {
+    name.iter().map(|n| reference::imgt_gene_name(n)).collect()
+}
+
+/// Segment names as the matcher reads them, in `to` nomenclature ("imgt" or
+/// "adaptive") at `level` ("allele", "gene" or "family"); backs `normalize_segments()`.
+#[extendr]
+pub fn segment_names(x: Vec<String>, to: &str, level: &str) -> RResult<Vec<String>> {
+    guard("segment_names", || {
+        let to = reference::Nomenclature::parse(to).map_err(extendr_api::error::Error::Other)?;
+        let level = parse_v_match_level(level)?;
+        if level == sequence::VMatchLevel::None {
+            return Err(extendr_api::error::Error::Other("level must be allele, gene or family".into()));
+        }
+        Ok(x.iter().map(|s| reference::normalize_segment(s, to, level)).collect())
+    })
+}
+
+/// Register `alias` as another name of `species` (a VDJdb species name such
+/// as "HomoSapiens", or a known synonym of one).
+/// @export
+#[extendr]
+pub fn register_species_synonym(alias: &str, species: &str) -> RResult<()> {
+    guard("register_species_synonym", || {
+        reference::register_species_synonym(alias, species).map_err(extendr_api::error::Error::Other)
+    })
+}
+
+/// VDJdb species names of species names or synonyms ("human", "Homo sapiens",
+/// "mmu"), NA when unknown.
+/// @export
+#[extendr]
+pub fn canonical_species(species: Vec<String>) -> Strings {
+    species
+        .iter()
+        .map(|s| reference::canonical_species(s).map_or_else(Rstr::na, Rstr::from))
+        .collect()
+}
+
+/// P(epitope | hits) of each query and hit epitope: the epitope's summed
+/// hit score plus `pseudo_count`, over the query's summed scores plus one
+/// pseudo-count per hit epitope and one for none of them. Columns
+/// query_index, antigen_epitope, n_hits, evidence, probability; ordered by
+/// query, most probable epitope first.
+#[extendr]
+pub fn epitope_probability_table(query_index: Vec<i32>, epitope: Vec<String>, score: Vec<f64>, pseudo_count: f64) -> RResult<List> {
+    guard("epitope_probability_table", || {
+        if epitope.len() != query_index.len() || score.len() != query_index.len() {
+            return Err(extendr_api::error::Error::Other("query_index, epitope and score must have the same length".into()));
+        }
+        if !(pseudo_count >= 0.0 && pseudo_count.is_finite()) {
+            return Err(extendr_api::error::Error::Other(format!("pseudo_count must be a non-negative number, got {}", pseudo_count)));
+        }
+        let hits = query_index.iter().zip(&epitope).zip(&score).map(|((&q, e), &s)| (q as usize, e.as_str(), s));
+        let rows = matching::epitope_probabilities(hits, pseudo_count);
+        let columns = list!(
+            query_index = rows.iter().map(|r| r.query as i32).collect::<Vec<_>>(),
+            antigen_epitope = rows.iter().map(|r| r.epitope.clone()).collect::<Vec<_>>(),
+            n_hits = rows.iter().map(|r| r.n_hits as i32).collect::<Vec<_>>(),
+            evidence = rows.iter().map(|r| r.evidence).collect::<Vec<_>>(),
+            probability = rows.iter().map(|r| r.probability).collect::<Vec<_>>()
+        );
+        as_data_frame(columns, 1, rows.len())
+    })
+}
+
+/// Load a database written by `db$save()`.
+/// @export
+#[extendr]
+pub fn db_load(path: &str) -> RResult<RDatabase> {
+    guard("db_load", || {
+        database::Database::load_saved(path)
+            .map(RDatabase::from)
+            .map_err(|e| r_error_with(&format!("Failed to load database from {}", path), e))
+    })
+}
+
+/// Open a database cache written by `db$save_cache()`.
+/// @export
+#[extendr]
+pub fn db_open_cache(path: &str) -> RResult<RDatabase> {
+    guard("db_open_cache", || {
+        io::cache::read_cache(path)
+            .map(RDatabase::from)
+            .map_err(|e| r_error_with(&format!("Failed to open database cache {}", path), e))
+    })
+}
+
+/// Load a match result written by `res$save()`.
+/// @export
+#[extendr]
+pub fn match_result_load(path: &str) -> RResult<RMatchResult> {
+    guard("match_result_load", || {
+        matching::BatchMatches::load(path)
+            .map(|inner| RMatchResult { inner: Some(inner), source: None })
+            .map_err(|e| r_error_with(&format!("Failed to load match result from {}", path), e))
+    })
+}
+
+/// Assign database rows to `n_folds` cross-validation folds (1-based), stratified by epitope.
+/// Without a `seed` the folds are drawn from the `set_vdjmatch_seed()` stream.
+/// Identical CDR3s share a fold; with `min_distance`, so do CDR3s within that CDR3 tcrdist.
+#[extendr]
+pub fn db_epitope_folds(db: &RDatabase, n_folds: i32, seed: Option<i32>, min_distance: Option<f64>) -> RResult<Vec<i32>> {
+    guard("db_epitope_folds", || {
+        let seed = random::rng_for(seed.map(|s| s as u64)).next_u64();
+        let folds = splits::epitope_folds(db.db()?, n_folds.max(0) as usize, seed, min_distance)
+            .map_err(extendr_api::error::Error::Other)?;
+        Ok(folds.into_iter().map(|f| f as i32 + 1).collect())
+    })
+}
+
+/// CDR3 length, pairwise edit distance and V usage diversity of each
+/// (epitope, chain) with at least `min_size` distinct CDR3s, largest first.
+/// Edit distances are averaged over at most `max_pairs` random CDR3 pairs, drawn
+/// from the `set_vdjmatch_seed()` stream without a `seed`. The `lengths` element
+/// counts distinct CDR3s per length.
+#[extendr]
+pub fn epitope_diversity_table(db: &RDatabase, min_size: i32, max_pairs: i32, seed: Option<i32>) -> RResult<List> {
+    guard("epitope_diversity_table", || {
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let stats = diversity::epitope_diversity(db.db()?, min_size.max(1) as usize, max_pairs.max(0) as usize, &mut rng);
+
+        let (mut l_epitope, mut l_gene, mut l_length, mut l_n) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for d in &stats {
+            for (&length, &n) in &d.length_counts {
+                l_epitope.push(d.epitope.clone());
+                l_gene.push(d.gene.clone());
+                l_length.push(length as i32);
+                l_n.push(n as i32);
+            }
+        }
+        Ok(list!(
+            epitope = stats.iter().map(|d| d.epitope.clone()).collect::<Vec<_>>(),
+            gene = stats.iter().map(|d| d.gene.clone()).collect::<Vec<_>>(),
+            n_records = stats.iter().map(|d| d.n_records as i32).collect::<Vec<_>>(),
+            n_unique_cdr3 = stats.iter().map(|d| d.n_unique_cdr3 as i32).collect::<Vec<_>>(),
+            mean_length = stats.iter().map(|d| d.mean_length).collect::<Vec<_>>(),
+            sd_length = stats.iter().map(|d| d.sd_length).collect::<Vec<_>>(),
+            min_length = stats.iter().map(|d| d.length_counts.keys().next().map_or(0, |&l| l as i32)).collect::<Vec<_>>(),
+            max_length = stats.iter().map(|d| d.length_counts.keys().last().map_or(0, |&l| l as i32)).collect::<Vec<_>>(),
+            mean_edit_distance = stats.iter().map(|d| d.mean_edit_distance).collect::<Vec<_>>(),
+            n_pairs = stats.iter().map(|d| d.n_pairs as i32).collect::<Vec<_>>(),
+            exhaustive = stats.iter().map(|d| d.exhaustive).collect::<Vec<_>>(),
+            n_v_genes = stats.iter().map(|d| d.n_v_genes as i32).collect::<Vec<_>>(),
+            v_shannon = stats.iter().map(|d| d.v_shannon).collect::<Vec<_>>(),
+            v_evenness = stats.iter().map(|d| d.v_evenness).collect::<Vec<_>>(),
+            lengths = list!(epitope = l_epitope, gene = l_gene, cdr3_length = l_length, n = l_n)
+        ))
+    })
+}
+
+/// Calibration of the `specificity` column on `n_queries` held-out database
+/// entries matched within `scope` against the rest of `db` (less their own
+/// reference): the shipped and refitted model coefficients, and observed
+/// against predicted same-epitope rates of the shipped, recalibrated
+/// confidence over `n_bins` probability bins. Without a `seed` the queries are
+/// drawn from the `set_vdjmatch_seed()` stream.
+#[extendr]
+pub fn specificity_calibration_table(db: &RDatabase, n_queries: i32, scope: &str, n_bins: i32, seed: Option<i32>) -> RResult<List> {
+    guard("specificity_calibration_table", || {
+        let config = match_config(db, scope, 0, 0, 3, 1, "ignore", true, None, "exclude", "penalize", ".-")?;
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let samples = matching::specificity_samples(db.db()?, &config, n_queries.max(0) as usize, &mut rng);
+        let shipped = scoring::SpecificityModel::VDJDB;
+        let fitted = scoring::SpecificityModel::fit(&samples);
+        let coefficients = |m: &scoring::SpecificityModel| {
+            std::iter::once(m.intercept).chain(m.weights.iter().copied()).collect::<Vec<_>>()
+        };
+
+        let predicted: Vec<(f64, bool)> = samples.iter().map(|(e, y)| (scoring::specificity_confidence(e), *y)).collect();
+        let n_bins = n_bins.max(1) as usize;
+        let table = scoring::calibration_table(&predicted, n_bins);
+        let width = 1.0 / n_bins as f64;
+        Ok(list!(
+            coefficients = list!(
+                term = ["intercept", "match_score", "vdjdb_score", "log_references", "edit_distance", "log_epitope_size"]
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>(),
+                shipped = coefficients(&shipped),
+                fitted = fitted.map_or_else(|| vec![None; 6], |m| coefficients(&m).into_iter().map(Some).collect())
+            ),
+            calibration = list!(
+                bin_lower = table.iter().map(|(i, _)| *i as f64 * width).collect::<Vec<_>>(),
+                bin_upper = table.iter().map(|(i, _)| (*i + 1) as f64 * width).collect::<Vec<_>>(),
+                n = table.iter().map(|(_, b)| b.n as i32).collect::<Vec<_>>(),
+                predicted = table.iter().map(|(_, b)| b.predicted).collect::<Vec<_>>(),
+                observed = table.iter().map(|(_, b)| b.observed).collect::<Vec<_>>()
+            ),
+            n_hits = samples.len() as i32
+        ))
+    })
+}
+
+/// Compare hits (query CDR3, database CDR3, epitope, score) with a reference
+/// run such as the Java vdjmatch or tcrdist3 output in `reference_path`.
+/// Returns concordance metrics and the hits found by only one side.
+#[extendr]
+pub fn compare_hits_to_reference(
+    query_cdr3: Vec<String>,
+    cdr3_db: Vec<String>,
+    epitope: Vec<String>,
+    score: Vec<f64>,
+    reference_path: &str,
+) -> RResult<List> {
+    guard("compare_hits_to_reference", || {
+        let n = query_cdr3.len();
+        if !(cdr3_db.len() == n && epitope.len() == n && score.len() == n) {
+            return Err(extendr_api::error::Error::Other(
+                "query_cdr3, cdr3_db, epitope and score must have equal length".into(),
+            ));
+        }
+
+        let mut ours = std::collections::HashMap::new();
+        for i in 0..n {
+            let key = validation::hit_key(&query_cdr3[i], &cdr3_db[i], &epitope[i]);
+            // NA scores arrive as NaN
+            validation::insert_hit(&mut ours, key, (!score[i].is_nan()).then_some(score[i]));
+        }
+        let reference = validation::load_reference_hits(reference_path).map_err(|e| {
+            r_error_with(&format!("Failed to read reference {}", reference_path), e)
+        })?;
+
+        let c = validation::compare_hits(&ours, &reference);
+        let column = |keys: &[validation::HitKey], part: fn(&validation::HitKey) -> &String| {
+            keys.iter().map(|k| part(k).clone()).collect::<Vec<String>>()
+        };
+
+        Ok(list!(
+            n_ours = c.n_ours as i32,
+            n_reference = c.n_reference as i32,
+            n_shared = c.n_shared as i32,
+            n_scored = c.n_scored as i32,
+            precision = c.precision(),
+            recall = c.recall(),
+            jaccard = c.jaccard(),
+            score_correlation = c.score_correlation,
+            max_score_difference = c.max_score_difference,
+            only_ours_query = column(&c.only_ours, |k| &k.0),
+            only_ours_cdr3_db = column(&c.only_ours, |k| &k.1),
+            only_ours_epitope = column(&c.only_ours, |k| &k.2),
+            only_reference_query = column(&c.only_reference, |k| &k.0),
+            only_reference_cdr3_db = column(&c.only_reference, |k| &k.1),
+            only_reference_epitope = column(&c.only_reference, |k| &k.2)
+        ))
+    })
+}
+
+/// Enumerate all distinct sequences within `subs` substitutions and `indels`
+/// insertions/deletions of `cdr3` (excluding `cdr3` itself), sorted.
+/// Useful for building custom lookup tables and ALICE-style neighbor counts.
+/// The output grows quickly; total scope is limited to 3.
+/// @export
+#[extendr]
+pub fn cdr3_neighbors(cdr3: &str, #[default = "1L"] subs: i32, #[default = "0L"] indels: i32) -> RResult<Vec<String>> {
+    guard("cdr3_neighbors", || {
+        if subs < 0 || indels < 0 || subs + indels > 3 {
+            return Err(extendr_api::error::Error::Other(format!(
+                "subs and indels must be non-negative with subs + indels <= 3 (got {}, {})",
+                subs, indels
+            )));
+        }
+        Ok(alignment::enumerate_neighbors(cdr3, subs as usize, indels as usize))
+    })
+}
+
+/// ALICE-style detection of antigen-driven expansion: per clonotype, the number
+/// of same-V/J CDR3s in the sample one substitution away, the Poisson expectation
+/// under a position-frequency generation model (fitted on `background`, or on
+/// the sample when empty), and p/q-values for neighbor excess.
+#[extendr]
+pub fn alice_neighbors(
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    background: Vec<String>,
+) -> RResult<List> {
+    guard("alice_neighbors", || {
+        let results = expansion::alice(&cdr3, &v_segment, &j_segment, &background)
+            .map_err(extendr_api::error::Error::Other)?;
+        Ok(list!(
+            n_neighbors = results.iter().map(|r| r.n_neighbors as i32).collect::<Vec<_>>(),
+            expected = results.iter().map(|r| r.expected).collect::<Vec<_>>(),
+            p_value = results.iter().map(|r| r.p_value).collect::<Vec<_>>(),
+            q_value = results.iter().map(|r| r.q_value).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Convergent recombination: per row, the number of distinct nucleotide CDR3s
+/// in the same sample encoding its amino acid CDR3 (empty `sample` = one sample)
+#[extendr]
+pub fn convergence_counts(cdr3_aa: Vec<String>, cdr3_nt: Vec<String>, sample: Vec<String>) -> RResult<Vec<i32>> {
+    guard("convergence_counts", || {
+        let counts = expansion::convergence_counts(&cdr3_aa, &cdr3_nt, &sample)
+            .map_err(extendr_api::error::Error::Other)?;
+        Ok(counts.into_iter().map(|c| c as i32).collect())
+    })
+}
+
+/// Sample x epitope hit sharing from stacked per-sample hits (one row per hit):
+/// distinct hit clonotypes, their summed frequency (NaN = unknown) and the
+/// number of samples hitting each epitope, as a tidy table over all combinations.
+#[extendr]
+pub fn hit_sharing_table(
+    sample: Vec<String>,
+    query_id: Vec<String>,
+    epitope: Vec<String>,
+    frequency: Vec<f64>,
+    samples: Vec<String>,
+) -> RResult<List> {
+    guard("hit_sharing_table", || {
+        let table = cohort::hit_sharing(&sample, &query_id, &epitope, &frequency, &samples)
+            .map_err(extendr_api::error::Error::Other)?;
+        Ok(list!(
+            sample = table.iter().map(|c| c.sample.clone()).collect::<Vec<_>>(),
+            epitope = table.iter().map(|c| c.epitope.clone()).collect::<Vec<_>>(),
+            n_clonotypes = table.iter().map(|c| c.n_clonotypes as i32).collect::<Vec<_>>(),
+            frequency = table.iter().map(|c| c.frequency).collect::<Vec<_>>(),
+            n_samples = table.iter().map(|c| c.n_samples as i32).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Per-epitope hit counts, hit and shared clonotypes, odds ratio and Fisher-exact
+/// p/q-values of two match results; backs `compare_annotations()`.
+#[extendr]
+pub fn annotation_comparison_table(a: &RMatchResult, b: &RMatchResult) -> RResult<List> {
+    guard("annotation_comparison_table", || {
+        let table = cohort::compare_annotations(a.batch()?, b.batch()?);
+        let count = |f: fn(&cohort::EpitopeComparison) -> usize| table.iter().map(|r| f(r) as i32).collect::<Vec<_>>();
+        Ok(list!(
+            epitope = table.iter().map(|r| r.epitope.clone()).collect::<Vec<_>>(),
+            hits_a = count(|r| r.hits_a),
+            hits_b = count(|r| r.hits_b),
+            clonotypes_a = count(|r| r.clonotypes_a),
+            clonotypes_b = count(|r| r.clonotypes_b),
+            shared_clonotypes = count(|r| r.shared_clonotypes),
+            odds_ratio = table.iter().map(|r| r.odds_ratio).collect::<Vec<_>>(),
+            p_value = table.iter().map(|r| r.p_value).collect::<Vec<_>>(),
+            q_value = table.iter().map(|r| r.q_value).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Annotation of RMatchResult handles in `results` downsampled `n_iter` times
+/// to `depth` clones or reads (`unit`; NULL depth: the smallest sample).
+/// `counts` holds one numeric vector per result (NULL: 1 per query). Rows give
+/// the 1-based sample, "annotated" or "epitope" level, depth, and the mean and
+/// interval of hit clonotypes and of their share; backs `downsample_annotation()`.
+#[extendr]
+pub fn downsampled_annotation_table(
+    results: List,
+    counts: List,
+    unit: &str,
+    depth: Option<f64>,
+    n_iter: i32,
+    conf_level: f64,
+    seed: Option<i32>,
+) -> RResult<List> {
+    guard("downsampled_annotation_table", || {
+        let unit = report::DownsampleUnit::parse(unit).map_err(extendr_api::error::Error::Other)?;
+        if counts.len() != results.len() {
+            return Err(extendr_api::error::Error::Other("counts must have one element per result".into()));
+        }
+        let handles: Vec<Robj> = results.values().collect();
+        let mut samples = Vec::with_capacity(handles.len());
+        for (k, (handle, counts)) in handles.iter().zip(counts.values()).enumerate() {
+            let result = <&RMatchResult>::from_robj(handle)
+                .map_err(|_| extendr_api::error::Error::Other(format!("result {} is not an RMatchResult", k + 1)))?;
+            let batch = result.batch()?;
+            let counts: Vec<u64> = if counts.is_null() {
+                vec![1; batch.queries.len()]
+            } else {
+                let counts = counts
+                    .as_real_vector()
+                    .ok_or_else(|| extendr_api::error::Error::Other(format!("counts of result {} must be numeric", k + 1)))?;
+                if counts.iter().any(|c| !c.is_finite() || *c < 0.0 || c.fract() != 0.0) {
+                    return Err(extendr_api::error::Error::Other(format!(
+                        "counts of result {} must be non-negative whole numbers",
+                        k + 1
+                    )));
+                }
+                counts.iter().map(|&c| c as u64).collect()
+            };
+            samples.push((batch, counts));
+        }
+        let depth = match depth {
+            Some(d) if !d.is_finite() || d < 1.0 => {
+                return Err(extendr_api::error::Error::Other(format!("depth must be a positive number, got {}", d)))
+            }
+            d => d.map(|d| d as u64),
+        };
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let rows = report::downsample_annotation(&samples, unit, depth, n_iter.max(1) as usize, conf_level, &mut rng)
+            .map_err(extendr_api::error::Error::Other)?;
+        let value = |get: fn(&report::DownsampledAnnotation) -> f64| rows.iter().map(get).collect::<Vec<_>>();
+        let columns = list!(
+            sample = rows.iter().map(|r| r.sample as i32 + 1).collect::<Vec<_>>(),
+            level = rows.iter().map(|r| if r.epitope.is_some() { "epitope" } else { "annotated" }).collect::<Vec<_>>(),
+            epitope = rows.iter().map(|r| r.epitope.clone().unwrap_or_default()).collect::<Vec<_>>(),
+            depth = value(|r| r.depth as f64),
+            n_clonotypes = value(|r| r.n_clonotypes.mean),
+            n_clonotypes_lower = value(|r| r.n_clonotypes.lower),
+            n_clonotypes_upper = value(|r| r.n_clonotypes.upper),
+            fraction = value(|r| r.fraction.mean),
+            fraction_lower = value(|r| r.fraction.lower),
+            fraction_upper = value(|r| r.fraction.upper)
+        );
+        as_data_frame(columns, 1, rows.len())
+    })
+}
+
+/// Antigen family and protein-level group of each (species, gene) pair.
+/// `rule_*` (equal length; "" for any gene or no family/group) are user rules
+/// taking precedence over the built-in ones.
+#[extendr]
+pub fn antigen_groups(
+    antigen_species: Vec<String>,
+    antigen_gene: Vec<String>,
+    rule_species: Vec<String>,
+    rule_gene: Vec<String>,
+    rule_family: Vec<String>,
+    rule_group: Vec<String>,
+) -> RResult<List> {
+    guard("antigen_groups", || {
+        if antigen_gene.len() != antigen_species.len() {
+            return Err(extendr_api::error::Error::Other("antigen_gene must have the same length as antigen_species".into()));
+        }
+        let n_rules = rule_species.len();
+        if rule_gene.len() != n_rules || rule_family.len() != n_rules || rule_group.len() != n_rules {
+            return Err(extendr_api::error::Error::Other("mapping columns must have equal length".into()));
+        }
+        let some = |s: &String| (!s.trim().is_empty()).then(|| s.trim().to_string());
+        let rules = (0..n_rules)
+            .map(|i| ontology::AntigenRule {
+                species: rule_species[i].clone(),
+                gene: some(&rule_gene[i]),
+                family: some(&rule_family[i]),
+                group: some(&rule_group[i]),
+            })
+            .collect();
+        let ontology = ontology::AntigenOntology::with_user_rules(rules);
+        let pairs = || antigen_species.iter().zip(&antigen_gene);
+        Ok(list!(
+            antigen_family = pairs().map(|(s, g)| ontology.family(s, g)).collect::<Vec<_>>(),
+            antigen_group = pairs().map(|(s, g)| ontology.group(s, g)).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// The built-in antigen rollup rules (NA gene: any gene; NA group: the family)
+#[extendr]
+pub fn antigen_ontology_rules() -> List {
+    let rules = ontology::builtin_rules();
+    list!(
+        antigen_species = rules.iter().map(|r| r.species.clone()).collect::<Vec<_>>(),
+        antigen_gene = rules.iter().map(|r| r.gene.clone()).collect::<Vec<_>>(),
+        antigen_family = rules.iter().map(|r| r.family.clone()).collect::<Vec<_>>(),
+        antigen_group = rules.iter().map(|r| r.group.clone()).collect::<Vec<_>>()
+    )
+}
+
+/// Whether each query has at least one database hit within `scope` (one logical
+/// per query). Uses an exact-CDR3 index or an early-exit scan instead of
+/// collecting hit tables, for tagging cells as annotated.
+/// `allele_mode`, `restrict_chain`, `mhc_class` and `missing_segments` are as in `match_tcr`.
+/// @export
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn has_match(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    #[default = "NULL"] v_segment: Nullable<Vec<String>>,
+    #[default = "NULL"] j_segment: Nullable<Vec<String>>,
+    #[default = "\"0,0,0,0\""] scope: &str,
+    #[default = "\"ignore\""] allele_mode: &str,
+    #[default = "TRUE"] restrict_chain: bool,
+    #[default = "NULL"] mhc_class: Option<String>,
+    #[default = "\"exclude\""] missing_segments: &str,
+) -> RResult<Vec<bool>> {
+    guard("has_match", || {
+        let n = cdr3.len();
+        let segments = |x: Nullable<Vec<String>>, name: &str| match x {
+            Nullable::NotNull(v) if v.len() == n => Ok(v),
+            Nullable::NotNull(_) => Err(extendr_api::error::Error::Other(format!("{} must have the same length as cdr3", name))),
+            Nullable::Null => Ok(vec![String::new(); n]),
+        };
+        let v_segment = segments(v_segment, "v_segment")?;
+        let j_segment = segments(j_segment, "j_segment")?;
+
+        let clonotypes: Vec<sequence::Clonotype> = (0..n)
+            .map(|i| sequence::Clonotype::new(cdr3[i].clone(), v_segment[i].clone(), j_segment[i].clone(), 1, 0.0))
+            .collect();
+        let config = match_config(db, scope, 0, 0, 3, 0, allele_mode, restrict_chain, mhc_class, missing_segments, "penalize", ".-")?;
+        let index = if config.search_scope.is_exact() { Some(db.cdr3_index()?) } else { None };
+        Ok(matching::has_matches_indexed(&clonotypes, db.db()?, index.as_deref(), &config))
+    })
+}
+
+/// Repertoire clonotypes with a hit to `epitope` within `scope`, most frequent
+/// first: their 1-based repertoire index, number of hits and best hit. Only the
+/// epitope's database entries are searched. `count`/`frequency` order the rows
+/// (NA counts as 0); the other arguments are as in `match_tcr`.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn specific_clonotypes(
+    db: &RDatabase,
+    cdr3: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    count: Vec<f64>,
+    frequency: Vec<f64>,
+    epitope: &str,
+    scope: &str,
+    allele_mode: &str,
+    restrict_chain: bool,
+    mhc_class: Option<String>,
+    missing_segments: &str,
+    ambiguous_residues: &str,
+    gap_chars: &str,
+) -> RResult<List> {
+    guard("specific_clonotypes", || {
+        let n = cdr3.len();
+        if v_segment.len() != n || j_segment.len() != n || count.len() != n || frequency.len() != n {
+            return Err(extendr_api::error::Error::Other(
+                "cdr3, v_segment, j_segment, count and frequency must have equal length".into(),
+            ));
+        }
+        let config = match_config(
+            db, scope, 0, 0, 3, 1, allele_mode, restrict_chain, mhc_class, missing_segments, ambiguous_residues,
+            gap_chars,
+        )?;
+        check_residues(&config.residues, "repertoire clonotype", cdr3.iter().map(String::as_str))?;
+        let or_zero = |x: f64| if x.is_nan() { 0.0 } else { x };
+        let clonotypes: Vec<sequence::Clonotype> = (0..n)
+            .map(|i| {
+                sequence::Clonotype::new(
+                    cdr3[i].clone(),
+                    v_segment[i].clone(),
+                    j_segment[i].clone(),
+                    or_zero(count[i]).max(0.0) as usize,
+                    or_zero(frequency[i]),
+                )
+            })
+            .collect();
+        let found = matching::find_specific_clonotypes(&clonotypes, db.db()?, epitope, &config);
+
+        let best = |f: &dyn Fn(&matching::ClonotypeMatch) -> String| found.iter().map(|s| f(&s.best)).collect::<Vec<_>>();
+        Ok(list!(
+            repertoire_index = found.iter().map(|s| s.index as i32 + 1).collect::<Vec<_>>(),
+            n_hits = found.iter().map(|s| s.n_hits as i32).collect::<Vec<_>>(),
+            cdr3_db = best(&|m| m.db_entry.cdr3.clone()),
+            v_db = best(&|m| m.db_entry.v_segment.clone()),
+            j_db = best(&|m| m.db_entry.j_segment.clone()),
+            antigen_epitope = best(&|m| m.db_entry.antigen_epitope.clone()),
+            reference_id = best(&|m| m.db_entry.reference_id.clone().unwrap_or_default()),
+            vdjdb_score = found.iter().map(|s| s.best.db_entry.vdjdb_score as i32).collect::<Vec<_>>(),
+            db_row = found.iter().map(|s| db_row_id(&s.best.db_entry)).collect::<Vec<_>>(),
+            score = found.iter().map(|s| s.best.score).collect::<Vec<_>>(),
+            edit_distance = found.iter().map(|s| s.best.edit_distance as i32).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Chain (TRA/TRB/TRG/TRD, NA if unknown) implied by each query's V/J names and
+/// whether the V and J names imply different chains.
+#[extendr]
+pub fn infer_query_chains(v_segment: Vec<String>, j_segment: Vec<String>) -> RResult<List> {
+    guard("infer_query_chains", || {
+        if v_segment.len() != j_segment.len() {
+            return Err(extendr_api::error::Error::Other("v_segment and j_segment must have equal length".into()));
+        }
+        let inferred: Vec<(Option<&str>, bool)> = v_segment
+            .iter()
+            .zip(&j_segment)
+            .map(|(v, j)| sequence::infer_chain(v, j))
+            .collect();
+        Ok(list!(
+            chain = inferred.iter().map(|(c, _)| c.map(|c| c.to_string())).collect::<Vec<Option<String>>>(),
+            conflict = inferred.iter().map(|(_, conflict)| *conflict).collect::<Vec<bool>>()
+        ))
+    })
+}
+
+/// Guess the species of a repertoire from species-specific V gene names
+/// (human-only or mouse-only TRBV genes, mouse "D"/"N" TRAV duplicates).
+/// Returns the species (VDJdb naming, NA if undecided) and the vote counts.
+#[extendr]
+pub fn detect_query_species(v_segment: Vec<String>, #[default = "3L"] min_informative: i32) -> RResult<List> {
+    guard("detect_query_species", || {
+        let d = species::detect_species(&v_segment, min_informative.max(0) as usize);
+        Ok(list!(
+            species = d.species.map(String::from),
+            n_human = d.n_human as i32,
+            n_mouse = d.n_mouse as i32
+        ))
+    })
+}
+
+/// Clonotype columns of a query table (data.frame or named list) found by name,
+/// alias (`cdr3_aa`, `v_gene`, `clones`, `cell_id`, ...) or `col_map`, e.g.
+/// `list(cdr3 = "junction_aa", count = "umis")`. Returns `cdr3`, `v_segment`,
+/// `j_segment` (character, NA and absent columns as ""), `d_segment` and
+/// `barcode` (NULL when absent), `count` and `frequency` (double, NA and
+/// absent columns as NA); the "columns" attribute names the column each
+/// field was read from. Fails without a cdr3 column, on character columns of
+/// another type and on negative counts or frequencies.
+#[extendr]
+pub fn query_table(table: List, #[default = "NULL"] col_map: Nullable<List>) -> RResult<List> {
+    guard("query_table", || {
+        let names: Vec<String> = table.names().map(|n| n.map(String::from).collect()).unwrap_or_default();
+        let col_map = match col_map {
+            Nullable::NotNull(map) => column_mapping(&map)?,
+            Nullable::Null => Vec::new(),
+        };
+        let index = schema::resolve_query_columns(&names, &col_map).map_err(r_error)?;
+        let column = |k: usize| index[k].map(|i| table.elt(i)).transpose();
+        let n = column(0)?.map_or(0, |c| c.len());
+
+        let mut strings: Vec<Option<Vec<String>>> = Vec::new();
+        let mut numbers: Vec<Vec<f64>> = Vec::new();
+        for (k, (field, _)) in schema::QUERY_FIELDS.iter().enumerate() {
+            let values = column(k)?;
+            if values.as_ref().is_some_and(|v| v.len() != n) {
+                return Err(extendr_api::error::Error::Other(format!("column {} must have {} rows", names[index[k].unwrap()], n)));
+            }
+            match *field {
+                "count" | "frequency" => numbers.push(match values {
+                    Some(v) => number_column(&names[index[k].unwrap()], &v)?,
+                    None => vec![f64::NAN; n],
+                }),
+                _ => strings.push(values.map(|v| string_column(&names[index[k].unwrap()], &v)).transpose()?),
+            }
+        }
+        let [cdr3, v_segment, j_segment, d_segment, barcode]: [Option<Vec<String>>; 5] =
+            strings.try_into().expect("five character fields");
+        let [count, frequency]: [Vec<f64>; 2] = numbers.try_into().expect("two numeric fields");
+        let or_empty = |v: Option<Vec<String>>| v.unwrap_or_else(|| vec![String::new(); n]);
+        let or_null = |v: Option<Vec<String>>| v.map_or_else(|| Robj::from(()), Robj::from);
+
+        let used: Vec<(String, String)> = schema::QUERY_FIELDS
+            .iter()
+            .zip(&index)
+            .filter_map(|((field, _), i)| Some((field.to_string(), names[(*i)?].clone())))
+            .collect();
+        list!(
+            cdr3 = or_empty(cdr3),
+            v_segment = or_empty(v_segment),
+            j_segment = or_empty(j_segment),
+            d_segment = or_null(d_segment),
+            count = count,
+            frequency = frequency,
+            barcode = or_null(barcode)
+        )
+        .set_attrib("columns", named_strings(&used)?)?
+        .try_into()
+    })
+}
+
+/// Character (or factor) column of a query table, trimmed, NA as ""
+fn string_column(name: &str, column: &Robj) -> Result<Vec<String>> {
+    let values = column
+        .as_str_iter()
+        .ok_or_else(|| extendr_api::error::Error::Other(format!("column {} must be character or factor", name)))?;
+    Ok(values.map(|s| if s.is_na() { String::new() } else { s.trim().to_string() }).collect())
+}
+
+/// Numeric column of a query table (numbers or numeric strings), NA as NaN
+fn number_column(name: &str, column: &Robj) -> Result<Vec<f64>> {
+    let invalid = |row: usize, what: &str| {
+        extendr_api::error::Error::Other(format!("column {} row {}: {}", name, row + 1, what))
+    };
+    let values: Vec<f64> = if let Some(v) = column.as_real_slice() {
+        v.to_vec()
+    } else if let (false, Some(v)) = (column.is_factor(), column.as_integer_slice()) {
+        v.iter().map(|&x| if x == i32::MIN { f64::NAN } else { x as f64 }).collect()
+    } else if let Some(v) = column.as_str_iter() {
+        v.enumerate()
+            .map(|(row, s)| match s.trim() {
+                _ if s.is_na() => Ok(f64::NAN),
+                "" | "NA" => Ok(f64::NAN),
+                text => text.parse().map_err(|_| invalid(row, &format!("'{}' is not a number", text))),
+            })
+            .collect::<Result<_>>()?
+    } else {
+        return Err(extendr_api::error::Error::Other(format!("column {} must be numeric", name)));
+    };
+    match values.iter().position(|&x| x < 0.0) {
+        Some(row) => Err(invalid(row, "must not be negative")),
+        None => Ok(values),
+    }
+}
+
+/// Closest human paralog of each mouse V segment (alleles dropped, ambiguous
+/// calls translated call by call), NA when no call has one.
+#[extendr]
+pub fn human_v_paralogs(v_segment: Vec<String>) -> RResult<Strings> {
+    guard("human_v_paralogs", || {
+        Ok(v_segment
+            .iter()
+            .map(|v| species::human_v_paralog(v).map_or_else(Rstr::na, Rstr::from))
+            .collect())
+    })
+}
+
+/// The built-in mouse to human V gene paralog table.
+#[extendr]
+pub fn v_paralog_table() -> RResult<List> {
+    guard("v_paralog_table", || {
+        let table = species::mouse_human_v_paralogs();
+        Ok(list!(
+            mouse = table.iter().map(|&(m, _)| m).collect::<Vec<_>>(),
+            human = table.iter().map(|&(_, h)| h).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Repertoire reshaped for immunarch (columns in immunarch order, named in snake
+/// case): identical rows merged, sorted by clone count, proportions from counts
+/// (or summed frequencies when counts are 0).
+/// `cdr3_nt`/`d_segment` may be empty strings; `count`/`frequency` use NaN for NA.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn immunarch_reshape(
+    cdr3_aa: Vec<String>,
+    v_segment: Vec<String>,
+    j_segment: Vec<String>,
+    count: Vec<f64>,
+    frequency: Vec<f64>,
+    cdr3_nt: Vec<String>,
+    d_segment: Vec<String>,
+) -> RResult<List> {
+    guard("immunarch_reshape", || {
+        let n = cdr3_aa.len();
+        if [v_segment.len(), j_segment.len(), count.len(), frequency.len(), cdr3_nt.len(), d_segment.len()]
+            .iter()
+            .any(|&len| len != n)
+        {
+            return Err(extendr_api::error::Error::Other("all inputs must have the same length".into()));
+        }
+        let clonotypes: Vec<sequence::Clonotype> = (0..n)
+            .map(|i| {
+                let count = if count[i].is_nan() { 1 } else { count[i].max(0.0) as usize };
+                let frequency = if frequency[i].is_nan() { 0.0 } else { frequency[i] };
+                let mut c = sequence::Clonotype::new(
+                    cdr3_aa[i].clone(), v_segment[i].clone(), j_segment[i].clone(), count, frequency,
+                );
+                c.cdr3_nt = Some(cdr3_nt[i].clone()).filter(|s| !s.is_empty());
+                c.d_segment = Some(d_segment[i].clone()).filter(|s| !s.is_empty());
+                c
+            })
+            .collect();
+        let rows = interop::immunarch_table(&clonotypes);
+        Ok(list!(
+            clones = rows.iter().map(|r| r.clones as f64).collect::<Vec<_>>(),
+            proportion = rows.iter().map(|r| r.proportion).collect::<Vec<_>>(),
+            cdr3_nt = rows.iter().map(|r| r.cdr3_nt.clone()).collect::<Vec<_>>(),
+            cdr3_aa = rows.iter().map(|r| r.cdr3_aa.clone()).collect::<Vec<_>>(),
+            v_name = rows.iter().map(|r| r.v_name.clone()).collect::<Vec<_>>(),
+            d_name = rows.iter().map(|r| r.d_name.clone()).collect::<Vec<_>>(),
+            j_name = rows.iter().map(|r| r.j_name.clone()).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Collapse 10x contigs (one row per contig) into one scRepertoire-style row per
+/// barcode with `CTgene`, `CTnt`, `CTaa` and `CTstrict`. Empty strings mark
+/// missing genes; contigs with an empty CDR3 are skipped.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn screpertoire_combine(
+    barcode: Vec<String>,
+    chain: Vec<String>,
+    cdr3_aa: Vec<String>,
+    cdr3_nt: Vec<String>,
+    v_gene: Vec<String>,
+    d_gene: Vec<String>,
+    j_gene: Vec<String>,
+    c_gene: Vec<String>,
+) -> RResult<List> {
+    guard("screpertoire_combine", || {
+        let contigs = contig_table(barcode, chain, cdr3_aa, cdr3_nt, v_gene, d_gene, j_gene, c_gene)?;
+        let cells = interop::screpertoire_cells(&contigs);
+        Ok(list!(
+            barcode = cells.iter().map(|c| c.barcode.clone()).collect::<Vec<_>>(),
+            CTgene = cells.iter().map(|c| c.ct_gene.clone()).collect::<Vec<_>>(),
+            CTnt = cells.iter().map(|c| c.ct_nt.clone()).collect::<Vec<_>>(),
+            CTaa = cells.iter().map(|c| c.ct_aa.clone()).collect::<Vec<_>>(),
+            CTstrict = cells.iter().map(|c| c.ct_strict.clone()).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Contigs from equal-length 10x contig table columns
+#[allow(clippy::too_many_arguments)]
+fn contig_table(
+    barcode: Vec<String>,
+    chain: Vec<String>,
+    cdr3_aa: Vec<String>,
+    cdr3_nt: Vec<String>,
+    v_gene: Vec<String>,
+    d_gene: Vec<String>,
+    j_gene: Vec<String>,
+    c_gene: Vec<String>,
+) -> Result<Vec<interop::Contig>> {
+    let n = barcode.len();
+    if [chain.len(), cdr3_aa.len(), cdr3_nt.len(), v_gene.len(), d_gene.len(), j_gene.len(), c_gene.len()]
+        .iter()
+        .any(|&len| len != n)
+    {
+        return Err(extendr_api::error::Error::Other("all contig columns must have the same length".into()));
+    }
+    let mut columns = (
+        chain.into_iter(), cdr3_aa.into_iter(), cdr3_nt.into_iter(), v_gene.into_iter(),
+        d_gene.into_iter(), j_gene.into_iter(), c_gene.into_iter(),
+    );
+    Ok(barcode
+        .into_iter()
+        .map(|barcode| interop::Contig {
+            barcode,
+            chain: columns.0.next().unwrap_or_default(),
+            cdr3_aa: columns.1.next().unwrap_or_default(),
+            cdr3_nt: columns.2.next().unwrap_or_default(),
+            v_gene: columns.3.next().unwrap_or_default(),
+            d_gene: columns.4.next().unwrap_or_default(),
+            j_gene: columns.5.next().unwrap_or_default(),
+            c_gene: columns.6.next().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Cell-level annotation from a 10x contig table (one row per contig): each
+/// productive contig is matched and every barcode gets its best-supported epitope,
+/// preferring epitopes hit by both chains. Missing hits are NA.
+/// `scope`, `allele_mode`, `restrict_chain`, `mhc_class` and `missing_segments` are as in `match_tcr`.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn annotate_cells_table(
+    db: &RDatabase,
+    barcode: Vec<String>,
+    chain: Vec<String>,
+    cdr3: Vec<String>,
+    v_gene: Vec<String>,
+    j_gene: Vec<String>,
+    #[default = "\"0,0,0,0\""] scope: &str,
+    #[default = "\"ignore\""] allele_mode: &str,
+    #[default = "TRUE"] restrict_chain: bool,
+    #[default = "NULL"] mhc_class: Option<String>,
+    #[default = "\"exclude\""] missing_segments: &str,
+) -> RResult<List> {
+    guard("annotate_cells_table", || {
+        let n = barcode.len();
+        let blank = vec![String::new(); n];
+        let contigs = contig_table(
+            barcode, chain, cdr3, blank.clone(), v_gene, blank.clone(), j_gene, blank,
+        )?;
+        let config = match_config(db, scope, 0, 0, 3, 1, allele_mode, restrict_chain, mhc_class, missing_segments, "penalize", ".-")?;
+        let cells = interop::annotate_cells(&contigs, db.db()?, &config);
+
+        let hit = |f: &dyn Fn(&matching::ClonotypeMatch) -> Option<String>| {
+            cells.iter().map(|c| c.best.as_ref().and_then(f)).collect::<Vec<Option<String>>>()
+        };
+        let number = |f: &dyn Fn(&matching::ClonotypeMatch) -> f64| {
+            cells.iter().map(|c| c.best.as_ref().map_or(f64::NAN, f)).collect::<Vec<f64>>()
+        };
+        let columns = list!(
+            barcode = cells.iter().map(|c| c.barcode.clone()).collect::<Vec<_>>(),
+            antigen_epitope = hit(&|m| Some(m.db_entry.antigen_epitope.clone())),
+            antigen_gene = hit(&|m| m.db_entry.antigen_gene.clone()),
+            antigen_species = hit(&|m| Some(m.db_entry.antigen_species.clone())),
+            mhc_class = hit(&|m| m.db_entry.mhc_class.clone()),
+            hit_chain = hit(&|m| Some(m.db_entry.gene.clone())),
+            query_cdr3 = hit(&|m| Some(m.query_clonotype.cdr3_aa.sequence.clone())),
+            cdr3_db = hit(&|m| Some(m.db_entry.cdr3.clone())),
+            score = number(&|m| m.score),
+            specificity = number(&|m| m.specificity),
+            paired = cells.iter().map(|c| c.paired).collect::<Vec<_>>(),
+            n_epitopes = cells.iter().map(|c| c.n_epitopes as i32).collect::<Vec<_>>(),
+            db_row = cells.iter().map(|c| c.best.as_ref().and_then(|m| db_row_id(&m.db_entry))).collect::<Vec<_>>()
+        );
+        with_db_provenance(columns, &db.db()?.metadata)
+    })
+}
+
+/// Compare per-cell epitope predictions ("" = none) with dextramer calls from a
+/// cells x features UMI matrix (`counts`, column-major, features named by
+/// `features`). A cell is called for its top feature with at least `min_umi` UMIs
+/// and `min_ratio` times the runner-up. Returns the per-cell truth epitope,
+/// summary counts, per-epitope precision/recall and a confusion table.
+#[extendr]
+pub fn compare_dextramer_calls(
+    predicted: Vec<String>,
+    counts: Vec<f64>,
+    features: Vec<String>,
+    min_umi: f64,
+    min_ratio: f64,
+) -> RResult<List> {
+    guard("compare_dextramer_calls", || {
+        let n_cells = predicted.len();
+        if counts.len() != n_cells * features.len() {
+            return Err(extendr_api::error::Error::Other(format!(
+                "counts must have {} cells x {} features",
+                n_cells,
+                features.len()
+            )));
+        }
+        let epitopes: Vec<String> = features.iter().map(|f| validation::dextramer_epitope(f)).collect();
+        let truth: Vec<Option<String>> = validation::dextramer_calls(&counts, n_cells, min_umi, min_ratio)
+            .into_iter()
+            .map(|call| call.map(|f| epitopes[f].clone()))
+            .collect();
+        let predicted: Vec<Option<String>> =
+            predicted.into_iter().map(|p| Some(p).filter(|p| !p.is_empty())).collect();
+        let c = validation::compare_labels(&predicted, &truth);
+
+        Ok(list!(
+            truth = truth,
+            n_cells = c.n_cells as i32,
+            n_truth = c.n_truth as i32,
+            n_predicted = c.n_predicted as i32,
+            n_both = c.n_both as i32,
+            n_agree = c.n_agree as i32,
+            concordance = c.concordance(),
+            epitope = c.per_epitope.iter().map(|e| e.epitope.clone()).collect::<Vec<_>>(),
+            epitope_n_truth = c.per_epitope.iter().map(|e| e.n_truth as i32).collect::<Vec<_>>(),
+            epitope_n_predicted = c.per_epitope.iter().map(|e| e.n_predicted as i32).collect::<Vec<_>>(),
+            epitope_n_true_positive = c.per_epitope.iter().map(|e| e.n_true_positive as i32).collect::<Vec<_>>(),
+            epitope_precision = c.per_epitope.iter().map(|e| e.precision().unwrap_or(f64::NAN)).collect::<Vec<_>>(),
+            epitope_recall = c.per_epitope.iter().map(|e| e.recall().unwrap_or(f64::NAN)).collect::<Vec<_>>(),
+            confusion_predicted = c.confusion.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+            confusion_truth = c.confusion.iter().map(|x| x.1.clone()).collect::<Vec<_>>(),
+            confusion_n = c.confusion.iter().map(|x| x.2 as i32).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Per-epitope position x amino acid residue counts of the database CDR3s,
+/// as long tables for `epitope_logos()`.
+/// `alignment` is "modal" (CDR3s of each epitope's most common length) or
+/// "imgt" (all CDR3s on IMGT junction positions). `residues` has one row per
+/// (epitope, position, amino acid); `positions` one row per (epitope, position)
+/// with its conservation (top residue frequency) and information content (bits).
+#[extendr]
+pub fn epitope_logo_table(
+    db: &RDatabase,
+    #[default = "5L"] min_size: i32,
+    #[default = "\"modal\""] alignment: &str,
+) -> RResult<List> {
+    guard("epitope_logo_table", || {
+        let alignment = motif::LogoAlignment::parse(alignment).map_err(extendr_api::error::Error::Other)?;
+        let logos = motif::epitope_logos(db.db()?, min_size.max(1) as usize, alignment);
+
+        let (mut r_epitope, mut r_position, mut r_aa, mut r_count, mut r_frequency) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        let (mut p_epitope, mut p_position, mut p_index, mut p_n, mut p_conservation, mut p_information) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (epitope, logo) in &logos {
+            let conservation = logo.conservation();
+            let information = logo.information();
+            for (i, position) in logo.positions.iter().enumerate() {
+                let frequencies = logo.frequencies(i);
+                for (a, &aa) in motif::AMINO_ACIDS.iter().enumerate() {
+                    r_epitope.push(epitope.clone());
+                    r_position.push(position.clone());
+                    r_aa.push((aa as char).to_string());
+                    r_count.push(logo.counts[i][a] as i32);
+                    r_frequency.push(frequencies[a]);
+                }
+                p_epitope.push(epitope.clone());
+                p_position.push(position.clone());
+                p_index.push(i as i32 + 1);
+                p_n.push(logo.counts[i].iter().sum::<usize>() as i32);
+                p_conservation.push(conservation[i]);
+                p_information.push(information[i]);
+            }
+        }
+
+        Ok(list!(
+            residues = list!(
+                epitope = r_epitope,
+                position = r_position,
+                amino_acid = r_aa,
+                count = r_count,
+                frequency = r_frequency
+            ),
+            positions = list!(
+                epitope = p_epitope,
+                position = p_position,
+                position_index = p_index,
+                n = p_n,
+                conservation = p_conservation,
+                information = p_information
+            ),
+            n_sequences = logos.iter().map(|(_, l)| l.n_sequences as i32).collect::<Vec<_>>(),
+            epitope = logos.into_iter().map(|(e, _)| e).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Per-position amino acid log-odds enrichment of `foreground` CDR3s over
+/// `background` ones, as a long table for `discriminative_motif()`.
+/// `alignment` is "modal" (both sets at the foreground's most common length)
+/// or "imgt"; `pseudocount` smooths the residue counts.
+#[extendr]
+pub fn discriminative_motif_table(
+    foreground: Vec<String>,
+    background: Vec<String>,
+    #[default = "\"imgt\""] alignment: &str,
+    #[default = "0.5"] pseudocount: f64,
+) -> RResult<List> {
+    guard("discriminative_motif_table", || {
+        let alignment = motif::LogoAlignment::parse(alignment).map_err(extendr_api::error::Error::Other)?;
+        if pseudocount.is_nan() || pseudocount <= 0.0 {
+            return Err(extendr_api::error::Error::Other("pseudocount must be positive".into()));
+        }
+        let as_strs = |seqs: &[String]| -> Vec<String> { seqs.iter().map(|s| s.trim().to_uppercase()).collect() };
+        let (foreground, background) = (as_strs(&foreground), as_strs(&background));
+        let fg: Vec<&str> = foreground.iter().map(String::as_str).filter(|s| !s.is_empty()).collect();
+        let bg: Vec<&str> = background.iter().map(String::as_str).filter(|s| !s.is_empty()).collect();
+        let motif = motif::discriminative_motif(&fg, &bg, alignment, pseudocount);
+
+        let count_at = |logo: &motif::LogoMatrix, position: &str, a: usize| {
+            logo.positions.iter().position(|p| p == position).map_or(0, |i| logo.counts[i][a] as i32)
+        };
+        let (mut position, mut amino_acid, mut log_odds, mut n_foreground, mut n_background) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (i, p) in motif.positions.iter().enumerate() {
+            for (a, &aa) in motif::AMINO_ACIDS.iter().enumerate() {
+                position.push(p.clone());
+                amino_acid.push((aa as char).to_string());
+                log_odds.push(motif.log_odds[i][a]);
+                n_foreground.push(count_at(&motif.foreground, p, a));
+                n_background.push(count_at(&motif.background, p, a));
+            }
+        }
+
+        Ok(list!(
+            position = position,
+            amino_acid = amino_acid,
+            log_odds = log_odds,
+            foreground_count = n_foreground,
+            background_count = n_background,
+            n_foreground = motif.foreground.n_sequences as i32,
+            n_background = motif.background.n_sequences as i32
+        ))
+    })
+}
+
+/// Sample `per_query` clonotypes from a pool for every query, matched on V gene,
+/// J gene and CDR3 length, drawing without replacement in proportion to
+/// `pool_weight`. With `relax`, strata the pool cannot fill are topped up with
+/// clonotypes of the same CDR3 length. Returns 1-based pool indices, the query
+/// stratum each stands in for and per-stratum requested/sampled counts.
+/// Without a `seed` the sample is drawn from the `set_vdjmatch_seed()` stream.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn sample_background_indices(
+    query_cdr3: Vec<String>,
+    query_v: Vec<String>,
+    query_j: Vec<String>,
+    pool_cdr3: Vec<String>,
+    pool_v: Vec<String>,
+    pool_j: Vec<String>,
+    pool_weight: Vec<f64>,
+    per_query: i32,
+    relax: bool,
+    seed: Option<i32>,
+) -> RResult<List> {
+    guard("sample_background_indices", || {
+        if query_v.len() != query_cdr3.len() || query_j.len() != query_cdr3.len() {
+            return Err(extendr_api::error::Error::Other("query_cdr3, query_v and query_j must have equal length".into()));
+        }
+        let n_pool = pool_cdr3.len();
+        if pool_v.len() != n_pool || pool_j.len() != n_pool || pool_weight.len() != n_pool {
+            return Err(extendr_api::error::Error::Other(
+                "pool_cdr3, pool_v, pool_j and pool_weight must have equal length".into(),
+            ));
+        }
+        let query_cdr3: Vec<String> = query_cdr3.iter().map(|s| s.trim().to_uppercase()).collect();
+        let pool_cdr3: Vec<String> = pool_cdr3.iter().map(|s| s.trim().to_uppercase()).collect();
+        let query: Vec<(&str, &str, &str)> = (0..query_cdr3.len())
+            .map(|i| (query_cdr3[i].as_str(), query_v[i].as_str(), query_j[i].as_str()))
+            .collect();
+        let pool: Vec<(&str, &str, &str)> =
+            (0..n_pool).map(|i| (pool_cdr3[i].as_str(), pool_v[i].as_str(), pool_j[i].as_str())).collect();
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let sample = background::sample_background(&query, &pool, &pool_weight, per_query.max(0) as usize, relax, &mut rng);
+
+        let mut sampled = vec![0i32; sample.strata.len()];
+        for &s in &sample.stratum {
+            sampled[s] += 1;
+        }
+        Ok(list!(
+            index = sample.indices.iter().map(|&i| i as i32 + 1).collect::<Vec<_>>(),
+            stratum = sample.stratum.iter().map(|&s| s as i32 + 1).collect::<Vec<_>>(),
+            relaxed = sample.relaxed,
+            strata = list!(
+                v_gene = sample.strata.iter().map(|(s, _)| s.v_gene.clone()).collect::<Vec<_>>(),
+                j_gene = sample.strata.iter().map(|(s, _)| s.j_gene.clone()).collect::<Vec<_>>(),
+                cdr3_length = sample.strata.iter().map(|(s, _)| s.length as i32).collect::<Vec<_>>(),
+                n_requested = sample.strata.iter().map(|(_, n)| *n as i32).collect::<Vec<_>>(),
+                n_sampled = sampled
+            )
+        ))
+    })
+}
+
+/// Seed the random number stream used by stochastic functions (background
+/// sampling, fold assignment, ...) called without an explicit `seed`, making a
+/// session's results reproducible. `NULL` reseeds from the clock.
+/// @export
+#[extendr]
+pub fn set_vdjmatch_seed(#[default = "NULL"] seed: Nullable<f64>) -> RResult<()> {
+    guard("set_vdjmatch_seed", || {
+        let seed = match seed {
+            Nullable::NotNull(s) if s.is_finite() => Some(s as i64 as u64),
+            Nullable::NotNull(_) => {
+                return Err(extendr_api::error::Error::Other("seed must be a finite number or NULL".into()))
+            }
+            Nullable::Null => None,
+        };
+        random::set_seed(seed);
+        Ok(())
+    })
+}
+
+/// Length of elementwise pairs of `a` and `b`, recycling a length-one side
+fn paired_len(a: usize, b: usize) -> Result<usize> {
+    match (a, b) {
+        _ if a == b => Ok(a),
+        (1, n) | (n, 1) => Ok(n),
+        _ => Err(extendr_api::error::Error::Other(format!(
+            "vectors must have equal length or length 1, got {} and {}",
+            a, b
+        ))),
+    }
+}
+
+/// Align CDR3s elementwise (a length-one side is recycled), in parallel.
+/// Returns per pair the operations as one-letter codes (M/S/I/D), substitution,
+/// insertion and deletion counts and the edit distance.
+/// Gap characters in `gap_chars` are removed first; `ambiguous_residues` is the
+/// X/`*` policy ("penalize", "neutral", "error" or "skip", whose pairs are NA).
+#[extendr]
+pub fn align_cdr3_pairs(
+    query: Vec<String>,
+    target: Vec<String>,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+) -> RResult<List> {
+    use rayon::prelude::*;
+
+    guard("align_cdr3_pairs", || {
+        let n = paired_len(query.len(), target.len())?;
+        let residues = parse_residues(ambiguous_residues, gap_chars)?;
+        check_residues(&residues, "query", query.iter().map(String::as_str))?;
+        check_residues(&residues, "target", target.iter().map(String::as_str))?;
+        let pick = |v: &[String], k: usize| {
+            let seq = v[if v.len() == 1 { 0 } else { k }].trim().to_uppercase();
+            residues.prepare_target(&seq).map(|s| s.into_owned())
+        };
+        let alignments: Vec<Option<alignment::Alignment>> = (0..n)
+            .into_par_iter()
+            .map(|k| match (pick(&query, k), pick(&target, k)) {
+                (Some(q), Some(t)) => Some(alignment::align_with(&q, &t, &residues)),
+                _ => None,
+            })
+            .collect();
+        let strings = |f: &dyn Fn(&alignment::Alignment) -> String| -> Vec<Option<String>> {
+            alignments.iter().map(|a| a.as_ref().map(f)).collect()
+        };
+        let counts = |f: &dyn Fn(&alignment::Alignment) -> usize| -> Vec<Option<i32>> {
+            alignments.iter().map(|a| a.as_ref().map(|a| f(a) as i32)).collect()
+        };
+        Ok(list!(
+            query = strings(&|a| a.query.clone()),
+            target = strings(&|a| a.target.clone()),
+            operations = strings(&|a| a.operation_codes()),
+            substitutions = counts(&|a| a.substitutions),
+            insertions = counts(&|a| a.insertions),
+            deletions = counts(&|a| a.deletions),
+            edit_distance = counts(&|a| a.edit_distance)
+        ))
+    })
+}
+
+/// Levenshtein distances between CDR3s elementwise (a length-one side is
+/// recycled), computed in parallel; NA where either CDR3 is NA or skipped.
+/// `ambiguous_residues` and `gap_chars` as in `align_cdr3_pairs`.
+/// @export
+#[extendr]
+pub fn edit_distance_many(
+    a: Strings,
+    b: Strings,
+    #[default = "\"penalize\""] ambiguous_residues: &str,
+    #[default = "\".-\""] gap_chars: &str,
+) -> RResult<Vec<Option<i32>>> {
+    use rayon::prelude::*;
+
+    guard("edit_distance_many", || {
+        let n = paired_len(a.len(), b.len())?;
+        let residues = parse_residues(ambiguous_residues, gap_chars)?;
+        let as_option = |s: &Strings| -> Vec<Option<String>> {
+            s.iter().map(|x| (!x.is_na()).then(|| x.as_str().trim().to_uppercase())).collect()
+        };
+        let (a, b) = (as_option(&a), as_option(&b));
+        check_residues(&residues, "a", a.iter().flatten().map(String::as_str))?;
+        check_residues(&residues, "b", b.iter().flatten().map(String::as_str))?;
+        let prepare = |v: &[Option<String>]| -> Vec<Option<String>> {
+            v.iter().map(|x| x.as_deref().and_then(|x| residues.prepare_target(x)).map(|x| x.into_owned())).collect()
+        };
+        let (a, b) = (prepare(&a), prepare(&b));
+        let at = |len: usize, k: usize| if len == 1 { 0 } else { k };
+        Ok((0..n)
+            .into_par_iter()
+            .map(|k| match (a[at(a.len(), k)].as_deref(), b[at(b.len(), k)].as_deref()) {
+                (Some(x), Some(y)) => Some(alignment::edit_distance_with(x, y, &residues) as i32),
+                _ => None,
+            })
+            .collect())
+    })
+}
+
+/// All-vs-all CDR3 distances ("edit", "hamming" or "blosum"), computed in parallel.
+/// Without `sparse_radius`, returns the n x n matrix as a flat vector (symmetric,
+/// so row- and column-major agree) with NA for undefined Hamming distances.
+/// With `sparse_radius`, returns only the pairs i < j within that distance as
+/// (i, j, distance) triplets with 1-based indices.
+#[extendr]
+pub fn cdr3_distances(cdr3: Vec<String>, metric: &str, sparse_radius: Option<f64>) -> RResult<List> {
+    guard("cdr3_distances", || {
+        let metric = distance::Cdr3Metric::parse(metric).map_err(extendr_api::error::Error::Other)?;
+        let n = cdr3.len() as i32;
+        match sparse_radius {
+            Some(radius) => {
+                let pairs = distance::cdr3_pairs_within(&cdr3, metric, radius);
+                Ok(list!(
+                    i = pairs.iter().map(|p| p.0 as i32 + 1).collect::<Vec<_>>(),
+                    j = pairs.iter().map(|p| p.1 as i32 + 1).collect::<Vec<_>>(),
+                    distance = pairs.iter().map(|p| p.2).collect::<Vec<_>>(),
+                    n = n
+                ))
+            }
+            None => Ok(list!(distance = distance::cdr3_distance_matrix(&cdr3, metric), n = n)),
+        }
+    })
+}
+
+/// Average, complete or single linkage clustering of `n` observations from
+/// condensed distances (R `dist` order) or a full n x n distance vector.
+/// Returns the merge pairs, heights and 1-based leaf order of an R `hclust`.
+#[extendr]
+pub fn hierarchical_clustering(distance: Vec<f64>, n: i32, linkage: &str) -> RResult<List> {
+    guard("hierarchical_clustering", || {
+        let linkage = clustering::Linkage::parse(linkage).map_err(extendr_api::error::Error::Other)?;
+        let n = n.max(0) as usize;
+        let condensed = clustering::condensed_distances(&distance, n).map_err(extendr_api::error::Error::Other)?;
+        let tree = clustering::hierarchical(&condensed, n, linkage);
+        Ok(list!(
+            merge_a = tree.merge.iter().map(|m| m.0 as i32).collect::<Vec<_>>(),
+            merge_b = tree.merge.iter().map(|m| m.1 as i32).collect::<Vec<_>>(),
+            height = tree.height,
+            order = tree.order.iter().map(|&o| o as i32 + 1).collect::<Vec<_>>()
+        ))
+    })
+}
+
+/// Clone-size-weighted mean distance between and within clusters from
+/// condensed or full n x n distances (NA pairs are skipped); see `calculate_tcrdist`
+#[extendr]
+pub fn tcrdist_cluster_distances(
+    distance: Vec<f64>,
+    n: i32,
+    clusters: Vec<String>,
+    #[default = "NULL"] clone_sizes: Nullable<Vec<f64>>,
+) -> RResult<List> {
+    guard("tcrdist_cluster_distances", || {
+        let n = n.max(0) as usize;
+        let clone_sizes = clone_size_weights(clone_sizes, n)?;
+        cluster_distance_columns(&distance, n, &clusters, clone_sizes.as_deref())
+    })
+}
+
+/// Louvain communities of a neighbor graph given as 1-based (i, j) pairs with
+/// positive similarity `weight`, over `n` nodes. With `connected`, communities
+/// are split into connected parts. Without a `seed` the node order is drawn from
+/// the `set_vdjmatch_seed()` stream. Returns 1-based memberships and modularity.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn community_detection(
+    i: Vec<i32>,
+    j: Vec<i32>,
+    weight: Vec<f64>,
+    n: i32,
+    resolution: f64,
+    connected: bool,
+    seed: Option<i32>,
+) -> RResult<List> {
+    guard("community_detection", || {
+        if j.len() != i.len() || weight.len() != i.len() {
+            return Err(extendr_api::error::Error::Other("i, j and weight must have equal length".into()));
+        }
+        let n = n.max(0) as usize;
+        let mut edges = Vec::with_capacity(i.len());
+        for ((&a, &b), &w) in i.iter().zip(&j).zip(&weight) {
+            if a < 1 || b < 1 || a as usize > n || b as usize > n {
+                return Err(extendr_api::error::Error::Other(format!("edge ({}, {}) outside 1..{}", a, b, n)));
+            }
+            if !(w.is_finite() && w > 0.0) {
+                return Err(extendr_api::error::Error::Other(format!("edge weights must be positive, got {}", w)));
+            }
+            edges.push((a as usize - 1, b as usize - 1, w));
+        }
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let result = clustering::louvain(n, &edges, resolution, connected, &mut rng);
+        Ok(list!(
+            membership = result.membership.iter().map(|&c| c as i32 + 1).collect::<Vec<_>>(),
+            n_communities = result.n_communities as i32,
+            modularity = result.modularity
+        ))
+    })
+}
+
+/// Embedding coordinates as a list of `dims` columns plus eigenvalues and
+/// 1-based landmark indices
+fn embedding_columns(embedding: &embedding::Embedding) -> List {
+    let n = embedding.coordinates.len() / embedding.dims.max(1);
+    let columns: Vec<Vec<f64>> = (0..embedding.dims)
+        .map(|d| (0..n).map(|i| embedding.coordinates[i * embedding.dims + d]).collect())
+        .collect();
+    list!(
+        coordinates = List::from_values(columns),
+        eigenvalues = embedding.eigenvalues.clone(),
+        landmarks = embedding.landmarks.iter().map(|&l| l as i32 + 1).collect::<Vec<_>>()
+    )
+}
+
+/// Classical MDS of precomputed distances (condensed in R `dist` order, or a
+/// full n x n vector) into `dims` dimensions.
+#[extendr]
+pub fn mds_from_distances(distance: Vec<f64>, n: i32, dims: i32, seed: Option<i32>) -> RResult<List> {
+    guard("mds_from_distances", || {
+        let n = n.max(0) as usize;
+        let condensed = clustering::condensed_distances(&distance, n).map_err(extendr_api::error::Error::Other)?;
+        let mut full = vec![0.0; n * n];
+        let mut k = 0;
+        for i in 0..n {
+            for j in i + 1..n {
+                full[i * n + j] = condensed[k];
+                full[j * n + i] = condensed[k];
+                k += 1;
+            }
+        }
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        Ok(embedding_columns(&embedding::classical_mds(&full, n, dims.max(1) as usize, &mut rng)))
+    })
+}
+
+/// Landmark MDS of TCRs by tcrdist (unpaired CDRs skipped) into `dims`
+/// dimensions; only distances to `n_landmarks` landmarks are computed.
+/// Pass empty strings for missing CDR sequences.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn tcrdist_landmark_mds(
+    cdr1_a: Vec<String>,
+    cdr2_a: Vec<String>,
+    cdr3_a: Vec<String>,
+    cdr1_b: Vec<String>,
+    cdr2_b: Vec<String>,
+    cdr3_b: Vec<String>,
+    dims: i32,
+    n_landmarks: i32,
+    seed: Option<i32>,
+) -> RResult<List> {
+    guard("tcrdist_landmark_mds", || {
+        let tcrs = build_tcrs(&cdr1_a, &cdr2_a, &cdr3_a, &cdr1_b, &cdr2_b, &cdr3_b)?;
+        let encoded: Vec<tcrdist::EncodedTCR> = tcrs.iter().map(tcrdist::EncodedTCR::new).collect();
+        let distance = |i: usize, j: usize| {
+            tcrdist::encoded_tcrdist(&encoded[i], &encoded[j], tcrdist::MissingPolicy::Skip).unwrap_or(0.0)
+        };
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let embedding = embedding::landmark_mds(
+            encoded.len(), distance, n_landmarks.max(1) as usize, dims.max(1) as usize, &mut rng,
+        );
+        Ok(embedding_columns(&embedding))
+    })
+}
+
+/// Background distribution of tcrdist over `n_background_pairs` random pairs
+/// of TCRs (all pairs when there are fewer) and the radius at `percentile`
+/// (0-100) of it. Pass empty strings for missing CDR sequences; unpaired CDRs
+/// follow `missing_policy` and chains `chain_weights` as in `calculate_tcrdist`.
+/// Without a `seed` the pairs are drawn from the `set_vdjmatch_seed()` stream.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn tcrdist_background(
+    cdr1_a: Vec<String>,
+    cdr2_a: Vec<String>,
+    cdr3_a: Vec<String>,
+    cdr1_b: Vec<String>,
+    cdr2_b: Vec<String>,
+    cdr3_b: Vec<String>,
+    n_background_pairs: i32,
+    percentile: f64,
+    n_bins: i32,
+    missing_policy: &str,
+    missing_penalty: i32,
+    chain_weights: Robj,
+    seed: Option<i32>,
+) -> RResult<List> {
+    guard("tcrdist_background", || {
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(extendr_api::error::Error::Other(format!(
+                "percentile must be between 0 and 100, got {}",
+                percentile
+            )));
+        }
+        let policy = tcrdist::MissingPolicy::parse(missing_policy, missing_penalty)
+            .map_err(extendr_api::error::Error::Other)?;
+        let weights = parse_chain_weights(&chain_weights)?;
+        let tcrs = build_tcrs(&cdr1_a, &cdr2_a, &cdr3_a, &cdr1_b, &cdr2_b, &cdr3_b)?;
+        let encoded: Vec<tcrdist::EncodedTCR> = tcrs.iter().map(tcrdist::EncodedTCR::new).collect();
+        let mut rng = random::rng_for(seed.map(|s| s as u64));
+        let (distances, exhaustive) = tcrdist::background_distances(
+            &encoded, n_background_pairs.max(0) as usize, policy, weights, &mut rng,
+        );
+        let (breaks, counts) = tcrdist::histogram(&distances, n_bins.max(1) as usize);
+        Ok(list!(
+            threshold = tcrdist::sorted_quantile(&distances, percentile / 100.0),
+            percentile = percentile,
+            n_pairs = distances.len() as i32,
+            exhaustive = exhaustive,
+            breaks = breaks,
+            counts = counts.iter().map(|&c| c as i32).collect::<Vec<_>>(),
+            distances = distances
+        ))
+    })
+}
+
+// Register exported functions/types with R.
+extendr_module! {
+    mod vdjmatchR;
+    impl RDatabase;
+    impl RMatchResult;
+    fn match_tcr;
+    fn match_tcr_many;
+    fn vdjdb_open_file;
+    fn vdjdb_len;
+    fn filter_db;
+    fn filter_db_by_epitope_size;
+    fn reapply_filters;
+    fn vdjdb_ensure;
+    fn vdjdb_update;
+    fn vdjdb_ensure_into;
+    fn vdjdb_update_into;
+    fn calculate_tcrdist;
+    fn tcrdist_single;
+    fn match_tcr_tcrdist;
+    fn predict_specificity_knn;
+    fn cluster_consensus;
+    fn write_tcrdist_neighbors;
+    fn match_tcr_many_lazy;
+    fn db_load;
+    fn match_result_load;
+    fn db_epitope_folds;
+    fn compare_hits_to_reference;
+    fn cdr3_neighbors;
+    fn alice_neighbors;
+    fn convergence_counts;
+    fn hit_sharing_table;
+    fn has_match;
+    fn infer_query_chains;
+    fn detect_query_species;
+    fn immunarch_reshape;
+    fn screpertoire_combine;
+    fn annotate_cells_table;
+    fn compare_dextramer_calls;
+    fn epitope_logo_table;
+    fn discriminative_motif_table;
+    fn sample_background_indices;
+    fn set_vdjmatch_seed;
+    fn align_cdr3_pairs;
+    fn edit_distance_many;
+    fn cdr3_distances;
+    fn hierarchical_clustering;
+    fn community_detection;
+    fn mds_from_distances;
+    fn tcrdist_landmark_mds;
+    fn tcrdist_cluster_distances;
+    fn tcrdist_background;
+    fn antigen_groups;
+    fn antigen_ontology_rules;
+    fn epitope_diversity_table;
+    fn specificity_calibration_table;
+    fn scope_tiers;
+    fn specific_clonotypes;
+    fn human_v_paralogs;
+    fn v_paralog_table;
+    fn query_table;
+    fn query_duplicates;
+    fn db_diff_table;
+    fn db_has_records;
+    fn db_open_cache;
+    fn tcrdist_as_precision;
+    fn float32_to_double;
+    fn register_v_gene;
+    fn v_gene_cdrs;
+    fn registered_v_genes;
+    fn register_gene_alias;
+    fn imgt_gene_names;
+    fn register_species_synonym;
+    fn canonical_species;
+    fn epitope_probability_table;
+    fn segment_names;
+    fn annotation_comparison_table;
+    fn downsampled_annotation_table;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_converts_panic_to_error() {
+        let res = guard("test_fn", || -> Result<i32> { panic!("boom at row {}", 3) }).0;
+        let msg = res.unwrap_err().to_string();
+        assert!(msg.contains("test_fn"));
+        assert!(msg.contains("boom at row 3"));
+
+        let res = guard("test_fn", || Ok(1)).0;
+        assert_eq!(res.unwrap(), 1);
+
+        // Failures keep their condition code for the R layer
+        let missing = error::VdjMatchError::DatabaseNotFound { path: "x.txt".into(), reason: "no such file".into() };
+        let res: Result<()> = guard("test_fn", || Err(r_error(missing))).0;
+        assert!(res.unwrap_err().to_string().contains("\u{1e}database_not_found\u{1e}path=x.txt\u{1e}"));
+
+        let res: Result<()> = guard("test_fn", || panic!("boom")).0;
+        assert!(res.unwrap_err().to_string().contains("\u{1e}internal\u{1e}"));
+    }
+
+    #[test]
+    fn test_closed_handles() {
+        let mut db = RDatabase::from(database::Database { entries: Vec::new(), metadata: Default::default() });
+        let copy = db.clone_handle().0.unwrap();
+        db.close();
+        assert!(db.is_closed());
+        let msg = db.len().0.unwrap_err().to_string();
+        assert!(msg.contains("\u{1e}handle_closed\u{1e}") && msg.ends_with("RDatabase handle is closed and can no longer be used"));
+        assert!(db.clone_handle().0.is_err());
+        // Other handles on the same database stay usable
+        assert_eq!(copy.len().0.unwrap(), 0);
+
+        let mut res = RMatchResult { inner: Some(matching::BatchMatches::new(Vec::new(), Vec::new(), Vec::new())), source: None };
+        assert_eq!(res.nrow().0.unwrap(), 0);
+        res.release();
+        assert!(res.is_released());
+        assert!(res.nrow().0.unwrap_err().to_string().contains("RMatchResult handle is closed"));
+    }
 }
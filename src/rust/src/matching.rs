@@ -1,9 +1,20 @@
-use crate::alignment::{align, matches_within_scope};
-use crate::database::{Database, DatabaseEntry};
-use crate::scoring::{compute_normalized_score, segment_match_score, simple_mismatch_score};
-use crate::sequence::{Clonotype, SearchScope};
+use crate::alignment::{align_with, matches_within_scope_prepared, EditScratch, KmerFilter};
+use crate::database::{normalize_mhc_class, Database, DatabaseEntry};
+use crate::diversity::AdaptiveScope;
+use crate::random::Rng;
+use crate::scoring::{
+    allele_segment_score, compute_alignment_score_with, v_segment_score, compute_normalized_score_with, specificity_confidence,
+    ScoreBackground, ScoreNormalization, ScoreNull, SimpleScore, SpecificityEvidence,
+};
+use crate::sequence::{
+    d_calls_match, infer_chain, segment_calls, AlleleMode, AmbiguousResiduePolicy, ChainScopes, Cdr3Tokens, Clonotype,
+    MissingSegmentPolicy, ResidueHandling, SearchScope, VMatchLevel,
+};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeInclusive;
 
 /// A match between a query clonotype and a database entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +27,42 @@ pub struct ClonotypeMatch {
     pub v_score: f64,
     pub j_score: f64,
     pub edit_distance: usize,
+    /// Probability that the hit's epitope is the query's, from `scoring::specificity_confidence`
+    pub specificity: f64,
+    /// Query V/J call that matched the entry (one of several for ambiguous calls)
+    #[serde(default)]
+    pub matched_v: String,
+    #[serde(default)]
+    pub matched_j: String,
+    /// Substitution score normalized per `MatchConfig::score_normalization`
+    #[serde(default)]
+    pub normalized_score: f64,
+    /// Chance of an equal-or-better CDR3 score against a random database CDR3
+    /// (`assign_p_values`; None when not requested)
+    #[serde(default)]
+    pub p_value: Option<f64>,
+    /// Benjamini-Hochberg adjusted `p_value`
+    #[serde(default)]
+    pub q_value: Option<f64>,
+    /// Database records of the hit's epitope (`assign_epitope_stats`; None when not assigned)
+    #[serde(default)]
+    pub epitope_records: Option<usize>,
+    /// Distinct CDR3s among the database records of the hit's epitope
+    #[serde(default)]
+    pub epitope_unique_cdr3: Option<usize>,
+}
+
+impl ClonotypeMatch {
+    /// CDR3 lengths (residues) of the query and the database entry
+    pub fn cdr3_lengths(&self) -> (usize, usize) {
+        (self.query_clonotype.cdr3_aa.sequence.chars().count(), self.db_entry.cdr3.chars().count())
+    }
+
+    /// Database CDR3 length minus query CDR3 length
+    pub fn cdr3_length_diff(&self) -> i64 {
+        let (query, db) = self.cdr3_lengths();
+        db as i64 - query as i64
+    }
 }
 
 /// Configuration for matching
@@ -24,13 +71,43 @@ pub struct MatchConfig {
     pub search_scope: SearchScope,
     pub match_v: bool,
     pub match_j: bool,
+    /// Require the D gene to agree when both the query and the database row
+    /// have one (TRB/TRD); rows or queries without a D call are not filtered
+    pub match_d: bool,
+    /// How V/J allele suffixes affect the segment filter and segment scores
+    pub allele_mode: AlleleMode,
+    /// How closely V segments must agree when `match_v` is set; also sets the V segment score
+    pub v_match_level: VMatchLevel,
+    /// Whether database rows without the V/J segment being matched are skipped
+    /// or kept with a `PARTIAL_SEGMENT_SCORE` segment score
+    pub missing_segments: MissingSegmentPolicy,
+    /// Only match database rows whose `gene` agrees with the chain implied by
+    /// the query's V/J names (TRAV... queries never hit TRB rows)
+    pub restrict_chain: bool,
+    /// Only match database rows restricted by this MHC class ("MHCI"/"MHCII")
+    pub mhc_class: Option<String>,
     pub use_vdjmatch_scoring: bool,
     pub scoring_mode: u8,
+    /// CDR3 score of hits not scored with the vdjmatch scheme
+    pub simple_score: SimpleScore,
+    /// 0 = stop at the first within-scope hit per query, 1 = all hits,
+    /// 2 = all hits rescored with the full vdjmatch scheme
     pub exhaustive_search: u8,
     pub score_threshold: Option<f64>,
     pub max_hits_only: bool,
     pub top_n_hits: Option<usize>,
     pub weight_by_informativeness: bool,
+    pub kmer_filter: Option<KmerFilter>,
+    /// Normalization of the `normalized_score` column
+    pub score_normalization: ScoreNormalization,
+    /// Gap characters and ambiguous residues in query and database CDR3s
+    pub residues: ResidueHandling,
+    /// Per-entry scope replacing `search_scope`, which then holds the widest
+    /// scope it allows
+    pub adaptive_scope: Option<AdaptiveScope>,
+    /// Per-chain scopes replacing `search_scope` (which then holds the widest)
+    /// for queries whose V/J names imply a chain
+    pub chain_scopes: Option<ChainScopes>,
 }
 
 impl Default for MatchConfig {
@@ -39,13 +116,25 @@ impl Default for MatchConfig {
             search_scope: SearchScope::EXACT,
             match_v: false,
             match_j: false,
+            match_d: false,
+            allele_mode: AlleleMode::Ignore,
+            v_match_level: VMatchLevel::Gene,
+            missing_segments: MissingSegmentPolicy::Exclude,
+            restrict_chain: false,
+            mhc_class: None,
             use_vdjmatch_scoring: false,
             scoring_mode: 1,
+            simple_score: SimpleScore::MaxLength,
             exhaustive_search: 1,
             score_threshold: None,
             max_hits_only: false,
             top_n_hits: None,
             weight_by_informativeness: false,
+            kmer_filter: None,
+            score_normalization: ScoreNormalization::None,
+            residues: ResidueHandling::default(),
+            adaptive_scope: None,
+            chain_scopes: None,
         }
     }
 }
@@ -55,59 +144,175 @@ pub fn match_clonotype(
     clonotype: &Clonotype,
     database: &Database,
     config: &MatchConfig,
+) -> Vec<ClonotypeMatch> {
+    let matches = match_entries(clonotype, &database.entries, config);
+    let background = score_background(database, config);
+    finalize_matches(matches, database, &epitope_sizes(database), background.as_ref(), config)
+}
+
+/// A query V, D or J segment split into its calls once per query
+struct QuerySegment<'a> {
+    calls: Vec<&'a str>,
+    /// Whether the segment is non-blank
+    given: bool,
+    /// Whether database rows are filtered on it
+    checked: bool,
+}
+
+impl<'a> QuerySegment<'a> {
+    fn new(segment: &'a str, checked: bool) -> Self {
+        let given = !segment.trim().is_empty();
+        Self { calls: segment_calls(segment), given, checked: checked && given }
+    }
+}
+
+/// What `match_entries` derives from the config and one query before
+/// scanning entries, so the per-entry loop only compares
+struct QueryPlan<'a> {
+    /// CDR3 as `ResidueHandling::prepare_target` leaves it
+    cdr3: Cow<'a, str>,
+    v: QuerySegment<'a>,
+    j: QuerySegment<'a>,
+    d: Option<QuerySegment<'a>>,
+    /// `config.mhc_class` as `normalize_mhc_class` gives it
+    mhc_class: Option<&'static str>,
+    chain: Option<&'static str>,
+    scope: SearchScope,
+    /// CDR3 lengths within the widest scope any entry can get
+    lengths: RangeInclusive<usize>,
+    kmer_filter: Option<KmerFilter>,
+    use_vdjmatch_scoring: bool,
+    scoring_mode: u8,
+    /// `config.score_threshold`, or -inf without one
+    min_score: f64,
+}
+
+impl<'a> QueryPlan<'a> {
+    /// None when the query cannot hit anything: its CDR3 is skipped (or,
+    /// past input validation, rejected) or the MHC class filter is unknown
+    fn new(clonotype: &'a Clonotype, config: &MatchConfig) -> Option<Self> {
+        let cdr3 = config.residues.prepare_target(&clonotype.cdr3_aa.sequence)?;
+        let mhc_class = match &config.mhc_class {
+            Some(class) => Some(normalize_mhc_class(class)?),
+            None => None,
+        };
+
+        let query_chain = infer_chain(&clonotype.v_segment, &clonotype.j_segment).0;
+        let scope = match &config.chain_scopes {
+            Some(scopes) => scopes.scope_for(query_chain),
+            None => config.search_scope,
+        };
+        let reach = config.adaptive_scope.as_ref().map_or(scope, |a| a.params.widest()).total;
+        let lengths = cdr3.len().saturating_sub(reach)..=cdr3.len().saturating_add(reach);
+
+        // Seed k-mers are only useful when the scope allows mismatches, and miss
+        // hits that differ only at wildcard positions
+        let kmer_filter = config.kmer_filter.filter(|_| {
+            !config.search_scope.is_exact() && config.residues.ambiguous != AmbiguousResiduePolicy::Neutral
+        });
+
+        // Full rescoring always uses the substitution-aware vdjmatch scheme
+        let (use_vdjmatch_scoring, scoring_mode) = if config.exhaustive_search >= 2 {
+            (true, 1)
+        } else {
+            (config.use_vdjmatch_scoring, config.scoring_mode)
+        };
+
+        Some(Self {
+            cdr3,
+            v: QuerySegment::new(&clonotype.v_segment, config.match_v && config.v_match_level != VMatchLevel::None),
+            j: QuerySegment::new(&clonotype.j_segment, config.match_j),
+            d: clonotype.d_segment.as_deref().filter(|_| config.match_d).map(|d| QuerySegment::new(d, true)),
+            mhc_class,
+            chain: query_chain.filter(|_| config.restrict_chain),
+            scope,
+            lengths,
+            kmer_filter,
+            use_vdjmatch_scoring,
+            scoring_mode,
+            min_score: config.score_threshold.unwrap_or(f64::NEG_INFINITY),
+        })
+    }
+}
+
+/// Collect raw hits of a clonotype against a slice of database entries
+/// Per-query hit filtering (max hits, top N, weights) is left to `finalize_matches`
+fn match_entries(
+    clonotype: &Clonotype,
+    entries: &[DatabaseEntry],
+    config: &MatchConfig,
 ) -> Vec<ClonotypeMatch> {
     let mut matches = Vec::new();
+    let Some(plan) = QueryPlan::new(clonotype, config) else {
+        return matches;
+    };
+    let query_kmers = plan
+        .kmer_filter
+        .map(|f| f.central_kmers(plan.cdr3.as_bytes()))
+        .unwrap_or_default();
+    let mut scratch = EditScratch::default();
     
-    for db_entry in &database.entries {
-        // Check segment matches if required and if query has non-empty segments
-        // Skip segment matching if query segment is empty (user wants CDR3-only matching)
-        if config.match_v && !clonotype.v_segment.is_empty() {
-            let v_match = Clonotype::normalize_segment(&clonotype.v_segment)
-                == Clonotype::normalize_segment(&db_entry.v_segment);
-            if !v_match {
+    for db_entry in entries {
+        // Gap removal only shortens CDR3s, so only gap-free ones are ruled out by length
+        let tokens = &db_entry.cdr3_tokens;
+        if !tokens.has_symbols() && !plan.lengths.contains(&tokens.residues().len()) {
+            continue;
+        }
+
+        if !segments_match(&plan, db_entry, config) {
+            continue;
+        }
+
+        if let Some(class) = plan.mhc_class {
+            if !db_entry.has_mhc_class(class) {
                 continue;
             }
         }
 
-        if config.match_j && !clonotype.j_segment.is_empty() {
-            let j_match = Clonotype::normalize_segment(&clonotype.j_segment)
-                == Clonotype::normalize_segment(&db_entry.j_segment);
-            if !j_match {
+        if let Some(chain) = plan.chain {
+            if !db_entry.gene.is_empty() && !db_entry.gene.eq_ignore_ascii_case(chain) {
                 continue;
             }
         }
         
         // Check CDR3 sequence match within scope
-        let query_cdr3_str = &clonotype.cdr3_aa.sequence;
-        let db_cdr3_str = &db_entry.cdr3;
+        let Some(db_cdr3_str) = config.residues.prepare_tokens(tokens) else {
+            continue;
+        };
+
+        if let Some(filter) = &plan.kmer_filter {
+            if !filter.passes(&query_kmers, db_cdr3_str.as_bytes()) {
+                continue;
+            }
+        }
         
-        if !matches_within_scope(
-            &clonotype.cdr3_aa,
-            &crate::sequence::Cdr3Sequence::new(db_cdr3_str.clone()),
-            &config.search_scope,
-        ) {
+        let scope = match &config.adaptive_scope {
+            Some(adaptive) => adaptive.scope_for(plan.cdr3.len(), db_entry),
+            None => plan.scope,
+        };
+        if !matches_within_scope_prepared(&plan.cdr3, &db_cdr3_str, &scope, &config.residues, &mut scratch) {
             continue;
         }
         
         // Perform alignment
-        let alignment = align(query_cdr3_str, db_cdr3_str);
+        let alignment = align_with(&plan.cdr3, &db_cdr3_str, &config.residues);
         
         // Compute scores
-        let cdr3_score = if config.use_vdjmatch_scoring {
-            if config.scoring_mode == 1 {
-                compute_normalized_score(&alignment)
+        let cdr3_score = if plan.use_vdjmatch_scoring {
+            if plan.scoring_mode == 1 {
+                compute_normalized_score_with(&alignment, &config.residues)
             } else {
-                simple_mismatch_score(&alignment)
+                config.simple_score.score(&alignment)
             }
         } else {
-            simple_mismatch_score(&alignment)
+            config.simple_score.score(&alignment)
         };
         
-        let v_score = segment_match_score(&clonotype.v_segment, &db_entry.v_segment, true);
-        let j_score = segment_match_score(&clonotype.j_segment, &db_entry.j_segment, true);
+        let (v_score, matched_v) = best_segment_call(&plan.v, &db_entry.v_segment, config, true);
+        let (j_score, matched_j) = best_segment_call(&plan.j, &db_entry.j_segment, config, false);
         
         // Aggregate score
-        let total_score = if config.use_vdjmatch_scoring {
+        let total_score = if plan.use_vdjmatch_scoring {
             // VDJMATCH scoring: weighted combination
             0.5 * cdr3_score + 0.25 * v_score + 0.25 * j_score
         } else {
@@ -115,10 +320,8 @@ pub fn match_clonotype(
         };
         
         // Apply score threshold
-        if let Some(threshold) = config.score_threshold {
-            if total_score < threshold {
-                continue;
-            }
+        if total_score < plan.min_score {
+            continue;
         }
         
         let matched = ClonotypeMatch {
@@ -130,11 +333,189 @@ pub fn match_clonotype(
             v_score,
             j_score,
             edit_distance: alignment.edit_distance,
+            specificity: 0.0, // Computed per query in `finalize_matches`
+            matched_v: matched_v.to_string(),
+            matched_j: matched_j.to_string(),
+            // Raw until normalized per query in `finalize_matches`
+            normalized_score: compute_alignment_score_with(&alignment, &config.residues),
+            p_value: None,
+            q_value: None,
+            epitope_records: None,
+            epitope_unique_cdr3: None,
         };
         
         matches.push(matched);
+        if config.exhaustive_search == 0 {
+            break;
+        }
     }
-    
+
+    matches
+}
+
+/// Segment score of a database row lacking the segment under `MissingSegmentPolicy::AllowAsPartial`
+pub const PARTIAL_SEGMENT_SCORE: f64 = 0.5;
+
+/// V/J filter of `match_entries`
+/// Empty or blank query segments are not checked (the user wants CDR3-only matching);
+/// ambiguous calls ("TRBV6-2,TRBV6-3") match if any listed gene does. Empty
+/// database segments pass only under `MissingSegmentPolicy::AllowAsPartial`.
+fn segments_match(plan: &QueryPlan, db_entry: &DatabaseEntry, config: &MatchConfig) -> bool {
+    let passes = |query: &QuerySegment, db: &str, v: bool| {
+        (db.is_empty() && config.missing_segments == MissingSegmentPolicy::AllowAsPartial)
+            || query.calls.iter().any(|call| {
+                if v {
+                    config.v_match_level.segments_match(call, db, config.allele_mode)
+                } else {
+                    config.allele_mode.segments_match(call, db)
+                }
+            })
+    };
+
+    if plan.v.checked && !passes(&plan.v, &db_entry.v_segment, true) {
+        return false;
+    }
+
+    if plan.j.checked && !passes(&plan.j, &db_entry.j_segment, false) {
+        return false;
+    }
+
+    if let (Some(query_d), Some(db_d)) = (&plan.d, &db_entry.d_segment) {
+        if !d_calls_match(&query_d.calls, db_d) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Best-scoring call of a possibly ambiguous query segment against a database segment
+/// Returns the score and the call ("" when none matches, or the database has no segment);
+/// V segments (`v`) are scored under `config.v_match_level`
+fn best_segment_call<'a>(query: &QuerySegment<'a>, db: &str, config: &MatchConfig, v: bool) -> (f64, &'a str) {
+    if db.is_empty() && query.given && config.missing_segments == MissingSegmentPolicy::AllowAsPartial {
+        return (PARTIAL_SEGMENT_SCORE, "");
+    }
+    query
+        .calls
+        .iter()
+        .map(|&call| {
+            let score = if v {
+                v_segment_score(call, db, config.allele_mode, config.v_match_level)
+            } else {
+                allele_segment_score(call, db, config.allele_mode)
+            };
+            (score, call)
+        })
+        .filter(|(score, _)| *score > 0.0)
+        .fold((0.0, ""), |best, cur| if cur.0 > best.0 { cur } else { best })
+}
+
+/// CDR3s sampled per length for z-score normalization and the p-value null
+pub const ZSCORE_BACKGROUND_SIZE: usize = 200;
+
+/// Fixed so z-scores are reproducible across runs
+const ZSCORE_BACKGROUND_SEED: u64 = 0x5EED;
+
+/// Same-length database background for `ScoreNormalization::ZScore`
+fn score_background(database: &Database, config: &MatchConfig) -> Option<ScoreBackground> {
+    (config.score_normalization == ScoreNormalization::ZScore).then(|| database_background(database))
+}
+
+/// Database CDR3s sampled per length, shared by z-scores and `score_null`
+fn database_background(database: &Database) -> ScoreBackground {
+    ScoreBackground::sample(
+        database.entries.iter().map(|e| e.cdr3.as_str()),
+        ZSCORE_BACKGROUND_SIZE,
+        &mut Rng::new(ZSCORE_BACKGROUND_SEED),
+    )
+}
+
+/// Length-conditional null of CDR3 scores for `assign_p_values`
+/// Scores every pair of sampled same-length CDR3s, so build it once per database.
+pub fn score_null(database: &Database) -> ScoreNull {
+    ScoreNull::from_background(&database_background(database))
+}
+
+/// Fill `p_value` of every hit from the null and `q_value` by Benjamini-Hochberg
+/// over all hits of all queries, scoring CDR3s as matching did per `residues`
+pub fn assign_p_values(matches: &mut [Vec<ClonotypeMatch>], null: &ScoreNull, residues: &ResidueHandling) {
+    let mut p_values = Vec::new();
+    for m in matches.iter_mut().flatten() {
+        let prepare = |tokens: &Cdr3Tokens| residues.prepare_tokens(tokens).map(|s| s.into_owned()).unwrap_or_default();
+        let query = prepare(&m.query_clonotype.cdr3_aa.tokens());
+        let score = compute_alignment_score_with(&align_with(&query, &prepare(&m.db_entry.cdr3_tokens), residues), residues);
+        let p = null.p_value(query.len(), score);
+        m.p_value = Some(p);
+        p_values.push(p);
+    }
+    let q_values = crate::expansion::benjamini_hochberg(&p_values);
+    for (m, q) in matches.iter_mut().flatten().zip(q_values) {
+        m.q_value = Some(q);
+    }
+}
+
+/// How well a database characterizes one epitope
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EpitopeStats {
+    /// Database records of the epitope
+    pub n_records: usize,
+    /// Distinct CDR3s among those records
+    pub n_unique_cdr3: usize,
+}
+
+/// Record and distinct-CDR3 counts per epitope
+/// Counts the whole database, so build it once per database.
+pub fn epitope_stats(database: &Database) -> HashMap<String, EpitopeStats> {
+    let mut cdr3s: HashMap<&str, HashSet<&str>> = HashMap::new();
+    let mut stats: HashMap<String, EpitopeStats> = HashMap::new();
+    for entry in &database.entries {
+        let epitope = entry.antigen_epitope.as_str();
+        stats.entry(epitope.to_string()).or_default().n_records += 1;
+        cdr3s.entry(epitope).or_default().insert(entry.cdr3.as_str());
+    }
+    for (epitope, distinct) in cdr3s {
+        if let Some(s) = stats.get_mut(epitope) {
+            s.n_unique_cdr3 = distinct.len();
+        }
+    }
+    stats
+}
+
+/// Fill `epitope_records` and `epitope_unique_cdr3` of every hit from `stats`
+pub fn assign_epitope_stats(matches: &mut [Vec<ClonotypeMatch>], stats: &HashMap<String, EpitopeStats>) {
+    for m in matches.iter_mut().flatten() {
+        let s = stats.get(&m.db_entry.antigen_epitope).copied().unwrap_or_default();
+        m.epitope_records = Some(s.n_records);
+        m.epitope_unique_cdr3 = Some(s.n_unique_cdr3);
+    }
+}
+
+/// Index of the tightest of `scopes` that a hit `edit_distance` edits away
+/// falls in, as matching decides it: exact scopes hold identical CDR3s only and
+/// other scopes hits within their total edits. Ties go to the first listed.
+pub fn scope_tier(edit_distance: usize, scopes: &[SearchScope]) -> Option<usize> {
+    scopes
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| edit_distance <= s.total)
+        .min_by_key(|(_, s)| s.total)
+        .map(|(i, _)| i)
+}
+
+/// Apply per-query hit filtering and weighting to the merged hits of one clonotype
+fn finalize_matches(
+    mut matches: Vec<ClonotypeMatch>,
+    database: &Database,
+    epitope_sizes: &HashMap<&str, usize>,
+    background: Option<&ScoreBackground>,
+    config: &MatchConfig,
+) -> Vec<ClonotypeMatch> {
+    // Merged shards may each contribute a first hit; keep the earliest
+    if config.exhaustive_search == 0 {
+        matches.truncate(1);
+    }
+
     // Apply hit filtering
     if config.max_hits_only && !matches.is_empty() {
         let max_score = matches.iter().map(|m| m.score).fold(f64::NEG_INFINITY, f64::max);
@@ -142,45 +523,680 @@ pub fn match_clonotype(
     }
     
     if let Some(top_n) = config.top_n_hits {
-        matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
         matches.truncate(top_n);
     }
     
     // Compute weights if requested
     if config.weight_by_informativeness {
-        compute_informativeness_weights(&mut matches, database);
+        compute_informativeness_weights(&mut matches, database, epitope_sizes);
+    }
+
+    compute_specificity(&mut matches, epitope_sizes);
+
+    if let Some(first) = matches.first() {
+        let query = first.query_clonotype.cdr3_aa.sequence.to_uppercase();
+        let moments = background.and_then(|b| b.moments(&query));
+        for m in &mut matches {
+            m.normalized_score = config.score_normalization.apply(m.normalized_score, &query, moments);
+        }
     }
-    
     matches
 }
 
+/// Database entries per epitope
+fn epitope_sizes(database: &Database) -> HashMap<&str, usize> {
+    let mut sizes = HashMap::new();
+    for entry in &database.entries {
+        *sizes.entry(entry.antigen_epitope.as_str()).or_insert(0) += 1;
+    }
+    sizes
+}
+
+/// Fill `specificity`, counting supporting references per epitope over the query's hits
+fn compute_specificity(matches: &mut [ClonotypeMatch], epitope_sizes: &HashMap<&str, usize>) {
+    let evidence = specificity_evidence(matches, epitope_sizes);
+    for (m, e) in matches.iter_mut().zip(evidence) {
+        m.specificity = specificity_confidence(&e);
+    }
+}
+
+/// Evidence of each of one query's hits for `specificity_confidence`
+fn specificity_evidence(matches: &[ClonotypeMatch], epitope_sizes: &HashMap<&str, usize>) -> Vec<SpecificityEvidence> {
+    let mut references: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for m in matches {
+        let refs = references.entry(m.db_entry.antigen_epitope.as_str()).or_default();
+        if let Some(r) = m.db_entry.reference_id.as_deref().filter(|r| !r.is_empty()) {
+            refs.insert(r);
+        }
+    }
+    matches
+        .iter()
+        .map(|m| SpecificityEvidence {
+            match_score: m.score,
+            vdjdb_score: m.db_entry.vdjdb_score,
+            n_references: references[m.db_entry.antigen_epitope.as_str()].len(),
+            epitope_size: epitope_sizes.get(m.db_entry.antigen_epitope.as_str()).copied().unwrap_or(1),
+            edit_distance: m.edit_distance,
+        })
+        .collect()
+}
+
+/// Labelled hits of held-out database entries, for `SpecificityModel::fit`
+/// Each of `n_queries` entries drawn with `rng` is matched (all hits, no
+/// ranking) against the rest of the database less the entries of its own
+/// reference, so a study's repeated clonotypes do not confirm themselves. Each
+/// hit gives its evidence and whether its epitope is the query entry's.
+pub fn specificity_samples(
+    database: &Database,
+    config: &MatchConfig,
+    n_queries: usize,
+    rng: &mut Rng,
+) -> Vec<(SpecificityEvidence, bool)> {
+    let mut picked: Vec<usize> = (0..database.entries.len()).collect();
+    rng.shuffle(&mut picked);
+    picked.truncate(n_queries);
+    held_out_samples(database, config, &picked)
+}
+
+/// `specificity_samples` of the database entries at `queries`
+fn held_out_samples(database: &Database, config: &MatchConfig, queries: &[usize]) -> Vec<(SpecificityEvidence, bool)> {
+    let config = MatchConfig {
+        exhaustive_search: 1,
+        max_hits_only: false,
+        top_n_hits: None,
+        adaptive_scope: None,
+        ..config.clone()
+    };
+    let sizes = epitope_sizes(database);
+    let samples: Vec<Vec<(SpecificityEvidence, bool)>> = queries
+        .par_iter()
+        .map(|&q| {
+            let query = &database.entries[q];
+            let clonotype = Clonotype::new(query.cdr3.clone(), query.v_segment.clone(), query.j_segment.clone(), 1, 0.0);
+            let mut hits = match_entries(&clonotype, &database.entries[..q], &config);
+            hits.extend(match_entries(&clonotype, &database.entries[q + 1..], &config));
+            if let Some(reference) = query.reference_id.as_deref().filter(|r| !r.is_empty()) {
+                hits.retain(|m| m.db_entry.reference_id.as_deref() != Some(reference));
+            }
+            let epitope = query.antigen_epitope.trim();
+            specificity_evidence(&hits, &sizes)
+                .into_iter()
+                .zip(&hits)
+                .map(|(e, m)| (e, m.db_entry.antigen_epitope.trim().eq_ignore_ascii_case(epitope)))
+                .collect()
+        })
+        .collect();
+    samples.into_iter().flatten().collect()
+}
+
+/// Database entries per shard when scheduling parallel matching
+const SHARD_SIZE: usize = 2048;
+
 /// Match multiple clonotypes in parallel
+/// Work is split into (query, database shard) tasks so that queries with many
+/// hits (public clonotypes) are spread over threads instead of straggling on one.
+/// Shard results are merged in database order, so output matches `match_clonotype`.
 pub fn match_clonotypes_parallel(
     clonotypes: &[Clonotype],
     database: &Database,
     config: &MatchConfig,
 ) -> Vec<Vec<ClonotypeMatch>> {
-    clonotypes
-        .par_iter()
-        .map(|clonotype| match_clonotype(clonotype, database, config))
+    let epitope_sizes = epitope_sizes(database);
+    let background = score_background(database, config);
+    let background = background.as_ref();
+
+    // First-hit searches stop early, which sharding would defeat
+    if config.exhaustive_search == 0 {
+        return clonotypes
+            .par_iter()
+            .map(|c| finalize_matches(match_entries(c, &database.entries, config), database, &epitope_sizes, background, config))
+            .collect();
+    }
+
+    let n_entries = database.entries.len();
+    let n_shards = ((n_entries + SHARD_SIZE - 1) / SHARD_SIZE).max(1);
+
+    let shard_hits: Vec<Vec<ClonotypeMatch>> = (0..clonotypes.len() * n_shards)
+        .into_par_iter()
+        .map(|task| {
+            let (query, shard) = (task / n_shards, task % n_shards);
+            let start = (shard * SHARD_SIZE).min(n_entries);
+            let end = (start + SHARD_SIZE).min(n_entries);
+            match_entries(&clonotypes[query], &database.entries[start..end], config)
+        })
+        .collect();
+
+    shard_hits
+        .par_chunks(n_shards)
+        .map(|shards| finalize_matches(shards.concat(), database, &epitope_sizes, background, config))
         .collect()
 }
 
+/// Database entry positions by uppercase CDR3, for exact-scope lookups
+pub type Cdr3Index = HashMap<String, Vec<usize>>;
+
+/// Index every entry under its uppercase CDR3
+/// Covers the whole database, so build it once per database.
+pub fn cdr3_index(database: &Database) -> Cdr3Index {
+    let mut index = Cdr3Index::new();
+    for (i, entry) in database.entries.iter().enumerate() {
+        index.entry(entry.cdr3_tokens.residues().to_string()).or_default().push(i);
+    }
+    index
+}
+
+/// Whether each clonotype has at least one hit, without collecting hit tables
+/// Exact scopes look CDR3s up in a hash index; wider scopes scan with early exit.
+pub fn has_matches(clonotypes: &[Clonotype], database: &Database, config: &MatchConfig) -> Vec<bool> {
+    let index = config.search_scope.is_exact().then(|| cdr3_index(database));
+    has_matches_indexed(clonotypes, database, index.as_ref(), config)
+}
+
+/// `has_matches` with the database's `cdr3_index` built beforehand; exact
+/// scopes without an index scan like wider ones.
+pub fn has_matches_indexed(
+    clonotypes: &[Clonotype],
+    database: &Database,
+    index: Option<&Cdr3Index>,
+    config: &MatchConfig,
+) -> Vec<bool> {
+    let config = MatchConfig { exhaustive_search: 0, ..config.clone() };
+    let (unique, mapping) = dedup_clonotypes(clonotypes);
+
+    let found: Vec<bool> = match index.filter(|_| config.search_scope.is_exact()) {
+        Some(index) => unique
+            .par_iter()
+            .map(|c| {
+                index.get(c.cdr3_aa.sequence.as_str()).is_some_and(|candidates| {
+                    candidates.iter().any(|&i| {
+                        !match_entries(c, std::slice::from_ref(&database.entries[i]), &config).is_empty()
+                    })
+                })
+            })
+            .collect(),
+        None => unique
+            .par_iter()
+            .map(|c| !match_entries(c, &database.entries, &config).is_empty())
+            .collect(),
+    };
+
+    mapping.into_iter().map(|u| found[u]).collect()
+}
+
+/// A repertoire clonotype with database hits to the searched epitope
+#[derive(Debug, Clone)]
+pub struct SpecificClonotype {
+    /// Position of the clonotype in the repertoire
+    pub index: usize,
+    /// Highest-scoring hit (first in database order on ties)
+    pub best: ClonotypeMatch,
+    pub n_hits: usize,
+}
+
+/// Reverse lookup: repertoire clonotypes hitting any database entry of
+/// `epitope` (case-insensitive) within scope, by decreasing frequency, then
+/// count and repertoire order. Only the epitope's entries are searched, and
+/// clonotypes whose CDR3 length is further than the scope's total edits from
+/// every entry's are skipped without aligning.
+pub fn find_specific_clonotypes(
+    clonotypes: &[Clonotype],
+    database: &Database,
+    epitope: &str,
+    config: &MatchConfig,
+) -> Vec<SpecificClonotype> {
+    let epitope = epitope.trim();
+    let entries: Vec<DatabaseEntry> = database
+        .entries
+        .iter()
+        .filter(|e| e.antigen_epitope.trim().eq_ignore_ascii_case(epitope))
+        .cloned()
+        .collect();
+    if entries.is_empty() || clonotypes.is_empty() {
+        return Vec::new();
+    }
+    let restricted = database.derive(entries, Vec::new());
+
+    let lengths: HashSet<usize> = restricted
+        .entries
+        .iter()
+        .filter_map(|e| config.residues.prepare_tokens(&e.cdr3_tokens).map(|s| s.len()))
+        .collect();
+    let reach = config.search_scope.total;
+    let (unique, mapping) = dedup_clonotypes(clonotypes);
+    let candidates: Vec<usize> = (0..unique.len())
+        .filter(|&u| {
+            config.residues.prepare_target(&unique[u].cdr3_aa.sequence).is_some_and(|q| {
+                let len = q.len();
+                (len.saturating_sub(reach)..=len + reach).any(|l| lengths.contains(&l))
+            })
+        })
+        .collect();
+    let queries: Vec<Clonotype> = candidates.iter().map(|&u| unique[u].clone()).collect();
+    let hits = match_clonotypes_parallel(&queries, &restricted, config);
+
+    let mut unique_hits: Vec<Option<&Vec<ClonotypeMatch>>> = vec![None; unique.len()];
+    for (&u, h) in candidates.iter().zip(&hits) {
+        unique_hits[u] = Some(h).filter(|h| !h.is_empty());
+    }
+    let mut found: Vec<SpecificClonotype> = mapping
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &u)| {
+            let hits = unique_hits[u]?;
+            let best = hits.iter().reduce(|a, b| if b.score > a.score { b } else { a })?;
+            Some(SpecificClonotype { index, best: best.clone(), n_hits: hits.len() })
+        })
+        .collect();
+    found.sort_by(|a, b| {
+        let (ca, cb) = (&clonotypes[a.index], &clonotypes[b.index]);
+        cb.frequency
+            .total_cmp(&ca.frequency)
+            .then(cb.count.cmp(&ca.count))
+            .then(a.index.cmp(&b.index))
+    });
+    found
+}
+
+/// Collapse identical (CDR3, V, J, D) queries, e.g. one row per cell in single-cell data
+/// Returns the unique clonotypes (in order of first appearance) and, for each
+/// input clonotype, the index of its unique representative
+pub fn dedup_clonotypes(clonotypes: &[Clonotype]) -> (Vec<Clonotype>, Vec<usize>) {
+    let mut seen: HashMap<(&str, &str, &str, &str), usize> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut mapping = Vec::with_capacity(clonotypes.len());
+
+    for clonotype in clonotypes {
+        let key = (
+            clonotype.cdr3_aa.sequence.as_str(),
+            clonotype.v_segment.as_str(),
+            clonotype.j_segment.as_str(),
+            clonotype.d_segment.as_deref().unwrap_or(""),
+        );
+        let idx = *seen.entry(key).or_insert_with(|| {
+            unique.push(clonotype.clone());
+            unique.len() - 1
+        });
+        mapping.push(idx);
+    }
+
+    (unique, mapping)
+}
+
+/// Clonotypes queried more than once, as (index of the first occurrence,
+/// number of copies) pairs: most copies first, ties in order of first appearance
+/// Identity is that of `dedup_clonotypes`.
+pub fn duplicate_queries(clonotypes: &[Clonotype]) -> Vec<(usize, usize)> {
+    let (unique, mapping) = dedup_clonotypes(clonotypes);
+    let mut first = vec![usize::MAX; unique.len()];
+    let mut copies = vec![0usize; unique.len()];
+    for (i, &u) in mapping.iter().enumerate() {
+        first[u] = first[u].min(i);
+        copies[u] += 1;
+    }
+    let mut duplicates: Vec<(usize, usize)> =
+        first.into_iter().zip(copies).filter(|&(_, n)| n > 1).collect();
+    duplicates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    duplicates
+}
+
+/// Thresholds for narrowing a match result after the fact (`BatchMatches::filter`)
+#[derive(Debug, Clone, Default)]
+pub struct HitFilter {
+    pub min_score: Option<f64>,
+    /// Keep hits on these epitopes only
+    pub epitopes: Option<HashSet<String>>,
+    pub max_edit_distance: Option<usize>,
+}
+
+impl HitFilter {
+    pub fn keeps(&self, m: &ClonotypeMatch) -> bool {
+        self.min_score.map_or(true, |min| m.score >= min)
+            && self.max_edit_distance.map_or(true, |max| m.edit_distance <= max)
+            && self.epitopes.as_ref().map_or(true, |e| e.contains(&m.db_entry.antigen_epitope))
+    }
+}
+
+/// Probability that a query recognizes one of its hit epitopes
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpitopeProbability {
+    pub query: usize,
+    pub epitope: String,
+    pub n_hits: usize,
+    /// Summed hit scores (negative scores count as 0)
+    pub evidence: f64,
+    pub probability: f64,
+}
+
+/// P(epitope | hits) of each query and hit epitope from `(query, epitope, score)`
+/// hits: the epitope's summed score plus `pseudo_count`, over the query's total
+/// evidence plus one pseudo-count per hit epitope and one for recognizing none
+/// of them. A single weak hit thus stays uncertain, and a query's probabilities
+/// sum to less than 1. Rows are ordered by query, then by decreasing probability.
+pub fn epitope_probabilities<'a>(
+    hits: impl IntoIterator<Item = (usize, &'a str, f64)>,
+    pseudo_count: f64,
+) -> Vec<EpitopeProbability> {
+    let mut by_query: BTreeMap<usize, HashMap<&str, (usize, f64)>> = BTreeMap::new();
+    for (query, epitope, score) in hits {
+        let e = by_query.entry(query).or_default().entry(epitope).or_insert((0, 0.0));
+        e.0 += 1;
+        e.1 += if score.is_nan() { 0.0 } else { score.max(0.0) };
+    }
+    let mut rows = Vec::new();
+    for (query, epitopes) in by_query {
+        let total: f64 = epitopes.values().map(|&(_, evidence)| evidence).sum::<f64>()
+            + pseudo_count * (epitopes.len() + 1) as f64;
+        let start = rows.len();
+        rows.extend(epitopes.into_iter().map(|(epitope, (n_hits, evidence))| EpitopeProbability {
+            query,
+            epitope: epitope.to_string(),
+            n_hits,
+            evidence,
+            probability: if total > 0.0 { (evidence + pseudo_count) / total } else { 0.0 },
+        }));
+        rows[start..].sort_by(|a, b| b.probability.total_cmp(&a.probability).then_with(|| a.epitope.cmp(&b.epitope)));
+    }
+    rows
+}
+
+/// Hits for a batch of queries, stored once per unique clonotype and expanded
+/// to one row per (query, hit) on demand
+#[derive(Debug, Clone)]
+pub struct BatchMatches {
+    pub queries: Vec<Clonotype>,
+    mapping: Vec<usize>,
+    unique_matches: Vec<Vec<ClonotypeMatch>>,
+    /// Row offset of each query's first hit; has `queries.len() + 1` elements
+    offsets: Vec<usize>,
+}
+
+impl BatchMatches {
+    /// `mapping[i]` indexes the entry of `unique_matches` holding the hits of `queries[i]`
+    pub fn new(queries: Vec<Clonotype>, mapping: Vec<usize>, unique_matches: Vec<Vec<ClonotypeMatch>>) -> Self {
+        let mut offsets = Vec::with_capacity(queries.len() + 1);
+        offsets.push(0);
+        for &u in &mapping {
+            offsets.push(offsets[offsets.len() - 1] + unique_matches[u].len());
+        }
+        Self { queries, mapping, unique_matches, offsets }
+    }
+
+    /// Number of (query, hit) rows
+    pub fn len(&self) -> usize {
+        self.offsets[self.offsets.len() - 1]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of distinct (CDR3, V, J) queries that were actually matched
+    pub fn n_unique(&self) -> usize {
+        self.unique_matches.len()
+    }
+
+    /// The result keeping only the hits `filter` keeps; every query stays,
+    /// so query indices are unchanged
+    pub fn filter(&self, filter: &HitFilter) -> Self {
+        let unique_matches = self
+            .unique_matches
+            .iter()
+            .map(|hits| hits.iter().filter(|m| filter.keeps(m)).cloned().collect())
+            .collect();
+        Self::new(self.queries.clone(), self.mapping.clone(), unique_matches)
+    }
+
+    /// Hits of query `i`
+    pub fn query_hits(&self, i: usize) -> &[ClonotypeMatch] {
+        &self.unique_matches[self.mapping[i]]
+    }
+
+    /// (query index, hit) for rows in `range`, clamped to the number of rows
+    pub fn rows(&self, range: std::ops::Range<usize>) -> impl Iterator<Item = (usize, &ClonotypeMatch)> + '_ {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        // Last query whose first row is at or before `start`
+        let first_query = self.offsets.partition_point(|&o| o <= start).saturating_sub(1);
+
+        (first_query..self.queries.len())
+            .flat_map(move |i| {
+                let base = self.offsets[i];
+                self.query_hits(i)
+                    .iter()
+                    .enumerate()
+                    .map(move |(k, m)| (base + k, i, m))
+            })
+            .skip_while(move |(row, _, _)| *row < start)
+            .take_while(move |(row, _, _)| *row < end)
+            .map(|(_, i, m)| (i, m))
+    }
+}
+
+/// One row of a saved batch result: a query and one of its hits, or a
+/// hit-less row for queries without hits (and repeats of an earlier query)
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedMatchRow {
+    query_index: usize,
+    query_cdr3: String,
+    query_v: String,
+    query_j: String,
+    #[serde(default)]
+    query_d: Option<String>,
+    has_hit: bool,
+    cdr3: String,
+    v_segment: String,
+    j_segment: String,
+    species: String,
+    gene: String,
+    mhc_class: Option<String>,
+    antigen_epitope: String,
+    antigen_gene: Option<String>,
+    antigen_species: String,
+    reference_id: Option<String>,
+    method: Option<String>,
+    meta: Option<String>,
+    cdr3_fix: Option<String>,
+    vdjdb_score: u8,
+    #[serde(default)]
+    db_row: usize,
+    score: f64,
+    weight: f64,
+    cdr3_alignment_score: f64,
+    v_score: f64,
+    j_score: f64,
+    edit_distance: usize,
+    #[serde(default)]
+    specificity: f64,
+    #[serde(default)]
+    matched_v: String,
+    #[serde(default)]
+    matched_j: String,
+    #[serde(default)]
+    d_segment: Option<String>,
+    #[serde(default)]
+    normalized_score: f64,
+    #[serde(default)]
+    p_value: Option<f64>,
+    #[serde(default)]
+    q_value: Option<f64>,
+    #[serde(default)]
+    epitope_records: Option<usize>,
+    #[serde(default)]
+    epitope_unique_cdr3: Option<usize>,
+    #[serde(default)]
+    complex_id: Option<String>,
+    #[serde(default)]
+    mhc_a: Option<String>,
+    #[serde(default)]
+    mhc_b: Option<String>,
+}
+
+impl BatchMatches {
+    /// Save queries and hits as TSV (gzip-compressed when the path ends in ".gz")
+    /// Hits of repeated queries are stored once and restored by `load`.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> crate::error::Result<()> {
+        crate::utils::write_to_path(path, |out| {
+            crate::utils::write_save_header(out, "matches", &[])?;
+            let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_writer(out);
+            let mut written = vec![false; self.unique_matches.len()];
+
+            for (i, query) in self.queries.iter().enumerate() {
+                let unique = self.mapping[i];
+                let hits: &[ClonotypeMatch] = if written[unique] { &[] } else { &self.unique_matches[unique] };
+                written[unique] = true;
+
+                let empty_hit = hits.is_empty().then_some(None);
+                for hit in hits.iter().map(Some).chain(empty_hit) {
+                    let entry = hit.map(|m| m.db_entry.clone());
+                    let entry = entry.as_ref();
+                    writer.serialize(SavedMatchRow {
+                        query_index: i,
+                        query_cdr3: query.cdr3_aa.sequence.clone(),
+                        query_v: query.v_segment.clone(),
+                        query_j: query.j_segment.clone(),
+                        query_d: query.d_segment.clone(),
+                        has_hit: hit.is_some(),
+                        cdr3: entry.map(|e| e.cdr3.clone()).unwrap_or_default(),
+                        v_segment: entry.map(|e| e.v_segment.clone()).unwrap_or_default(),
+                        j_segment: entry.map(|e| e.j_segment.clone()).unwrap_or_default(),
+                        species: entry.map(|e| e.species.clone()).unwrap_or_default(),
+                        gene: entry.map(|e| e.gene.clone()).unwrap_or_default(),
+                        mhc_class: entry.and_then(|e| e.mhc_class.clone()),
+                        antigen_epitope: entry.map(|e| e.antigen_epitope.clone()).unwrap_or_default(),
+                        antigen_gene: entry.and_then(|e| e.antigen_gene.clone()),
+                        antigen_species: entry.map(|e| e.antigen_species.clone()).unwrap_or_default(),
+                        reference_id: entry.and_then(|e| e.reference_id.clone()),
+                        method: entry.and_then(|e| e.method.clone()),
+                        meta: entry.and_then(|e| e.meta.clone()),
+                        cdr3_fix: entry.and_then(|e| e.cdr3_fix.clone()),
+                        vdjdb_score: entry.map(|e| e.vdjdb_score).unwrap_or(0),
+                        db_row: entry.map(|e| e.row_id).unwrap_or(0),
+                        score: hit.map(|m| m.score).unwrap_or(0.0),
+                        weight: hit.map(|m| m.weight).unwrap_or(0.0),
+                        cdr3_alignment_score: hit.map(|m| m.cdr3_alignment_score).unwrap_or(0.0),
+                        v_score: hit.map(|m| m.v_score).unwrap_or(0.0),
+                        j_score: hit.map(|m| m.j_score).unwrap_or(0.0),
+                        edit_distance: hit.map(|m| m.edit_distance).unwrap_or(0),
+                        specificity: hit.map(|m| m.specificity).unwrap_or(0.0),
+                        matched_v: hit.map(|m| m.matched_v.clone()).unwrap_or_default(),
+                        matched_j: hit.map(|m| m.matched_j.clone()).unwrap_or_default(),
+                        d_segment: entry.and_then(|e| e.d_segment.clone()),
+                        normalized_score: hit.map(|m| m.normalized_score).unwrap_or(0.0),
+                        p_value: hit.and_then(|m| m.p_value),
+                        q_value: hit.and_then(|m| m.q_value),
+                        epitope_records: hit.and_then(|m| m.epitope_records),
+                        epitope_unique_cdr3: hit.and_then(|m| m.epitope_unique_cdr3),
+                        complex_id: entry.and_then(|e| e.complex_id.clone()),
+                        mhc_a: entry.and_then(|e| e.mhc_a.clone()),
+                        mhc_b: entry.and_then(|e| e.mhc_b.clone()),
+                    })?;
+                }
+            }
+            writer.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Load a batch result written by `save`
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> crate::error::Result<Self> {
+        let mut reader = crate::utils::open_reader(path)?;
+        crate::utils::read_save_header(&mut reader, "matches")?;
+        let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_reader(reader);
+
+        let mut queries: Vec<Clonotype> = Vec::new();
+        let mut hits: Vec<Vec<ClonotypeMatch>> = Vec::new();
+        for row in reader.deserialize::<SavedMatchRow>() {
+            let row = row?;
+            if row.query_index != queries.len().wrapping_sub(1) {
+                if row.query_index != queries.len() {
+                    return Err(crate::error::VdjMatchError::InvalidFormat(format!(
+                        "saved matches are out of order at query {}",
+                        row.query_index + 1
+                    )));
+                }
+                let mut query = Clonotype::new(row.query_cdr3.clone(), row.query_v.clone(), row.query_j.clone(), 1, 0.0);
+                query.d_segment = row.query_d.clone();
+                queries.push(query);
+                hits.push(Vec::new());
+            }
+            if row.has_hit {
+                let query_clonotype = queries[row.query_index].clone();
+                let reference = row.reference_id.as_deref();
+                let (pmid, publication_year) = (
+                    reference.and_then(crate::database::parse_pmid),
+                    reference.and_then(crate::database::reference_year),
+                );
+                let cdr3_tokens = Cdr3Tokens::new(&row.cdr3);
+                hits[row.query_index].push(ClonotypeMatch {
+                    query_clonotype,
+                    db_entry: DatabaseEntry {
+                        cdr3: row.cdr3,
+                        v_segment: row.v_segment,
+                        j_segment: row.j_segment,
+                        species: row.species,
+                        gene: row.gene,
+                        mhc_class: row.mhc_class,
+                        antigen_epitope: row.antigen_epitope,
+                        antigen_gene: row.antigen_gene,
+                        antigen_species: row.antigen_species,
+                        reference_id: row.reference_id,
+                        method: row.method,
+                        meta: row.meta,
+                        cdr3_fix: row.cdr3_fix,
+                        vdjdb_score: row.vdjdb_score,
+                        row_id: row.db_row,
+                        d_segment: row.d_segment,
+                        complex_id: row.complex_id,
+                        mhc_a: row.mhc_a,
+                        mhc_b: row.mhc_b,
+                        pmid,
+                        publication_year,
+                        study_records: 0,
+                        cdr3_tokens,
+                    },
+                    score: row.score,
+                    weight: row.weight,
+                    cdr3_alignment_score: row.cdr3_alignment_score,
+                    v_score: row.v_score,
+                    j_score: row.j_score,
+                    edit_distance: row.edit_distance,
+                    specificity: row.specificity,
+                    matched_v: row.matched_v,
+                    matched_j: row.matched_j,
+                    normalized_score: row.normalized_score,
+                    p_value: row.p_value,
+                    q_value: row.q_value,
+                    epitope_records: row.epitope_records,
+                    epitope_unique_cdr3: row.epitope_unique_cdr3,
+                });
+            }
+        }
+
+        // Repeated queries were saved without hits; take them from the first occurrence
+        let (unique, mapping) = dedup_clonotypes(&queries);
+        let mut unique_matches = vec![Vec::new(); unique.len()];
+        let mut filled = vec![false; unique.len()];
+        for (i, query_hits) in hits.into_iter().enumerate() {
+            if !filled[mapping[i]] {
+                unique_matches[mapping[i]] = query_hits;
+                filled[mapping[i]] = true;
+            }
+        }
+
+        Ok(Self::new(queries, mapping, unique_matches))
+    }
+}
+
 /// Compute informativeness weights for matches
 /// Weight = -log10(P(match by chance))
-fn compute_informativeness_weights(matches: &mut [ClonotypeMatch], database: &Database) {
-    // Group by epitope
-    let mut epitope_cdr3_counts: std::collections::HashMap<String, usize> =
-        std::collections::HashMap::new();
-    
-    for entry in &database.entries {
-        *epitope_cdr3_counts
-            .entry(entry.antigen_epitope.clone())
-            .or_insert(0) += 1;
-    }
-    
+fn compute_informativeness_weights(
+    matches: &mut [ClonotypeMatch],
+    database: &Database,
+    epitope_cdr3_counts: &HashMap<&str, usize>,
+) {
     for m in matches.iter_mut() {
-        let epitope = &m.db_entry.antigen_epitope;
+        let epitope = m.db_entry.antigen_epitope.as_str();
         let count = epitope_cdr3_counts.get(epitope).copied().unwrap_or(1);
         
         // Simple informativeness: inverse of frequency
@@ -194,6 +1210,7 @@ fn compute_informativeness_weights(matches: &mut [ClonotypeMatch], database: &Da
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scoring::FAMILY_SEGMENT_SCORE;
     
     #[test]
     fn test_match_clonotype() {
@@ -206,28 +1223,16 @@ mod tests {
         );
         
         let db_entry = DatabaseEntry {
-            cdr3: "CASSLGQAYEQYF".to_string(),
-            v_segment: "TRBV12-3".to_string(),
-            j_segment: "TRBJ2-7".to_string(),
-            species: "HomoSapiens".to_string(),
-            gene: "TRB".to_string(),
             mhc_class: Some("MHCI".to_string()),
-            antigen_epitope: "GLCTLVAML".to_string(),
             antigen_gene: Some("BMLF1".to_string()),
-            antigen_species: "EBV".to_string(),
             reference_id: Some("PMID:12345".to_string()),
-            method: None,
-            meta: None,
-            cdr3_fix: None,
             vdjdb_score: 3,
+            ..DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML")
         };
         
         let database = Database {
             entries: vec![db_entry],
-            metadata: crate::database::DatabaseMetadata {
-                columns: vec![],
-                version: None,
-            },
+            metadata: crate::database::DatabaseMetadata::default(),
         };
         
         let config = MatchConfig::default();
@@ -237,4 +1242,585 @@ mod tests {
         assert_eq!(matches.len(), 1);
         assert_eq!(matches[0].score, 1.0);
     }
+
+    fn test_database(entries: Vec<DatabaseEntry>) -> Database {
+        Database {
+            entries,
+            metadata: crate::database::DatabaseMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_top_n_with_degenerate_inputs() {
+        let clonotype = Clonotype::new(String::new(), String::new(), String::new(), 1, 0.0);
+        let database = test_database(vec![
+            DatabaseEntry::test("", "GLCTLVAML"),
+            DatabaseEntry::test("C", "NLVPMVATV"),
+            DatabaseEntry::test("CASSF", "NLVPMVATV"),
+        ]);
+
+        let config = MatchConfig {
+            search_scope: SearchScope::parse("5,5,5").unwrap(),
+            top_n_hits: Some(2),
+            ..MatchConfig::default()
+        };
+
+        let matches = match_clonotype(&clonotype, &database, &config);
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|m| !m.score.is_nan()));
+        assert_eq!(matches[0].db_entry.cdr3, "");
+        assert_eq!(matches[0].score, 1.0);
+    }
+
+    #[test]
+    fn test_cdr3_lengths() {
+        let clonotype = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3".into(), "TRBJ2-7".into(), 1, 0.0);
+        let database = test_database(vec![DatabaseEntry::test("CASSLGQAYEQYF", "A"), DatabaseEntry::test("CASSLGAYEQYF", "B")]);
+        let config = MatchConfig { search_scope: SearchScope::parse("0,0,1").unwrap(), ..MatchConfig::default() };
+
+        let matches = match_clonotype(&clonotype, &database, &config);
+        let lengths: Vec<_> = matches.iter().map(|m| (m.cdr3_lengths(), m.cdr3_length_diff())).collect();
+        assert_eq!(lengths, [((13, 13), 0), ((13, 12), -1)]);
+    }
+
+    #[test]
+    fn test_parallel_matches_serial_across_shards() {
+        let entries: Vec<DatabaseEntry> = (0..SHARD_SIZE * 2 + 7)
+            .map(|i| DatabaseEntry::test(if i % 3 == 0 { "CASSLGQAYEQYF" } else { "CASRTGELFF" }, &i.to_string()))
+            .collect();
+        let database = test_database(entries);
+        let clonotypes = vec![
+            Clonotype::new("CASSLGQAYEQYF".to_string(), String::new(), String::new(), 1, 0.0),
+            Clonotype::new("CAVRDF".to_string(), String::new(), String::new(), 1, 0.0),
+        ];
+        let config = MatchConfig {
+            top_n_hits: Some(5),
+            ..MatchConfig::default()
+        };
+
+        let parallel = match_clonotypes_parallel(&clonotypes, &database, &config);
+        assert_eq!(parallel.len(), clonotypes.len());
+        for (clonotype, hits) in clonotypes.iter().zip(&parallel) {
+            let serial = match_clonotype(clonotype, &database, &config);
+            let ids = |m: &[ClonotypeMatch]| m.iter().map(|h| h.db_entry.antigen_epitope.clone()).collect::<Vec<_>>();
+            assert_eq!(ids(hits), ids(&serial));
+        }
+        assert_eq!(parallel[0].len(), 5);
+        assert!(parallel[1].is_empty());
+
+        let empty = test_database(vec![]);
+        assert!(match_clonotypes_parallel(&clonotypes, &empty, &config).iter().all(|h| h.is_empty()));
+    }
+
+    #[test]
+    fn test_kmer_prefilter_skips_unrelated_entries() {
+        let clonotype = Clonotype::new("CASSLGQAYEQYF".to_string(), String::new(), String::new(), 1, 0.0);
+        let database = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYY", "GLCTLVAML"),
+            DatabaseEntry::test("CASSPPPPPEQYF", "NLVPMVATV"),
+            // Compared through the stored uppercase tokens
+            DatabaseEntry::test("casslgqayeqyw", "YLQPRTFLL"),
+        ]);
+        let mut config = MatchConfig {
+            search_scope: SearchScope::parse("5,5,5").unwrap(),
+            ..MatchConfig::default()
+        };
+        assert_eq!(match_clonotype(&clonotype, &database, &config).len(), 3);
+
+        config.kmer_filter = Some(KmerFilter::new(3, 2));
+        let matches = match_clonotype(&clonotype, &database, &config);
+        let mut epitopes: Vec<&str> = matches.iter().map(|m| m.db_entry.antigen_epitope.as_str()).collect();
+        epitopes.sort_unstable();
+        assert_eq!(epitopes, ["GLCTLVAML", "YLQPRTFLL"]);
+    }
+
+    #[test]
+    fn test_dedup_clonotypes() {
+        let clonotype = |cdr3: &str, v: &str| Clonotype::new(cdr3.to_string(), v.to_string(), String::new(), 1, 0.0);
+        let clonotypes = vec![
+            clonotype("CASSLF", "TRBV5-1"),
+            clonotype("cassLF", "TRBV5-1"),
+            clonotype("CASSLF", "TRBV6-1"),
+            clonotype("CASSLF", "TRBV5-1"),
+        ];
+
+        let (unique, mapping) = dedup_clonotypes(&clonotypes);
+        assert_eq!(unique.len(), 2);
+        assert_eq!(mapping, vec![0, 0, 1, 0]);
+        assert_eq!(unique[1].v_segment, "TRBV6-1");
+    }
+
+    #[test]
+    fn test_duplicate_queries() {
+        let clonotype = |cdr3: &str| Clonotype::new(cdr3.to_string(), String::new(), String::new(), 1, 0.0);
+        let queries: Vec<Clonotype> =
+            ["CASSLF", "CAVRDF", "CASSIF", "CAVRDF", "CASSLF", "CAVRDF", "CASSIF", "CATSF"].map(clonotype).to_vec();
+        assert_eq!(duplicate_queries(&queries), [(1, 3), (0, 2), (2, 2)]);
+        assert!(duplicate_queries(&queries[..3]).is_empty());
+    }
+
+    #[test]
+    fn test_epitope_probabilities() {
+        let hits = [(2, "GIL", 1.0), (0, "GIL", 0.5), (0, "NLV", 0.5), (0, "GIL", -1.0), (0, "GIL", 1.0)];
+        let rows = epitope_probabilities(hits, 1.0);
+        let summary: Vec<_> = rows.iter().map(|r| (r.query, r.epitope.as_str(), r.n_hits)).collect();
+        assert_eq!(summary, [(0, "GIL", 3), (0, "NLV", 1), (2, "GIL", 1)]);
+        // (1.5 + 1) / (2 + 3), (0.5 + 1) / 5 and (1 + 1) / (1 + 2)
+        assert!((rows[0].probability - 0.5).abs() < 1e-12);
+        assert!((rows[1].probability - 0.3).abs() < 1e-12);
+        assert!((rows[2].probability - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(rows[0].evidence, 1.5);
+        // Without pseudo-counts a lone epitope is certain
+        assert_eq!(epitope_probabilities([(0, "GIL", 0.2)], 0.0)[0].probability, 1.0);
+        assert!(epitope_probabilities([(0, "GIL", 0.0)], 0.0)[0].probability == 0.0);
+    }
+
+    #[test]
+    fn test_batch_matches_rows() {
+        let database = test_database(vec![
+            DatabaseEntry::test("CASSLF", "A"),
+            DatabaseEntry::test("CASSLF", "B"),
+            DatabaseEntry::test("CASSIF", "C"),
+        ]);
+        let clonotype = |cdr3: &str| Clonotype::new(cdr3.to_string(), String::new(), String::new(), 1, 0.0);
+        let queries = vec![clonotype("CASSLF"), clonotype("CAVRDF"), clonotype("CASSIF"), clonotype("CASSLF")];
+
+        let (unique, mapping) = dedup_clonotypes(&queries);
+        let unique_matches = match_clonotypes_parallel(&unique, &database, &MatchConfig::default());
+        let batch = BatchMatches::new(queries, mapping, unique_matches);
+
+        assert_eq!(batch.len(), 5);
+        assert_eq!(batch.n_unique(), 3);
+        let all: Vec<(usize, &str)> = batch.rows(0..100).map(|(i, m)| (i, m.db_entry.antigen_epitope.as_str())).collect();
+        assert_eq!(all, vec![(0, "A"), (0, "B"), (2, "C"), (3, "A"), (3, "B")]);
+
+        let page: Vec<usize> = batch.rows(1..3).map(|(i, _)| i).collect();
+        assert_eq!(page, vec![0, 2]);
+        assert_eq!(batch.rows(3..4).next().map(|(i, _)| i), Some(3));
+        assert_eq!(batch.rows(10..20).count(), 0);
+
+        let filter = HitFilter { epitopes: Some(["B".to_string(), "C".to_string()].into()), ..Default::default() };
+        let filtered = batch.filter(&filter);
+        assert_eq!(filtered.queries.len(), 4);
+        let kept: Vec<(usize, &str)> = filtered.rows(0..100).map(|(i, m)| (i, m.db_entry.antigen_epitope.as_str())).collect();
+        assert_eq!(kept, vec![(0, "B"), (2, "C"), (3, "B")]);
+        assert_eq!(batch.filter(&HitFilter { min_score: Some(2.0), ..Default::default() }).len(), 0);
+        assert_eq!(batch.filter(&HitFilter { max_edit_distance: Some(0), ..Default::default() }).len(), 5);
+    }
+
+    #[test]
+    fn test_batch_matches_save_load_roundtrip() {
+        let mut entry = DatabaseEntry::test("CASSLF", "A");
+        entry.meta = Some("{\"note\": \"tab\tinside\"}".to_string());
+        let database = test_database(vec![entry, DatabaseEntry::test("CASSLF", "B")]);
+        let clonotype = |cdr3: &str| Clonotype::new(cdr3.to_string(), String::new(), String::new(), 1, 0.0);
+        let queries = vec![clonotype("CASSLF"), clonotype("CAVRDF"), clonotype("CASSLF")];
+
+        let (unique, mapping) = dedup_clonotypes(&queries);
+        let unique_matches = match_clonotypes_parallel(&unique, &database, &MatchConfig::default());
+        let batch = BatchMatches::new(queries, mapping, unique_matches);
+
+        let path = std::env::temp_dir().join(format!("vdjmatchR-matches-{}.tsv.gz", std::process::id()));
+        batch.save(&path).unwrap();
+        let loaded = BatchMatches::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.queries.len(), 3);
+        assert_eq!(loaded.len(), batch.len());
+        assert_eq!(loaded.n_unique(), 2);
+        let epitopes = |b: &BatchMatches| b.rows(0..b.len()).map(|(i, m)| (i, m.db_entry.antigen_epitope.clone())).collect::<Vec<_>>();
+        assert_eq!(epitopes(&loaded), epitopes(&batch));
+        assert_eq!(loaded.query_hits(2)[0].db_entry.meta, batch.query_hits(0)[0].db_entry.meta);
+    }
+
+    #[test]
+    fn test_epitope_stats() {
+        let database = test_database(vec![
+            DatabaseEntry::test("CASSLF", "A"),
+            DatabaseEntry::test("CASSLF", "A"),
+            DatabaseEntry::test("CASSQF", "A"),
+            DatabaseEntry::test("CASSLF", "B"),
+        ]);
+        let stats = epitope_stats(&database);
+        assert_eq!(stats["A"], EpitopeStats { n_records: 3, n_unique_cdr3: 2 });
+        assert_eq!(stats["B"], EpitopeStats { n_records: 1, n_unique_cdr3: 1 });
+
+        let query = Clonotype::new("CASSLF".into(), String::new(), String::new(), 1, 0.0);
+        let mut hits = vec![match_clonotype(&query, &database, &MatchConfig::default())];
+        assert!(hits[0].iter().all(|m| m.epitope_records.is_none()));
+        assign_epitope_stats(&mut hits, &stats);
+        let counts: Vec<_> = hits[0]
+            .iter()
+            .map(|m| (m.db_entry.antigen_epitope.as_str(), m.epitope_records, m.epitope_unique_cdr3))
+            .collect();
+        assert!(counts.contains(&("A", Some(3), Some(2))));
+        assert!(counts.contains(&("B", Some(1), Some(1))));
+    }
+
+    #[test]
+    fn test_scope_tiers() {
+        let db = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYF", "A"),
+            DatabaseEntry::test("CASSLGQTYEQYF", "B"),
+            DatabaseEntry::test("CASSLGTYEQYF", "C"),
+            DatabaseEntry::test("CASSLGQAYEQF", "D"),
+            DatabaseEntry::test("CAVRDGYNF", "E"),
+        ]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "".into(), "".into(), 1, 0.0);
+        let scopes: Vec<SearchScope> = ["0,0,0", "2,1,2", "1,0,1"].iter().map(|s| SearchScope::parse(s).unwrap()).collect();
+        let widest = MatchConfig { search_scope: SearchScope::widest(&scopes), ..Default::default() };
+
+        // Each hit of the single wide search lands in the tightest scope that finds it on its own
+        let hits = match_clonotype(&query, &db, &widest);
+        assert_eq!(hits.len(), 4);
+        for m in &hits {
+            let tier = scope_tier(m.edit_distance, &scopes).unwrap();
+            let found_by = |scope: &SearchScope| {
+                let config = MatchConfig { search_scope: *scope, ..Default::default() };
+                match_clonotype(&query, &db, &config).iter().any(|h| h.db_entry.antigen_epitope == m.db_entry.antigen_epitope)
+            };
+            assert!(found_by(&scopes[tier]));
+            assert!(scopes.iter().filter(|s| s.total < scopes[tier].total).all(|s| !found_by(s)));
+        }
+        let tiers: Vec<_> = hits.iter().map(|m| (m.db_entry.antigen_epitope.as_str(), scope_tier(m.edit_distance, &scopes))).collect();
+        assert_eq!(tiers, [("A", Some(0)), ("B", Some(2)), ("C", Some(1)), ("D", Some(2))]);
+        assert_eq!(scope_tier(3, &scopes), None);
+    }
+
+    #[test]
+    fn test_chain_scopes() {
+        let db = test_database(vec![DatabaseEntry::test("CASSLGQAYEQYF", "A"), DatabaseEntry::test("CASSLGQTYEQYF", "B")]);
+        let config = MatchConfig {
+            chain_scopes: ChainScopes::parse("TRA=1,0,1;TRB=0,0,0").unwrap(),
+            search_scope: SearchScope::parse("1,0,1").unwrap(),
+            ..Default::default()
+        };
+        let query = |v: &str| Clonotype::new("CASSLGQAYEQYF".into(), v.into(), "".into(), 1, 0.0);
+        let hits = match_clonotypes_parallel(&[query("TRAV12-1"), query("TRBV9"), query("")], &db, &config);
+        let epitopes: Vec<Vec<&str>> =
+            hits.iter().map(|h| h.iter().map(|m| m.db_entry.antigen_epitope.as_str()).collect()).collect();
+        // Queries without a recognizable chain get the unlabelled (here exact) scope
+        assert_eq!(epitopes, [vec!["A", "B"], vec!["A"], vec!["A"]]);
+    }
+
+    #[test]
+    fn test_find_specific_clonotypes() {
+        let db = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML"),
+            DatabaseEntry::test("CASSIRSSYEQYF", "GILGFVFTL"),
+            DatabaseEntry::test("CASSLGQTYEQYF", "glctlvaml"),
+        ]);
+        let clonotype = |cdr3: &str, count: usize, frequency: f64| {
+            Clonotype::new(cdr3.to_string(), String::new(), String::new(), count, frequency)
+        };
+        let repertoire = vec![
+            clonotype("CASSLGQAYEQYF", 2, 0.01),
+            clonotype("CASSIRSSYEQYF", 50, 0.5),
+            clonotype("CASSLGQTYEQYF", 10, 0.1),
+            clonotype("CASSLGQSYEQYF", 10, 0.1),
+            clonotype("CAVRDGYNF", 100, 0.9),
+        ];
+        let config = MatchConfig { search_scope: SearchScope::parse("1,0,1").unwrap(), ..Default::default() };
+
+        let found = find_specific_clonotypes(&repertoire, &db, "GLCTLVAML", &config);
+        let rows: Vec<_> = found.iter().map(|f| (f.index, f.n_hits, f.best.edit_distance)).collect();
+        // Frequency ties are broken by count, then repertoire order
+        assert_eq!(rows, [(2, 2, 0), (3, 2, 1), (0, 2, 0)]);
+        assert_eq!(found[0].best.db_entry.cdr3, "CASSLGQTYEQYF");
+
+        assert!(find_specific_clonotypes(&repertoire, &db, "NLVPMVATV", &config).is_empty());
+        let exact = find_specific_clonotypes(&repertoire, &db, "GILGFVFTL", &MatchConfig::default());
+        assert_eq!(exact.iter().map(|f| f.index).collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn test_exhaustive_search_modes() {
+        let db = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML"),
+            DatabaseEntry::test("CASSLGQTYEQYF", "NLVPMVATV"),
+            DatabaseEntry::test("CASSLGQAYEQYF", "YVLDHLIVV"),
+        ]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "".into(), "".into(), 1, 0.0);
+        let mut config = MatchConfig {
+            search_scope: SearchScope::parse("1,0,0,1").unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(match_clonotype(&query, &db, &config).len(), 3);
+
+        config.exhaustive_search = 0;
+        let first = match_clonotype(&query, &db, &config);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].db_entry.antigen_epitope, "GLCTLVAML");
+        let parallel = match_clonotypes_parallel(std::slice::from_ref(&query), &db, &config);
+        assert_eq!(parallel[0].len(), 1);
+
+        config.exhaustive_search = 2;
+        let rescored = match_clonotype(&query, &db, &config);
+        assert_eq!(rescored.len(), 3);
+        let mismatch = rescored.iter().find(|m| m.edit_distance == 1).unwrap();
+        assert!((mismatch.score - (0.5 * mismatch.cdr3_alignment_score + 0.25 * mismatch.v_score + 0.25 * mismatch.j_score)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_specificity_samples() {
+        let entry = |cdr3: &str, epitope: &str, reference: &str| DatabaseEntry {
+            reference_id: Some(reference.to_string()),
+            ..DatabaseEntry::test(cdr3, epitope)
+        };
+        let db = test_database(vec![
+            entry("CASSLGQAYEQYF", "GLCTLVAML", "PMID:1"),
+            entry("CASSLGQAYEQYF", "GLCTLVAML", "PMID:2"),
+            entry("CASSLGQTYEQYF", "NLVPMVATV", "PMID:3"),
+            entry("CAVRDGGNKLTF", "GILGFVFTL", "PMID:1"),
+            entry("CASSLGQAYEQYF", "GLCTLVAML", "PMID:1"),
+        ]);
+        let config = MatchConfig { search_scope: SearchScope::parse("1,0,0,1").unwrap(), ..Default::default() };
+        // Every entry is a query once; entries of the query's own reference never count
+        let samples = specificity_samples(&db, &config, 10, &mut Rng::new(1));
+        assert_eq!(samples.len(), 10);
+        assert_eq!(samples.iter().filter(|(_, same)| *same).count(), 4);
+        assert!(samples.iter().all(|(e, same)| *same == (e.edit_distance == 0)));
+        assert_eq!(specificity_samples(&db, &config, 0, &mut Rng::new(1)).len(), 0);
+    }
+
+    /// Slow: run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_specificity_confidence_held_out() {
+        use crate::scoring::{calibration_table, specificity_confidence};
+        let db = Database::load_from_file("../../inst/extdata/vdjdb.slim.txt.gz").unwrap();
+        let config = MatchConfig {
+            search_scope: SearchScope::parse("2,1,1,2").unwrap(),
+            match_v: true,
+            match_j: true,
+            ..Default::default()
+        };
+        // The seed-1 draw behind the shipped model (first 5000 queries) and
+        // recalibration (next 20000); these 15000 queries were used for neither
+        let mut picked: Vec<usize> = (0..db.entries.len()).collect();
+        Rng::new(1).shuffle(&mut picked);
+        let samples = held_out_samples(&db, &config, &picked[25000..40000]);
+        let predicted: Vec<(f64, bool)> = samples.iter().map(|(e, y)| (specificity_confidence(e), *y)).collect();
+        let table = calibration_table(&predicted, 10);
+        let (_, top) = table.last().unwrap();
+        assert!(top.n >= 1000);
+        assert!((top.predicted - top.observed).abs() < 0.05, "{:?}", top);
+    }
+
+    #[test]
+    fn test_has_matches() {
+        let db = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML"),
+            DatabaseEntry::test("CASSPDRGNTEAFF", "NLVPMVATV"),
+        ]);
+        let queries: Vec<Clonotype> = ["casslgqayeqyf", "CASSLGQTYEQYF", "CAVRDFF", "CASSLGQAYEQYF"]
+            .iter()
+            .map(|c| Clonotype::new(c.to_string(), "".into(), "".into(), 1, 0.0))
+            .collect();
+
+        let exact = MatchConfig::default();
+        assert_eq!(has_matches(&queries, &db, &exact), vec![true, false, false, true]);
+
+        let wide = MatchConfig { search_scope: SearchScope::parse("1,0,0,1").unwrap(), ..Default::default() };
+        assert_eq!(has_matches(&queries, &db, &wide), vec![true, true, false, true]);
+
+        // A prebuilt index serves exact scopes and is ignored by wider ones
+        let index = cdr3_index(&db);
+        assert_eq!(index["CASSLGQAYEQYF"], vec![0]);
+        assert_eq!(has_matches_indexed(&queries, &db, Some(&index), &exact), vec![true, false, false, true]);
+        assert_eq!(has_matches_indexed(&queries, &db, Some(&index), &wide), vec![true, true, false, true]);
+        assert_eq!(has_matches_indexed(&queries, &db, None, &exact), vec![true, false, false, true]);
+
+        let mut other_v = queries[0].clone();
+        other_v.v_segment = "TRBV5-1".into();
+        let with_v = MatchConfig { match_v: true, ..Default::default() };
+        assert_eq!(has_matches(&[other_v], &db, &with_v), vec![false]);
+    }
+
+    #[test]
+    fn test_tokenized_database_cdr3() {
+        let db = test_database(vec![
+            DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML"),
+            DatabaseEntry::test("casslgqayeqyf", "GLCTLVAML"),
+            DatabaseEntry::test("CASSLGQ.AYEQYF", "GLCTLVAML"),
+        ]);
+        let query = Clonotype::new("CASSLGQTYEQYF".into(), "".into(), "".into(), 1, 0.0);
+        let config = MatchConfig {
+            search_scope: SearchScope::parse("1,0,0,1").unwrap(),
+            kmer_filter: Some(KmerFilter::new(3, 1)),
+            ..Default::default()
+        };
+        let matches = match_clonotype(&query, &db, &config);
+        assert_eq!(matches.len(), 3);
+        for m in &matches {
+            assert_eq!(m.edit_distance, 1);
+            assert_eq!(m.cdr3_alignment_score, matches[0].cdr3_alignment_score);
+        }
+
+        let exact = Clonotype::new("CASSLGQAYEQYF".into(), "".into(), "".into(), 1, 0.0);
+        assert_eq!(match_clonotype(&exact, &db, &MatchConfig::default()).len(), 3);
+    }
+
+    #[test]
+    fn test_query_plan() {
+        let mut class_i = DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML");
+        class_i.mhc_class = Some("MHCI".into());
+        let db = test_database(vec![class_i, DatabaseEntry::test("CASS.LGQAYEQYF", "GLCTLVAML"), DatabaseEntry::test("CASSLGQAYEQYFF", "GLCTLVAML")]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3".into(), "".into(), 1, 0.0);
+
+        // Gapped CDR3s are not ruled out by their raw length
+        let plan = QueryPlan::new(&query, &MatchConfig::default()).unwrap();
+        assert_eq!(plan.lengths, 13..=13);
+        assert!(plan.v.given && !plan.v.checked && !plan.j.given);
+        assert_eq!(match_clonotype(&query, &db, &MatchConfig::default()).len(), 2);
+
+        let wide = MatchConfig { search_scope: SearchScope::parse("0,1,0,1").unwrap(), ..Default::default() };
+        assert_eq!(QueryPlan::new(&query, &wide).unwrap().lengths, 12..=14);
+        assert_eq!(match_clonotype(&query, &db, &wide).len(), 3);
+
+        let class = |c: &str| MatchConfig { mhc_class: Some(c.into()), ..Default::default() };
+        assert_eq!(match_clonotype(&query, &db, &class("class I")).len(), 1);
+        assert!(QueryPlan::new(&query, &class("MHCIII")).is_none());
+        assert!(match_clonotype(&query, &db, &class("MHCIII")).is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_segment_calls() {
+        let db = test_database(vec![DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML")]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV6-2,TRBV12-3".into(), "TRBJ2-7".into(), 1, 0.0);
+        let config = MatchConfig { match_v: true, match_j: true, ..Default::default() };
+
+        let hits = match_clonotype(&query, &db, &config);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].matched_v.as_str(), hits[0].matched_j.as_str()), ("TRBV12-3", "TRBJ2-7"));
+        assert_eq!(hits[0].v_score, 1.0);
+
+        let unrelated = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV6-2,TRBV6-3".into(), "".into(), 1, 0.0);
+        assert!(match_clonotype(&unrelated, &db, &config).is_empty());
+
+        // Case and whitespace of segment names do not matter; blank means unset
+        for (v, j) in [("trbv12-3 ", " trbj2-7*01"), ("TRBV12-3*01\t", "TrBJ2-7"), ("  ", "TRBJ2-7")] {
+            let query = Clonotype::new("CASSLGQAYEQYF".into(), v.into(), j.into(), 1, 0.0);
+            let hits = match_clonotype(&query, &db, &config);
+            assert_eq!(hits.len(), 1, "{:?}", (v, j));
+            assert_eq!(hits[0].j_score, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_v_match_level() {
+        let db = test_database(vec![DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML")]);
+        let family = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12".into(), "".into(), 1, 0.0);
+        let other_gene = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-4".into(), "".into(), 1, 0.0);
+        let other_family = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV6-2".into(), "".into(), 1, 0.0);
+        let at = |level: VMatchLevel| MatchConfig { match_v: true, v_match_level: level, ..Default::default() };
+
+        assert!(match_clonotype(&family, &db, &at(VMatchLevel::Gene)).is_empty());
+        assert!(match_clonotype(&other_gene, &db, &at(VMatchLevel::Gene)).is_empty());
+        for query in [&family, &other_gene] {
+            let hits = match_clonotype(query, &db, &at(VMatchLevel::Family));
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].v_score, FAMILY_SEGMENT_SCORE);
+        }
+        assert!(match_clonotype(&other_family, &db, &at(VMatchLevel::Family)).is_empty());
+        assert_eq!(match_clonotype(&other_family, &db, &at(VMatchLevel::None)).len(), 1);
+
+        let allele = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3*01".into(), "".into(), 1, 0.0);
+        assert_eq!(match_clonotype(&allele, &db, &at(VMatchLevel::Gene)).len(), 1);
+        assert!(match_clonotype(&allele, &db, &at(VMatchLevel::Allele)).is_empty());
+    }
+
+    #[test]
+    fn test_restrict_chain() {
+        let mut alpha = DatabaseEntry::test("CAVRDSNYQLIW", "GLCTLVAML");
+        alpha.gene = "TRA".into();
+        let db = test_database(vec![alpha, DatabaseEntry::test("CAVRDSNYQLIW", "NLVPMVATV")]);
+        let query = Clonotype::new("CAVRDSNYQLIW".into(), "TRAV12-1".into(), "".into(), 1, 0.0);
+
+        let mut config = MatchConfig::default();
+        assert_eq!(match_clonotype(&query, &db, &config).len(), 2);
+        config.restrict_chain = true;
+        let hits = match_clonotype(&query, &db, &config);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].db_entry.gene, "TRA");
+
+        // No chain information: nothing to restrict on
+        let bare = Clonotype::new("CAVRDSNYQLIW".into(), "".into(), "".into(), 1, 0.0);
+        assert_eq!(match_clonotype(&bare, &db, &config).len(), 2);
+    }
+
+    #[test]
+    fn test_mhc_class_restriction() {
+        let mut class_i = DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML");
+        class_i.mhc_class = Some("MHCI".into());
+        let mut class_ii = DatabaseEntry::test("CASSLGQAYEQYF", "PKYVKQNTLKLAT");
+        class_ii.mhc_class = Some("MHCII".into());
+        let db = test_database(vec![class_i, class_ii, DatabaseEntry::test("CASSLGQAYEQYF", "NLVPMVATV")]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "".into(), "".into(), 1, 0.0);
+
+        let config = MatchConfig { mhc_class: Some("MHCII".into()), ..Default::default() };
+        let hits = match_clonotype(&query, &db, &config);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].db_entry.antigen_epitope, "PKYVKQNTLKLAT");
+        assert_eq!(match_clonotype(&query, &db, &MatchConfig::default()).len(), 3);
+    }
+
+    #[test]
+    fn test_missing_database_segments() {
+        let mut cdr3_only = DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML");
+        cdr3_only.v_segment = String::new();
+        let db = test_database(vec![cdr3_only]);
+        let query = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3".into(), "TRBJ2-7".into(), 1, 0.0);
+
+        let mut config = MatchConfig { match_v: true, match_j: true, ..Default::default() };
+        assert!(match_clonotype(&query, &db, &config).is_empty());
+
+        config.missing_segments = MissingSegmentPolicy::AllowAsPartial;
+        let hits = match_clonotype(&query, &db, &config);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].v_score, hits[0].j_score), (PARTIAL_SEGMENT_SCORE, 1.0));
+        assert_eq!(hits[0].matched_v, "");
+    }
+
+    #[test]
+    fn test_d_segment_matching() {
+        let mut d1 = DatabaseEntry::test("CASSLGQAYEQYF", "GLCTLVAML");
+        d1.d_segment = Some("TRBD1*01".into());
+        let mut d2 = DatabaseEntry::test("CASSLGQAYEQYF", "NLVPMVATV");
+        d2.d_segment = Some("TRBD2".into());
+        let no_d = DatabaseEntry::test("CASSLGQAYEQYF", "YLQPRTFLL");
+        let db = test_database(vec![d1, d2, no_d]);
+        let mut query = Clonotype::new("CASSLGQAYEQYF".into(), "TRBV12-3".into(), "TRBJ2-7".into(), 1, 0.0);
+        query.d_segment = Some("TRBD1*02".into());
+
+        let mut config = MatchConfig::default();
+        assert_eq!(match_clonotype(&query, &db, &config).len(), 3);
+        config.match_d = true;
+        let mut epitopes: Vec<String> =
+            match_clonotype(&query, &db, &config).into_iter().map(|m| m.db_entry.antigen_epitope).collect();
+        epitopes.sort();
+        assert_eq!(epitopes, ["GLCTLVAML", "YLQPRTFLL"]);
+        query.d_segment = None;
+        assert_eq!(match_clonotype(&query, &db, &config).len(), 3);
+
+        let with_d = query.clone();
+        let (unique, _) = dedup_clonotypes(&[with_d, Clonotype { d_segment: Some("TRBD2".into()), ..query }]);
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn test_assign_p_values() {
+        let cdr3s = ["CASSLGF", "CASSPGF", "CASRQDF", "CAVRDNF", "CSARDGF", "CASSQEF", "CAWSVGF", "CTSGRGF"];
+        let database = test_database(cdr3s.iter().map(|c| DatabaseEntry::test(c, "GLCTLVAML")).collect());
+        let config = MatchConfig { search_scope: SearchScope::parse("3,0,0,3").unwrap(), ..Default::default() };
+        let query = Clonotype::new("CASSLGF".into(), String::new(), String::new(), 1, 0.0);
+        let mut hits = vec![match_clonotype(&query, &database, &config)];
+        assert!(hits[0].iter().all(|m| m.p_value.is_none()));
+
+        assign_p_values(&mut hits, &score_null(&database), &ResidueHandling::default());
+        let exact = hits[0].iter().find(|m| m.edit_distance == 0).unwrap();
+        assert!(hits[0].iter().all(|m| m.p_value.unwrap() >= exact.p_value.unwrap()));
+        assert!(hits[0].iter().all(|m| m.q_value.unwrap() >= m.p_value.unwrap()));
+    }
 }
@@ -0,0 +1,279 @@
+use crate::database::Database;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Amino acids in the column order of every matrix here
+pub const AMINO_ACIDS: &[u8; 20] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// How CDR3s of different lengths are put on common positions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogoAlignment {
+    /// Only sequences of the most common length, positions "1".."L"
+    ModalLength,
+    /// All sequences on IMGT junction positions 104-118, gaps and insertions
+    /// (111.1, 112.1, ...) in the middle
+    ImgtGapped,
+}
+
+impl LogoAlignment {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "modal" | "modal_length" | "length" => Ok(LogoAlignment::ModalLength),
+            "imgt" | "gapped" | "imgt_gapped" => Ok(LogoAlignment::ImgtGapped),
+            other => Err(format!("unknown alignment '{}': use \"modal\" or \"imgt\"", other)),
+        }
+    }
+}
+
+/// IMGT position labels of the residues of a junction (CDR3 with its conserved
+/// C and F/W) of `len` residues
+/// Up to 15 residues fill 104-111 from the left and 118-112 from the right,
+/// leaving gaps at 111/112 first; longer junctions insert 111.1, 112.1, 111.2, ...
+pub fn imgt_positions(len: usize) -> Vec<String> {
+    let (left, right) = if len <= 15 {
+        ((len + 1) / 2, len / 2)
+    } else {
+        (8 + (len - 14) / 2, 7 + (len - 15) / 2)
+    };
+    let mut labels: Vec<String> = (0..left)
+        .map(|k| if k < 8 { (104 + k).to_string() } else { format!("111.{}", k - 7) })
+        .collect();
+    labels.extend(
+        (0..right)
+            .rev()
+            .map(|j| if j < 7 { (118 - j).to_string() } else { format!("112.{}", j - 6) }),
+    );
+    labels
+}
+
+/// Sort key putting IMGT labels in sequence order (111 < 111.1 < 112.1 < 112)
+fn position_order(label: &str) -> (u32, i64) {
+    let (base, insertion) = label.split_once('.').unwrap_or((label, "0"));
+    let base: u32 = base.parse().unwrap_or(0);
+    let insertion: i64 = insertion.parse().unwrap_or(0);
+    (base, if base >= 112 { -insertion } else { insertion })
+}
+
+/// Most common length, ties towards the shorter
+pub fn modal_length(seqs: &[&str]) -> Option<usize> {
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for s in seqs {
+        *counts.entry(s.len()).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+        .map(|(len, _)| len)
+}
+
+/// Position x amino acid residue counts of a set of CDR3s
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogoMatrix {
+    /// Position labels, in sequence order
+    pub positions: Vec<String>,
+    /// Counts per position, columns in `AMINO_ACIDS` order
+    pub counts: Vec<[usize; 20]>,
+    /// Sequences contributing (all of them, or those of the modal length)
+    pub n_sequences: usize,
+}
+
+impl LogoMatrix {
+    /// Count residues of `seqs` on positions given by `alignment`; with
+    /// `ModalLength`, `length` (default: the modal length) selects the sequences
+    /// Residues outside the 20 standard amino acids are not counted.
+    pub fn from_sequences(seqs: &[&str], alignment: LogoAlignment, length: Option<usize>) -> Self {
+        let mut by_position: HashMap<String, [usize; 20]> = HashMap::new();
+        let mut n_sequences = 0;
+        let length = length.or_else(|| modal_length(seqs));
+
+        for s in seqs {
+            let labels = match alignment {
+                LogoAlignment::ModalLength if Some(s.len()) != length => continue,
+                LogoAlignment::ModalLength => (1..=s.len()).map(|i| i.to_string()).collect(),
+                LogoAlignment::ImgtGapped => imgt_positions(s.len()),
+            };
+            n_sequences += 1;
+            for (label, residue) in labels.into_iter().zip(s.bytes()) {
+                let column = by_position.entry(label).or_insert([0; 20]);
+                if let Some(a) = AMINO_ACIDS.iter().position(|&aa| aa == residue.to_ascii_uppercase()) {
+                    column[a] += 1;
+                }
+            }
+        }
+        if alignment == LogoAlignment::ModalLength {
+            // Keep every position of the selected length, even with no sequences
+            for i in 1..=length.unwrap_or(0) {
+                by_position.entry(i.to_string()).or_insert([0; 20]);
+            }
+        }
+
+        let mut positions: Vec<String> = by_position.keys().cloned().collect();
+        positions.sort_by_key(|label| position_order(label));
+        let counts = positions.iter().map(|p| by_position[p]).collect();
+        Self { positions, counts, n_sequences }
+    }
+
+    /// Residue frequencies at position `i` (zeros when nothing was counted)
+    pub fn frequencies(&self, i: usize) -> [f64; 20] {
+        let total: usize = self.counts[i].iter().sum();
+        let mut freq = [0.0; 20];
+        if total > 0 {
+            for (f, &c) in freq.iter_mut().zip(&self.counts[i]) {
+                *f = c as f64 / total as f64;
+            }
+        }
+        freq
+    }
+
+    /// Frequency of the most common residue at each position
+    pub fn conservation(&self) -> Vec<f64> {
+        (0..self.positions.len())
+            .map(|i| self.frequencies(i).iter().copied().fold(0.0, f64::max))
+            .collect()
+    }
+
+    /// Information content (bits, log2(20) minus the Shannon entropy) per position
+    pub fn information(&self) -> Vec<f64> {
+        (0..self.positions.len())
+            .map(|i| {
+                let freq = self.frequencies(i);
+                if freq.iter().all(|&f| f == 0.0) {
+                    return 0.0;
+                }
+                let entropy: f64 = freq.iter().filter(|&&f| f > 0.0).map(|&f| -f * f.log2()).sum();
+                20f64.log2() - entropy
+            })
+            .collect()
+    }
+}
+
+/// Logo matrix of the distinct CDR3s of each epitope with at least `min_size`
+/// of them, largest epitopes first
+pub fn epitope_logos(db: &Database, min_size: usize, alignment: LogoAlignment) -> Vec<(String, LogoMatrix)> {
+    let mut cdr3s: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for entry in &db.entries {
+        if !entry.cdr3.is_empty() {
+            cdr3s.entry(entry.antigen_epitope.as_str()).or_default().insert(entry.cdr3.as_str());
+        }
+    }
+    let mut epitopes: Vec<(&str, Vec<&str>)> = cdr3s
+        .into_iter()
+        .filter(|(_, seqs)| seqs.len() >= min_size.max(1))
+        .map(|(epitope, seqs)| {
+            let mut seqs: Vec<&str> = seqs.into_iter().collect();
+            seqs.sort_unstable();
+            (epitope, seqs)
+        })
+        .collect();
+    epitopes.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(b.0)));
+
+    epitopes
+        .into_iter()
+        .map(|(epitope, seqs)| (epitope.to_string(), LogoMatrix::from_sequences(&seqs, alignment, None)))
+        .collect()
+}
+
+/// Per-position amino acid enrichment of a foreground over a background set
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotifEnrichment {
+    pub positions: Vec<String>,
+    /// log2 ratio of pseudocount-smoothed residue frequencies, `AMINO_ACIDS` order
+    pub log_odds: Vec<[f64; 20]>,
+    pub foreground: LogoMatrix,
+    pub background: LogoMatrix,
+}
+
+/// Log-odds enrichment of residues in `foreground` CDR3s over `background` ones
+/// (e.g. annotated vs non-annotated queries, or epitope A vs B)
+/// With `ModalLength` both sets are restricted to the foreground's modal length.
+/// Frequencies are smoothed as (count + pseudocount) / (n + 20 * pseudocount) per
+/// position, so positions seen in one set only get finite scores.
+pub fn discriminative_motif(
+    foreground: &[&str],
+    background: &[&str],
+    alignment: LogoAlignment,
+    pseudocount: f64,
+) -> MotifEnrichment {
+    let length = modal_length(foreground);
+    let fg = LogoMatrix::from_sequences(foreground, alignment, length);
+    let bg = LogoMatrix::from_sequences(background, alignment, length);
+
+    let mut positions: Vec<String> = fg.positions.clone();
+    positions.extend(bg.positions.iter().filter(|p| !fg.positions.contains(p)).cloned());
+    positions.sort_by_key(|label| position_order(label));
+
+    let smoothed = |logo: &LogoMatrix, position: &str| -> [f64; 20] {
+        let counts = logo.positions.iter().position(|p| p == position).map_or([0; 20], |i| logo.counts[i]);
+        let total = counts.iter().sum::<usize>() as f64 + 20.0 * pseudocount;
+        let mut freq = [0.0; 20];
+        for (f, &c) in freq.iter_mut().zip(&counts) {
+            *f = if total > 0.0 { (c as f64 + pseudocount) / total } else { 0.0 };
+        }
+        freq
+    };
+    let log_odds = positions
+        .iter()
+        .map(|position| {
+            let (f, b) = (smoothed(&fg, position), smoothed(&bg, position));
+            let mut scores = [0.0; 20];
+            for a in 0..20 {
+                scores[a] = if f[a] > 0.0 && b[a] > 0.0 { (f[a] / b[a]).log2() } else { 0.0 };
+            }
+            scores
+        })
+        .collect();
+
+    MotifEnrichment { positions, log_odds, foreground: fg, background: bg }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imgt_positions() {
+        assert_eq!(imgt_positions(13).first().map(String::as_str), Some("104"));
+        assert_eq!(imgt_positions(13).last().map(String::as_str), Some("118"));
+        assert_eq!(imgt_positions(15).len(), 15);
+        assert!(!imgt_positions(14).contains(&"111".to_string()));
+        let long = imgt_positions(17);
+        assert_eq!(&long[7..10], ["111", "111.1", "112.1"]);
+        let mut sorted = long.clone();
+        sorted.sort_by_key(|l| position_order(l));
+        assert_eq!(sorted, long);
+    }
+
+    #[test]
+    fn test_logo_matrix() {
+        let seqs = ["CASSF", "CASTF", "CAF", "CATSSF"];
+        let modal = LogoMatrix::from_sequences(&seqs, LogoAlignment::ModalLength, None);
+        assert_eq!(modal.n_sequences, 2);
+        assert_eq!(modal.positions, ["1", "2", "3", "4", "5"]);
+        assert_eq!(modal.conservation(), [1.0, 1.0, 1.0, 0.5, 1.0]);
+        assert!((modal.information()[0] - 20f64.log2()).abs() < 1e-12);
+        assert!((modal.information()[3] - (20f64.log2() - 1.0)).abs() < 1e-12);
+
+        let gapped = LogoMatrix::from_sequences(&seqs, LogoAlignment::ImgtGapped, None);
+        assert_eq!(gapped.n_sequences, 4);
+        assert_eq!(gapped.positions.first().map(String::as_str), Some("104"));
+        assert_eq!(gapped.positions.last().map(String::as_str), Some("118"));
+        let c = AMINO_ACIDS.iter().position(|&a| a == b'C').unwrap();
+        assert_eq!(gapped.counts[0][c], 4);
+    }
+
+    #[test]
+    fn test_discriminative_motif() {
+        let fg = ["CASSLF", "CASSLF", "CASRLF"];
+        let bg = ["CASSGF", "CASSGF", "CASSQF", "CASSLF"];
+        let motif = discriminative_motif(&fg, &bg, LogoAlignment::ModalLength, 0.5);
+        assert_eq!(motif.positions.len(), 6);
+        assert_eq!((motif.foreground.n_sequences, motif.background.n_sequences), (3, 4));
+        let aa = |c: u8| AMINO_ACIDS.iter().position(|&a| a == c).unwrap();
+        // L at position 5: 3/3 in the foreground vs 1/4 in the background
+        let expected = ((3.5_f64 / 13.0) / (1.5 / 14.0)).log2();
+        assert!((motif.log_odds[4][aa(b'L')] - expected).abs() < 1e-12);
+        assert!(motif.log_odds[4][aa(b'G')] < 0.0);
+        // C is fixed in both sets, but smoothing weighs the smaller foreground less
+        let fixed = ((3.5_f64 / 13.0) / (4.5 / 14.0)).log2();
+        assert!((motif.log_odds[0][aa(b'C')] - fixed).abs() < 1e-12);
+    }
+}
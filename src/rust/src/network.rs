@@ -0,0 +1,183 @@
+use crate::database::DatabaseEntry;
+use crate::matching::{dedup_clonotypes, BatchMatches};
+use std::collections::HashMap;
+
+/// What query nodes of a hit network link to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkTarget {
+    /// One node per database entry hit
+    Entry,
+    /// One node per epitope; hits of a query on several entries of the
+    /// epitope become a single edge
+    Epitope,
+}
+
+impl NetworkTarget {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "entry" => Ok(Self::Entry),
+            "epitope" => Ok(Self::Epitope),
+            _ => Err(format!("Invalid network target: {} (expected entry or epitope)", s)),
+        }
+    }
+}
+
+/// A node of a hit network
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkNode {
+    /// Unique id, prefixed by the node type: "Q<first query>", "D<database
+    /// row>" (or "D:<cdr3>:<epitope>" for entries without a row) or "E:<epitope>"
+    pub id: String,
+    /// "query", "entry" or "epitope"
+    pub kind: &'static str,
+    /// CDR3 of query and entry nodes, the epitope of epitope nodes
+    pub label: String,
+    pub epitope: String,
+    /// Queries collapsed into a query node (identical CDR3/V/J/D); 0 otherwise
+    pub copies: usize,
+}
+
+/// A query-target edge
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkEdge {
+    pub source: String,
+    pub target: String,
+    /// Best hit score between the two nodes
+    pub weight: f64,
+    /// Smallest CDR3 edit distance between the two nodes
+    pub edit_distance: usize,
+    /// Hits merged into the edge (several entries of an epitope)
+    pub n_hits: usize,
+}
+
+/// Bipartite query-database graph of a batch result
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HitNetwork {
+    pub nodes: Vec<NetworkNode>,
+    pub edges: Vec<NetworkEdge>,
+}
+
+fn entry_id(entry: &DatabaseEntry) -> String {
+    if entry.row_id > 0 {
+        format!("D{}", entry.row_id)
+    } else {
+        format!("D:{}:{}", entry.cdr3, entry.antigen_epitope)
+    }
+}
+
+/// Build the hit network of a batch result
+/// Identical queries share a node (named after their first occurrence), so
+/// repeated cells do not duplicate edges. Query nodes without hits are left
+/// out; nodes appear in order of first hit, query nodes before targets.
+pub fn hit_network(batch: &BatchMatches, target: NetworkTarget) -> HitNetwork {
+    let (unique, mapping) = dedup_clonotypes(&batch.queries);
+    let mut first = vec![usize::MAX; unique.len()];
+    let mut copies = vec![0usize; unique.len()];
+    for (i, &u) in mapping.iter().enumerate() {
+        first[u] = first[u].min(i);
+        copies[u] += 1;
+    }
+
+    let mut query_nodes = Vec::new();
+    let mut target_nodes: Vec<NetworkNode> = Vec::new();
+    let mut target_index: HashMap<String, usize> = HashMap::new();
+    let mut edges: Vec<NetworkEdge> = Vec::new();
+    for (u, &i) in first.iter().enumerate() {
+        let hits = batch.query_hits(i);
+        if hits.is_empty() {
+            continue;
+        }
+        let source = format!("Q{}", i + 1);
+        query_nodes.push(NetworkNode {
+            id: source.clone(),
+            kind: "query",
+            label: unique[u].cdr3_aa.sequence.clone(),
+            epitope: String::new(),
+            copies: copies[u],
+        });
+
+        let mut edge_index: HashMap<String, usize> = HashMap::new();
+        for m in hits {
+            let e = &m.db_entry;
+            let (id, kind, label) = match target {
+                NetworkTarget::Entry => (entry_id(e), "entry", e.cdr3.clone()),
+                NetworkTarget::Epitope => (format!("E:{}", e.antigen_epitope), "epitope", e.antigen_epitope.clone()),
+            };
+            if !target_index.contains_key(&id) {
+                target_index.insert(id.clone(), target_nodes.len());
+                target_nodes.push(NetworkNode {
+                    id: id.clone(),
+                    kind,
+                    label,
+                    epitope: e.antigen_epitope.clone(),
+                    copies: 0,
+                });
+            }
+            match edge_index.get(&id) {
+                Some(&k) => {
+                    let edge = &mut edges[k];
+                    edge.weight = edge.weight.max(m.score);
+                    edge.edit_distance = edge.edit_distance.min(m.edit_distance);
+                    edge.n_hits += 1;
+                }
+                None => {
+                    edge_index.insert(id.clone(), edges.len());
+                    edges.push(NetworkEdge {
+                        source: source.clone(),
+                        target: id,
+                        weight: m.score,
+                        edit_distance: m.edit_distance,
+                        n_hits: 1,
+                    });
+                }
+            }
+        }
+    }
+
+    query_nodes.extend(target_nodes);
+    HitNetwork { nodes: query_nodes, edges }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseMetadata};
+    use crate::matching::{match_clonotypes_parallel, MatchConfig};
+    use crate::sequence::{Clonotype, SearchScope};
+
+    #[test]
+    fn test_hit_network() {
+        let entry = |cdr3: &str, epitope: &str, row_id: usize| DatabaseEntry {
+            row_id,
+            ..DatabaseEntry::test(cdr3, epitope)
+        };
+        let database = Database {
+            entries: vec![
+                entry("CASSLGQAYEQYF", "GLCTLVAML", 3),
+                entry("CASSLGQTYEQYF", "GLCTLVAML", 7),
+                entry("CASSLGQAYEQYF", "NLVPMVATV", 0),
+            ],
+            metadata: DatabaseMetadata::default(),
+        };
+        let clonotype = |cdr3: &str| Clonotype::new(cdr3.to_string(), String::new(), String::new(), 1, 0.0);
+        let queries = vec![clonotype("CASSLGQAYEQYF"), clonotype("CAVRDF"), clonotype("CASSLGQAYEQYF")];
+        let config = MatchConfig { search_scope: SearchScope::parse("1,0,1").unwrap(), ..Default::default() };
+        let (unique, mapping) = dedup_clonotypes(&queries);
+        let batch = BatchMatches::new(queries, mapping, match_clonotypes_parallel(&unique, &database, &config));
+
+        let network = hit_network(&batch, NetworkTarget::Entry);
+        let ids: Vec<&str> = network.nodes.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, ["Q1", "D3", "D7", "D:CASSLGQAYEQYF:NLVPMVATV"]);
+        assert_eq!(network.nodes[0].copies, 2);
+        assert_eq!(network.edges.len(), 3);
+
+        let network = hit_network(&batch, NetworkTarget::Epitope);
+        let edges: Vec<(&str, &str, usize, usize)> = network
+            .edges
+            .iter()
+            .map(|e| (e.source.as_str(), e.target.as_str(), e.n_hits, e.edit_distance))
+            .collect();
+        assert_eq!(edges, [("Q1", "E:GLCTLVAML", 2, 0), ("Q1", "E:NLVPMVATV", 1, 0)]);
+        assert!(NetworkTarget::parse("cluster").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+/// Built-in rollups of VDJdb antigen species/genes into pathogen families and
+/// protein-level groups: (antigen species, antigen gene or "" for any gene,
+/// family, group or "" to use the family)
+const BUILTIN_RULES: &[(&str, &str, &str, &str)] = &[
+    ("CMV", "", "Herpesviridae", ""),
+    ("EBV", "", "Herpesviridae", ""),
+    ("VZV", "", "Herpesviridae", ""),
+    ("HSV-1", "", "Herpesviridae", ""),
+    ("HSV-2", "", "Herpesviridae", ""),
+    ("HHV", "", "Herpesviridae", ""),
+    ("KSHV", "", "Herpesviridae", ""),
+    ("MCMV", "", "Herpesviridae", ""),
+    ("InfluenzaA", "", "Orthomyxoviridae", ""),
+    ("InfluenzaB", "", "Orthomyxoviridae", ""),
+    ("HIV", "", "Retroviridae", ""),
+    ("HIV-1", "", "Retroviridae", ""),
+    ("SIV", "", "Retroviridae", ""),
+    ("HTLV-1", "", "Retroviridae", ""),
+    ("SARS-CoV-2", "", "Coronaviridae", ""),
+    ("SARS-CoV", "", "Coronaviridae", ""),
+    ("HCoV-HKU1", "", "Coronaviridae", ""),
+    ("HCoV-OC43", "", "Coronaviridae", ""),
+    ("HCoV-229E", "", "Coronaviridae", ""),
+    ("HCoV-NL63", "", "Coronaviridae", ""),
+    ("HCV", "", "Flaviviridae", ""),
+    ("YFV", "", "Flaviviridae", ""),
+    ("DENV", "", "Flaviviridae", ""),
+    ("ZIKV", "", "Flaviviridae", ""),
+    ("LCMV", "", "Arenaviridae", ""),
+    ("RSV", "", "Pneumoviridae", ""),
+    ("RotavirusA", "", "Reoviridae", ""),
+    ("CoxsackievirusB", "", "Picornaviridae", ""),
+    ("VSV", "", "Rhabdoviridae", ""),
+    ("HPV", "", "Papillomaviridae", ""),
+    ("HPV-16", "", "Papillomaviridae", ""),
+    ("AdV", "", "Adenoviridae", ""),
+    ("MCPyV", "", "Polyomaviridae", ""),
+    ("HBV", "", "Hepadnaviridae", ""),
+    ("M.tuberculosis", "", "Mycobacteriaceae", ""),
+    ("PlasmodiumFalciparum", "", "Plasmodiidae", ""),
+    ("PlasmodiumBerghei", "", "Plasmodiidae", ""),
+    ("Trypanosoma cruzi", "", "Trypanosomatidae", ""),
+    ("HomoSapiens", "", "Self", ""),
+    ("MusMusculus", "", "Self", ""),
+    ("SARS-CoV-2", "Spike", "Coronaviridae", "SARS-CoV-2 structural"),
+    ("SARS-CoV-2", "Nucleocapsid", "Coronaviridae", "SARS-CoV-2 structural"),
+    ("SARS-CoV-2", "RNP", "Coronaviridae", "SARS-CoV-2 structural"),
+    ("SARS-CoV-2", "Matrix", "Coronaviridae", "SARS-CoV-2 structural"),
+    ("SARS-CoV-2", "Envelope", "Coronaviridae", "SARS-CoV-2 structural"),
+    ("SARS-CoV-2", "ORF1ab", "Coronaviridae", "SARS-CoV-2 non-structural"),
+    ("SARS-CoV-2", "NSP*", "Coronaviridae", "SARS-CoV-2 non-structural"),
+    ("SARS-CoV-2", "ORF3*", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF6", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF7*", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF8", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF9b", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF10", "Coronaviridae", "SARS-CoV-2 accessory"),
+    ("SARS-CoV-2", "ORF14", "Coronaviridae", "SARS-CoV-2 accessory"),
+];
+
+/// Maps an antigen species, and optionally gene, to a family and/or group
+#[derive(Debug, Clone, PartialEq)]
+pub struct AntigenRule {
+    pub species: String,
+    /// Gene the rule is limited to (a trailing '*' matches any suffix); None for any gene
+    pub gene: Option<String>,
+    pub family: Option<String>,
+    pub group: Option<String>,
+}
+
+impl AntigenRule {
+    fn matches(&self, species: &str, gene: &str) -> bool {
+        if !self.species.trim().eq_ignore_ascii_case(species) {
+            return false;
+        }
+        match self.gene.as_deref().map(str::trim) {
+            None => true,
+            Some(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => gene.get(..prefix.len()).is_some_and(|g| g.eq_ignore_ascii_case(prefix)),
+                None => pattern.eq_ignore_ascii_case(gene),
+            },
+        }
+    }
+}
+
+/// Antigen family/group lookup: user rules take precedence over the built-in
+/// ones, and within each, gene-level rules over species-level rules
+#[derive(Debug, Clone)]
+pub struct AntigenOntology {
+    user: Vec<AntigenRule>,
+    builtin: Vec<AntigenRule>,
+}
+
+impl Default for AntigenOntology {
+    fn default() -> Self {
+        Self::with_user_rules(Vec::new())
+    }
+}
+
+impl AntigenOntology {
+    /// Built-in rules extended (and overridden) by `user`
+    pub fn with_user_rules(user: Vec<AntigenRule>) -> Self {
+        Self { user, builtin: builtin_rules() }
+    }
+
+    /// Family of an antigen; the antigen species itself when no rule applies
+    pub fn family(&self, species: &str, gene: &str) -> String {
+        self.lookup(species, gene, |r| r.family.as_deref()).unwrap_or_else(|| species.trim().to_string())
+    }
+
+    /// Protein-level group of an antigen, falling back to its family
+    pub fn group(&self, species: &str, gene: &str) -> String {
+        self.lookup(species, gene, |r| r.group.as_deref()).unwrap_or_else(|| self.family(species, gene))
+    }
+
+    fn lookup<'a>(&'a self, species: &str, gene: &str, field: impl Fn(&'a AntigenRule) -> Option<&'a str>) -> Option<String> {
+        let (species, gene) = (species.trim(), gene.trim());
+        [&self.user, &self.builtin]
+            .into_iter()
+            .find_map(|rules| {
+                let applicable = rules.iter().filter(|r| r.matches(species, gene));
+                let by_gene = applicable.clone().filter(|r| r.gene.is_some()).find_map(&field);
+                by_gene.or_else(|| applicable.clone().find_map(&field))
+            })
+            .map(str::to_string)
+    }
+}
+
+/// The built-in rules, in table order
+pub fn builtin_rules() -> Vec<AntigenRule> {
+    let some = |s: &str| (!s.is_empty()).then(|| s.to_string());
+    BUILTIN_RULES
+        .iter()
+        .map(|&(species, gene, family, group)| AntigenRule {
+            species: species.to_string(),
+            gene: some(gene),
+            family: some(family),
+            group: some(group),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_antigen_ontology() {
+        let ontology = AntigenOntology::default();
+        assert_eq!(ontology.family("CMV", "pp65"), "Herpesviridae");
+        assert_eq!(ontology.group("EBV", "BMLF1"), "Herpesviridae");
+        assert_eq!(ontology.group("SARS-CoV-2", "Spike"), "SARS-CoV-2 structural");
+        // VDJdb carries stray whitespace and varying case
+        assert_eq!(ontology.group("SARS-CoV-2", "Nucleocapsid "), "SARS-CoV-2 structural");
+        assert_eq!(ontology.group("sars-cov-2", "nsp12"), "SARS-CoV-2 non-structural");
+        assert_eq!(ontology.group("SARS-CoV-2", "ORF3a"), "SARS-CoV-2 accessory");
+        assert_eq!(ontology.group("SARS-CoV-2", "Unknown"), "Coronaviridae");
+        assert_eq!(ontology.family("Wheat", "gliadin"), "Wheat");
+
+        let user = AntigenOntology::with_user_rules(vec![
+            AntigenRule { species: "CMV".into(), gene: Some("pp65".into()), family: None, group: Some("CMV pp65".into()) },
+            AntigenRule { species: "Wheat".into(), gene: None, family: Some("Allergen".into()), group: None },
+        ]);
+        assert_eq!(user.group("CMV", "pp65"), "CMV pp65");
+        assert_eq!(user.family("CMV", "pp65"), "Herpesviridae");
+        assert_eq!(user.group("CMV", "IE1"), "Herpesviridae");
+        assert_eq!(user.group("Wheat", "gliadin"), "Allergen");
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small seedable pseudo-random generator (SplitMix64)
+/// Deterministic for a given seed on every platform, which is all the
+/// stochastic utilities here need; not suitable for cryptographic use.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform integer in [0, n); `n` must be positive
+    pub fn below(&mut self, n: usize) -> usize {
+        // Multiply-shift keeps the bias negligible for the sizes used here
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// Fisher-Yates shuffle in place
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+/// Session-wide generator that seeds stochastic functions called without a seed
+static SESSION: Mutex<Option<Rng>> = Mutex::new(None);
+
+/// Seed the session-wide generator; None reseeds it from the clock
+/// Calls without an explicit seed are then reproducible as a sequence, like R's
+/// `set.seed()`.
+pub fn set_seed(seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(clock_seed);
+    *SESSION.lock().unwrap_or_else(|e| e.into_inner()) = Some(Rng::new(seed));
+}
+
+/// Generator for one stochastic call: from `seed` when given, otherwise from the
+/// next draw of the session-wide generator (seeded from the clock until `set_seed`)
+pub fn rng_for(seed: Option<u64>) -> Rng {
+    match seed {
+        Some(seed) => Rng::new(seed),
+        None => {
+            let mut session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+            let rng = session.get_or_insert_with(|| Rng::new(clock_seed()));
+            Rng::new(rng.next_u64())
+        }
+    }
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let xs: Vec<u64> = (0..5).map(|_| a.next_u64()).collect();
+        let ys: Vec<u64> = (0..5).map(|_| b.next_u64()).collect();
+        assert_eq!(xs, ys);
+        assert_ne!(Rng::new(43).next_u64(), xs[0]);
+
+        let mut items: Vec<usize> = (0..10).collect();
+        Rng::new(7).shuffle(&mut items);
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+
+        let mut rng = Rng::new(1);
+        assert!((0..1000).all(|_| rng.below(3) < 3 && (0.0..1.0).contains(&rng.next_f64())));
+    }
+
+    #[test]
+    fn test_session_seed() {
+        set_seed(Some(11));
+        let first: Vec<u64> = (0..3).map(|_| rng_for(None).next_u64()).collect();
+        set_seed(Some(11));
+        let again: Vec<u64> = (0..3).map(|_| rng_for(None).next_u64()).collect();
+        assert_eq!(first, again);
+        assert_ne!(first[0], first[1]);
+        assert_eq!(rng_for(Some(5)).next_u64(), Rng::new(5).next_u64());
+    }
+}
@@ -0,0 +1,323 @@
+//! Germline reference data: V gene CDR1/CDR2 sequences, gene names of other
+//! nomenclatures (Adaptive ImmunoSEQ) and species synonyms
+//!
+//! Built-in data covers the standard names; genes, aliases and synonyms
+//! registered at runtime extend it for the rest of the session, so a
+//! non-standard or newly named allele can be described instead of failing.
+use crate::sequence::{canonical_segment, segment_calls, segment_family, split_allele, VMatchLevel};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// CDR1 and CDR2 (amino acids) of a V gene or allele
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VGene {
+    /// IMGT name, with or without allele ("TRBV19", "TRBV19*01")
+    pub name: String,
+    pub cdr1: String,
+    pub cdr2: String,
+    /// VDJdb species name ("HomoSapiens")
+    pub species: String,
+}
+
+/// Species synonyms, compared lowercase with spaces, "_" and "." removed
+const SPECIES_SYNONYMS: &[(&str, &str)] = &[
+    ("homosapiens", "HomoSapiens"),
+    ("human", "HomoSapiens"),
+    ("hsa", "HomoSapiens"),
+    ("hs", "HomoSapiens"),
+    ("musmusculus", "MusMusculus"),
+    ("mouse", "MusMusculus"),
+    ("mmu", "MusMusculus"),
+    ("mm", "MusMusculus"),
+    ("macacamulatta", "MacacaMulatta"),
+    ("macaque", "MacacaMulatta"),
+    ("rhesus", "MacacaMulatta"),
+    ("rhesusmacaque", "MacacaMulatta"),
+];
+
+/// Human TRB genes with a single member, named without "-1" in IMGT but
+/// numbered "-01" by Adaptive ("TCRBV09-01" is TRBV9)
+const SINGLE_MEMBER_TRB: &[&str] = &[
+    "TRBV2", "TRBV9", "TRBV13", "TRBV14", "TRBV15", "TRBV16", "TRBV17", "TRBV18", "TRBV19", "TRBV27",
+    "TRBV28", "TRBV30", "TRBD1", "TRBD2",
+];
+
+#[derive(Default)]
+struct Registry {
+    /// By (species, name as registered, uppercase)
+    v_genes: HashMap<(String, String), VGene>,
+    /// Gene name aliases (uppercase) to IMGT names
+    gene_aliases: HashMap<String, String>,
+    /// Normalized species synonyms to VDJdb names
+    species: HashMap<String, String>,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<Registry> = RwLock::new(Registry::default());
+}
+
+fn species_key(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '.' | '-'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// VDJdb species name for a species or synonym ("human", "Homo sapiens",
+/// "MusMusculus"); None for unknown names
+pub fn canonical_species(name: &str) -> Option<String> {
+    let key = species_key(name);
+    if let Some(species) = REGISTRY.read().ok().and_then(|r| r.species.get(&key).cloned()) {
+        return Some(species);
+    }
+    SPECIES_SYNONYMS
+        .iter()
+        .find(|(synonym, _)| *synonym == key)
+        .map(|(_, species)| species.to_string())
+}
+
+/// Register `alias` as another name of `species` (a VDJdb species name or a
+/// known synonym of one)
+pub fn register_species_synonym(alias: &str, species: &str) -> Result<(), String> {
+    let key = species_key(alias);
+    if key.is_empty() {
+        return Err("species alias must not be empty".into());
+    }
+    let species = canonical_species(species).unwrap_or_else(|| species.trim().to_string());
+    if species.is_empty() {
+        return Err("species must not be empty".into());
+    }
+    REGISTRY.write().map_err(|e| e.to_string())?.species.insert(key, species);
+    Ok(())
+}
+
+fn is_amino_acids(seq: &str) -> bool {
+    seq.bytes().all(|b| b.is_ascii_uppercase() && !matches!(b, b'B' | b'J' | b'O' | b'U' | b'X' | b'Z'))
+}
+
+/// Register the CDR1 and CDR2 of a V gene or allele of `species`, replacing an
+/// earlier registration of the same name
+pub fn register_v_gene(name: &str, cdr1: &str, cdr2: &str, species: &str) -> Result<VGene, String> {
+    let name = name.trim().to_uppercase();
+    if split_allele(&name).0.is_empty() || !name.contains('V') {
+        return Err(format!("not a V gene name: \"{}\"", name));
+    }
+    let (cdr1, cdr2) = (cdr1.trim().to_uppercase(), cdr2.trim().to_uppercase());
+    for (region, seq) in [("cdr1", &cdr1), ("cdr2", &cdr2)] {
+        if seq.is_empty() || !is_amino_acids(seq) {
+            return Err(format!("{} of {} must be an amino acid sequence, got \"{}\"", region, name, seq));
+        }
+    }
+    let species = canonical_species(species).unwrap_or_else(|| species.trim().to_string());
+    let gene = VGene { name: name.clone(), cdr1, cdr2, species: species.clone() };
+    REGISTRY.write().map_err(|e| e.to_string())?.v_genes.insert((species, name), gene.clone());
+    Ok(gene)
+}
+
+/// CDR1/CDR2 of a V segment of `species`: the allele's entry, else the
+/// gene's (registered without allele), else any allele of the gene
+pub fn v_gene_cdrs(segment: &str, species: &str) -> Option<VGene> {
+    let segment = imgt_gene_name(segment.trim());
+    let species = canonical_species(species).unwrap_or_else(|| species.trim().to_string());
+    let registry = REGISTRY.read().ok()?;
+    let find = |name: &str| registry.v_genes.get(&(species.clone(), name.to_string())).cloned();
+    let (gene, _) = split_allele(&segment);
+    find(&segment).or_else(|| find(gene)).or_else(|| {
+        registry
+            .v_genes
+            .values()
+            .filter(|g| g.species == species && split_allele(&g.name).0 == gene)
+            .min_by(|a, b| a.name.cmp(&b.name))
+            .cloned()
+    })
+}
+
+/// Registered V genes, sorted by species and name
+pub fn registered_v_genes() -> Vec<VGene> {
+    let mut genes: Vec<VGene> = REGISTRY.read().map(|r| r.v_genes.values().cloned().collect()).unwrap_or_default();
+    genes.sort_by(|a, b| (&a.species, &a.name).cmp(&(&b.species, &b.name)));
+    genes
+}
+
+/// Register `alias` as another name of the IMGT gene or allele `imgt`
+pub fn register_gene_alias(alias: &str, imgt: &str) -> Result<(), String> {
+    let (alias, imgt) = (alias.trim().to_uppercase(), imgt.trim().to_uppercase());
+    if alias.is_empty() || imgt.is_empty() {
+        return Err("gene alias and IMGT name must not be empty".into());
+    }
+    REGISTRY.write().map_err(|e| e.to_string())?.gene_aliases.insert(alias, imgt);
+    Ok(())
+}
+
+/// IMGT name of an Adaptive ImmunoSEQ gene name ("TCRBV05-01*01" -> "TRBV5-1*01",
+/// "TCRBV09-01" -> "TRBV9"); None for other names
+pub fn adaptive_to_imgt(name: &str) -> Option<String> {
+    let rest = name.trim().to_uppercase().strip_prefix("TCR")?.to_string();
+    let (gene, allele) = split_allele(&rest);
+    let mut chars = gene.chars();
+    let locus = chars.next().filter(|c| matches!(c, 'A' | 'B' | 'G' | 'D'))?;
+    let segment = chars.next().filter(|c| matches!(c, 'V' | 'D' | 'J'))?;
+    let numbers = chars.as_str();
+    let (family, member) = match numbers.split_once('-') {
+        Some((family, member)) => (family, Some(member)),
+        None => (numbers, None),
+    };
+    let mut imgt = format!("TR{}{}{}", locus, segment, gene_number(family)?);
+    if let Some(member) = member {
+        let member = gene_number(member)?;
+        if !(member == 1 && SINGLE_MEMBER_TRB.contains(&imgt.as_str())) {
+            imgt.push_str(&format!("-{}", member));
+        }
+    }
+    if let Some(allele) = allele {
+        imgt.push_str(&format!("*{:02}", gene_number(allele)?));
+    }
+    Some(imgt)
+}
+
+/// Adaptive ImmunoSEQ name of an IMGT gene name ("TRBV5-1*01" -> "TCRBV05-01*01",
+/// "TRBV9" -> "TCRBV09-01"); None for other names (including "TRAV29/DV5")
+pub fn imgt_to_adaptive(name: &str) -> Option<String> {
+    let upper = name.trim().to_uppercase();
+    let (gene, allele) = split_allele(&upper);
+    let mut chars = gene.strip_prefix("TR")?.chars();
+    let locus = chars.next().filter(|c| matches!(c, 'A' | 'B' | 'G' | 'D'))?;
+    let segment = chars.next().filter(|c| matches!(c, 'V' | 'D' | 'J'))?;
+    let numbers = chars.as_str();
+    let (family, member) = match numbers.split_once('-') {
+        Some((family, member)) => (family, Some(member)),
+        None => (numbers, None),
+    };
+    let mut adaptive = format!("TCR{}{}{:02}", locus, segment, gene_number(family)?);
+    match member {
+        Some(member) => adaptive.push_str(&format!("-{:02}", gene_number(member)?)),
+        None if SINGLE_MEMBER_TRB.contains(&gene) => adaptive.push_str("-01"),
+        None => {}
+    }
+    if let Some(allele) = allele {
+        adaptive.push_str(&format!("*{:02}", gene_number(allele)?));
+    }
+    Some(adaptive)
+}
+
+/// Family, member or allele number of a gene name ("05" -> 5); None unless all digits
+fn gene_number(s: &str) -> Option<u32> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// IMGT name of a gene name: registered aliases first, then Adaptive names;
+/// other names are returned unchanged
+pub fn imgt_gene_name(name: &str) -> String {
+    let upper = name.trim().to_uppercase();
+    if let Some(imgt) = REGISTRY.read().ok().and_then(|r| r.gene_aliases.get(&upper).cloned()) {
+        return imgt;
+    }
+    adaptive_to_imgt(&upper).unwrap_or_else(|| name.to_string())
+}
+
+/// Gene name nomenclature of `normalize_segment`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nomenclature {
+    Imgt,
+    Adaptive,
+}
+
+impl Nomenclature {
+    /// Parse "imgt" or "adaptive"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "imgt" => Ok(Self::Imgt),
+            "adaptive" => Ok(Self::Adaptive),
+            _ => Err(format!("Invalid nomenclature: {} (expected imgt or adaptive)", s)),
+        }
+    }
+}
+
+/// A segment assignment as the matcher reads it, written in `to` nomenclature at
+/// `level` (`VMatchLevel::None` keeps the allele like `Allele`): ambiguous calls
+/// are split and MiXCR scores dropped, names canonicalized and converted to
+/// IMGT, then reduced to the gene or family; distinct calls are joined by ","
+/// ("trbv6-2*01, TRBV6-3" at the family level is "TRBV6"). Names that cannot
+/// be converted are kept in canonical form.
+pub fn normalize_segment(segment: &str, to: Nomenclature, level: VMatchLevel) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for call in segment_calls(segment) {
+        let imgt = canonical_segment(&imgt_gene_name(call));
+        let name = match to {
+            Nomenclature::Adaptive => imgt_to_adaptive(&imgt).unwrap_or(imgt),
+            Nomenclature::Imgt => imgt,
+        };
+        let name = match (level, split_allele(&name)) {
+            (VMatchLevel::Family, _) => segment_family(&name).to_string(),
+            (VMatchLevel::Gene, (gene, _)) | (_, (gene, None)) => gene.to_string(),
+            (_, (gene, Some(allele))) => format!("{}*{}", gene, allele),
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_to_imgt() {
+        assert_eq!(adaptive_to_imgt("TCRBV05-01*01").as_deref(), Some("TRBV5-1*01"));
+        assert_eq!(adaptive_to_imgt("TCRBV09-01").as_deref(), Some("TRBV9"));
+        assert_eq!(adaptive_to_imgt("TCRBV19-01*01").as_deref(), Some("TRBV19*01"));
+        assert_eq!(adaptive_to_imgt("TCRBJ02-07").as_deref(), Some("TRBJ2-7"));
+        assert_eq!(adaptive_to_imgt("TCRBV12").as_deref(), Some("TRBV12"));
+        assert_eq!(adaptive_to_imgt("TRBV5-1"), None);
+        assert_eq!(adaptive_to_imgt("TCRBVXX-01"), None);
+        assert_eq!(imgt_gene_name("TRBV5-1*01"), "TRBV5-1*01");
+
+        for imgt in ["TRBV5-1*01", "TRBV9", "TRBV19*01", "TRBJ2-7", "TRAV12-2*02"] {
+            assert_eq!(adaptive_to_imgt(&imgt_to_adaptive(imgt).unwrap()).as_deref(), Some(imgt));
+        }
+        assert_eq!(imgt_to_adaptive("trbv9*01").as_deref(), Some("TCRBV09-01*01"));
+        assert_eq!(imgt_to_adaptive("TRAV29/DV5"), None);
+    }
+
+    #[test]
+    fn test_normalize_segment() {
+        use Nomenclature::{Adaptive, Imgt};
+        assert_eq!(normalize_segment(" trbv6-2*01", Imgt, VMatchLevel::Allele), "TRBV6-2*01");
+        assert_eq!(normalize_segment("TCRBV06-02*01", Imgt, VMatchLevel::Gene), "TRBV6-2");
+        assert_eq!(normalize_segment("TRBV6-2*00(1045),TRBV6-3*00(1040)", Imgt, VMatchLevel::Family), "TRBV6");
+        assert_eq!(normalize_segment("TRBV6-2,TRBV6-3", Imgt, VMatchLevel::Gene), "TRBV6-2,TRBV6-3");
+        assert_eq!(normalize_segment("TRBV9*01", Adaptive, VMatchLevel::Allele), "TCRBV09-01*01");
+        assert_eq!(normalize_segment("TRBV6-2", Adaptive, VMatchLevel::Family), "TCRBV06");
+        assert_eq!(normalize_segment("TRAV29/DV5*01", Adaptive, VMatchLevel::Family), "TRAV29");
+        assert_eq!(normalize_segment(" ", Imgt, VMatchLevel::Gene), "");
+    }
+
+    #[test]
+    fn test_registered_reference_data() {
+        assert_eq!(canonical_species("Homo sapiens").as_deref(), Some("HomoSapiens"));
+        assert_eq!(canonical_species("mouse").as_deref(), Some("MusMusculus"));
+        assert_eq!(canonical_species("zebrafish"), None);
+        register_species_synonym("Danio rerio", "DanioRerio").unwrap();
+        assert_eq!(canonical_species("zebrafish"), None);
+        assert_eq!(canonical_species("danio_rerio").as_deref(), Some("DanioRerio"));
+
+        register_v_gene("TRBV99-1*02", "mnhey", "SVGAGI", "human").unwrap();
+        assert!(register_v_gene("TRBV99-2", "MN1EY", "SVGAGI", "human").is_err());
+        assert!(register_v_gene("CASSF", "MNHEY", "SVGAGI", "human").is_err());
+        let gene = v_gene_cdrs("TRBV99-1*02", "HomoSapiens").unwrap();
+        assert_eq!((gene.cdr1.as_str(), gene.cdr2.as_str()), ("MNHEY", "SVGAGI"));
+        // Other alleles of a registered gene fall back to a registered allele
+        assert_eq!(v_gene_cdrs("TRBV99-1*01", "human").map(|g| g.name).as_deref(), Some("TRBV99-1*02"));
+        assert!(v_gene_cdrs("TRBV99-1", "MusMusculus").is_none());
+
+        register_gene_alias("lab-v99", "TRBV99-1*02").unwrap();
+        assert_eq!(imgt_gene_name("LAB-V99"), "TRBV99-1*02");
+        assert_eq!(v_gene_cdrs("lab-v99", "human").map(|g| g.cdr1).as_deref(), Some("MNHEY"));
+        assert!(registered_v_genes().iter().any(|g| g.name == "TRBV99-1*02"));
+    }
+}
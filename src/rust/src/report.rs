@@ -0,0 +1,895 @@
+//! Per-sample annotation reports: annotation rates, top epitopes, score
+//! distribution and database provenance of a batch match, as JSON or as a
+//! self-contained HTML page, bootstrap confidence intervals of annotation
+//! fractions, and annotation rates of samples downsampled to a common depth
+use crate::database::DatabaseMetadata;
+use crate::matching::BatchMatches;
+use crate::random::Rng;
+use crate::sequence::infer_chain;
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+
+/// Number of score histogram bins
+const SCORE_BINS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Html,
+}
+
+impl ReportFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "html" => Ok(Self::Html),
+            _ => Err(format!("Invalid report format: {} (expected json or html)", s)),
+        }
+    }
+}
+
+/// Queries and annotated queries of one chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainRate {
+    /// "TRA", "TRB", ... or "unknown" when V/J do not tell
+    pub chain: String,
+    pub n_queries: usize,
+    pub n_annotated: usize,
+}
+
+/// Hits of one epitope
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpitopeSummary {
+    pub epitope: String,
+    pub antigen_gene: String,
+    pub antigen_species: String,
+    /// Queries with at least one hit on the epitope
+    pub n_queries: usize,
+    pub n_hits: usize,
+    pub best_score: f64,
+}
+
+/// Distribution of hit scores
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScoreSummary {
+    pub n: usize,
+    pub min: f64,
+    pub q1: f64,
+    pub median: f64,
+    pub q3: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// (lower, upper, count) of equal-width bins over [min, max]
+    pub histogram: Vec<(f64, f64, usize)>,
+}
+
+impl ScoreSummary {
+    fn new(mut scores: Vec<f64>) -> Self {
+        scores.retain(|s| s.is_finite());
+        if scores.is_empty() {
+            return Self::default();
+        }
+        scores.sort_by(f64::total_cmp);
+        let (min, max) = (scores[0], scores[scores.len() - 1]);
+        let bins = if max > min { SCORE_BINS } else { 1 };
+        let width = (max - min) / bins as f64;
+        let mut histogram: Vec<(f64, f64, usize)> =
+            (0..bins).map(|b| (min + b as f64 * width, if b + 1 == bins { max } else { min + (b + 1) as f64 * width }, 0)).collect();
+        for &s in &scores {
+            let b = if width > 0.0 { (((s - min) / width) as usize).min(bins - 1) } else { 0 };
+            histogram[b].2 += 1;
+        }
+        Self {
+            n: scores.len(),
+            min,
+            q1: quantile(&scores, 0.25),
+            median: quantile(&scores, 0.5),
+            q3: quantile(&scores, 0.75),
+            max,
+            mean: scores.iter().sum::<f64>() / scores.len() as f64,
+            histogram,
+        }
+    }
+}
+
+/// Summary of a batch match for pipelines that keep one artifact per sample
+#[derive(Debug, Clone)]
+pub struct AnnotationReport {
+    /// UTC time (ISO 8601) the report was made
+    pub generated_at: String,
+    pub n_queries: usize,
+    pub n_unique_queries: usize,
+    /// Queries with at least one hit
+    pub n_annotated: usize,
+    pub n_hits: usize,
+    pub by_chain: Vec<ChainRate>,
+    /// Epitopes hit by the most queries, most first
+    pub top_epitopes: Vec<EpitopeSummary>,
+    pub scores: ScoreSummary,
+    /// Database the hits came from (None for results loaded from disk)
+    pub database: Option<DatabaseMetadata>,
+}
+
+impl AnnotationReport {
+    /// Report on `batch`, listing at most `top_n` epitopes
+    pub fn new(batch: &BatchMatches, database: Option<&DatabaseMetadata>, top_n: usize) -> Self {
+        let n_queries = batch.queries.len();
+        let mut chains: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut epitopes: HashMap<&str, EpitopeSummary> = HashMap::new();
+        let mut n_annotated = 0;
+        for (i, query) in batch.queries.iter().enumerate() {
+            let hits = batch.query_hits(i);
+            let chain = infer_chain(&query.v_segment, &query.j_segment).0.unwrap_or("unknown");
+            let rate = chains.entry(chain.to_string()).or_default();
+            rate.0 += 1;
+            if hits.is_empty() {
+                continue;
+            }
+            rate.1 += 1;
+            n_annotated += 1;
+            let mut seen: Vec<&str> = Vec::new();
+            for m in hits {
+                let e = &m.db_entry;
+                let summary = epitopes.entry(e.antigen_epitope.as_str()).or_insert_with(|| EpitopeSummary {
+                    epitope: e.antigen_epitope.clone(),
+                    antigen_gene: e.antigen_gene.clone().unwrap_or_default(),
+                    antigen_species: e.antigen_species.clone(),
+                    n_queries: 0,
+                    n_hits: 0,
+                    best_score: f64::NEG_INFINITY,
+                });
+                summary.n_hits += 1;
+                summary.best_score = summary.best_score.max(m.score);
+                if !seen.contains(&e.antigen_epitope.as_str()) {
+                    seen.push(&e.antigen_epitope);
+                    summary.n_queries += 1;
+                }
+            }
+        }
+
+        let mut by_chain: Vec<ChainRate> = chains
+            .into_iter()
+            .map(|(chain, (n_queries, n_annotated))| ChainRate { chain, n_queries, n_annotated })
+            .collect();
+        by_chain.sort_by(|a, b| (a.chain == "unknown", &a.chain).cmp(&(b.chain == "unknown", &b.chain)));
+        let mut top_epitopes: Vec<EpitopeSummary> = epitopes.into_values().collect();
+        top_epitopes.sort_by(|a, b| {
+            b.n_queries.cmp(&a.n_queries).then(b.n_hits.cmp(&a.n_hits)).then_with(|| a.epitope.cmp(&b.epitope))
+        });
+        top_epitopes.truncate(top_n);
+
+        Self {
+            generated_at: crate::utils::utc_timestamp(),
+            n_queries,
+            n_unique_queries: batch.n_unique(),
+            n_annotated,
+            n_hits: batch.len(),
+            by_chain,
+            top_epitopes,
+            scores: ScoreSummary::new(batch.rows(0..batch.len()).map(|(_, m)| m.score).collect()),
+            database: database.cloned(),
+        }
+    }
+
+    /// Share of queries with at least one hit (0 without queries)
+    pub fn annotation_rate(&self) -> f64 {
+        rate(self.n_annotated, self.n_queries)
+    }
+
+    pub fn to_json(&self) -> String {
+        let database = match &self.database {
+            Some(db) => Json::Object(vec![
+                ("version", db.version.as_deref().into()),
+                ("source_path", db.source_path.as_deref().into()),
+                ("loaded_at", db.loaded_at.as_deref().into()),
+                ("filters", Json::Array(db.filters.iter().map(|f| f.as_str().into()).collect())),
+            ]),
+            None => Json::Null,
+        };
+        let s = &self.scores;
+        let report = Json::Object(vec![
+            ("generated_at", self.generated_at.as_str().into()),
+            ("database", database),
+            (
+                "queries",
+                Json::Object(vec![
+                    ("n_queries", self.n_queries.into()),
+                    ("n_unique_queries", self.n_unique_queries.into()),
+                    ("n_annotated", self.n_annotated.into()),
+                    ("annotation_rate", self.annotation_rate().into()),
+                    ("n_hits", self.n_hits.into()),
+                ]),
+            ),
+            (
+                "by_chain",
+                Json::Array(
+                    self.by_chain
+                        .iter()
+                        .map(|c| {
+                            Json::Object(vec![
+                                ("chain", c.chain.as_str().into()),
+                                ("n_queries", c.n_queries.into()),
+                                ("n_annotated", c.n_annotated.into()),
+                                ("annotation_rate", rate(c.n_annotated, c.n_queries).into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "top_epitopes",
+                Json::Array(
+                    self.top_epitopes
+                        .iter()
+                        .map(|e| {
+                            Json::Object(vec![
+                                ("epitope", e.epitope.as_str().into()),
+                                ("antigen_gene", e.antigen_gene.as_str().into()),
+                                ("antigen_species", e.antigen_species.as_str().into()),
+                                ("n_queries", e.n_queries.into()),
+                                ("n_hits", e.n_hits.into()),
+                                ("best_score", e.best_score.into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "scores",
+                Json::Object(vec![
+                    ("n", s.n.into()),
+                    ("min", s.min.into()),
+                    ("q1", s.q1.into()),
+                    ("median", s.median.into()),
+                    ("q3", s.q3.into()),
+                    ("max", s.max.into()),
+                    ("mean", s.mean.into()),
+                    (
+                        "histogram",
+                        Json::Array(
+                            s.histogram
+                                .iter()
+                                .map(|&(lower, upper, count)| {
+                                    Json::Object(vec![("lower", lower.into()), ("upper", upper.into()), ("count", count.into())])
+                                })
+                                .collect(),
+                        ),
+                    ),
+                ]),
+            ),
+        ]);
+        let mut out = String::new();
+        report.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+
+    /// Self-contained HTML page (inline style, no scripts or external assets)
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>vdjmatchR annotation report</title>\n\
+             <style>\nbody {{ font-family: sans-serif; margin: 2em; color: #222; }}\n\
+             table {{ border-collapse: collapse; margin-bottom: 1.5em; }}\n\
+             th, td {{ border: 1px solid #ccc; padding: 0.3em 0.7em; text-align: left; }}\n\
+             td.num {{ text-align: right; }}\n.bar {{ background: #4a7ab5; height: 1em; }}\n</style>\n</head>\n<body>\n\
+             <h1>Annotation report</h1>\n<p>Generated {}</p>\n",
+            html_escape(&self.generated_at)
+        );
+
+        out.push_str("<h2>Queries</h2>\n<table>\n");
+        for (label, value) in [
+            ("Queries", self.n_queries.to_string()),
+            ("Unique queries", self.n_unique_queries.to_string()),
+            ("Annotated queries", self.n_annotated.to_string()),
+            ("Annotation rate", percent(self.annotation_rate())),
+            ("Hits", self.n_hits.to_string()),
+        ] {
+            let _ = writeln!(out, "<tr><th>{}</th><td class=\"num\">{}</td></tr>", label, value);
+        }
+        out.push_str("</table>\n");
+
+        out.push_str("<h2>Annotation by chain</h2>\n<table>\n<tr><th>Chain</th><th>Queries</th><th>Annotated</th><th>Rate</th></tr>\n");
+        for c in &self.by_chain {
+            let _ = writeln!(
+                out,
+                "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                html_escape(&c.chain),
+                c.n_queries,
+                c.n_annotated,
+                percent(rate(c.n_annotated, c.n_queries))
+            );
+        }
+        out.push_str("</table>\n");
+
+        out.push_str(
+            "<h2>Top epitopes</h2>\n<table>\n<tr><th>Epitope</th><th>Antigen</th><th>Species</th>\
+             <th>Queries</th><th>Hits</th><th>Best score</th></tr>\n",
+        );
+        for e in &self.top_epitopes {
+            let _ = writeln!(
+                out,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{:.3}</td></tr>",
+                html_escape(&e.epitope),
+                html_escape(&e.antigen_gene),
+                html_escape(&e.antigen_species),
+                e.n_queries,
+                e.n_hits,
+                e.best_score
+            );
+        }
+        out.push_str("</table>\n");
+
+        let s = &self.scores;
+        let _ = write!(
+            out,
+            "<h2>Hit scores</h2>\n<p>{} hits; min {:.3}, quartiles {:.3} / {:.3} / {:.3}, max {:.3}, mean {:.3}</p>\n<table>\n\
+             <tr><th>Score</th><th>Hits</th><th></th></tr>\n",
+            s.n, s.min, s.q1, s.median, s.q3, s.max, s.mean
+        );
+        let most = s.histogram.iter().map(|h| h.2).max().unwrap_or(0).max(1);
+        for &(lower, upper, count) in &s.histogram {
+            let _ = writeln!(
+                out,
+                "<tr><td>{:.3} &ndash; {:.3}</td><td class=\"num\">{}</td><td style=\"width: 20em\"><div class=\"bar\" style=\"width: {:.1}%\"></div></td></tr>",
+                lower,
+                upper,
+                count,
+                100.0 * count as f64 / most as f64
+            );
+        }
+        out.push_str("</table>\n");
+
+        out.push_str("<h2>Database</h2>\n");
+        match &self.database {
+            Some(db) => {
+                out.push_str("<table>\n");
+                let field = |v: &Option<String>| html_escape(v.as_deref().unwrap_or("unknown"));
+                let filters = if db.filters.is_empty() { "none".to_string() } else { html_escape(&db.filters.join(", ")) };
+                for (label, value) in [
+                    ("Version", field(&db.version)),
+                    ("Source", field(&db.source_path)),
+                    ("Loaded", field(&db.loaded_at)),
+                    ("Filters", filters),
+                ] {
+                    let _ = writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", label, value);
+                }
+                out.push_str("</table>\n");
+            }
+            None => out.push_str("<p>Unknown (results loaded from disk)</p>\n"),
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => self.to_json(),
+            ReportFormat::Html => self.to_html(),
+        }
+    }
+}
+
+/// Quantile `p` of sorted, non-empty `values` by linear interpolation between
+/// order statistics (R's type 7)
+fn quantile(values: &[f64], p: f64) -> f64 {
+    let h = (values.len() - 1) as f64 * p;
+    let (lo, hi) = (h.floor() as usize, h.ceil() as usize);
+    values[lo] + (h - lo as f64) * (values[hi] - values[lo])
+}
+
+/// An annotation fraction and its bootstrap percentile interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FractionEstimate {
+    pub fraction: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// Fractions of queries annotated at all and per epitope, with bootstrap intervals
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotationFractions {
+    /// Queries with at least one hit
+    pub annotated: FractionEstimate,
+    /// Queries with a hit on the epitope, by epitope
+    pub epitopes: Vec<(String, FractionEstimate)>,
+    pub n_boot: usize,
+    pub conf_level: f64,
+}
+
+/// Share of (weighted) queries with any hit and with a hit on each epitope,
+/// with percentile intervals from `n_boot` bootstrap resamples of the queries
+/// Queries are the resampled units (cells of one clonotype are not
+/// independent); `weights` (default: each query's `count`) weight them in every
+/// fraction, so "fraction" means the share of cells or reads, not of clonotypes,
+/// when counts are given. Epitopes are ordered by fraction, largest first.
+pub fn annotation_fractions(
+    batch: &BatchMatches,
+    weights: Option<&[f64]>,
+    n_boot: usize,
+    conf_level: f64,
+    rng: &mut Rng,
+) -> Result<AnnotationFractions, String> {
+    let n = batch.queries.len();
+    if !(conf_level > 0.0 && conf_level < 1.0) {
+        return Err(format!("conf_level must be between 0 and 1, got {}", conf_level));
+    }
+    let weights: Vec<f64> = match weights {
+        Some(w) if w.len() != n => return Err(format!("{} weights given for {} queries", w.len(), n)),
+        Some(w) => w.to_vec(),
+        None => batch.queries.iter().map(|q| q.count as f64).collect(),
+    };
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err("weights must be finite and non-negative".into());
+    }
+
+    let names = hit_epitope_names(&[batch]);
+    let hit_epitopes = query_epitopes(batch, &names);
+
+    // Fractions of one sample of queries: [annotated, epitope 0, epitope 1, ...]
+    let fractions = |sample: &mut dyn Iterator<Item = usize>| {
+        let mut sums = vec![0.0; names.len() + 1];
+        let mut total = 0.0;
+        for i in sample {
+            total += weights[i];
+            if !hit_epitopes[i].is_empty() {
+                sums[0] += weights[i];
+            }
+            for &k in &hit_epitopes[i] {
+                sums[k + 1] += weights[i];
+            }
+        }
+        sums.iter().map(|s| if total > 0.0 { s / total } else { 0.0 }).collect::<Vec<f64>>()
+    };
+    let observed = fractions(&mut (0..n));
+
+    let seeds: Vec<u64> = (0..n_boot).map(|_| rng.next_u64()).collect();
+    let replicates: Vec<Vec<f64>> = if n == 0 {
+        Vec::new()
+    } else {
+        seeds
+            .par_iter()
+            .map(|&seed| {
+                let mut rng = Rng::new(seed);
+                fractions(&mut (0..n).map(|_| rng.below(n)))
+            })
+            .collect()
+    };
+    let alpha = (1.0 - conf_level) / 2.0;
+    let estimate = |k: usize| {
+        let mut values: Vec<f64> = replicates.iter().map(|r| r[k]).collect();
+        values.sort_by(f64::total_cmp);
+        let (lower, upper) = if values.is_empty() {
+            (f64::NAN, f64::NAN)
+        } else {
+            (quantile(&values, alpha), quantile(&values, 1.0 - alpha))
+        };
+        FractionEstimate { fraction: observed[k], lower, upper }
+    };
+
+    let mut epitopes: Vec<(String, FractionEstimate)> =
+        names.iter().enumerate().map(|(k, e)| (e.to_string(), estimate(k + 1))).collect();
+    epitopes.sort_by(|a, b| b.1.fraction.total_cmp(&a.1.fraction).then_with(|| a.0.cmp(&b.0)));
+    Ok(AnnotationFractions { annotated: estimate(0), epitopes, n_boot, conf_level })
+}
+
+/// Epitopes hit in any of `batches`, sorted
+fn hit_epitope_names<'a>(batches: &[&'a BatchMatches]) -> Vec<&'a str> {
+    batches
+        .iter()
+        .flat_map(|b| b.rows(0..b.len()).map(|(_, m)| m.db_entry.antigen_epitope.as_str()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Indices into `names` of the distinct epitopes each query of `batch` hits
+fn query_epitopes(batch: &BatchMatches, names: &[&str]) -> Vec<Vec<usize>> {
+    let index: HashMap<&str, usize> = names.iter().enumerate().map(|(k, &e)| (e, k)).collect();
+    (0..batch.queries.len())
+        .map(|i| {
+            let mut hit: Vec<usize> = batch.query_hits(i).iter().map(|m| index[m.db_entry.antigen_epitope.as_str()]).collect();
+            hit.sort_unstable();
+            hit.dedup();
+            hit
+        })
+        .collect()
+}
+
+/// What `downsample_annotation` draws to reach a common depth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownsampleUnit {
+    /// Clonotypes, without replacement
+    Clones,
+    /// Reads (or cells), without replacement; clonotypes with a read drawn are kept
+    Reads,
+}
+
+impl DownsampleUnit {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "clones" => Ok(Self::Clones),
+            "reads" => Ok(Self::Reads),
+            _ => Err(format!("Invalid downsampling unit: {} (expected clones or reads)", s)),
+        }
+    }
+}
+
+/// Mean and percentile interval of a statistic over downsampling iterations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResampledValue {
+    pub mean: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl ResampledValue {
+    fn new(mut values: Vec<f64>, conf_level: f64) -> Self {
+        if values.is_empty() {
+            return Self { mean: f64::NAN, lower: f64::NAN, upper: f64::NAN };
+        }
+        values.sort_by(f64::total_cmp);
+        let alpha = (1.0 - conf_level) / 2.0;
+        Self {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            lower: quantile(&values, alpha),
+            upper: quantile(&values, 1.0 - alpha),
+        }
+    }
+}
+
+/// Annotation of one sample after downsampling: overall (`epitope` None) or on one epitope
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownsampledAnnotation {
+    /// Index of the sample in the input
+    pub sample: usize,
+    pub epitope: Option<String>,
+    /// Clonotypes or reads the sample was downsampled to
+    pub depth: u64,
+    /// Kept clonotypes with a hit (on the epitope)
+    pub n_clonotypes: ResampledValue,
+    /// Those clonotypes as a share of the kept clonotypes
+    pub fraction: ResampledValue,
+}
+
+/// `k` distinct indices below `n` (Floyd's algorithm), `k` <= `n`
+fn sample_distinct(n: u64, k: u64, rng: &mut Rng) -> Vec<u64> {
+    let mut chosen: HashSet<u64> = HashSet::with_capacity(k as usize);
+    for j in n - k..n {
+        let t = rng.below(j as usize + 1) as u64;
+        if !chosen.insert(t) {
+            chosen.insert(j);
+        }
+    }
+    chosen.into_iter().collect()
+}
+
+/// Annotation rates of samples made comparable across sequencing depths
+/// Each sample (a batch and one count per query, its reads or cells) is
+/// downsampled `n_iter` times to `depth` units (default: the smallest sample)
+/// and the kept clonotypes with any hit and with a hit on each epitope are
+/// counted; iterations run in parallel. Returns, per sample, the overall row
+/// then one row per epitope hit in any sample, with means and `conf_level`
+/// percentile intervals over the iterations.
+pub fn downsample_annotation(
+    samples: &[(&BatchMatches, Vec<u64>)],
+    unit: DownsampleUnit,
+    depth: Option<u64>,
+    n_iter: usize,
+    conf_level: f64,
+    rng: &mut Rng,
+) -> Result<Vec<DownsampledAnnotation>, String> {
+    if !(conf_level > 0.0 && conf_level < 1.0) {
+        return Err(format!("conf_level must be between 0 and 1, got {}", conf_level));
+    }
+    for (k, (batch, counts)) in samples.iter().enumerate() {
+        if counts.len() != batch.queries.len() {
+            return Err(format!("sample {}: {} counts given for {} queries", k + 1, counts.len(), batch.queries.len()));
+        }
+    }
+    let sizes: Vec<u64> = samples
+        .iter()
+        .map(|(batch, counts)| match unit {
+            DownsampleUnit::Clones => batch.queries.len() as u64,
+            DownsampleUnit::Reads => counts.iter().sum(),
+        })
+        .collect();
+    let depth = match depth {
+        Some(depth) => depth,
+        None => sizes.iter().copied().min().unwrap_or(0),
+    };
+    if let Some(k) = sizes.iter().position(|&size| size < depth) {
+        let unit = if unit == DownsampleUnit::Clones { "clonotypes" } else { "reads" };
+        return Err(format!("sample {} has {} {}, fewer than the depth of {}", k + 1, sizes[k], unit, depth));
+    }
+
+    let batches: Vec<&BatchMatches> = samples.iter().map(|(b, _)| *b).collect();
+    let names = hit_epitope_names(&batches);
+    let hit_epitopes: Vec<Vec<Vec<usize>>> = batches.iter().map(|b| query_epitopes(b, &names)).collect();
+    // Cumulative read counts, to find the query of a drawn read
+    let cumulative: Vec<Vec<u64>> = samples
+        .iter()
+        .map(|(_, counts)| counts.iter().scan(0, |total, &c| { *total += c; Some(*total) }).collect())
+        .collect();
+
+    let tasks: Vec<(usize, u64)> = (0..samples.len()).flat_map(|k| (0..n_iter).map(move |_| k)).map(|k| (k, rng.next_u64())).collect();
+    // Per task: [kept, annotated, epitope 0, epitope 1, ...] clonotype counts
+    let counts: Vec<Vec<f64>> = tasks
+        .par_iter()
+        .map(|&(k, seed)| {
+            let mut rng = Rng::new(seed);
+            let kept: HashSet<usize> = match unit {
+                DownsampleUnit::Clones => sample_distinct(sizes[k], depth, &mut rng).into_iter().map(|i| i as usize).collect(),
+                DownsampleUnit::Reads => sample_distinct(sizes[k], depth, &mut rng)
+                    .into_iter()
+                    .map(|read| cumulative[k].partition_point(|&c| c <= read))
+                    .collect(),
+            };
+            let mut totals = vec![0.0; names.len() + 2];
+            for &i in &kept {
+                totals[0] += 1.0;
+                if !hit_epitopes[k][i].is_empty() {
+                    totals[1] += 1.0;
+                }
+                for &e in &hit_epitopes[k][i] {
+                    totals[e + 2] += 1.0;
+                }
+            }
+            totals
+        })
+        .collect();
+
+    let mut rows = Vec::with_capacity(samples.len() * (names.len() + 1));
+    for k in 0..samples.len() {
+        let iterations: Vec<&Vec<f64>> = counts.iter().zip(&tasks).filter(|(_, t)| t.0 == k).map(|(c, _)| c).collect();
+        let value = |column: usize| {
+            let n: Vec<f64> = iterations.iter().map(|c| c[column]).collect();
+            let share: Vec<f64> = iterations.iter().map(|c| if c[0] > 0.0 { c[column] / c[0] } else { 0.0 }).collect();
+            (ResampledValue::new(n, conf_level), ResampledValue::new(share, conf_level))
+        };
+        for (column, epitope) in std::iter::once(None).chain(names.iter().map(|e| Some(e.to_string()))).enumerate() {
+            let (n_clonotypes, fraction) = value(column + 1);
+            rows.push(DownsampledAnnotation { sample: k, epitope, depth, n_clonotypes, fraction });
+        }
+    }
+    Ok(rows)
+}
+
+fn rate(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+fn percent(rate: f64) -> String {
+    format!("{:.1}%", 100.0 * rate)
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Just enough JSON for the report, written with two-space indentation
+enum Json {
+    Null,
+    Int(u64),
+    Num(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Int(n as u64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(x: f64) -> Self {
+        Json::Num(x)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<Option<&str>> for Json {
+    fn from(s: Option<&str>) -> Self {
+        s.map_or(Json::Null, Json::from)
+    }
+}
+
+impl Json {
+    fn write(&self, out: &mut String, indent: usize) {
+        let pad = |out: &mut String, level: usize| out.extend(std::iter::repeat("  ").take(level));
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Int(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            // JSON has no NaN or infinity
+            Json::Num(x) if !x.is_finite() => out.push_str("null"),
+            Json::Num(x) => {
+                let _ = write!(out, "{}", x);
+            }
+            Json::Str(s) => json_string(out, s),
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Array(items) => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    pad(out, indent + 1);
+                    item.write(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    pad(out, indent + 1);
+                    json_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseEntry};
+    use crate::matching::{dedup_clonotypes, match_clonotypes_parallel, MatchConfig};
+    use crate::sequence::{Clonotype, SearchScope};
+
+    fn entry(cdr3: &str, epitope: &str) -> DatabaseEntry {
+        DatabaseEntry {
+            v_segment: "TRBV19".to_string(),
+            antigen_species: "Influenza<A>".to_string(),
+            ..DatabaseEntry::test(cdr3, epitope)
+        }
+    }
+
+    #[test]
+    fn test_annotation_report() {
+        let database = Database {
+            entries: vec![entry("CASSIRSSYEQYF", "GILGFVFTL"), entry("CASSIRSAYEQYF", "GILGFVFTL"), entry("CASSLF", "NLVPMVATV")],
+            metadata: DatabaseMetadata { version: Some("2024-06".to_string()), ..DatabaseMetadata::default() },
+        };
+        let queries: Vec<Clonotype> = ["CASSIRSSYEQYF", "CASSLF", "CAVRDGQKLLF"]
+            .iter()
+            .map(|cdr3| Clonotype::new(cdr3.to_string(), "TRBV19".to_string(), "TRBJ2-7".to_string(), 1, 0.0))
+            .collect();
+        let config = MatchConfig { search_scope: SearchScope::parse("1,0,1").unwrap(), ..MatchConfig::default() };
+        let (unique, mapping) = dedup_clonotypes(&queries);
+        let unique_matches = match_clonotypes_parallel(&unique, &database, &config);
+        let batch = BatchMatches::new(queries, mapping, unique_matches);
+        let report = AnnotationReport::new(&batch, Some(&database.metadata), 10);
+
+        assert_eq!((report.n_queries, report.n_annotated, report.n_hits), (3, 2, 3));
+        assert_eq!(report.by_chain, [ChainRate { chain: "TRB".to_string(), n_queries: 3, n_annotated: 2 }]);
+        let top: Vec<_> = report.top_epitopes.iter().map(|e| (e.epitope.as_str(), e.n_queries, e.n_hits)).collect();
+        assert_eq!(top, [("GILGFVFTL", 1, 2), ("NLVPMVATV", 1, 1)]);
+        assert_eq!(report.scores.n, 3);
+        assert_eq!(report.scores.histogram.iter().map(|h| h.2).sum::<usize>(), 3);
+
+        let json = report.to_json();
+        assert!(json.contains("\"annotation_rate\": 0.6666666666666666"));
+        assert!(json.contains("\"version\": \"2024-06\""));
+        assert!(json.contains("\"source_path\": null"));
+        let html = report.to_html();
+        assert!(html.contains("Influenza&lt;A&gt;") && html.ends_with("</html>\n"));
+        assert_eq!(ReportFormat::parse(" HTML "), Ok(ReportFormat::Html));
+        assert!(ReportFormat::parse("pdf").is_err());
+
+        let mut rng = Rng::new(7);
+        let fractions = annotation_fractions(&batch, None, 200, 0.9, &mut rng).unwrap();
+        assert!((fractions.annotated.fraction - 2.0 / 3.0).abs() < 1e-12);
+        assert!(fractions.annotated.lower <= fractions.annotated.fraction);
+        assert!(fractions.annotated.upper >= fractions.annotated.fraction && fractions.annotated.upper <= 1.0);
+        let names: Vec<&str> = fractions.epitopes.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(names, ["GILGFVFTL", "NLVPMVATV"]);
+        // Weights make fractions shares of cells: 8 of the 10 cells are on NLVPMVATV
+        let weighted = annotation_fractions(&batch, Some(&[1.0, 8.0, 1.0]), 200, 0.9, &mut Rng::new(7)).unwrap();
+        assert_eq!(weighted.epitopes[0].0, "NLVPMVATV");
+        assert!((weighted.epitopes[0].1.fraction - 0.8).abs() < 1e-12);
+        assert_eq!(weighted, annotation_fractions(&batch, Some(&[1.0, 8.0, 1.0]), 200, 0.9, &mut Rng::new(7)).unwrap());
+        assert!(annotation_fractions(&batch, Some(&[1.0]), 10, 0.9, &mut rng).is_err());
+        assert!(annotation_fractions(&batch, None, 10, 1.5, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_downsample_annotation() {
+        let mut rng = Rng::new(3);
+        let mut drawn = sample_distinct(10, 10, &mut rng);
+        drawn.sort_unstable();
+        assert_eq!(drawn, (0..10).collect::<Vec<u64>>());
+        assert_eq!(sample_distinct(1000, 5, &mut rng).iter().collect::<HashSet<_>>().len(), 5);
+
+        let queries = |n: usize| -> Vec<Clonotype> {
+            (0..n).map(|i| Clonotype::new(format!("CASS{}F", "A".repeat(i)), "TRBV19".into(), "TRBJ2-7".into(), 1, 0.0)).collect()
+        };
+        let database = Database { entries: vec![entry("CASSF", "GILGFVFTL")], metadata: DatabaseMetadata::default() };
+        let batch = |queries: Vec<Clonotype>| {
+            let (unique, mapping) = dedup_clonotypes(&queries);
+            let hits = match_clonotypes_parallel(&unique, &database, &MatchConfig::default());
+            BatchMatches::new(queries, mapping, hits)
+        };
+        // One annotated clonotype among 2 and among 10
+        let (small, large) = (batch(queries(2)), batch(queries(10)));
+        let samples = [(&small, vec![1, 1]), (&large, vec![1; 10])];
+
+        let rows = downsample_annotation(&samples, DownsampleUnit::Clones, None, 200, 0.95, &mut rng).unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!((rows[0].sample, rows[0].epitope.as_deref(), rows[0].depth), (0, None, 2));
+        assert_eq!(rows[1].epitope.as_deref(), Some("GILGFVFTL"));
+        // The full small sample is kept every time
+        assert_eq!(rows[0].fraction, ResampledValue { mean: 0.5, lower: 0.5, upper: 0.5 });
+        // 2 of 10 clonotypes keep the hit with probability 0.2
+        assert!((rows[2].n_clonotypes.mean - 0.2).abs() < 0.1);
+        assert_eq!((rows[2].n_clonotypes.lower, rows[2].n_clonotypes.upper), (0.0, 1.0));
+
+        // By reads, the annotated clonotype holding 9 of 10 reads is nearly always kept
+        let reads = [(&large, [vec![91], vec![1; 9]].concat())];
+        let rows = downsample_annotation(&reads, DownsampleUnit::Reads, Some(5), 100, 0.95, &mut rng).unwrap();
+        assert!(rows[0].n_clonotypes.mean > 0.9);
+        assert!(downsample_annotation(&samples, DownsampleUnit::Clones, Some(5), 10, 0.95, &mut rng).is_err());
+        assert_eq!(DownsampleUnit::parse(" Reads"), Ok(DownsampleUnit::Reads));
+    }
+
+    #[test]
+    fn test_score_summary() {
+        let s = ScoreSummary::new(vec![1.0, 2.0, 3.0, 4.0, f64::NAN]);
+        assert_eq!((s.n, s.min, s.median, s.max, s.mean), (4, 1.0, 2.5, 4.0, 2.5));
+        assert_eq!((s.q1, s.q3), (1.75, 3.25));
+        assert_eq!(s.histogram.len(), SCORE_BINS);
+        let last = s.histogram[SCORE_BINS - 1];
+        assert!((last.0 - 3.7).abs() < 1e-12 && last.1 == 4.0 && last.2 == 1);
+        assert_eq!(s.histogram[0].2, 1);
+        let single = ScoreSummary::new(vec![0.5, 0.5]);
+        assert_eq!(single.histogram, [(0.5, 0.5, 2)]);
+        assert_eq!(ScoreSummary::new(Vec::new()).n, 0);
+    }
+}
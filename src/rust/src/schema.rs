@@ -0,0 +1,550 @@
+use crate::error::{Result, VdjMatchError};
+
+/// Database fields read from a TSV, by their VDJdb column names
+pub const DATABASE_FIELDS: &[&str] = &[
+    "gene",
+    "cdr3",
+    "species",
+    "v.segm",
+    "j.segm",
+    "d.segm",
+    "antigen.epitope",
+    "antigen.gene",
+    "antigen.species",
+    "mhc.class",
+    "reference.id",
+    "vdjdb.score",
+    "method",
+    "meta",
+    "cdr3fix",
+    "complex.id",
+    "mhc.a",
+    "mhc.b",
+];
+
+/// Fields a database cannot be matched against without
+pub const REQUIRED_FIELDS: &[&str] = &["cdr3", "v.segm", "j.segm", "antigen.epitope"];
+
+/// Other spellings of each field, compared after `normalize`
+const ALIASES: &[(&str, &[&str])] = &[
+    ("gene", &["chain", "locus"]),
+    ("cdr3", &["cdr3.aa", "cdr3aa", "junction.aa", "cdr3.amino.acid", "amino.acid"]),
+    ("v.segm", &["v.segment", "v.gene", "v.call", "v", "vgene", "v.name"]),
+    ("j.segm", &["j.segment", "j.gene", "j.call", "j", "jgene", "j.name"]),
+    ("d.segm", &["d.segment", "d.gene", "d.call", "d", "dgene", "d.name"]),
+    ("antigen.epitope", &["epitope", "epitope.aa", "peptide", "antigen.peptide"]),
+    ("antigen.species", &["epitope.species", "pathogen", "antigen.organism"]),
+    ("antigen.gene", &["epitope.gene", "antigen.protein"]),
+    ("mhc.class", &["mhc"]),
+    ("mhc.a", &["mhc.allele", "hla.allele"]),
+    ("reference.id", &["reference", "pmid", "pubmed.id"]),
+    ("vdjdb.score", &["score", "confidence.score"]),
+];
+
+/// Lowercase with "_", "-" and " " read as "."
+fn normalize(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| if matches!(c, '_' | '-' | ' ') { '.' } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+/// Database field a column name or alias refers to
+pub fn canonical_field(name: &str) -> Option<&'static str> {
+    let name = normalize(name);
+    DATABASE_FIELDS.iter().copied().find(|field| *field == name).or_else(|| {
+        ALIASES
+            .iter()
+            .find(|(_, aliases)| aliases.contains(&name.as_str()))
+            .map(|(field, _)| *field)
+    })
+}
+
+/// Layout of a VDJdb table, recognised from its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaProfile {
+    /// vdjdb.txt since 2018: complex.id, JSON method/meta/cdr3fix and web.* columns
+    Standard,
+    /// vdjdb.slim.txt: one row per clonotype and epitope, no method/meta
+    Slim,
+    /// Releases before the web.* columns: JSON method/meta without them
+    Legacy,
+    /// Early releases with method, meta and cdr3fix spread over
+    /// "method.identification", "meta.study.id", ... columns
+    Flattened,
+    /// Any other table, read through the column aliases
+    #[default]
+    Custom,
+}
+
+/// Fields early releases split into one column per key ("<field>.<key>")
+pub const FLATTENED_FIELDS: &[&str] = &["method", "meta", "cdr3fix"];
+
+impl SchemaProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchemaProfile::Standard => "standard",
+            SchemaProfile::Slim => "slim",
+            SchemaProfile::Legacy => "legacy",
+            SchemaProfile::Flattened => "flattened",
+            SchemaProfile::Custom => "custom",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(SchemaProfile::Standard),
+            "slim" => Some(SchemaProfile::Slim),
+            "legacy" => Some(SchemaProfile::Legacy),
+            "flattened" => Some(SchemaProfile::Flattened),
+            "custom" => Some(SchemaProfile::Custom),
+            _ => None,
+        }
+    }
+
+    /// Profile of a table with these header columns
+    /// Only tables using VDJdb's own column names get a release profile; a
+    /// table that needs aliases to load is `Custom`.
+    pub fn detect(columns: &[String]) -> Self {
+        let has = |name: &str| columns.iter().any(|c| c == name);
+        let vdjdb_names = REQUIRED_FIELDS.iter().all(|f| has(f)) && has("gene") && has("species");
+        if !vdjdb_names {
+            return SchemaProfile::Custom;
+        }
+        let flattened = columns.iter().any(|c| {
+            FLATTENED_FIELDS
+                .iter()
+                .any(|f| c.strip_prefix(f).is_some_and(|rest| rest.starts_with('.') && rest.len() > 1))
+        });
+        if flattened && !has("method") && !has("meta") {
+            SchemaProfile::Flattened
+        } else if has("method") && has("meta") {
+            if columns.iter().any(|c| c.starts_with("web.")) {
+                SchemaProfile::Standard
+            } else {
+                SchemaProfile::Legacy
+            }
+        } else if has("complex.id") && has("vdjdb.score") {
+            SchemaProfile::Slim
+        } else {
+            SchemaProfile::Custom
+        }
+    }
+}
+
+impl SchemaProfile {
+    /// "fat" for the full VDJdb table in any release layout, "slim" for
+    /// vdjdb.slim.txt and "custom" for other tables
+    pub fn variant(&self) -> &'static str {
+        match self {
+            SchemaProfile::Standard | SchemaProfile::Legacy | SchemaProfile::Flattened => "fat",
+            SchemaProfile::Slim => "slim",
+            SchemaProfile::Custom => "custom",
+        }
+    }
+}
+
+/// Features that need columns only some VDJdb tables have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Parsing the JSON `method` field (needs the fat table)
+    Method,
+    /// Pairing alpha and beta chains recorded in the same complex; slim
+    /// files merge records and list several complex ids per row
+    ComplexPairing,
+    /// Filtering by MHC allele (`mhc.a`/`mhc.b` columns)
+    MhcAlleles,
+}
+
+pub const CAPABILITIES: &[Capability] = &[Capability::Method, Capability::ComplexPairing, Capability::MhcAlleles];
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Method => "method",
+            Capability::ComplexPairing => "complex_pairing",
+            Capability::MhcAlleles => "mhc_alleles",
+        }
+    }
+
+    /// Whether a table with these header columns and profile supports the feature
+    pub fn supported(&self, columns: &[String], profile: SchemaProfile) -> bool {
+        let has = |field: &str| columns.iter().any(|c| canonical_field(c) == Some(field));
+        match self {
+            Capability::Method => has("method") || !flattened_columns(columns, "method").is_empty(),
+            Capability::ComplexPairing => has("complex.id") && profile != SchemaProfile::Slim,
+            Capability::MhcAlleles => has("mhc.a") || has("mhc.b"),
+        }
+    }
+
+    /// Why a table lacks the feature, ending with what to load instead
+    pub fn unsupported_reason(&self, profile: SchemaProfile) -> String {
+        let why = match (self, profile) {
+            (Capability::Method, SchemaProfile::Slim) => "slim VDJdb files have no method column".to_string(),
+            (Capability::Method, _) => "the database has no method column".to_string(),
+            (Capability::ComplexPairing, SchemaProfile::Slim) => {
+                "slim VDJdb files merge records and list several complex ids per row".to_string()
+            }
+            (Capability::ComplexPairing, _) => "the database has no complex.id column".to_string(),
+            (Capability::MhcAlleles, _) => "the database has no mhc.a/mhc.b columns".to_string(),
+        };
+        format!("{}: {}; load the full database instead, e.g. vdjdb_open(use_fat_db = TRUE)", self.as_str(), why)
+    }
+}
+
+/// Key/value pairs of a flat JSON object of strings as VDJdb stores them
+/// (`{"identification": "tetramer-sort", "frequency": "3/10"}`); None when
+/// the text is not such an object
+pub fn parse_flat_json(text: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = text.trim().chars().peekable();
+    let skip_ws = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+    };
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut out = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => out.push(match chars.next()? {
+                    't' => '\t',
+                    'n' => '\n',
+                    'r' => '\r',
+                    c => c,
+                }),
+                c => out.push(c),
+            }
+        }
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+    let mut pairs = Vec::new();
+    skip_ws(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return chars.next().is_none().then_some(pairs);
+    }
+    loop {
+        skip_ws(&mut chars);
+        let key = string(&mut chars)?;
+        skip_ws(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_ws(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            string(&mut chars)?
+        } else {
+            // Bare numbers, booleans and null
+            let mut bare = String::new();
+            while chars.peek().is_some_and(|c| !matches!(c, ',' | '}') && !c.is_whitespace()) {
+                bare.push(chars.next()?);
+            }
+            if bare == "null" { String::new() } else { bare }
+        };
+        pairs.push((key, value));
+        skip_ws(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+    skip_ws(&mut chars);
+    chars.next().is_none().then_some(pairs)
+}
+
+/// Header names given to more than one column, with their 1-based column
+/// positions, in order of first appearance
+pub fn duplicate_columns(columns: &[String]) -> Vec<(String, Vec<usize>)> {
+    let mut seen: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        match seen.iter_mut().find(|(name, _)| name == column) {
+            Some((_, positions)) => positions.push(i + 1),
+            None => seen.push((column.clone(), vec![i + 1])),
+        }
+    }
+    seen.retain(|(_, positions)| positions.len() > 1);
+    seen
+}
+
+/// Whether a column is read into a database field: a field name or alias,
+/// a `col_map` column or a column of a flattened field ("meta.study.id")
+pub fn is_field_column(column: &str, col_map: &[(String, String)]) -> bool {
+    canonical_field(column).is_some()
+        || col_map.iter().any(|(_, c)| c == column)
+        || FLATTENED_FIELDS
+            .iter()
+            .any(|f| column.strip_prefix(f).is_some_and(|rest| rest.starts_with('.') && rest.len() > 1))
+}
+
+/// Columns of a flattened `field` ("meta" -> [("study.id", 12), ...])
+pub fn flattened_columns(columns: &[String], field: &str) -> Vec<(String, usize)> {
+    columns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            let key = c.strip_prefix(field)?.strip_prefix('.')?;
+            (!key.is_empty()).then(|| (key.to_string(), i))
+        })
+        .collect()
+}
+
+/// JSON object in VDJdb's formatting (`{"key": "value", ...}`) from the
+/// values of flattened columns
+pub fn flattened_json<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let quote = |s: &str| {
+        let mut out = String::with_capacity(s.len() + 2);
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\t' => out.push_str("\\t"),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+        out
+    };
+    let fields: Vec<String> = pairs.into_iter().map(|(k, v)| format!("{}: {}", quote(k), quote(v))).collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// Column index of each of `DATABASE_FIELDS` (None when absent)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnIndex {
+    indices: Vec<Option<usize>>,
+}
+
+impl ColumnIndex {
+    pub fn get(&self, field: &str) -> Option<usize> {
+        let i = DATABASE_FIELDS.iter().position(|f| *f == field)?;
+        self.indices[i]
+    }
+
+    /// (field, column) pairs for fields read from a column of another name
+    pub fn renamed(&self, columns: &[String]) -> Vec<(String, String)> {
+        DATABASE_FIELDS
+            .iter()
+            .zip(&self.indices)
+            .filter_map(|(field, i)| Some((*field, columns.get((*i)?)?)))
+            .filter(|(field, column)| field != column)
+            .map(|(field, column)| (field.to_string(), column.clone()))
+            .collect()
+    }
+}
+
+/// Find the column holding each database field
+/// `col_map` pairs a field (VDJdb name or alias, e.g. "cdr3" or "v_segment")
+/// with the column that holds it and takes precedence. Other fields are found
+/// by exact name, then case-insensitively or through an alias ("CDR3",
+/// "cdr3_aa", "v_call"). Fails listing every missing required field.
+pub fn resolve_columns(columns: &[String], col_map: &[(String, String)]) -> Result<ColumnIndex> {
+    let mut indices = vec![None; DATABASE_FIELDS.len()];
+    let field_index = |field: &str| DATABASE_FIELDS.iter().position(|f| *f == field);
+
+    for (field, column) in col_map {
+        let field = canonical_field(field)
+            .ok_or_else(|| VdjMatchError::Configuration(format!("unknown database field in column mapping: {}", field)))?;
+        let i = columns.iter().position(|c| c == column).ok_or_else(|| {
+            VdjMatchError::InvalidFormat(format!("column mapping {} = {}: no such column", field, column))
+        })?;
+        indices[field_index(field).unwrap()] = Some(i);
+    }
+    for (i, column) in columns.iter().enumerate() {
+        if let Some(k) = field_index(column) {
+            indices[k].get_or_insert(i);
+        }
+    }
+    for (i, column) in columns.iter().enumerate() {
+        if let Some(k) = canonical_field(column).and_then(field_index) {
+            indices[k].get_or_insert(i);
+        }
+    }
+
+    let missing: Vec<&str> = REQUIRED_FIELDS
+        .iter()
+        .copied()
+        .filter(|field| indices[field_index(field).unwrap()].is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(VdjMatchError::MissingColumns {
+            missing: missing.into_iter().map(String::from).collect(),
+            found: columns.to_vec(),
+        });
+    }
+    Ok(ColumnIndex { indices })
+}
+
+/// Fields of a clonotype (query) table and their other spellings, compared
+/// after `normalize`
+pub const QUERY_FIELDS: &[(&str, &[&str])] = &[
+    ("cdr3", &["cdr3.aa", "cdr3aa", "junction.aa", "amino.acid"]),
+    ("v_segment", &["v.segm", "v.gene", "v.call", "v", "v.name"]),
+    ("j_segment", &["j.segm", "j.gene", "j.call", "j", "j.name"]),
+    ("d_segment", &["d.segm", "d.gene", "d.call", "d", "d.name"]),
+    ("count", &["clones", "clone.count", "duplicate.count", "umis", "reads"]),
+    ("frequency", &["freq", "proportion", "fraction", "clone.fraction"]),
+    ("barcode", &["cell.id", "cell.barcode"]),
+];
+
+/// `QUERY_FIELDS` position of the field a column name or alias refers to
+fn query_field(name: &str) -> Option<usize> {
+    let name = normalize(name);
+    QUERY_FIELDS
+        .iter()
+        .position(|(field, aliases)| normalize(field) == name || aliases.contains(&name.as_str()))
+}
+
+/// Column of each `QUERY_FIELDS` field in a clonotype table (None when absent)
+/// `col_map` (field, column) pairs take precedence; other fields are found by
+/// exact name, then case-insensitively or through an alias. Only cdr3 is required.
+pub fn resolve_query_columns(columns: &[String], col_map: &[(String, String)]) -> Result<Vec<Option<usize>>> {
+    let mut indices = vec![None; QUERY_FIELDS.len()];
+    for (field, column) in col_map {
+        let k = query_field(field)
+            .ok_or_else(|| VdjMatchError::Configuration(format!("unknown clonotype field in column mapping: {}", field)))?;
+        let i = columns.iter().position(|c| c == column).ok_or_else(|| {
+            VdjMatchError::InvalidFormat(format!("column mapping {} = {}: no such column", QUERY_FIELDS[k].0, column))
+        })?;
+        indices[k] = Some(i);
+    }
+    for (i, column) in columns.iter().enumerate() {
+        if let Some(k) = QUERY_FIELDS.iter().position(|(field, _)| field == column) {
+            indices[k].get_or_insert(i);
+        }
+    }
+    for (i, column) in columns.iter().enumerate() {
+        if let Some(k) = query_field(column) {
+            indices[k].get_or_insert(i);
+        }
+    }
+    if indices[0].is_none() {
+        return Err(VdjMatchError::MissingColumns { missing: vec!["cdr3".to_string()], found: columns.to_vec() });
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_resolve_columns() {
+        let columns = strings(&["CDR3", "v_call", "J.Segm", "Epitope", "cdr3_aa", "lab_v"]);
+        let index = resolve_columns(&columns, &[]).unwrap();
+        assert_eq!(index.get("cdr3"), Some(0));
+        assert_eq!(index.get("v.segm"), Some(1));
+        assert_eq!(index.get("j.segm"), Some(2));
+        assert_eq!(index.get("antigen.epitope"), Some(3));
+        assert_eq!(index.get("species"), None);
+
+        let mapped = [("v_segment".to_string(), "lab_v".to_string()), ("cdr3".to_string(), "cdr3_aa".to_string())];
+        let index = resolve_columns(&columns, &mapped).unwrap();
+        assert_eq!((index.get("cdr3"), index.get("v.segm")), (Some(4), Some(5)));
+        assert_eq!(
+            index.renamed(&columns),
+            [("cdr3", "cdr3_aa"), ("v.segm", "lab_v"), ("j.segm", "J.Segm"), ("antigen.epitope", "Epitope")]
+                .map(|(f, c)| (f.to_string(), c.to_string()))
+        );
+
+        let err = resolve_columns(&strings(&["cdr3", "epitope"]), &[]).unwrap_err().to_string();
+        assert!(err.contains("v.segm, j.segm"), "{}", err);
+        assert!(resolve_columns(&columns, &[("cdr3".to_string(), "nope".to_string())]).is_err());
+        assert!(resolve_columns(&columns, &[("colour".to_string(), "CDR3".to_string())]).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_columns() {
+        let columns = strings(&["cdr3", "note", "v.segm", "note", "cdr3", "note"]);
+        assert_eq!(
+            duplicate_columns(&columns),
+            [("cdr3".to_string(), vec![1, 5]), ("note".to_string(), vec![2, 4, 6])]
+        );
+        assert!(duplicate_columns(&strings(&["cdr3", "CDR3"])).is_empty());
+
+        assert!(is_field_column("junction_aa", &[]));
+        assert!(is_field_column("meta.study.id", &[]));
+        assert!(!is_field_column("note", &[]));
+        assert!(is_field_column("note", &[("meta".to_string(), "note".to_string())]));
+    }
+
+    #[test]
+    fn test_resolve_query_columns() {
+        let columns = strings(&["barcode", "CDR3_aa", "v_gene", "j_gene", "umis", "cdr3"]);
+        let index = resolve_query_columns(&columns, &[]).unwrap();
+        assert_eq!(index, [Some(5), Some(2), Some(3), None, Some(4), None, Some(0)]);
+
+        let mapped = [("cdr3".to_string(), "CDR3_aa".to_string()), ("count".to_string(), "barcode".to_string())];
+        let index = resolve_query_columns(&columns, &mapped).unwrap();
+        assert_eq!((index[0], index[4]), (Some(1), Some(0)));
+
+        let err = resolve_query_columns(&strings(&["v_gene"]), &[]).unwrap_err();
+        assert_eq!(err.code(), "missing_columns");
+        assert!(resolve_query_columns(&columns, &[("cdr3".to_string(), "nope".to_string())]).is_err());
+        assert!(resolve_query_columns(&columns, &[("epitope".to_string(), "cdr3".to_string())]).is_err());
+    }
+
+    #[test]
+    fn test_detect_profile() {
+        let base = ["gene", "cdr3", "v.segm", "j.segm", "species", "antigen.epitope"];
+        let with = |extra: &[&str]| strings(&[&base[..], extra].concat());
+        assert_eq!(
+            SchemaProfile::detect(&with(&["complex.id", "method", "meta", "cdr3fix", "vdjdb.score", "web.method"])),
+            SchemaProfile::Standard
+        );
+        assert_eq!(SchemaProfile::detect(&with(&["method", "meta", "cdr3fix", "vdjdb.score"])), SchemaProfile::Legacy);
+        assert_eq!(SchemaProfile::detect(&with(&["complex.id", "vdjdb.score", "j.start"])), SchemaProfile::Slim);
+        assert_eq!(
+            SchemaProfile::detect(&with(&["method.identification", "meta.study.id", "vdjdb.score"])),
+            SchemaProfile::Flattened
+        );
+        assert_eq!(SchemaProfile::detect(&strings(&["CDR3", "v_call", "j_call", "epitope"])), SchemaProfile::Custom);
+        for profile in ["standard", "slim", "legacy", "flattened", "custom"] {
+            assert_eq!(SchemaProfile::parse(profile).unwrap().as_str(), profile);
+        }
+        assert_eq!(flattened_columns(&with(&["meta.study.id", "meta"]), "meta"), [("study.id".to_string(), 6)]);
+        assert_eq!(flattened_json([("note", "a \"b\"")]), "{\"note\": \"a \\\"b\\\"\"}");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let slim = strings(&["gene", "cdr3", "species", "antigen.epitope", "complex.id", "v.segm", "j.segm", "mhc.a", "mhc.b", "vdjdb.score"]);
+        let fat = strings(&["complex.id", "gene", "cdr3", "v.segm", "j.segm", "species", "mhc.a", "antigen.epitope", "method", "meta", "web.method"]);
+        let custom = strings(&["cdr3_aa", "v_call", "j_call", "epitope"]);
+        let supported = |columns: &[String]| {
+            let profile = SchemaProfile::detect(columns);
+            CAPABILITIES.iter().filter(|c| c.supported(columns, profile)).map(|c| c.as_str()).collect::<Vec<_>>()
+        };
+        assert_eq!(SchemaProfile::detect(&slim).variant(), "slim");
+        assert_eq!(supported(&slim), ["mhc_alleles"]);
+        assert_eq!(SchemaProfile::detect(&fat).variant(), "fat");
+        assert_eq!(supported(&fat), ["method", "complex_pairing", "mhc_alleles"]);
+        assert!(supported(&custom).is_empty());
+        assert!(Capability::Method.unsupported_reason(SchemaProfile::Slim).contains("use_fat_db = TRUE"));
+
+        let method = "{\"identification\": \"tetramer-sort\", \"frequency\": \"3/10\", \"count\": 4, \"note\": null}";
+        assert_eq!(
+            parse_flat_json(method).unwrap(),
+            [("identification", "tetramer-sort"), ("frequency", "3/10"), ("count", "4"), ("note", "")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+        let round_trip = flattened_json([("note", "a \"b\"\tc")]);
+        assert_eq!(parse_flat_json(&round_trip).unwrap(), [("note".to_string(), "a \"b\"\tc".to_string())]);
+        assert_eq!(parse_flat_json("{}"), Some(vec![]));
+        assert_eq!(parse_flat_json("not json"), None);
+        assert_eq!(parse_flat_json("{\"a\": \"b\""), None);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::alignment::{Alignment, EditOp};
+use crate::alignment::{align, Alignment, EditOp};
+use crate::random::Rng;
+use crate::sequence::{segment_family, split_allele, AlleleMode, ResidueHandling, VMatchLevel};
 use std::collections::HashMap;
 
 lazy_static::lazy_static! {
@@ -43,6 +45,19 @@ lazy_static::lazy_static! {
 
 /// Compute alignment score using BLOSUM62-like matrix
 pub fn compute_alignment_score(aln: &Alignment) -> f64 {
+    compute_alignment_score_with(aln, &ResidueHandling::default())
+}
+
+/// Substitution score of a residue pair; wildcards under the neutral policy score 0
+fn pair_score(aa1: u8, aa2: u8, residues: &ResidueHandling) -> i32 {
+    if residues.is_wildcard(aa1) || residues.is_wildcard(aa2) {
+        return 0;
+    }
+    BLOSUM62.get(&(aa1, aa2)).copied().unwrap_or(-3)
+}
+
+/// `compute_alignment_score` with ambiguous residues scored per `residues`
+pub fn compute_alignment_score_with(aln: &Alignment, residues: &ResidueHandling) -> f64 {
     let query_bytes = aln.query.as_bytes();
     let target_bytes = aln.target.as_bytes();
     
@@ -56,7 +71,7 @@ pub fn compute_alignment_score(aln: &Alignment) -> f64 {
                 if qi < query_bytes.len() && ti < target_bytes.len() {
                     let aa1 = query_bytes[qi];
                     let aa2 = target_bytes[ti];
-                    score += BLOSUM62.get(&(aa1, aa2)).copied().unwrap_or(-3);
+                    score += pair_score(aa1, aa2, residues);
                     qi += 1;
                     ti += 1;
                 }
@@ -65,7 +80,7 @@ pub fn compute_alignment_score(aln: &Alignment) -> f64 {
                 if qi < query_bytes.len() && ti < target_bytes.len() {
                     let aa1 = query_bytes[qi];
                     let aa2 = target_bytes[ti];
-                    score += BLOSUM62.get(&(aa1, aa2)).copied().unwrap_or(-3);
+                    score += pair_score(aa1, aa2, residues);
                     qi += 1;
                     ti += 1;
                 }
@@ -86,7 +101,12 @@ pub fn compute_alignment_score(aln: &Alignment) -> f64 {
 
 /// Compute normalized alignment score (0-1 range)
 pub fn compute_normalized_score(aln: &Alignment) -> f64 {
-    let raw_score = compute_alignment_score(aln);
+    compute_normalized_score_with(aln, &ResidueHandling::default())
+}
+
+/// `compute_normalized_score` with ambiguous residues scored per `residues`
+pub fn compute_normalized_score_with(aln: &Alignment, residues: &ResidueHandling) -> f64 {
+    let raw_score = compute_alignment_score_with(aln, residues);
     
     // Compute max possible score (perfect match)
     let query_bytes = aln.query.as_bytes();
@@ -109,35 +129,546 @@ pub fn compute_normalized_score(aln: &Alignment) -> f64 {
     
     // Normalize: negative scores -> 0, positive scores scale to 1
     let normalized = (raw_score - (aln.edit_distance as f64 * -4.0)) / (max_possible as f64);
-    normalized.max(0.0).min(1.0)
+    if normalized.is_nan() {
+        return 0.0;
+    }
+    normalized.clamp(0.0, 1.0)
 }
 
 /// Simple scoring: just count mismatches
 pub fn simple_mismatch_score(aln: &Alignment) -> f64 {
-    1.0 - (aln.edit_distance as f64 / aln.query.len().max(aln.target.len()) as f64)
+    let max_len = aln.query.len().max(aln.target.len());
+
+    // Two empty sequences are trivially identical; avoid 0/0 = NaN
+    if max_len == 0 {
+        return 1.0;
+    }
+
+    1.0 - (aln.edit_distance as f64 / max_len as f64)
+}
+
+/// Simple (alignment-free) CDR3 score used outside the vdjmatch scoring scheme
+/// `MaxLength` divides the edit distance by the longer CDR3, so one mismatch
+/// costs more on a short CDR3 than on a long one; the other metrics keep a
+/// threshold meaning the same number of differences at every length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SimpleScore {
+    /// `simple_mismatch_score`: 1 - edit distance / longer length
+    #[default]
+    MaxLength,
+    /// Identity over the aligned columns, leaving out the first and last
+    /// column (the conserved Cys and Phe/Trp anchors, which nearly always
+    /// match and would inflate the identity of every hit)
+    TrimmedIdentity,
+    /// 1 / (1 + edit distance), independent of CDR3 length
+    Absolute,
+}
+
+impl SimpleScore {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "max_length" | "length" => Ok(Self::MaxLength),
+            "trimmed_identity" | "identity" => Ok(Self::TrimmedIdentity),
+            "absolute" | "distance" => Ok(Self::Absolute),
+            _ => Err(format!(
+                "Invalid simple score: {} (expected max_length, trimmed_identity or absolute)",
+                s
+            )),
+        }
+    }
+
+    pub fn score(self, aln: &Alignment) -> f64 {
+        match self {
+            Self::MaxLength => simple_mismatch_score(aln),
+            Self::TrimmedIdentity => trimmed_identity_score(aln),
+            Self::Absolute => 1.0 / (1.0 + aln.edit_distance as f64),
+        }
+    }
+}
+
+/// Fraction of matching columns once the terminal alignment columns are
+/// trimmed; alignments too short to trim fall back to all columns
+pub fn trimmed_identity_score(aln: &Alignment) -> f64 {
+    let ops = &aln.operations;
+    let inner = if ops.len() > 2 { &ops[1..ops.len() - 1] } else { &ops[..] };
+    if inner.is_empty() {
+        return if aln.edit_distance == 0 { 1.0 } else { 0.0 };
+    }
+    let matches = inner.iter().filter(|&&op| op == EditOp::Match).count();
+    matches as f64 / inner.len() as f64
 }
 
 /// Segment matching score
+/// Case and surrounding whitespace are ignored; `normalize` also drops alleles.
 pub fn segment_match_score(query_segment: &str, db_segment: &str, normalize: bool) -> f64 {
     let query_norm = if normalize {
-        query_segment.split('*').next().unwrap_or(query_segment)
+        split_allele(query_segment).0
     } else {
-        query_segment
+        query_segment.trim()
     };
     
     let db_norm = if normalize {
-        db_segment.split('*').next().unwrap_or(db_segment)
+        split_allele(db_segment).0
     } else {
-        db_segment
+        db_segment.trim()
     };
     
-    if query_norm == db_norm {
+    if query_norm.eq_ignore_ascii_case(db_norm) {
         1.0
     } else {
         0.0
     }
 }
 
+/// Segment score under an allele mode
+/// With `AlleleMode::Prefer`, a gene match with a differing allele scores 0.5.
+pub fn allele_segment_score(query_segment: &str, db_segment: &str, mode: AlleleMode) -> f64 {
+    let score = segment_match_score(query_segment, db_segment, true);
+    if mode == AlleleMode::Prefer && score > 0.0 && !AlleleMode::alleles_agree(query_segment, db_segment) {
+        0.5
+    } else {
+        score
+    }
+}
+
+/// V score of a hit matched on the family only under `VMatchLevel::Family`
+pub const FAMILY_SEGMENT_SCORE: f64 = 0.5;
+
+/// V segment score under a V match level: as `allele_segment_score`, with
+/// `FAMILY_SEGMENT_SCORE` for other genes of the family at the family level
+pub fn v_segment_score(query_segment: &str, db_segment: &str, mode: AlleleMode, level: VMatchLevel) -> f64 {
+    let score = allele_segment_score(query_segment, db_segment, level.allele_mode(mode));
+    if score == 0.0
+        && level == VMatchLevel::Family
+        && segment_family(query_segment).eq_ignore_ascii_case(segment_family(db_segment))
+    {
+        FAMILY_SEGMENT_SCORE
+    } else {
+        score
+    }
+}
+
+/// Evidence behind a single hit, combined by `specificity_confidence`
+#[derive(Debug, Clone, Copy)]
+pub struct SpecificityEvidence {
+    /// Match score in [0, 1]
+    pub match_score: f64,
+    /// VDJdb confidence score (0-3)
+    pub vdjdb_score: u8,
+    /// Distinct references supporting the hit's epitope among the query's hits
+    pub n_references: usize,
+    /// Database entries for the hit's epitope
+    pub epitope_size: usize,
+    pub edit_distance: usize,
+}
+
+/// Logistic model of the probability that a hit's epitope is the query's
+/// Weights apply to the match score, VDJdb score, ln(references), edit distance
+/// and ln(1 + epitope database size) of `SpecificityEvidence`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpecificityModel {
+    pub intercept: f64,
+    pub weights: [f64; 5],
+}
+
+/// Newton steps of `SpecificityModel::fit`
+const FIT_ITERATIONS: usize = 100;
+
+/// L2 penalty on the weights (not the intercept), keeping the fit finite when
+/// a feature separates the outcomes
+const FIT_RIDGE: f64 = 1e-3;
+
+/// (coefficient, sign) of the match quality terms: a better match score never
+/// lowers the probability and a larger edit distance never raises it
+const MATCH_QUALITY_SIGNS: [(usize, f64); 2] = [(1, 1.0), (4, -1.0)];
+
+impl SpecificityModel {
+    /// Fitted with `fit` to `matching::specificity_samples` of the packaged
+    /// VDJdb slim release (5000 held-out queries, scope "2,1,1,2" with V/J
+    /// matching, seed 1). Both match quality weights come out at their bound
+    /// of 0: among hits already within scope they carry no evidence beyond
+    /// the reference support.
+    pub const VDJDB: Self = Self { intercept: -3.3699, weights: [0.0, 0.1250, 1.5385, 0.0, -0.0130] };
+
+    fn features(e: &SpecificityEvidence) -> [f64; 6] {
+        [
+            1.0,
+            e.match_score.clamp(0.0, 1.0),
+            e.vdjdb_score.min(3) as f64,
+            (e.n_references.max(1) as f64).ln(),
+            e.edit_distance as f64,
+            (1.0 + e.epitope_size as f64).ln(),
+        ]
+    }
+
+    fn coefficients(&self) -> [f64; 6] {
+        let w = &self.weights;
+        [self.intercept, w[0], w[1], w[2], w[3], w[4]]
+    }
+
+    pub fn probability(&self, e: &SpecificityEvidence) -> f64 {
+        logistic(&self.coefficients(), &Self::features(e))
+    }
+
+    /// Maximum-likelihood weights for samples of (evidence, whether the hit's
+    /// epitope was the query's) by Newton-Raphson; None unless both outcomes occur
+    /// The match score and edit distance weights keep their signs (a weight
+    /// that would take the wrong sign is 0), so the probability is monotone in
+    /// match quality even where the two terms are collinear.
+    pub fn fit(samples: &[(SpecificityEvidence, bool)]) -> Option<Self> {
+        let positives = samples.iter().filter(|(_, y)| *y).count();
+        if positives == 0 || positives == samples.len() {
+            return None;
+        }
+        let rows: Vec<([f64; 6], f64)> =
+            samples.iter().map(|(e, y)| (Self::features(e), if *y { 1.0 } else { 0.0 })).collect();
+        let rate = positives as f64 / samples.len() as f64;
+
+        // The constrained optimum is the best unconstrained fit, over the sets
+        // of match quality weights held at 0, that keeps the signs
+        let feasible = |beta: &[f64; 6]| MATCH_QUALITY_SIGNS.iter().all(|&(i, sign)| beta[i] * sign >= 0.0);
+        let mut best: Option<([f64; 6], f64)> = None;
+        for held in 0..1usize << MATCH_QUALITY_SIGNS.len() {
+            let pinned: Vec<usize> = MATCH_QUALITY_SIGNS
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| held >> k & 1 == 1)
+                .map(|(_, &(i, _))| i)
+                .collect();
+            let Some(beta) = fit_logistic(&rows, rate, &pinned) else { continue };
+            if !feasible(&beta) {
+                continue;
+            }
+            let objective = penalized_log_likelihood(&beta, &rows);
+            if best.map_or(true, |(_, b)| objective > b) {
+                best = Some((beta, objective));
+            }
+        }
+        let (beta, _) = best?;
+        Some(Self { intercept: beta[0], weights: [beta[1], beta[2], beta[3], beta[4], beta[5]] })
+    }
+}
+
+/// Ridge-penalized logistic regression by Newton-Raphson, with the
+/// coefficients in `pinned` held at 0
+fn fit_logistic(rows: &[([f64; 6], f64)], rate: f64, pinned: &[usize]) -> Option<[f64; 6]> {
+    // Start from the base rate so the first step is well scaled
+    let mut beta = [(rate / (1.0 - rate)).ln(), 0.0, 0.0, 0.0, 0.0, 0.0];
+    for _ in 0..FIT_ITERATIONS {
+        let mut gradient = [0.0; 6];
+        let mut hessian = [[0.0; 6]; 6];
+        for (x, y) in rows {
+            let p = logistic(&beta, x);
+            let w = p * (1.0 - p);
+            for i in 0..6 {
+                gradient[i] += (y - p) * x[i];
+                for j in 0..6 {
+                    hessian[i][j] += w * x[i] * x[j];
+                }
+            }
+        }
+        for i in 1..6 {
+            gradient[i] -= FIT_RIDGE * beta[i];
+            hessian[i][i] += FIT_RIDGE;
+        }
+        for &i in pinned {
+            gradient[i] = 0.0;
+            hessian.iter_mut().for_each(|row| row[i] = 0.0);
+            hessian[i] = [0.0; 6];
+            hessian[i][i] = 1.0;
+        }
+        let step = solve(hessian, gradient)?;
+        beta.iter_mut().zip(&step).for_each(|(b, s)| *b += s);
+        if step.iter().map(|s| s.abs()).fold(0.0, f64::max) < 1e-10 {
+            break;
+        }
+    }
+    Some(beta)
+}
+
+/// Log-likelihood of `rows` under `beta`, less the ridge penalty of `fit_logistic`
+fn penalized_log_likelihood(beta: &[f64; 6], rows: &[([f64; 6], f64)]) -> f64 {
+    let likelihood: f64 = rows
+        .iter()
+        .map(|(x, y)| {
+            let z: f64 = beta.iter().zip(x).map(|(b, v)| b * v).sum();
+            // y z - ln(1 + e^z), computed without overflow
+            y * z - z.max(0.0) - (-z.abs()).exp().ln_1p()
+        })
+        .sum();
+    likelihood - 0.5 * FIT_RIDGE * beta[1..].iter().map(|b| b * b).sum::<f64>()
+}
+
+fn logistic(beta: &[f64; 6], x: &[f64; 6]) -> f64 {
+    let z: f64 = beta.iter().zip(x).map(|(b, v)| b * v).sum();
+    1.0 / (1.0 + (-z).exp())
+}
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting; None when singular
+fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col];
+        for row in col + 1..N {
+            let factor = a[row][col] / pivot_row[col];
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let rest: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Predicted against observed outcome rate in one probability bin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationBin {
+    pub n: usize,
+    pub predicted: f64,
+    pub observed: f64,
+}
+
+/// Reliability table of (probability, outcome) pairs over `n_bins` equal-width
+/// probability bins; empty bins are left out
+pub fn calibration_table(samples: &[(f64, bool)], n_bins: usize) -> Vec<(usize, CalibrationBin)> {
+    let n_bins = n_bins.max(1);
+    let mut bins = vec![(0usize, 0.0, 0.0); n_bins];
+    for &(p, y) in samples {
+        let bin = &mut bins[((p * n_bins as f64) as usize).min(n_bins - 1)];
+        bin.0 += 1;
+        bin.1 += p;
+        bin.2 += if y { 1.0 } else { 0.0 };
+    }
+    bins.into_iter()
+        .enumerate()
+        .filter(|(_, (n, _, _))| *n > 0)
+        .map(|(i, (n, p, y))| (i, CalibrationBin { n, predicted: p / n as f64, observed: y / n as f64 }))
+        .collect()
+}
+
+/// Isotonic regression of outcomes on probabilities: (probability, observed
+/// rate) knots, both increasing, for `recalibrate`
+/// Samples are pooled in runs of at least `min_block` by probability before
+/// adjacent violators are merged, so no knot rests on a handful of hits.
+pub fn fit_recalibration(samples: &[(f64, bool)], min_block: usize) -> Vec<(f64, f64)> {
+    let mut sorted: Vec<(f64, bool)> = samples.iter().copied().filter(|(p, _)| p.is_finite()).collect();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let min_block = min_block.max(1);
+
+    // (sum of probabilities, sum of outcomes, count) per block
+    let mut blocks: Vec<(f64, f64, usize)> = Vec::new();
+    for chunk in sorted.chunks(min_block) {
+        let block = chunk.iter().fold((0.0, 0.0, 0), |(p, y, n), &(pi, yi)| (p + pi, y + if yi { 1.0 } else { 0.0 }, n + 1));
+        match blocks.last_mut() {
+            // A short final chunk joins the one before it
+            Some(last) if chunk.len() < min_block => *last = (last.0 + block.0, last.1 + block.1, last.2 + block.2),
+            _ => blocks.push(block),
+        }
+        // Pool adjacent violators: rates must increase strictly
+        while blocks.len() > 1 {
+            let (b, a) = (blocks[blocks.len() - 1], blocks[blocks.len() - 2]);
+            if a.1 / (a.2 as f64) < b.1 / (b.2 as f64) {
+                break;
+            }
+            blocks.pop();
+            *blocks.last_mut().expect("two blocks") = (a.0 + b.0, a.1 + b.1, a.2 + b.2);
+        }
+    }
+    blocks.into_iter().map(|(p, y, n)| (p / n as f64, y / n as f64)).collect()
+}
+
+/// Map a probability through `fit_recalibration` knots: linear between knots,
+/// the end rates beyond them; unchanged without knots
+pub fn recalibrate(knots: &[(f64, f64)], p: f64) -> f64 {
+    let (Some(first), Some(last)) = (knots.first(), knots.last()) else { return p };
+    if p <= first.0 {
+        return first.1;
+    }
+    if p >= last.0 {
+        return last.1;
+    }
+    let upper = knots.partition_point(|k| k.0 <= p);
+    let ((x0, y0), (x1, y1)) = (knots[upper - 1], knots[upper]);
+    y0 + (y1 - y0) * (p - x0) / (x1 - x0)
+}
+
+/// `fit_recalibration` (runs of 500 hits) of `SpecificityModel::VDJDB` on the
+/// 20000 held-out queries that follow the model's 5000 in the same seed-1 draw
+/// Confidence tops out at 0.88: no held-out run of hits did better.
+pub const VDJDB_RECALIBRATION: &[(f64, f64)] = &[
+    (0.0293, 0.0000),
+    (0.0385, 0.0387),
+    (0.1052, 0.2111),
+    (0.1633, 0.2200),
+    (0.2240, 0.2413),
+    (0.3688, 0.4582),
+    (0.4713, 0.4740),
+    (0.5440, 0.7008),
+    (0.6689, 0.7710),
+    (0.7466, 0.8267),
+    (0.7970, 0.8550),
+    (0.8727, 0.8687),
+    (0.9315, 0.8755),
+];
+
+/// Probability that a hit's epitope is the query's: `SpecificityModel::VDJDB`,
+/// mapped through `VDJDB_RECALIBRATION` so predicted rates match observed ones
+/// on held-out VDJdb queries. It never falls as the match score rises or the
+/// edit distance shrinks. On other databases or scopes check it with
+/// `matching::specificity_samples` and `calibration_table`.
+pub fn specificity_confidence(e: &SpecificityEvidence) -> f64 {
+    recalibrate(VDJDB_RECALIBRATION, SpecificityModel::VDJDB.probability(e))
+}
+
+/// How a hit's substitution score (`compute_alignment_score`) is made
+/// comparable across CDR3 lengths for the `normalized_score` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreNormalization {
+    /// Raw substitution score
+    #[default]
+    None,
+    /// Score per query residue
+    QueryLength,
+    /// Fraction of the query's self-alignment score
+    MaxScore,
+    /// Standard deviations above the query's scores against database CDR3s of
+    /// the same length (`ScoreBackground`)
+    ZScore,
+}
+
+impl ScoreNormalization {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "none" | "raw" => Ok(Self::None),
+            "length" | "query_length" => Ok(Self::QueryLength),
+            "max" | "max_score" => Ok(Self::MaxScore),
+            "zscore" | "z" => Ok(Self::ZScore),
+            _ => Err(format!("Invalid score normalization: {} (expected none, length, max or zscore)", s)),
+        }
+    }
+
+    /// Normalize a raw score of `query`; z-scores need the query's background
+    /// (mean, sd) and are NaN without one
+    pub fn apply(self, raw: f64, query: &str, background: Option<(f64, f64)>) -> f64 {
+        match self {
+            Self::None => raw,
+            Self::QueryLength if query.is_empty() => 0.0,
+            Self::QueryLength => raw / query.len() as f64,
+            Self::MaxScore => {
+                let max = max_alignment_score(query);
+                if max > 0.0 { raw / max } else { 0.0 }
+            }
+            Self::ZScore => match background {
+                Some((mean, sd)) if sd > 0.0 => (raw - mean) / sd,
+                _ => f64::NAN,
+            },
+        }
+    }
+}
+
+/// Highest achievable substitution score of a CDR3 (its self-alignment score)
+pub fn max_alignment_score(cdr3: &str) -> f64 {
+    cdr3.bytes().map(|aa| BLOSUM62.get(&(aa, aa)).copied().unwrap_or(4)).sum::<i32>() as f64
+}
+
+/// Background CDR3s per length for z-score normalization
+#[derive(Debug, Clone, Default)]
+pub struct ScoreBackground {
+    by_length: HashMap<usize, Vec<String>>,
+}
+
+impl ScoreBackground {
+    /// Up to `per_length` CDR3s of each length, drawn uniformly without replacement
+    pub fn sample<'a>(cdr3s: impl IntoIterator<Item = &'a str>, per_length: usize, rng: &mut Rng) -> Self {
+        let mut by_length: HashMap<usize, Vec<String>> = HashMap::new();
+        for cdr3 in cdr3s {
+            let cdr3 = cdr3.to_uppercase();
+            by_length.entry(cdr3.len()).or_default().push(cdr3);
+        }
+        // Shuffle lengths in a fixed order so the sample depends only on the seed
+        let mut lengths: Vec<usize> = by_length.keys().copied().collect();
+        lengths.sort_unstable();
+        for len in lengths {
+            let group = by_length.get_mut(&len).unwrap();
+            group.sort_unstable();
+            group.dedup();
+            rng.shuffle(group);
+            group.truncate(per_length);
+        }
+        Self { by_length }
+    }
+
+    /// Mean and standard deviation of the query's scores against background
+    /// CDR3s of its length (None with fewer than two)
+    pub fn moments(&self, query: &str) -> Option<(f64, f64)> {
+        let group = self.by_length.get(&query.len()).filter(|g| g.len() >= 2)?;
+        let scores: Vec<f64> = group.iter().map(|cdr3| compute_alignment_score(&align(query, cdr3))).collect();
+        let n = scores.len() as f64;
+        let mean = scores.iter().sum::<f64>() / n;
+        let var = scores.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        Some((mean, var.sqrt()))
+    }
+}
+
+/// Empirical null of CDR3 substitution scores, per CDR3 length
+/// Built from all pairs of distinct same-length CDR3s of a `ScoreBackground`;
+/// a hit's p-value is the chance that a random database CDR3 of the query's
+/// length scores at least as well.
+#[derive(Debug, Clone, Default)]
+pub struct ScoreNull {
+    /// Sorted ascending
+    by_length: HashMap<usize, Vec<f64>>,
+    pooled: Vec<f64>,
+}
+
+/// Fewest null scores a length needs before the pooled null is used instead
+const MIN_NULL_SCORES: usize = 20;
+
+impl ScoreNull {
+    pub fn from_background(background: &ScoreBackground) -> Self {
+        let mut by_length = HashMap::new();
+        let mut pooled = Vec::new();
+        for (&len, group) in &background.by_length {
+            let mut scores = Vec::with_capacity(group.len() * group.len().saturating_sub(1) / 2);
+            for (i, a) in group.iter().enumerate() {
+                for b in &group[i + 1..] {
+                    scores.push(compute_alignment_score(&align(a, b)));
+                }
+            }
+            scores.sort_by(f64::total_cmp);
+            pooled.extend_from_slice(&scores);
+            by_length.insert(len, scores);
+        }
+        pooled.sort_by(f64::total_cmp);
+        Self { by_length, pooled }
+    }
+
+    /// P(null score >= `score`) for a query of length `query_len`, with a +1
+    /// correction so p-values are never 0 (NaN for an empty null)
+    pub fn p_value(&self, query_len: usize, score: f64) -> f64 {
+        let scores = self
+            .by_length
+            .get(&query_len)
+            .filter(|s| s.len() >= MIN_NULL_SCORES)
+            .unwrap_or(&self.pooled);
+        if scores.is_empty() {
+            return f64::NAN;
+        }
+        let at_least = scores.len() - scores.partition_point(|&x| x < score);
+        (at_least + 1) as f64 / (scores.len() + 1) as f64
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,5 +690,191 @@ mod tests {
         assert_eq!(segment_match_score("TRBV12-3*01", "TRBV12-3*02", true), 1.0);
         assert_eq!(segment_match_score("TRBV12-3*01", "TRBV12-3*02", false), 0.0);
         assert_eq!(segment_match_score("TRBV12-3", "TRBV12-4", true), 0.0);
+
+        assert_eq!(allele_segment_score("TRBV12-3*01", "TRBV12-3*02", AlleleMode::Ignore), 1.0);
+        assert_eq!(allele_segment_score("TRBV12-3*01", "TRBV12-3*02", AlleleMode::Prefer), 0.5);
+        assert_eq!(allele_segment_score("TRBV12-3*01", "TRBV12-3", AlleleMode::Prefer), 1.0);
+        assert_eq!(allele_segment_score("trbv12-3*01 ", "TRBV12-3*02", AlleleMode::Prefer), 0.5);
+        assert_eq!(segment_match_score(" TRBV12-3", "trbv12-3", false), 1.0);
+        assert_eq!(v_segment_score("TRBV6-2", "TRBV6-3", AlleleMode::Ignore, VMatchLevel::Family), FAMILY_SEGMENT_SCORE);
+        assert_eq!(v_segment_score("TRBV6-2", "TRBV6-2*01", AlleleMode::Ignore, VMatchLevel::Family), 1.0);
+        assert_eq!(v_segment_score("TRBV6-2", "TRBV6-3", AlleleMode::Ignore, VMatchLevel::Gene), 0.0);
+    }
+
+    #[test]
+    fn test_scores_on_empty_sequences() {
+        let aln = align("", "");
+        assert_eq!(simple_mismatch_score(&aln), 1.0);
+        assert!(!compute_normalized_score(&aln).is_nan());
+
+        let aln = align("", "CASSF");
+        assert_eq!(simple_mismatch_score(&aln), 0.0);
+        assert!(!compute_normalized_score(&aln).is_nan());
+    }
+
+    #[test]
+    fn test_simple_scores() {
+        // One substitution on a short and on a long CDR3
+        let short = align("CASSLGF", "CASSQGF");
+        let long = align("CASSLAPGATNEKLFF", "CASSLAPGQTNEKLFF");
+        assert!(SimpleScore::MaxLength.score(&short) < SimpleScore::MaxLength.score(&long));
+        assert_eq!(SimpleScore::Absolute.score(&short), 0.5);
+        assert_eq!(SimpleScore::Absolute.score(&short), SimpleScore::Absolute.score(&long));
+        assert!((SimpleScore::TrimmedIdentity.score(&short) - 4.0 / 5.0).abs() < 1e-12);
+        assert!((SimpleScore::TrimmedIdentity.score(&long) - 13.0 / 14.0).abs() < 1e-12);
+
+        // Terminal columns do not count
+        let anchors = align("CASSLGF", "SASSLGW");
+        assert_eq!(SimpleScore::TrimmedIdentity.score(&anchors), 1.0);
+        assert_eq!(SimpleScore::TrimmedIdentity.score(&align("", "")), 1.0);
+        assert_eq!(SimpleScore::TrimmedIdentity.score(&align("", "CF")), 0.0);
+
+        assert_eq!(SimpleScore::parse("Absolute"), Ok(SimpleScore::Absolute));
+        assert_eq!(SimpleScore::parse("identity"), Ok(SimpleScore::TrimmedIdentity));
+        assert!(SimpleScore::parse("bogus").is_err());
+    }
+
+    #[test]
+    fn test_specificity_confidence() {
+        let base = SpecificityEvidence { match_score: 1.0, vdjdb_score: 1, n_references: 1, epitope_size: 100, edit_distance: 0 };
+        let c = specificity_confidence(&base);
+        assert!(c > 0.0 && c < 1.0);
+        // Independent references are the strongest evidence in held-out VDJdb hits
+        assert!(specificity_confidence(&SpecificityEvidence { n_references: 4, ..base }) > c);
+        assert!(specificity_confidence(&SpecificityEvidence { vdjdb_score: 3, ..base }) > c);
+
+        // A closer match never lowers the confidence
+        for refs in [1, 3, 10] {
+            let e = SpecificityEvidence { n_references: refs, ..base };
+            let by_score: Vec<f64> = (0..=10)
+                .map(|i| specificity_confidence(&SpecificityEvidence { match_score: i as f64 / 10.0, ..e }))
+                .collect();
+            assert!(by_score.windows(2).all(|w| w[0] <= w[1]), "{:?}", by_score);
+            let by_distance: Vec<f64> =
+                (0..6).map(|d| specificity_confidence(&SpecificityEvidence { edit_distance: d, ..e })).collect();
+            assert!(by_distance.windows(2).all(|w| w[0] >= w[1]), "{:?}", by_distance);
+        }
+        // Capped at the best held-out rate
+        let strong = SpecificityEvidence { vdjdb_score: 3, n_references: 50, ..base };
+        assert_eq!(specificity_confidence(&strong), VDJDB_RECALIBRATION.last().unwrap().1);
+        assert!(VDJDB_RECALIBRATION.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn test_specificity_model_fit() {
+        // Outcomes drawn from a known model: the fit recovers it and is calibrated
+        let truth = SpecificityModel { intercept: -2.0, weights: [3.0, 0.5, 1.0, -0.8, -0.2] };
+        let mut rng = Rng::new(3);
+        let samples: Vec<(SpecificityEvidence, bool)> = (0..20000)
+            .map(|_| {
+                let e = SpecificityEvidence {
+                    match_score: rng.next_f64(),
+                    vdjdb_score: rng.below(4) as u8,
+                    n_references: 1 + rng.below(6),
+                    epitope_size: 1 + rng.below(3000),
+                    edit_distance: rng.below(4),
+                };
+                let y = rng.next_f64() < truth.probability(&e);
+                (e, y)
+            })
+            .collect();
+        let fit = SpecificityModel::fit(&samples).unwrap();
+        assert!((fit.intercept - truth.intercept).abs() < 0.5, "{:?}", fit);
+        for (w, t) in fit.weights.iter().zip(&truth.weights) {
+            assert!((w - t).abs() < 0.25, "{:?}", fit);
+        }
+
+        let predicted: Vec<(f64, bool)> = samples.iter().map(|(e, y)| (fit.probability(e), *y)).collect();
+        let table = calibration_table(&predicted, 5);
+        assert_eq!(table.iter().map(|(_, b)| b.n).sum::<usize>(), samples.len());
+        for (_, bin) in table.iter().filter(|(_, b)| b.n >= 500) {
+            assert!((bin.predicted - bin.observed).abs() < 0.05, "{:?}", bin);
+        }
+
+        // A match score that lowers the odds is held at 0 rather than fitted
+        let reversed = SpecificityModel { intercept: -2.0, weights: [-3.0, 0.5, 1.0, -0.8, -0.2] };
+        let flipped: Vec<(SpecificityEvidence, bool)> =
+            samples.iter().map(|(e, _)| (*e, rng.next_f64() < reversed.probability(e))).collect();
+        let fit = SpecificityModel::fit(&flipped).unwrap();
+        assert_eq!(fit.weights[0], 0.0);
+        assert!(fit.weights[3] <= 0.0);
+
+        assert!(SpecificityModel::fit(&[]).is_none());
+        let positives: Vec<_> = samples.iter().map(|(e, _)| (*e, true)).collect();
+        assert!(SpecificityModel::fit(&positives).is_none());
+    }
+
+    #[test]
+    fn test_recalibration() {
+        // An overconfident model: the log-odds are three times the truth
+        let mut rng = Rng::new(5);
+        let mut draw = |n: usize| -> Vec<(f64, bool)> {
+            (0..n)
+                .map(|_| {
+                    let truth = rng.next_f64();
+                    let raw = 1.0 / (1.0 + ((1.0 - truth) / truth).powi(3));
+                    (raw, rng.next_f64() < truth)
+                })
+                .collect()
+        };
+        let (fitting, held_out) = (draw(20000), draw(20000));
+        let top = |samples: &[(f64, bool)]| calibration_table(samples, 10).last().unwrap().1;
+        let raw = top(&held_out);
+        assert!(raw.predicted - raw.observed > 0.1, "{:?}", raw);
+
+        let knots = fit_recalibration(&fitting, 200);
+        assert!(knots.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1), "{:?}", knots);
+        let recalibrated: Vec<(f64, bool)> = held_out.iter().map(|&(p, y)| (recalibrate(&knots, p), y)).collect();
+        let bin = top(&recalibrated);
+        assert!(bin.n >= 500 && (bin.predicted - bin.observed).abs() < 0.03, "{:?}", bin);
+
+        // Linear between knots, flat beyond them, identity without any
+        let knots = [(0.2, 0.1), (0.6, 0.5)];
+        assert!((recalibrate(&knots, 0.4) - 0.3).abs() < 1e-12);
+        assert_eq!(recalibrate(&knots, 0.0), 0.1);
+        assert_eq!(recalibrate(&knots, 0.9), 0.5);
+        assert_eq!(recalibrate(&[], 0.9), 0.9);
+        // Decreasing outcomes pool into one knot; a short tail joins its neighbour
+        let falling = [(0.1, true), (0.2, true), (0.3, false), (0.4, false), (0.5, false)];
+        assert_eq!(fit_recalibration(&falling, 2), vec![(0.3, 0.4)]);
+        assert!(fit_recalibration(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn test_score_normalization() {
+        let query = "CASSLGF";
+        let raw = compute_alignment_score(&align(query, query));
+        assert_eq!(raw, max_alignment_score(query));
+        assert_eq!(ScoreNormalization::MaxScore.apply(raw, query, None), 1.0);
+        assert_eq!(ScoreNormalization::QueryLength.apply(raw, query, None), raw / 7.0);
+        assert_eq!(ScoreNormalization::None.apply(raw, query, None), raw);
+        assert!(ScoreNormalization::ZScore.apply(raw, query, None).is_nan());
+        assert_eq!(ScoreNormalization::parse("zscore"), Ok(ScoreNormalization::ZScore));
+        assert!(ScoreNormalization::parse("quantile").is_err());
+
+        let pool = ["CASSPGF", "CASRQDF", "CAVRDNF", "CASSLGF", "CASS"];
+        let background = ScoreBackground::sample(pool, 10, &mut Rng::new(1));
+        let (mean, sd) = background.moments(query).unwrap();
+        assert!(sd > 0.0 && mean < raw);
+        assert!(ScoreNormalization::ZScore.apply(raw, query, Some((mean, sd))) > 0.0);
+        assert!(background.moments("CAS").is_none());
+        assert_eq!(ScoreBackground::sample(pool, 2, &mut Rng::new(1)).by_length[&7].len(), 2);
+    }
+
+    #[test]
+    fn test_score_null_p_values() {
+        let pool = ["CASSLGF", "CASSPGF", "CASRQDF", "CAVRDNF", "CSARDGF", "CASSQEF", "CAWSVGF", "CASS"];
+        let null = ScoreNull::from_background(&ScoreBackground::sample(pool, 10, &mut Rng::new(1)));
+        // 7 length-7 CDR3s give 21 pairs, enough for a length-specific null
+        assert_eq!(null.by_length[&7].len(), 21);
+        let best = null.p_value(7, max_alignment_score("CASSLGF"));
+        let worst = null.p_value(7, f64::NEG_INFINITY);
+        assert!(best < 0.1 && best > 0.0);
+        assert_eq!(worst, 1.0);
+        // Too few length-4 pairs: falls back to the pooled null
+        assert_eq!(null.p_value(4, f64::NEG_INFINITY), 1.0);
+        assert!(ScoreNull::default().p_value(7, 0.0).is_nan());
     }
 }
+
+
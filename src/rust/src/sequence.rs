@@ -23,6 +23,52 @@ impl Cdr3Sequence {
     pub fn as_bytes(&self) -> &[u8] {
         self.sequence.as_bytes()
     }
+
+    pub fn tokens(&self) -> Cdr3Tokens {
+        Cdr3Tokens::new(&self.sequence)
+    }
+}
+
+/// CDR3 converted once for the comparison kernels: uppercase residues, their
+/// tcrdist cost-table indices and whether any residue is ambiguous or not a letter
+/// Gap characters are kept; which characters are gaps is a matching option
+/// applied by `ResidueHandling::prepare_tokens`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cdr3Tokens {
+    residues: Box<str>,
+    encoded: Box<[u8]>,
+    ambiguous: bool,
+    symbols: bool,
+}
+
+impl Cdr3Tokens {
+    pub fn new(sequence: &str) -> Self {
+        let residues = sequence.to_uppercase();
+        Self {
+            encoded: crate::tcrdist::encode_sequence(&residues).into(),
+            ambiguous: residues.bytes().any(is_ambiguous_residue),
+            symbols: !residues.bytes().all(|aa| aa.is_ascii_uppercase()),
+            residues: residues.into(),
+        }
+    }
+
+    pub fn residues(&self) -> &str {
+        &self.residues
+    }
+
+    /// Residues as `tcrdist::encode_sequence` maps them
+    pub fn encoded(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    pub fn is_ambiguous(&self) -> bool {
+        self.ambiguous
+    }
+
+    /// Whether any residue is something other than an uppercase letter, such as a gap
+    pub fn has_symbols(&self) -> bool {
+        self.symbols
+    }
 }
 
 impl fmt::Display for Cdr3Sequence {
@@ -66,9 +112,9 @@ impl Clonotype {
         }
     }
     
-    /// Normalize segment names (remove allele information)
+    /// Normalize segment names (case and surrounding whitespace, allele removed)
     pub fn normalize_segment(segment: &str) -> String {
-        segment.split('*').next().unwrap_or(segment).to_string()
+        split_allele(&canonical_segment(segment)).0.to_string()
     }
     
     pub fn v_normalized(&self) -> String {
@@ -136,12 +182,389 @@ impl SearchScope {
     pub fn is_exact(&self) -> bool {
         self.total == 0
     }
+
+    /// Scope allowing at least what each of `scopes` allows
+    pub fn widest(scopes: &[Self]) -> Self {
+        scopes.iter().fold(Self::EXACT, |w, s| Self {
+            substitutions: w.substitutions.max(s.substitutions),
+            insertions: w.insertions.max(s.insertions),
+            deletions: w.deletions.max(s.deletions),
+            total: w.total.max(s.total),
+        })
+    }
+}
+
+impl fmt::Display for SearchScope {
+    /// "s,i,d,t", accepted by `parse`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.substitutions, self.insertions, self.deletions, self.total)
+    }
+}
+
+/// Search scopes per query chain (from the query's V/J names), to match mixed
+/// TRA/TRB queries in one pass, e.g. "TRA=2,1,2;TRB=1,0,1". An unlabelled scope
+/// applies to queries of other or unknown chains (exact if absent).
+#[derive(Debug, Clone)]
+pub struct ChainScopes {
+    pub per_chain: Vec<(&'static str, SearchScope)>,
+    pub other: SearchScope,
+}
+
+impl ChainScopes {
+    /// Parse "CHAIN=scope;...", None for a plain scope without chain labels
+    pub fn parse(s: &str) -> Result<Option<Self>, String> {
+        if !s.contains('=') {
+            return Ok(None);
+        }
+        let mut scopes = Self { per_chain: Vec::new(), other: SearchScope::EXACT };
+        for part in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((chain, scope)) = part.split_once('=') else {
+                scopes.other = SearchScope::parse(part)?;
+                continue;
+            };
+            let chain = ["TRA", "TRB", "TRG", "TRD"]
+                .into_iter()
+                .find(|c| c.eq_ignore_ascii_case(chain.trim()))
+                .ok_or_else(|| format!("Invalid chain in search scope: {} (expected TRA, TRB, TRG or TRD)", chain.trim()))?;
+            if scopes.per_chain.iter().any(|(c, _)| *c == chain) {
+                return Err(format!("Search scope for {} given more than once", chain));
+            }
+            scopes.per_chain.push((chain, SearchScope::parse(scope.trim())?));
+        }
+        Ok(Some(scopes))
+    }
+
+    /// Scope of a query of `chain`
+    pub fn scope_for(&self, chain: Option<&str>) -> SearchScope {
+        chain
+            .and_then(|chain| self.per_chain.iter().find(|(c, _)| *c == chain))
+            .map_or(self.other, |&(_, scope)| scope)
+    }
+
+    /// Scope allowing what any of the scopes allows
+    pub fn widest(&self) -> SearchScope {
+        let all: Vec<SearchScope> = self.per_chain.iter().map(|&(_, s)| s).chain([self.other]).collect();
+        SearchScope::widest(&all)
+    }
+}
+
+/// How allele suffixes ("TRBV12-3*01") take part in V/J matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlleleMode {
+    /// Compare genes only (alleles stripped)
+    #[default]
+    Ignore,
+    /// Match on gene, but score hits with a differing allele lower
+    Prefer,
+    /// When the query has an allele, the database entry must carry the same one
+    Require,
+}
+
+impl AlleleMode {
+    /// Parse "ignore", "prefer" or "require"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "ignore" => Ok(Self::Ignore),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            _ => Err(format!("Invalid allele mode: {} (expected ignore, prefer or require)", s)),
+        }
+    }
+
+    /// Whether a query segment passes the V/J filter against a database segment
+    pub fn segments_match(self, query: &str, db: &str) -> bool {
+        let (query_gene, query_allele) = split_allele(query);
+        let (db_gene, db_allele) = split_allele(db);
+        if !query_gene.eq_ignore_ascii_case(db_gene) {
+            return false;
+        }
+        match (self, query_allele) {
+            (Self::Require, Some(allele)) => db_allele == Some(allele),
+            _ => true,
+        }
+    }
+
+    /// Whether both segments carry the same allele, or either has none to compare
+    pub fn alleles_agree(query: &str, db: &str) -> bool {
+        match (split_allele(query).1, split_allele(db).1) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+}
+
+/// How closely query and database V segments must agree for a hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VMatchLevel {
+    /// Same gene, and the same allele when the query names one
+    Allele,
+    /// Same gene; alleles count as the `AlleleMode` says
+    #[default]
+    Gene,
+    /// Same IMGT family, for low-resolution calls ("TRBV6" matches "TRBV6-2" and "TRBV6-3")
+    Family,
+    /// V segments are not compared
+    None,
+}
+
+impl VMatchLevel {
+    /// Parse "allele", "gene", "family" or "none"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "allele" => Ok(Self::Allele),
+            "gene" => Ok(Self::Gene),
+            "family" => Ok(Self::Family),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Invalid V match level: {} (expected allele, gene, family or none)", s)),
+        }
+    }
+
+    /// Allele mode V segments are compared under
+    pub fn allele_mode(self, mode: AlleleMode) -> AlleleMode {
+        match self {
+            Self::Allele => AlleleMode::Require,
+            _ => mode,
+        }
+    }
+
+    /// Whether a query V call passes the filter against a database V segment
+    pub fn segments_match(self, query: &str, db: &str, mode: AlleleMode) -> bool {
+        match self {
+            Self::Family => segment_family(query).eq_ignore_ascii_case(segment_family(db)),
+            Self::None => true,
+            _ => self.allele_mode(mode).segments_match(query, db),
+        }
+    }
+}
+
+/// IMGT family of a segment: the gene name up to the member number
+/// ("TRBV6-2*01" -> "TRBV6", "TRAV29/DV5" -> "TRAV29", "TRBV19" -> "TRBV19")
+pub fn segment_family(segment: &str) -> &str {
+    let gene = split_allele(segment).0;
+    &gene[..gene.find(['-', '/']).unwrap_or(gene.len())]
+}
+
+/// How database rows without a V or J segment (CDR3-only VDJdb records) are
+/// treated when the query's segment is matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingSegmentPolicy {
+    /// Rows lacking a segment the query is matched on are skipped
+    #[default]
+    Exclude,
+    /// Such rows pass the segment filter with a reduced segment score
+    AllowAsPartial,
+}
+
+impl MissingSegmentPolicy {
+    /// Parse "exclude" or "allow_as_partial"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "exclude" => Ok(Self::Exclude),
+            "allow_as_partial" | "partial" => Ok(Self::AllowAsPartial),
+            _ => Err(format!(
+                "Invalid missing segment policy: {} (expected exclude or allow_as_partial)",
+                s
+            )),
+        }
+    }
+}
+
+/// Residues that do not name a single amino acid: X (any) and * (stop codon)
+pub const AMBIGUOUS_RESIDUES: &[u8] = b"X*";
+
+/// Characters read as alignment gaps and removed before comparison
+pub const DEFAULT_GAP_CHARS: &str = ".-";
+
+/// Whether a residue is one of `AMBIGUOUS_RESIDUES` (either case)
+pub fn is_ambiguous_residue(aa: u8) -> bool {
+    AMBIGUOUS_RESIDUES.contains(&aa.to_ascii_uppercase())
+}
+
+/// How X and * residues take part in alignment, scoring and tcrdist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmbiguousResiduePolicy {
+    /// Compared like any unknown residue: a mismatch against everything,
+    /// itself included (BLOSUM -4)
+    #[default]
+    Penalize,
+    /// Match any residue at no cost (BLOSUM 0)
+    Neutral,
+    /// Inputs holding them are rejected; database CDR3s holding them are not matched
+    Error,
+    /// Inputs holding them get no hits (NA distances); database CDR3s holding
+    /// them are not matched
+    Skip,
+}
+
+impl AmbiguousResiduePolicy {
+    /// Parse "penalize", "neutral", "error" or "skip"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "penalize" | "penalise" => Ok(Self::Penalize),
+            "neutral" => Ok(Self::Neutral),
+            "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "Invalid ambiguous residue policy: {} (expected penalize, neutral, error or skip)",
+                s
+            )),
+        }
+    }
+}
+
+/// Treatment of gap characters and ambiguous residues shared by `alignment`,
+/// `scoring` and `tcrdist`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResidueHandling {
+    pub ambiguous: AmbiguousResiduePolicy,
+    /// Characters removed from sequences before comparison ("" keeps them,
+    /// so they count as unknown residues)
+    pub gap_chars: String,
+}
+
+impl Default for ResidueHandling {
+    fn default() -> Self {
+        Self { ambiguous: AmbiguousResiduePolicy::Penalize, gap_chars: DEFAULT_GAP_CHARS.to_string() }
+    }
+}
+
+impl ResidueHandling {
+    pub fn new(ambiguous: AmbiguousResiduePolicy, gap_chars: &str) -> Self {
+        Self { ambiguous, gap_chars: gap_chars.to_string() }
+    }
+
+    /// Sequence ready for comparison: gap characters removed, None when the
+    /// policy skips it, an error when the policy rejects it
+    pub fn prepare<'a>(&self, seq: &'a str) -> Result<Option<std::borrow::Cow<'a, str>>, String> {
+        let seq = if seq.contains(|c| self.gap_chars.contains(c)) {
+            std::borrow::Cow::Owned(seq.chars().filter(|c| !self.gap_chars.contains(*c)).collect())
+        } else {
+            std::borrow::Cow::Borrowed(seq)
+        };
+        let ambiguous = || seq.bytes().any(is_ambiguous_residue);
+        match self.ambiguous {
+            AmbiguousResiduePolicy::Error if ambiguous() => {
+                Err(format!("sequence '{}' has an ambiguous residue (X or *)", seq))
+            }
+            AmbiguousResiduePolicy::Skip if ambiguous() => Ok(None),
+            _ => Ok(Some(seq)),
+        }
+    }
+
+    /// `prepare` for database sequences, which are never rejected: None when
+    /// the sequence would be skipped or rejected as an input
+    pub fn prepare_target<'a>(&self, seq: &'a str) -> Option<std::borrow::Cow<'a, str>> {
+        self.prepare(seq).ok().flatten()
+    }
+
+    /// `prepare_target` over pre-tokenized residues, reusing their flags
+    /// unless gap characters have to be removed first
+    pub fn prepare_tokens<'a>(&self, tokens: &'a Cdr3Tokens) -> Option<std::borrow::Cow<'a, str>> {
+        let seq = tokens.residues();
+        let letter_gaps = || self.gap_chars.contains(|c: char| c.is_alphabetic());
+        if (tokens.has_symbols() || letter_gaps()) && seq.contains(|c| self.gap_chars.contains(c)) {
+            return self.prepare_target(seq);
+        }
+        match self.ambiguous {
+            AmbiguousResiduePolicy::Error | AmbiguousResiduePolicy::Skip if tokens.is_ambiguous() => None,
+            _ => Some(std::borrow::Cow::Borrowed(seq)),
+        }
+    }
+
+    /// Whether the residue matches anything at no cost
+    pub fn is_wildcard(&self, aa: u8) -> bool {
+        self.ambiguous == AmbiguousResiduePolicy::Neutral && is_ambiguous_residue(aa)
+    }
+
+    /// Whether two residues count as a match in edit distances
+    pub fn same_residue(&self, a: u8, b: u8) -> bool {
+        a == b || self.is_wildcard(a) || self.is_wildcard(b)
+    }
+}
+
+/// Individual gene calls of a possibly ambiguous segment assignment
+/// "TRBV6-2,TRBV6-3" (10x) and "TRBV6-2*00(1045),TRBV6-3*00(1040)" (MiXCR,
+/// alignment scores in parentheses) both give ["TRBV6-2...", "TRBV6-3..."].
+pub fn segment_calls(segment: &str) -> Vec<&str> {
+    segment
+        .split(',')
+        .map(|call| call.split('(').next().unwrap_or(call).trim())
+        .filter(|call| !call.is_empty())
+        .collect()
+}
+
+/// Receptor chain implied by a gene name ("TRAV12-1" -> "TRA", "TRBJ2-7*01" -> "TRB")
+/// For ambiguous calls the first recognized call decides.
+pub fn segment_chain(segment: &str) -> Option<&'static str> {
+    segment_calls(segment).into_iter().find_map(|call| {
+        let call = call.to_uppercase();
+        ["TRA", "TRB", "TRG", "TRD"]
+            .into_iter()
+            .find(|chain| ["V", "D", "J"].iter().any(|kind| call.starts_with(&format!("{}{}", chain, kind))))
+    })
+}
+
+/// Chain of a query from its V and J names, and whether the two disagree
+/// (e.g. a TRAV paired with a TRBJ, usually a column mix-up in the input)
+pub fn infer_chain(v_segment: &str, j_segment: &str) -> (Option<&'static str>, bool) {
+    match (segment_chain(v_segment), segment_chain(j_segment)) {
+        (Some(v), Some(j)) => (Some(v), v != j),
+        (v, j) => (v.or(j), false),
+    }
+}
+
+/// D segment of a clonotype table, None for the placeholders tools write when
+/// no D was assigned ("", ".", "-", "NA", "None")
+pub fn parse_d_segment(value: &str) -> Option<String> {
+    let value = value.trim();
+    let missing = ["", ".", "-", "NA", "None", "none", "null"].contains(&value);
+    (!missing).then(|| value.to_string())
+}
+
+/// Whether two D segment calls share a gene (alleles ignored)
+/// TRBD1 and TRBD2 are the only human/mouse TRB D genes, so the gene-level
+/// call is all that is reliable from short D alignments.
+pub fn d_genes_match(query: &str, db: &str) -> bool {
+    d_calls_match(&segment_calls(query), db)
+}
+
+/// `d_genes_match` with the query already split by `segment_calls`
+pub fn d_calls_match(query_calls: &[&str], db: &str) -> bool {
+    let db_calls = segment_calls(db);
+    query_calls
+        .iter()
+        .any(|q| db_calls.iter().any(|d| split_allele(q).0.eq_ignore_ascii_case(split_allele(d).0)))
+}
+
+/// Split "TRBV12-3*01" into ("TRBV12-3", Some("01")), trimming both parts
+pub fn split_allele(segment: &str) -> (&str, Option<&str>) {
+    match segment.split_once('*') {
+        Some((gene, allele)) if !allele.trim().is_empty() => (gene.trim(), Some(allele.trim())),
+        Some((gene, _)) => (gene.trim(), None),
+        None => (segment.trim(), None),
+    }
+}
+
+/// Segment name as V/J/D values are stored and compared: trimmed and
+/// uppercase ("trbv12-3*01 " -> "TRBV12-3*01")
+pub fn canonical_segment(segment: &str) -> String {
+    segment.trim().to_uppercase()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     
+    #[test]
+    fn test_parse_d_segment() {
+        assert_eq!(parse_d_segment(" TRBD2*01 ").as_deref(), Some("TRBD2*01"));
+        assert_eq!(parse_d_segment("."), None);
+        assert_eq!(parse_d_segment("NA"), None);
+        assert!(d_genes_match("TRBD1*01", "trbd1*02"));
+        assert!(d_genes_match("TRBD1,TRBD2", "TRBD2"));
+        assert!(!d_genes_match("TRBD1", "TRBD2"));
+    }
+
     #[test]
     fn test_search_scope_parse() {
         let scope = SearchScope::parse("2,1,2,3").unwrap();
@@ -155,5 +578,131 @@ mod tests {
         assert_eq!(scope.insertions, 2);
         assert_eq!(scope.deletions, 2);
         assert_eq!(scope.total, 3);
+
+        let widest = SearchScope::widest(&[SearchScope::parse("0,1,1").unwrap(), SearchScope::parse("2,0,0,2").unwrap()]);
+        assert_eq!(widest.to_string(), "2,1,1,2");
+        assert_eq!(SearchScope::widest(&[]).to_string(), "0,0,0,0");
+    }
+
+    #[test]
+    fn test_chain_scopes() {
+        assert!(ChainScopes::parse("2,1,2").unwrap().is_none());
+        let scopes = ChainScopes::parse("TRA=2,1,2; trb=1,0,1").unwrap().unwrap();
+        assert_eq!(scopes.scope_for(Some("TRA")).to_string(), "2,1,1,2");
+        assert_eq!(scopes.scope_for(Some("TRB")).to_string(), "1,0,0,1");
+        assert!(scopes.scope_for(None).is_exact());
+        assert_eq!(scopes.widest().to_string(), "2,1,1,2");
+
+        let scopes = ChainScopes::parse("TRB=1,0,1;0,1,1").unwrap().unwrap();
+        assert_eq!(scopes.scope_for(Some("TRG")).to_string(), "0,1,1,1");
+        assert!(ChainScopes::parse("TRX=1,0,1").is_err());
+        assert!(ChainScopes::parse("TRB=1,0,1;TRB=2,0,2").is_err());
+        assert!(ChainScopes::parse("TRB=x").is_err());
+    }
+
+    #[test]
+    fn test_segment_calls() {
+        assert_eq!(segment_calls("TRBV6-2, TRBV6-3"), vec!["TRBV6-2", "TRBV6-3"]);
+        assert_eq!(segment_calls("TRBV6-2*00(1045),TRBV6-3*00(1040)"), vec!["TRBV6-2*00", "TRBV6-3*00"]);
+        assert_eq!(segment_calls("TRBV12-3"), vec!["TRBV12-3"]);
+        assert!(segment_calls("").is_empty());
+    }
+
+    #[test]
+    fn test_infer_chain() {
+        assert_eq!(segment_chain("TRAV12-1*01"), Some("TRA"));
+        assert_eq!(segment_chain("trbj2-7"), Some("TRB"));
+        assert_eq!(segment_chain("TRAV14/DV4"), Some("TRA"));
+        assert_eq!(segment_chain("IGHV1-2"), None);
+        assert_eq!(infer_chain("TRBV12-3", ""), (Some("TRB"), false));
+        assert_eq!(infer_chain("", "TRAJ33"), (Some("TRA"), false));
+        assert_eq!(infer_chain("TRAV12-1", "TRBJ2-7"), (Some("TRA"), true));
+    }
+
+    #[test]
+    fn test_segment_formatting() {
+        for variant in ["TRBV12-3", "trbv12-3 ", " TrBV12-3*01", "TRBV12-3 * 01", "TRBV12-3*"] {
+            assert_eq!(Clonotype::normalize_segment(variant), "TRBV12-3", "{:?}", variant);
+        }
+        assert_eq!(split_allele("TRBV12-3 * 01"), ("TRBV12-3", Some("01")));
+        assert_eq!(canonical_segment(" trbj2-7*01\t"), "TRBJ2-7*01");
+        assert_eq!(segment_calls(" trbv6-2 , TRBV6-3 "), vec!["trbv6-2", "TRBV6-3"]);
+    }
+
+    #[test]
+    fn test_v_match_level() {
+        assert_eq!(VMatchLevel::parse(" Family").unwrap(), VMatchLevel::Family);
+        assert!(VMatchLevel::parse("subgroup").is_err());
+        assert_eq!(segment_family("TRBV6-2*01"), "TRBV6");
+        assert_eq!(segment_family("TRAV29/DV5*01"), "TRAV29");
+        assert_eq!(segment_family("TRBV19"), "TRBV19");
+
+        let ignore = AlleleMode::Ignore;
+        assert!(VMatchLevel::Family.segments_match("TRBV6", "TRBV6-2", ignore));
+        assert!(VMatchLevel::Family.segments_match("trbv6-3*01", "TRBV6-2*02", ignore));
+        assert!(!VMatchLevel::Family.segments_match("TRBV6", "TRBV7-2", ignore));
+        // TRBV1 and TRBV10 are different families
+        assert!(!VMatchLevel::Family.segments_match("TRBV1", "TRBV10-1", ignore));
+        assert!(!VMatchLevel::Gene.segments_match("TRBV6", "TRBV6-2", ignore));
+        assert!(VMatchLevel::Gene.segments_match("TRBV6-2*01", "TRBV6-2*02", ignore));
+        assert!(!VMatchLevel::Allele.segments_match("TRBV6-2*01", "TRBV6-2*02", ignore));
+        assert!(VMatchLevel::None.segments_match("TRBV6-2", "TRBV20-1", ignore));
+    }
+
+    #[test]
+    fn test_allele_mode() {
+        assert_eq!(AlleleMode::parse("Prefer").unwrap(), AlleleMode::Prefer);
+        assert!(AlleleMode::parse("strict").is_err());
+
+        assert!(AlleleMode::Ignore.segments_match("TRBV12-3*01", "TRBV12-3*02"));
+        assert!(AlleleMode::Prefer.segments_match("TRBV12-3*01", "TRBV12-3*02"));
+        assert!(!AlleleMode::Require.segments_match("TRBV12-3*01", "TRBV12-3*02"));
+        assert!(!AlleleMode::Require.segments_match("TRBV12-3*01", "TRBV12-3"));
+        assert!(AlleleMode::Require.segments_match("TRBV12-3", "TRBV12-3*02"));
+        assert!(!AlleleMode::Ignore.segments_match("TRBV12-3", "TRBV12-4"));
+        assert!(AlleleMode::Require.segments_match("trbv12-3*01", "TRBV12-3*01"));
+    }
+
+    #[test]
+    fn test_residue_handling() {
+        assert_eq!(AmbiguousResiduePolicy::parse("Neutral").unwrap(), AmbiguousResiduePolicy::Neutral);
+        assert!(AmbiguousResiduePolicy::parse("ignore").is_err());
+
+        let default = ResidueHandling::default();
+        assert_eq!(default.prepare("CASS.LGF").unwrap().as_deref(), Some("CASSLGF"));
+        assert_eq!(default.prepare("CASSXGF").unwrap().as_deref(), Some("CASSXGF"));
+        assert!(!default.same_residue(b'X', b'L'));
+
+        let neutral = ResidueHandling::new(AmbiguousResiduePolicy::Neutral, "");
+        assert_eq!(neutral.prepare("CASS-LGF").unwrap().as_deref(), Some("CASS-LGF"));
+        assert!(neutral.same_residue(b'X', b'L') && neutral.same_residue(b'F', b'*'));
+        assert!(!neutral.same_residue(b'-', b'L'));
+
+        let error = ResidueHandling::new(AmbiguousResiduePolicy::Error, ".-");
+        assert!(error.prepare("CASS*GF").unwrap_err().contains("CASS*GF"));
+        assert_eq!(error.prepare_target("CASS*GF"), None);
+
+        let skip = ResidueHandling::new(AmbiguousResiduePolicy::Skip, ".-");
+        assert_eq!(skip.prepare("CASSXGF").unwrap(), None);
+        assert_eq!(skip.prepare("CASSLGF").unwrap().as_deref(), Some("CASSLGF"));
+    }
+
+    #[test]
+    fn test_prepare_tokens() {
+        let tokens = Cdr3Tokens::new("cass.xgf");
+        assert_eq!(tokens.residues(), "CASS.XGF");
+        assert_eq!(tokens.encoded(), crate::tcrdist::encode_sequence("CASS.XGF").as_slice());
+        assert!(tokens.is_ambiguous() && !Cdr3Tokens::new("CASSLGF").is_ambiguous());
+
+        for residues in [
+            ResidueHandling::default(),
+            ResidueHandling::new(AmbiguousResiduePolicy::Neutral, ""),
+            ResidueHandling::new(AmbiguousResiduePolicy::Skip, ".-"),
+            ResidueHandling::new(AmbiguousResiduePolicy::Error, "X"),
+        ] {
+            for seq in ["CASS.XGF", "CASSLGF", "CASS-LGF", "CASS*GF"] {
+                assert_eq!(residues.prepare_tokens(&Cdr3Tokens::new(seq)), residues.prepare_target(seq));
+            }
+        }
     }
 }
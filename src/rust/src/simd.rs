@@ -0,0 +1,106 @@
+// Vectorized inner loops for sequence comparison.
+// With the `simd` feature on x86_64 these use SSE2 (part of the x86_64 baseline,
+// so no runtime detection); otherwise the scalar fallbacks give identical results.
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+/// Number of positions where `a` and `b` differ, over the shorter length
+pub fn count_mismatches(a: &[u8], b: &[u8]) -> usize {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        let mut mismatches = 0;
+        let mut chunks_a = a.chunks_exact(16);
+        let mut chunks_b = b.chunks_exact(16);
+        for (ca, cb) in (&mut chunks_a).zip(&mut chunks_b) {
+            // SAFETY: SSE2 is part of the x86_64 baseline and both chunks hold 16 bytes
+            let equal = unsafe {
+                let va = _mm_loadu_si128(ca.as_ptr() as *const __m128i);
+                let vb = _mm_loadu_si128(cb.as_ptr() as *const __m128i);
+                _mm_movemask_epi8(_mm_cmpeq_epi8(va, vb)) as u32
+            };
+            mismatches += 16 - equal.count_ones() as usize;
+        }
+        mismatches + count_mismatches_scalar(chunks_a.remainder(), chunks_b.remainder())
+    }
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        count_mismatches_scalar(a, b)
+    }
+}
+
+fn count_mismatches_scalar(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y).count()
+}
+
+/// One Needleman-Wunsch row step without the insertion term:
+/// `out[j] = min(prev[j] + costs[j], prev[j + 1] + gap)`
+/// The caller resolves insertions with a sequential pass over `out`.
+pub fn diag_up_min(prev: &[i32], costs: &[i32], gap: i32, out: &mut [i32]) {
+    let n = out.len();
+    assert!(prev.len() > n && costs.len() >= n);
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    let start = {
+        let mut j = 0;
+        // SAFETY: SSE2 is part of the x86_64 baseline; j + 4 <= n keeps every
+        // load from prev[j + 1..j + 5], costs[j..j + 4] and store to out[j..j + 4] in bounds
+        unsafe {
+            let vgap = _mm_set1_epi32(gap);
+            while j + 4 <= n {
+                let diag = _mm_add_epi32(
+                    _mm_loadu_si128(prev.as_ptr().add(j) as *const __m128i),
+                    _mm_loadu_si128(costs.as_ptr().add(j) as *const __m128i),
+                );
+                let up = _mm_add_epi32(
+                    _mm_loadu_si128(prev.as_ptr().add(j + 1) as *const __m128i),
+                    vgap,
+                );
+                // SSE2 has no i32 min; select lanes with a compare mask
+                let up_smaller = _mm_cmpgt_epi32(diag, up);
+                let min = _mm_or_si128(_mm_and_si128(up_smaller, up), _mm_andnot_si128(up_smaller, diag));
+                _mm_storeu_si128(out.as_mut_ptr().add(j) as *mut __m128i, min);
+                j += 4;
+            }
+        }
+        j
+    };
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    let start = 0;
+
+    for j in start..n {
+        out[j] = (prev[j] + costs[j]).min(prev[j + 1] + gap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_mismatches_matches_scalar() {
+        let a = b"CASSLGQAYEQYFCASSLGQAYEQYFCASSLGQ";
+        let b = b"CASSLGQTYEQYFCASRLGQAYEQYYCASSLGQAAA";
+        assert_eq!(count_mismatches(a, b), count_mismatches_scalar(a, &b[..a.len()]));
+        assert_eq!(count_mismatches(a, b), 3);
+        assert_eq!(count_mismatches(b"", b"CASS"), 0);
+    }
+
+    #[test]
+    fn test_diag_up_min_matches_scalar() {
+        let prev: Vec<i32> = (0..12).map(|x| (x * 7) % 11).collect();
+        let costs: Vec<i32> = (0..11).map(|x| (x * 5) % 9).collect();
+        let mut out = vec![0; 11];
+        diag_up_min(&prev, &costs, 4, &mut out);
+
+        let expected: Vec<i32> = (0..11)
+            .map(|j| (prev[j] + costs[j]).min(prev[j + 1] + 4))
+            .collect();
+        assert_eq!(out, expected);
+    }
+}
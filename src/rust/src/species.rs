@@ -0,0 +1,148 @@
+use crate::sequence::{segment_calls, split_allele};
+
+/// TRBV genes whose IMGT names exist only in the human locus
+const HUMAN_TRBV: &[&str] = &[
+    "TRBV3-1", "TRBV4-1", "TRBV4-2", "TRBV4-3", "TRBV5-1", "TRBV5-4", "TRBV5-5", "TRBV5-6", "TRBV5-8",
+    "TRBV6-1", "TRBV6-2", "TRBV6-3", "TRBV6-4", "TRBV6-5", "TRBV6-6", "TRBV6-8", "TRBV6-9", "TRBV7-2",
+    "TRBV7-3", "TRBV7-4", "TRBV7-6", "TRBV7-7", "TRBV7-8", "TRBV7-9", "TRBV9", "TRBV10-1", "TRBV10-2",
+    "TRBV10-3", "TRBV11-1", "TRBV11-2", "TRBV11-3", "TRBV12-3", "TRBV12-4", "TRBV12-5", "TRBV13", "TRBV18",
+    "TRBV20-1", "TRBV24-1", "TRBV25-1", "TRBV27", "TRBV28", "TRBV29-1",
+];
+
+/// TRBV genes whose IMGT names exist only in the mouse locus
+const MOUSE_TRBV: &[&str] = &[
+    "TRBV1", "TRBV3", "TRBV4", "TRBV5", "TRBV12-1", "TRBV12-2", "TRBV13-1", "TRBV13-2", "TRBV13-3",
+    "TRBV17", "TRBV20", "TRBV23", "TRBV24", "TRBV26", "TRBV29", "TRBV31",
+];
+
+/// Closest human TRBV gene of each mouse TRBV gene, by IMGT subgroup orthology.
+/// Mouse and human genes sharing a name are not orthologous in general, so every
+/// mouse gene is translated.
+const MOUSE_HUMAN_TRBV: &[(&str, &str)] = &[
+    ("TRBV1", "TRBV2"),
+    ("TRBV2", "TRBV3-1"),
+    ("TRBV3", "TRBV4-1"),
+    ("TRBV4", "TRBV4-2"),
+    ("TRBV5", "TRBV5-1"),
+    ("TRBV12-1", "TRBV5-1"),
+    ("TRBV12-2", "TRBV5-1"),
+    ("TRBV13-1", "TRBV6-5"),
+    ("TRBV13-2", "TRBV6-5"),
+    ("TRBV13-3", "TRBV6-5"),
+    ("TRBV14", "TRBV27"),
+    ("TRBV15", "TRBV24-1"),
+    ("TRBV16", "TRBV14"),
+    ("TRBV17", "TRBV13"),
+    ("TRBV19", "TRBV9"),
+    ("TRBV20", "TRBV11-2"),
+    ("TRBV23", "TRBV16"),
+    ("TRBV24", "TRBV28"),
+    ("TRBV26", "TRBV10-3"),
+    ("TRBV29", "TRBV7-9"),
+    ("TRBV30", "TRBV20-1"),
+    ("TRBV31", "TRBV30"),
+];
+
+pub const HUMAN: &str = "HomoSapiens";
+pub const MOUSE: &str = "MusMusculus";
+
+/// Species evidence from query V gene names
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeciesDetection {
+    /// Majority species (VDJdb naming), None without enough informative genes
+    pub species: Option<&'static str>,
+    pub n_human: usize,
+    pub n_mouse: usize,
+}
+
+/// Species implied by a single V gene name, if it is specific to one species
+/// Mouse TRAV genes from the duplicated region carry "D"/"N" designations
+/// (TRAV6D-6, TRAV14N-1) that do not occur in human names.
+pub fn gene_species(segment: &str) -> Option<&'static str> {
+    let call = segment_calls(segment).into_iter().next()?;
+    let gene = split_allele(call).0.to_uppercase();
+    if HUMAN_TRBV.contains(&gene.as_str()) {
+        return Some(HUMAN);
+    }
+    if MOUSE_TRBV.contains(&gene.as_str()) {
+        return Some(MOUSE);
+    }
+    let family = gene.strip_prefix("TRAV")?.split('-').next()?;
+    let digits = family.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    (digits.len() < family.len() && !digits.is_empty() && family.ends_with(['D', 'N'])).then_some(MOUSE)
+}
+
+/// Vote over V genes; a species is reported when it has at least `min_informative`
+/// species-specific genes and at least 80% of them
+pub fn detect_species(v_segments: &[String], min_informative: usize) -> SpeciesDetection {
+    let (mut n_human, mut n_mouse) = (0, 0);
+    for v in v_segments {
+        match gene_species(v) {
+            Some(HUMAN) => n_human += 1,
+            Some(_) => n_mouse += 1,
+            None => {}
+        }
+    }
+    let total = n_human + n_mouse;
+    let species = if total < min_informative.max(1) {
+        None
+    } else if n_human as f64 >= 0.8 * total as f64 {
+        Some(HUMAN)
+    } else if n_mouse as f64 >= 0.8 * total as f64 {
+        Some(MOUSE)
+    } else {
+        None
+    };
+    SpeciesDetection { species, n_human, n_mouse }
+}
+
+/// Human paralog of a mouse V segment for matching against human entries:
+/// each call of an ambiguous segment is translated, alleles dropped and calls
+/// without a paralog left out. None when no call has a paralog.
+pub fn human_v_paralog(segment: &str) -> Option<String> {
+    let mut human: Vec<&str> = Vec::new();
+    for call in segment_calls(segment) {
+        let gene = split_allele(call).0.to_uppercase();
+        if let Some(&(_, paralog)) = MOUSE_HUMAN_TRBV.iter().find(|(mouse, _)| *mouse == gene) {
+            if !human.contains(&paralog) {
+                human.push(paralog);
+            }
+        }
+    }
+    (!human.is_empty()).then(|| human.join(","))
+}
+
+/// The built-in (mouse gene, human paralog) pairs, in table order
+pub fn mouse_human_v_paralogs() -> &'static [(&'static str, &'static str)] {
+    MOUSE_HUMAN_TRBV
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_species() {
+        assert_eq!(gene_species("TRBV6-2*01"), Some(HUMAN));
+        assert_eq!(gene_species("TRBV13-2"), Some(MOUSE));
+        assert_eq!(gene_species("TRAV6D-6*01"), Some(MOUSE));
+        assert_eq!(gene_species("TRAV14N-1"), Some(MOUSE));
+        assert_eq!(gene_species("TRBV19"), None);
+        assert_eq!(gene_species("TRAV12-1"), None);
+
+        let mouse: Vec<String> = ["TRBV13-2", "TRBV19", "TRBV29", "TRAV6D-6", "TRBV5"].iter().map(|s| s.to_string()).collect();
+        let d = detect_species(&mouse, 3);
+        assert_eq!((d.species, d.n_mouse, d.n_human), (Some(MOUSE), 4, 0));
+        assert_eq!(detect_species(&mouse[..2], 3).species, None);
+    }
+
+    #[test]
+    fn test_human_v_paralog() {
+        assert_eq!(human_v_paralog("TRBV13-2*01").as_deref(), Some("TRBV6-5"));
+        assert_eq!(human_v_paralog("TRBV19").as_deref(), Some("TRBV9"));
+        // 10x-style ambiguous calls collapse to distinct paralogs
+        assert_eq!(human_v_paralog("TRBV13-1,TRBV13-3,TRBV31").as_deref(), Some("TRBV6-5,TRBV30"));
+        assert_eq!(human_v_paralog("TRAV6D-6"), None);
+        assert_eq!(human_v_paralog(""), None);
+    }
+}
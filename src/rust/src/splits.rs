@@ -0,0 +1,141 @@
+use crate::database::Database;
+use crate::random::Rng;
+use crate::tcrdist::cdr3_neighbor_lists;
+use std::collections::{BTreeMap, HashMap};
+
+/// Union-find root of `i` with path halving
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Group ids for `cdr3`: identical CDR3s always share a group, and with
+/// `min_distance` CDR3s within that CDR3 tcrdist are joined transitively
+fn cdr3_groups(cdr3: &[String], min_distance: Option<f64>) -> Vec<usize> {
+    let mut unique_index: HashMap<String, usize> = HashMap::new();
+    let mut unique: Vec<String> = Vec::new();
+    let entry_unique: Vec<usize> = cdr3
+        .iter()
+        .map(|c| {
+            let key = c.to_uppercase();
+            *unique_index.entry(key.clone()).or_insert_with(|| {
+                unique.push(key);
+                unique.len() - 1
+            })
+        })
+        .collect();
+
+    let mut parent: Vec<usize> = (0..unique.len()).collect();
+    if let Some(max_dist) = min_distance {
+        for (i, neighbors) in cdr3_neighbor_lists(&unique, &unique, max_dist).into_iter().enumerate() {
+            for (j, _) in neighbors {
+                let (ri, rj) = (find(&mut parent, i), find(&mut parent, j));
+                if ri != rj {
+                    parent[ri.max(rj)] = ri.min(rj);
+                }
+            }
+        }
+    }
+
+    entry_unique.into_iter().map(|u| find(&mut parent, u)).collect()
+}
+
+/// Assign database entries to `n_folds` cross-validation folds, stratified by epitope
+/// Entries sharing a CDR3 always land in the same fold. With `min_distance`,
+/// CDR3s within that CDR3 tcrdist of each other are kept together as well, so
+/// no held-out CDR3 has a close neighbour in the other folds.
+/// Returns the 0-based fold of every entry.
+pub fn epitope_folds(
+    database: &Database,
+    n_folds: usize,
+    seed: u64,
+    min_distance: Option<f64>,
+) -> Result<Vec<usize>, String> {
+    if n_folds < 2 {
+        return Err(format!("n_folds must be at least 2, got {}", n_folds));
+    }
+
+    let cdr3: Vec<String> = database.entries.iter().map(|e| e.cdr3.clone()).collect();
+    let groups = cdr3_groups(&cdr3, min_distance);
+
+    // Members of each group, and the epitope most of them recognize
+    let mut members: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (entry, &group) in groups.iter().enumerate() {
+        members.entry(group).or_default().push(entry);
+    }
+    let mut by_epitope: BTreeMap<&str, Vec<&Vec<usize>>> = BTreeMap::new();
+    for entries in members.values() {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for &e in entries {
+            *counts.entry(database.entries[e].antigen_epitope.as_str()).or_insert(0) += 1;
+        }
+        let dominant = counts.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))).map(|(e, _)| *e).unwrap_or("");
+        by_epitope.entry(dominant).or_default().push(entries);
+    }
+
+    // Deal each epitope's groups (shuffled) to the fold holding the fewest of its entries
+    let mut rng = Rng::new(seed);
+    let mut fold_of = vec![0; database.entries.len()];
+    let mut fold_sizes = vec![0usize; n_folds];
+    for groups in by_epitope.values_mut() {
+        rng.shuffle(groups);
+        let mut epitope_sizes = vec![0usize; n_folds];
+        for entries in groups.iter() {
+            let fold = (0..n_folds)
+                .min_by_key(|&f| (epitope_sizes[f], fold_sizes[f], f))
+                .unwrap_or(0);
+            epitope_sizes[fold] += entries.len();
+            fold_sizes[fold] += entries.len();
+            for &e in entries.iter() {
+                fold_of[e] = fold;
+            }
+        }
+    }
+
+    Ok(fold_of)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabaseEntry, DatabaseMetadata};
+
+    fn entry(cdr3: &str, epitope: &str) -> DatabaseEntry {
+        DatabaseEntry {
+            v_segment: String::new(),
+            j_segment: String::new(),
+            antigen_species: String::new(),
+            ..DatabaseEntry::test(cdr3, epitope)
+        }
+    }
+
+    #[test]
+    fn test_epitope_folds() {
+        let database = Database {
+            entries: vec![
+                entry("CASSLF", "A"),
+                entry("CASSLY", "A"),
+                entry("CAVRDF", "A"),
+                entry("CSARDGGNKLTF", "A"),
+                entry("CASSLF", "B"),
+                entry("CSVEEGRQETQYF", "B"),
+            ],
+            metadata: DatabaseMetadata::default(),
+        };
+
+        let folds = epitope_folds(&database, 2, 1, None).unwrap();
+        assert_eq!(folds, epitope_folds(&database, 2, 1, None).unwrap());
+        assert_eq!(folds[0], folds[4]); // identical CDR3s stay together
+        let epitope_a: Vec<usize> = folds[..4].to_vec();
+        assert_eq!(epitope_a.iter().filter(|&&f| f == 0).count(), 2);
+
+        // CASSLF and CASSLY are one substitution apart and must not be split
+        let folds = epitope_folds(&database, 2, 3, Some(24.0)).unwrap();
+        assert_eq!(folds[0], folds[1]);
+
+        assert!(epitope_folds(&database, 1, 1, None).is_err());
+    }
+}
@@ -1,4 +1,12 @@
+use crate::database::Database;
+use crate::random::Rng;
+use crate::sequence::ResidueHandling;
+use crate::simd;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 
 /// BLOSUM62 substitution matrix for amino acid scoring
 /// Amino acid order: A  R  N  D  C  Q  E  G  H  I  L  K  M  F  P  S  T  W  Y  V
@@ -26,9 +34,9 @@ const BLOSUM62: [[i8; 20]; 20] = [
     [  0, -3, -3, -3, -1, -2, -2, -3, -3,  3,  1, -2,  1, -1, -2, -2,  0, -3, -1,  4], // V
 ];
 
-/// Convert amino acid character to BLOSUM62 matrix index
+/// Convert amino acid character (either case) to BLOSUM62 matrix index
 fn aa_to_index(aa: u8) -> Option<usize> {
-    match aa {
+    match aa.to_ascii_uppercase() {
         b'A' => Some(0),  b'R' => Some(1),  b'N' => Some(2),  b'D' => Some(3),
         b'C' => Some(4),  b'Q' => Some(5),  b'E' => Some(6),  b'G' => Some(7),
         b'H' => Some(8),  b'I' => Some(9),  b'L' => Some(10), b'K' => Some(11),
@@ -38,7 +46,58 @@ fn aa_to_index(aa: u8) -> Option<usize> {
     }
 }
 
+/// Cost-table index used for residues outside the 20 standard amino acids
+const UNKNOWN_AA: u8 = 20;
+
+/// BLOSUM62 score assumed for any pair involving an unknown residue
+const UNKNOWN_BLOSUM: i32 = -4;
+
+/// Cost-table index for ambiguous residues treated as neutral, which cost
+/// nothing against any residue
+const WILDCARD_AA: u8 = 21;
+
+/// tcrdist position cost `max(0, 4 - BLOSUM62[a][b])`, precomputed for all
+/// residue pairs; index 20 stands for unknown residues, 21 for wildcards
+const POSITION_COST: [[i32; 22]; 22] = {
+    let mut table = [[0i32; 22]; 22];
+    let mut i = 0;
+    while i < 22 {
+        let mut j = 0;
+        while j < 22 {
+            let blosum = if i < 20 && j < 20 { BLOSUM62[i][j] as i32 } else { UNKNOWN_BLOSUM };
+            let wildcard = i == WILDCARD_AA as usize || j == WILDCARD_AA as usize;
+            table[i][j] = if wildcard || 4 - blosum <= 0 { 0 } else { 4 - blosum };
+            j += 1;
+        }
+        i += 1;
+    }
+    table
+};
+
+/// Map a sequence to cost-table indices once so it can be reused across many pairs
+pub fn encode_sequence(seq: &str) -> Vec<u8> {
+    seq.bytes()
+        .map(|aa| aa_to_index(aa).map(|i| i as u8).unwrap_or(UNKNOWN_AA))
+        .collect()
+}
+
+/// `encode_sequence` after removing gap characters, with ambiguous residues
+/// mapped per `residues`; None when the policy skips or rejects the sequence
+pub fn encode_sequence_with(seq: &str, residues: &ResidueHandling) -> Option<Vec<u8>> {
+    let seq = residues.prepare_target(seq)?;
+    Some(
+        seq.bytes()
+            .map(|aa| match aa_to_index(aa) {
+                Some(i) => i as u8,
+                None if residues.is_wildcard(aa) => WILDCARD_AA,
+                None => UNKNOWN_AA,
+            })
+            .collect(),
+    )
+}
+
 /// Get BLOSUM62 score for two amino acids
+#[cfg(test)]
 fn blosum62_score(aa1: u8, aa2: u8) -> i8 {
     match (aa_to_index(aa1), aa_to_index(aa2)) {
         (Some(i1), Some(i2)) => BLOSUM62[i1][i2],
@@ -46,8 +105,9 @@ fn blosum62_score(aa1: u8, aa2: u8) -> i8 {
     }
 }
 
-/// Calculate tcrdist-style position score
+/// Calculate tcrdist-style position score (reference for `POSITION_COST`)
 /// Formula: max(0, 4 - BLOSUM62[a][b])
+#[cfg(test)]
 fn position_score(aa1: u8, aa2: u8) -> i32 {
     let blosum_score = blosum62_score(aa1, aa2) as i32;
     std::cmp::max(0, 4 - blosum_score)
@@ -56,10 +116,26 @@ fn position_score(aa1: u8, aa2: u8) -> i32 {
 /// Needleman-Wunsch alignment with tcrdist-style scoring
 /// Returns alignment score (distance)
 fn align_sequences(seq1: &str, seq2: &str, gap_penalty: i32) -> i32 {
-    let seq1_bytes = seq1.as_bytes();
-    let seq2_bytes = seq2.as_bytes();
-    let len1 = seq1_bytes.len();
-    let len2 = seq2_bytes.len();
+    align_encoded(&encode_sequence(seq1), &encode_sequence(seq2), gap_penalty)
+}
+
+/// Needleman-Wunsch alignment over sequences already passed through `encode_sequence`
+pub(crate) fn align_encoded(seq1: &[u8], seq2: &[u8], gap_penalty: i32) -> i32 {
+    align_encoded_in(seq1, seq2, gap_penalty, &mut AlignScratch::default())
+}
+
+/// DP rows reused across calls of `align_encoded_in`
+#[derive(Debug, Default)]
+struct AlignScratch {
+    prev: Vec<i32>,
+    curr: Vec<i32>,
+    costs: Vec<i32>,
+}
+
+/// `align_encoded` with DP rows taken from `scratch` instead of allocated
+fn align_encoded_in(seq1: &[u8], seq2: &[u8], gap_penalty: i32, scratch: &mut AlignScratch) -> i32 {
+    let len1 = seq1.len();
+    let len2 = seq2.len();
 
     // Handle empty sequences
     if len1 == 0 && len2 == 0 {
@@ -72,29 +148,32 @@ fn align_sequences(seq1: &str, seq2: &str, gap_penalty: i32) -> i32 {
         return (len1 as i32) * gap_penalty;
     }
 
-    // Initialize DP matrix
-    let mut dp = vec![vec![0i32; len2 + 1]; len1 + 1];
+    // Rolling DP rows; diagonal and deletion terms are vectorized per row,
+    // insertions need the left neighbour so they are resolved in a scalar pass
+    let AlignScratch { prev, curr, costs } = scratch;
+    prev.clear();
+    prev.extend((0..=len2).map(|j| (j as i32) * gap_penalty));
+    curr.clear();
+    curr.resize(len2 + 1, 0);
+    costs.clear();
+    costs.resize(len2, 0);
 
-    // Initialize first row and column
-    for i in 0..=len1 {
-        dp[i][0] = (i as i32) * gap_penalty;
-    }
-    for j in 0..=len2 {
-        dp[0][j] = (j as i32) * gap_penalty;
-    }
+    for (i, &aa1) in seq1.iter().enumerate() {
+        let cost_row = &POSITION_COST[aa1 as usize];
+        for (cost, &aa2) in costs.iter_mut().zip(seq2) {
+            *cost = cost_row[aa2 as usize];
+        }
 
-    // Fill DP matrix
-    for i in 1..=len1 {
+        curr[0] = (i as i32 + 1) * gap_penalty;
+        simd::diag_up_min(prev, costs, gap_penalty, &mut curr[1..]);
         for j in 1..=len2 {
-            let match_score = dp[i - 1][j - 1] + position_score(seq1_bytes[i - 1], seq2_bytes[j - 1]);
-            let delete_score = dp[i - 1][j] + gap_penalty;
-            let insert_score = dp[i][j - 1] + gap_penalty;
-
-            dp[i][j] = std::cmp::min(match_score, std::cmp::min(delete_score, insert_score));
+            curr[j] = curr[j].min(curr[j - 1] + gap_penalty);
         }
+
+        std::mem::swap(prev, curr);
     }
 
-    dp[len1][len2]
+    prev[len2]
 }
 
 /// T-cell receptor with alpha and beta chain CDR sequences
@@ -128,57 +207,576 @@ impl TCR {
     }
 }
 
+/// How to score a CDR that is present in one TCR but missing from the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingPolicy {
+    /// Ignore the unpaired CDR (contributes 0 to the distance)
+    Skip,
+    /// Add a fixed cost for each unpaired CDR
+    Penalize(i32),
+    /// The distance is undefined (NA in R)
+    Na,
+}
+
+impl MissingPolicy {
+    /// Parse a policy name ("skip", "penalize" or "na"); `penalty` is used by "penalize"
+    pub fn parse(s: &str, penalty: i32) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "penalize" | "penalise" => Ok(Self::Penalize(penalty)),
+            "na" => Ok(Self::Na),
+            _ => Err(format!("Invalid missing policy: {}", s)),
+        }
+    }
+}
+
+/// TCR with CDRs pre-mapped to cost-table indices, in the order
+/// CDR1a, CDR2a, CDR3a, CDR1b, CDR2b, CDR3b
+#[derive(Debug, Clone)]
+pub struct EncodedTCR {
+    cdrs: [Option<Vec<u8>>; 6],
+}
+
+impl EncodedTCR {
+    pub fn new(tcr: &TCR) -> Self {
+        let encode = |cdr: &Option<String>| cdr.as_deref().map(encode_sequence);
+        Self {
+            cdrs: [
+                encode(&tcr.cdr1_a_aa),
+                encode(&tcr.cdr2_a_aa),
+                encode(&tcr.cdr3_a_aa),
+                encode(&tcr.cdr1_b_aa),
+                encode(&tcr.cdr2_b_aa),
+                encode(&tcr.cdr3_b_aa),
+            ],
+        }
+    }
+
+    /// Encode with gap characters and ambiguous residues handled per
+    /// `residues`; a skipped CDR counts as missing, a rejected one is an error
+    pub fn with_residues(tcr: &TCR, residues: &ResidueHandling) -> Result<Self, String> {
+        let encode = |cdr: &Option<String>| -> Result<Option<Vec<u8>>, String> {
+            match cdr.as_deref() {
+                Some(seq) => {
+                    residues.prepare(seq)?;
+                    Ok(encode_sequence_with(seq, residues))
+                }
+                None => Ok(None),
+            }
+        };
+        Ok(Self {
+            cdrs: [
+                encode(&tcr.cdr1_a_aa)?,
+                encode(&tcr.cdr2_a_aa)?,
+                encode(&tcr.cdr3_a_aa)?,
+                encode(&tcr.cdr1_b_aa)?,
+                encode(&tcr.cdr2_b_aa)?,
+                encode(&tcr.cdr3_b_aa)?,
+            ],
+        })
+    }
+}
+
+/// Numeric type distances are returned in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistancePrecision {
+    Double,
+    /// Single precision: half the memory, exact for distances below 2^24
+    Float,
+    /// 32-bit integers: half the memory, exact with integer chain weights
+    Integer,
+}
+
+impl DistancePrecision {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "double" | "f64" => Ok(Self::Double),
+            "float" | "f32" | "single" => Ok(Self::Float),
+            "integer" | "i32" => Ok(Self::Integer),
+            _ => Err(format!("Invalid distance precision: {} (expected double, float or integer)", s)),
+        }
+    }
+}
+
+/// Undefined single-precision distance: R's integer NA, whose bit pattern is
+/// -0.0, a value no distance takes
+pub const FLOAT_NA_BITS: i32 = i32::MIN;
+
+/// Bits of a distance as f32, stored in an R integer vector
+pub fn float_bits(distance: Option<f64>) -> i32 {
+    match distance {
+        Some(d) => (d as f32).to_bits() as i32,
+        None => FLOAT_NA_BITS,
+    }
+}
+
+/// Distance stored by `float_bits`
+pub fn from_float_bits(bits: i32) -> Option<f64> {
+    (bits != FLOAT_NA_BITS).then(|| f32::from_bits(bits as u32) as f64)
+}
+
+/// Distance rounded to an integer
+pub fn integer_distance(distance: Option<f64>) -> Option<i32> {
+    distance.map(|d| d.round() as i32)
+}
+
+/// A distance matrix in one of the `DistancePrecision`s
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distances {
+    Double(Vec<Option<f64>>),
+    /// `float_bits` of each distance
+    Float(Vec<i32>),
+    Integer(Vec<Option<i32>>),
+}
+
+impl Distances {
+    /// `distances` (None when undefined) in the given precision
+    pub fn new(distances: impl IntoIterator<Item = Option<f64>>, precision: DistancePrecision) -> Self {
+        let distances = distances.into_iter();
+        match precision {
+            DistancePrecision::Double => Distances::Double(distances.collect()),
+            DistancePrecision::Float => Distances::Float(distances.map(float_bits).collect()),
+            DistancePrecision::Integer => Distances::Integer(distances.map(integer_distance).collect()),
+        }
+    }
+
+    /// Distances as f64, NaN when undefined
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self {
+            Distances::Double(d) => d.iter().map(|d| d.unwrap_or(f64::NAN)).collect(),
+            Distances::Float(d) => d.iter().map(|&b| from_float_bits(b).unwrap_or(f64::NAN)).collect(),
+            Distances::Integer(d) => d.iter().map(|d| d.map_or(f64::NAN, f64::from)).collect(),
+        }
+    }
+}
+
+/// Multipliers of the alpha (CDR1a-3a) and beta (CDR1b-3b) chain distances
+/// A chain with weight 0 is ignored entirely, including unpaired CDRs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainWeights {
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl Default for ChainWeights {
+    fn default() -> Self {
+        Self { alpha: 1.0, beta: 1.0 }
+    }
+}
+
+impl ChainWeights {
+    pub const ALPHA_ONLY: Self = Self { alpha: 1.0, beta: 0.0 };
+    pub const BETA_ONLY: Self = Self { alpha: 0.0, beta: 1.0 };
+
+    /// Whether both weights are whole numbers, so distances are integral
+    pub fn is_integral(&self) -> bool {
+        self.alpha.fract() == 0.0 && self.beta.fract() == 0.0
+    }
+
+    /// Weights must be finite, non-negative and not both zero
+    pub fn new(alpha: f64, beta: f64) -> Result<Self, String> {
+        if !(alpha.is_finite() && beta.is_finite() && alpha >= 0.0 && beta >= 0.0) || alpha + beta == 0.0 {
+            return Err(format!(
+                "chain weights must be non-negative with at least one positive, got alpha = {}, beta = {}",
+                alpha, beta
+            ));
+        }
+        Ok(Self { alpha, beta })
+    }
+
+    /// Parse a shortcut: "alpha_only", "beta_only" or "paired" (equal weights)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "alpha_only" | "alpha" => Ok(Self::ALPHA_ONLY),
+            "beta_only" | "beta" => Ok(Self::BETA_ONLY),
+            "paired" | "both" => Ok(Self::default()),
+            _ => Err(format!("Invalid chain weights: {} (expected alpha_only, beta_only or paired)", s)),
+        }
+    }
+
+    /// Weight of CDR `region` (0-2 alpha, 3-5 beta, as in `EncodedTCR`)
+    fn of_region(&self, region: usize) -> f64 {
+        if region < 3 { self.alpha } else { self.beta }
+    }
+}
+
+/// (weight, gap penalty) per CDR: CDR1/2 weight 1 gap 4, CDR3 weight 3 gap 8
+const REGION_PARAMS: [(i32, i32); 6] = [(1, 4), (1, 4), (3, 8), (1, 4), (1, 4), (3, 8)];
+
 /// Calculate tcrdist distance between two TCRs
-/// Combines alpha and beta chain distances
+/// Combines alpha and beta chain distances; unpaired CDRs are skipped
 pub fn tcrdist(tcr1: &TCR, tcr2: &TCR) -> f64 {
-    let alpha_dist = chain_distance(
-        &tcr1.cdr1_a_aa,
-        &tcr1.cdr2_a_aa,
-        &tcr1.cdr3_a_aa,
-        &tcr2.cdr1_a_aa,
-        &tcr2.cdr2_a_aa,
-        &tcr2.cdr3_a_aa,
-    );
+    tcrdist_with_policy(tcr1, tcr2, MissingPolicy::Skip).unwrap_or(0.0)
+}
+
+/// Calculate tcrdist distance between two TCRs with an explicit policy for
+/// CDRs present in only one of them. Returns None when the policy is `Na` and
+/// any CDR is unpaired.
+pub fn tcrdist_with_policy(tcr1: &TCR, tcr2: &TCR, policy: MissingPolicy) -> Option<f64> {
+    encoded_tcrdist(&EncodedTCR::new(tcr1), &EncodedTCR::new(tcr2), policy)
+}
+
+/// `tcrdist_with_policy` with the alpha and beta chain distances scaled by `weights`
+pub fn tcrdist_with_weights(tcr1: &TCR, tcr2: &TCR, policy: MissingPolicy, weights: ChainWeights) -> Option<f64> {
+    encoded_tcrdist_weighted(&EncodedTCR::new(tcr1), &EncodedTCR::new(tcr2), policy, weights)
+}
+
+/// `tcrdist_with_policy` over pre-encoded TCRs
+pub fn encoded_tcrdist(tcr1: &EncodedTCR, tcr2: &EncodedTCR, policy: MissingPolicy) -> Option<f64> {
+    encoded_tcrdist_weighted(tcr1, tcr2, policy, ChainWeights::default())
+}
+
+/// `tcrdist_with_weights` over pre-encoded TCRs
+pub fn encoded_tcrdist_weighted(
+    tcr1: &EncodedTCR,
+    tcr2: &EncodedTCR,
+    policy: MissingPolicy,
+    weights: ChainWeights,
+) -> Option<f64> {
+    let mut chains = [0i32; 2];
+    for (region, ((cdr1, cdr2), &(weight, gap_penalty))) in
+        tcr1.cdrs.iter().zip(&tcr2.cdrs).zip(&REGION_PARAMS).enumerate()
+    {
+        if weights.of_region(region) == 0.0 {
+            continue;
+        }
+        chains[region / 3] += match (cdr1, cdr2) {
+            (Some(s1), Some(s2)) => weight * align_encoded(s1, s2, gap_penalty),
+            (None, None) => 0,
+            _ => match policy {
+                MissingPolicy::Skip => 0,
+                MissingPolicy::Penalize(cost) => cost,
+                MissingPolicy::Na => return None,
+            },
+        };
+    }
+    Some(weights.alpha * chains[0] as f64 + weights.beta * chains[1] as f64)
+}
+
+/// Full n x n distance matrix (row-major, symmetric) between `tcrs`
+/// Each TCR is encoded once and only the upper triangle is aligned.
+pub fn pairwise_distances(tcrs: &[TCR], policy: MissingPolicy) -> Vec<Option<f64>> {
+    let encoded: Vec<EncodedTCR> = tcrs.iter().map(EncodedTCR::new).collect();
+    encoded_pairwise_distances(&encoded, policy, ChainWeights::default())
+}
+
+/// `pairwise_distances` with gap characters and ambiguous residues handled
+/// per `residues` and chains scaled by `weights`; the error names the first rejected TCR (1-based)
+pub fn pairwise_distances_with(
+    tcrs: &[TCR],
+    policy: MissingPolicy,
+    residues: &ResidueHandling,
+    weights: ChainWeights,
+) -> Result<Vec<Option<f64>>, String> {
+    let encoded = tcrs
+        .iter()
+        .enumerate()
+        .map(|(i, tcr)| EncodedTCR::with_residues(tcr, residues).map_err(|e| format!("TCR {}: {}", i + 1, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(encoded_pairwise_distances(&encoded, policy, weights))
+}
+
+/// `pairwise_distances_with` in the given precision; the matrix (and its
+/// upper triangle while computing) is held in that precision throughout
+pub fn pairwise_distances_as(
+    tcrs: &[TCR],
+    policy: MissingPolicy,
+    residues: &ResidueHandling,
+    weights: ChainWeights,
+    precision: DistancePrecision,
+) -> Result<Distances, String> {
+    let encoded = tcrs
+        .iter()
+        .enumerate()
+        .map(|(i, tcr)| EncodedTCR::with_residues(tcr, residues).map_err(|e| format!("TCR {}: {}", i + 1, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(match precision {
+        DistancePrecision::Double => Distances::Double(encoded_pairwise_distances(&encoded, policy, weights)),
+        DistancePrecision::Float => Distances::Float(encoded_pairwise_map(&encoded, policy, weights, float_bits)),
+        DistancePrecision::Integer => {
+            Distances::Integer(encoded_pairwise_map(&encoded, policy, weights, integer_distance))
+        }
+    })
+}
+
+fn encoded_pairwise_distances(encoded: &[EncodedTCR], policy: MissingPolicy, weights: ChainWeights) -> Vec<Option<f64>> {
+    encoded_pairwise_map(encoded, policy, weights, |d| d)
+}
 
-    let beta_dist = chain_distance(
-        &tcr1.cdr1_b_aa,
-        &tcr1.cdr2_b_aa,
-        &tcr1.cdr3_b_aa,
-        &tcr2.cdr1_b_aa,
-        &tcr2.cdr2_b_aa,
-        &tcr2.cdr3_b_aa,
-    );
+fn encoded_pairwise_map<T: Copy + Send>(
+    encoded: &[EncodedTCR],
+    policy: MissingPolicy,
+    weights: ChainWeights,
+    convert: impl Fn(Option<f64>) -> T + Sync,
+) -> Vec<T> {
+    let n = encoded.len();
+    let upper: Vec<Vec<T>> = match Cdr3Pack::new(encoded) {
+        // CDR3-only input (the common case): packed kernel with reused DP rows
+        Some(pack) => (0..n)
+            .into_par_iter()
+            .map_init(AlignScratch::default, |scratch, i| {
+                (i..n).map(|j| convert(pack.distance(i, j, policy, weights, scratch))).collect()
+            })
+            .collect(),
+        None => (0..n)
+            .into_par_iter()
+            .map(|i| {
+                (i..n)
+                    .map(|j| convert(encoded_tcrdist_weighted(&encoded[i], &encoded[j], policy, weights)))
+                    .collect()
+            })
+            .collect(),
+    };
 
-    (alpha_dist + beta_dist) as f64
+    let Some(&fill) = upper.first().and_then(|row| row.first()) else {
+        return Vec::new();
+    };
+    let mut matrix = vec![fill; n * n];
+    for (i, row) in upper.into_iter().enumerate() {
+        for (offset, dist) in row.into_iter().enumerate() {
+            let j = i + offset;
+            matrix[i * n + j] = dist;
+            matrix[j * n + i] = dist;
+        }
+    }
+    matrix
+}
+
+/// Marks a missing CDR3 in `Cdr3Pack::spans`
+const NO_CDR3: u32 = u32::MAX;
+
+/// CDR3a and CDR3b of TCRs without any CDR1/2, packed into one residue buffer
+/// so the pairwise kernel indexes slices instead of walking six optional CDRs
+struct Cdr3Pack {
+    residues: Vec<u8>,
+    /// Per TCR and chain (alpha, beta): start and length in `residues`, or
+    /// `NO_CDR3` as the start when the CDR3 is missing
+    spans: Vec<[(u32, u32); 2]>,
+}
+
+impl Cdr3Pack {
+    /// None when any TCR has a CDR1 or CDR2
+    fn new(tcrs: &[EncodedTCR]) -> Option<Self> {
+        const CDR12: [usize; 4] = [0, 1, 3, 4];
+        if tcrs.iter().any(|t| CDR12.iter().any(|&r| t.cdrs[r].is_some())) {
+            return None;
+        }
+        let mut residues = Vec::new();
+        let spans = tcrs
+            .iter()
+            .map(|t| {
+                [2, 5].map(|r| match &t.cdrs[r] {
+                    Some(cdr3) => {
+                        let start = residues.len() as u32;
+                        residues.extend_from_slice(cdr3);
+                        (start, cdr3.len() as u32)
+                    }
+                    None => (NO_CDR3, 0),
+                })
+            })
+            .collect();
+        Some(Self { residues, spans })
+    }
+
+    fn cdr3(&self, (start, len): (u32, u32)) -> &[u8] {
+        &self.residues[start as usize..(start + len) as usize]
+    }
+
+    /// `encoded_tcrdist_weighted` of TCRs `i` and `j`
+    fn distance(&self, i: usize, j: usize, policy: MissingPolicy, weights: ChainWeights, scratch: &mut AlignScratch) -> Option<f64> {
+        let mut total = 0.0;
+        for (chain, weight) in [weights.alpha, weights.beta].into_iter().enumerate() {
+            if weight == 0.0 {
+                continue;
+            }
+            let (a, b) = (self.spans[i][chain], self.spans[j][chain]);
+            let dist = match (a.0 == NO_CDR3, b.0 == NO_CDR3) {
+                (false, false) => 3 * align_encoded_in(self.cdr3(a), self.cdr3(b), 8, scratch),
+                (true, true) => 0,
+                _ => match policy {
+                    MissingPolicy::Skip => 0,
+                    MissingPolicy::Penalize(cost) => cost,
+                    MissingPolicy::Na => return None,
+                },
+            };
+            total += weight * dist as f64;
+        }
+        Some(total)
+    }
+}
+
+/// CDR3-only tcrdist between two CDR3 sequences (weight = 3, gap penalty = 8)
+pub fn cdr3_distance(cdr3_1: &str, cdr3_2: &str) -> i32 {
+    3 * align_sequences(cdr3_1, cdr3_2, 8)
+}
+
+/// Indices and distances of `targets` within `max_dist` CDR3 tcrdist of `query`,
+/// sorted by distance
+pub fn cdr3_neighbors<'a>(
+    query: &str,
+    targets: impl Iterator<Item = &'a str>,
+    max_dist: f64,
+) -> Vec<(usize, f64)> {
+    let query = encode_sequence(&query.to_uppercase());
+    encoded_cdr3_neighbors(&query, targets.map(encode_sequence), max_dist)
+}
+
+/// `cdr3_neighbors` over sequences already passed through `encode_sequence`
+fn encoded_cdr3_neighbors<T: AsRef<[u8]>>(
+    query: &[u8],
+    targets: impl Iterator<Item = T>,
+    max_dist: f64,
+) -> Vec<(usize, f64)> {
+    let mut hits: Vec<(usize, f64)> = targets
+        .enumerate()
+        .filter(|(_, target)| {
+            // Each residue of length difference costs at least one CDR3 gap
+            let len_diff = query.len().abs_diff(target.as_ref().len());
+            (3 * 8 * len_diff) as f64 <= max_dist
+        })
+        .filter_map(|(idx, target)| {
+            let dist = (3 * align_encoded(query, target.as_ref(), 8)) as f64;
+            (dist <= max_dist).then_some((idx, dist))
+        })
+        .collect();
+    hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+    hits
+}
+
+/// Find database entries within `max_dist` CDR3 tcrdist of each query CDR3
+/// Returns, per query, (entry index, distance) pairs sorted by distance
+pub fn database_neighbors(
+    queries: &[String],
+    database: &Database,
+    max_dist: f64,
+) -> Vec<Vec<(usize, f64)>> {
+    let targets: Vec<&[u8]> = database.entries.iter().map(|e| e.cdr3_tokens.encoded()).collect();
+    encoded_neighbor_lists(queries, &targets, max_dist)
+}
+
+/// Indices of `targets` within `max_dist` CDR3 tcrdist of each query, sorted by distance
+/// Targets are encoded once and reused for every query.
+pub fn cdr3_neighbor_lists<Q: AsRef<str> + Sync, T: AsRef<str>>(
+    queries: &[Q],
+    targets: &[T],
+    max_dist: f64,
+) -> Vec<Vec<(usize, f64)>> {
+    let targets: Vec<Vec<u8>> = targets.iter().map(|t| encode_sequence(t.as_ref())).collect();
+    encoded_neighbor_lists(queries, &targets, max_dist)
+}
+
+/// `cdr3_neighbor_lists` over targets already passed through `encode_sequence`
+fn encoded_neighbor_lists<Q: AsRef<str> + Sync, T: AsRef<[u8]> + Sync>(
+    queries: &[Q],
+    targets: &[T],
+    max_dist: f64,
+) -> Vec<Vec<(usize, f64)>> {
+    queries
+        .par_iter()
+        .map(|query| {
+            let query = encode_sequence(&query.as_ref().to_uppercase());
+            encoded_cdr3_neighbors(&query, targets.iter(), max_dist)
+        })
+        .collect()
 }
 
-/// Calculate distance for a single chain (alpha or beta)
-fn chain_distance(
-    cdr1_1: &Option<String>,
-    cdr2_1: &Option<String>,
-    cdr3_1: &Option<String>,
-    cdr1_2: &Option<String>,
-    cdr2_2: &Option<String>,
-    cdr3_2: &Option<String>,
-) -> i32 {
-    let mut total_distance = 0;
+/// Background tcrdist distribution, sorted ascending: distances of `n_pairs`
+/// random pairs of distinct TCRs (drawn with replacement), or of every pair
+/// when there are no more than `n_pairs`. Undefined (NA) distances are dropped.
+/// Returns the distances and whether every pair was used.
+pub fn background_distances(
+    tcrs: &[EncodedTCR],
+    n_pairs: usize,
+    policy: MissingPolicy,
+    weights: ChainWeights,
+    rng: &mut Rng,
+) -> (Vec<f64>, bool) {
+    let n = tcrs.len();
+    let n_all = n * n.saturating_sub(1) / 2;
+    let exhaustive = n_all <= n_pairs;
+    let pairs: Vec<(usize, usize)> = if exhaustive {
+        (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))).collect()
+    } else {
+        (0..n_pairs)
+            .map(|_| {
+                let i = rng.below(n);
+                // Shift past i so the pair is always two distinct TCRs
+                let j = rng.below(n - 1);
+                (i, if j >= i { j + 1 } else { j })
+            })
+            .collect()
+    };
+    let mut distances: Vec<f64> = pairs
+        .par_iter()
+        .filter_map(|&(i, j)| encoded_tcrdist_weighted(&tcrs[i], &tcrs[j], policy, weights))
+        .collect();
+    distances.sort_by(f64::total_cmp);
+    (distances, exhaustive)
+}
 
-    // CDR1 distance (weight = 1, gap penalty = 4)
-    if let (Some(seq1), Some(seq2)) = (cdr1_1, cdr1_2) {
-        total_distance += align_sequences(seq1, seq2, 4);
+/// Quantile `p` (0-1) of sorted values, interpolated linearly as R's default
+/// `quantile(type = 7)`; None for no values
+pub fn sorted_quantile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
     }
+    let h = (sorted.len() - 1) as f64 * p.clamp(0.0, 1.0);
+    let (lo, hi) = (h.floor() as usize, h.ceil() as usize);
+    Some(sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo]))
+}
 
-    // CDR2 distance (weight = 1, gap penalty = 4)
-    if let (Some(seq1), Some(seq2)) = (cdr2_1, cdr2_2) {
-        total_distance += align_sequences(seq1, seq2, 4);
+/// Counts of `values` in `n_bins` equal-width bins from 0 to the maximum;
+/// returns the `n_bins + 1` breaks and the counts (right-closed bins, the
+/// first also holding 0, as R's `hist()`)
+pub fn histogram(values: &[f64], n_bins: usize) -> (Vec<f64>, Vec<usize>) {
+    let n_bins = n_bins.max(1);
+    let max = values.iter().copied().fold(0.0f64, f64::max);
+    let width = if max > 0.0 { max / n_bins as f64 } else { 1.0 };
+    let breaks: Vec<f64> = (0..=n_bins).map(|b| b as f64 * width).collect();
+    let mut counts = vec![0usize; n_bins];
+    for &v in values {
+        let bin = ((v / width).ceil() as usize).clamp(1, n_bins) - 1;
+        counts[bin] += 1;
     }
+    (breaks, counts)
+}
+
+/// All pairs (i, j) with i in `rows`, j > i and tcrdist(i, j) <= `radius`
+pub fn neighbor_block(tcrs: &[EncodedTCR], rows: Range<usize>, radius: f64) -> Vec<(usize, usize, f64)> {
+    rows.into_par_iter()
+        .flat_map_iter(|i| {
+            (i + 1..tcrs.len()).filter_map(move |j| {
+                let dist = encoded_tcrdist(&tcrs[i], &tcrs[j], MissingPolicy::Skip).unwrap_or(0.0);
+                (dist <= radius).then_some((i, j, dist))
+            })
+        })
+        .collect()
+}
+
+/// Rows computed in parallel per block when streaming neighbors to disk
+const NEIGHBOR_BLOCK_ROWS: usize = 512;
 
-    // CDR3 distance (weight = 3, gap penalty = 8)
-    if let (Some(seq1), Some(seq2)) = (cdr3_1, cdr3_2) {
-        total_distance += 3 * align_sequences(seq1, seq2, 8);
+/// Stream neighbor triplets (1-based i < j, distance) within `radius` as TSV
+/// Rows are processed in parallel blocks so memory stays bounded by one block.
+/// Returns the number of pairs written.
+pub fn write_neighbors<W: Write + ?Sized>(tcrs: &[TCR], radius: f64, out: &mut W) -> crate::error::Result<usize> {
+    writeln!(out, "i\tj\tdistance")?;
+    let tcrs: Vec<EncodedTCR> = tcrs.iter().map(EncodedTCR::new).collect();
+    let mut n_pairs = 0;
+    for start in (0..tcrs.len()).step_by(NEIGHBOR_BLOCK_ROWS) {
+        let end = (start + NEIGHBOR_BLOCK_ROWS).min(tcrs.len());
+        for (i, j, dist) in neighbor_block(&tcrs, start..end, radius) {
+            writeln!(out, "{}\t{}\t{}", i + 1, j + 1, dist)?;
+            n_pairs += 1;
+        }
     }
+    out.flush()?;
+    Ok(n_pairs)
+}
 
-    total_distance
+/// Write neighbor triplets to `path`, gzip-compressed when it ends in ".gz"
+pub fn write_neighbors_to_path<P: AsRef<Path>>(tcrs: &[TCR], radius: f64, path: P) -> crate::error::Result<usize> {
+    crate::utils::write_to_path(path, |out| write_neighbors(tcrs, radius, out))
 }
 
 #[cfg(test)]
@@ -256,4 +854,254 @@ mod tests {
         let dist = tcrdist(&tcr1, &tcr2);
         assert!(dist > 0.0);
     }
+
+    #[test]
+    fn test_missing_policy() {
+        let paired = TCR::new(None, None, Some("CAVSF".to_string()), None, None, Some("CASSF".to_string()));
+        let beta_only = TCR::new(None, None, None, None, None, Some("CASSF".to_string()));
+
+        assert_eq!(tcrdist(&paired, &beta_only), 0.0);
+        assert_eq!(tcrdist_with_policy(&paired, &beta_only, MissingPolicy::Skip), Some(0.0));
+        assert_eq!(tcrdist_with_policy(&paired, &beta_only, MissingPolicy::Penalize(24)), Some(24.0));
+        assert_eq!(tcrdist_with_policy(&paired, &beta_only, MissingPolicy::Na), None);
+
+        // Regions missing on both sides are not "unpaired"
+        assert_eq!(tcrdist_with_policy(&beta_only, &beta_only, MissingPolicy::Na), Some(0.0));
+    }
+
+    #[test]
+    fn test_missing_policy_parse() {
+        assert_eq!(MissingPolicy::parse("skip", 10).unwrap(), MissingPolicy::Skip);
+        assert_eq!(MissingPolicy::parse("Penalize", 10).unwrap(), MissingPolicy::Penalize(10));
+        assert_eq!(MissingPolicy::parse("na", 10).unwrap(), MissingPolicy::Na);
+        assert!(MissingPolicy::parse("drop", 10).is_err());
+    }
+
+    #[test]
+    fn test_database_neighbors() {
+        use crate::database::{DatabaseEntry, DatabaseMetadata};
+
+        let entry = |cdr3: &str| DatabaseEntry {
+            v_segment: String::new(),
+            j_segment: String::new(),
+            ..DatabaseEntry::test(cdr3, "GLCTLVAML")
+        };
+        let database = Database {
+            entries: vec![entry("CASSLGQAYEQYF"), entry("CASSLGQAYEQFF"), entry("CAVRDGGNKLTF")],
+            metadata: DatabaseMetadata::default(),
+        };
+
+        let hits = database_neighbors(&["cassLGQAYEQYF".to_string()], &database, 12.0);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0][0], (0, 0.0));
+        assert_eq!(hits[0].len(), 2);
+        assert_eq!(hits[0][1].1, cdr3_distance("CASSLGQAYEQYF", "CASSLGQAYEQFF") as f64);
+    }
+
+    #[test]
+    fn test_write_neighbors() {
+        let cdr3 = |s: &str| TCR::new(None, None, None, None, None, Some(s.to_string()));
+        let tcrs = vec![cdr3("CASSLF"), cdr3("CASSLY"), cdr3("CAVRDGGNKLTF")];
+
+        let mut out = Vec::new();
+        let n = write_neighbors(&tcrs, 10.0, &mut out).unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(String::from_utf8(out).unwrap(), "i\tj\tdistance\n1\t2\t3\n");
+    }
+
+    #[test]
+    fn test_position_cost_table_matches_blosum() {
+        let residues = b"ARNDCQEGHILKMFPSTWYVX*";
+        for &a in residues {
+            for &b in residues {
+                let (ia, ib) = (encode_sequence(&(a as char).to_string()), encode_sequence(&(b as char).to_string()));
+                assert_eq!(POSITION_COST[ia[0] as usize][ib[0] as usize], position_score(a, b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_pairwise_distances_symmetric() {
+        let cdr3 = |s: &str| TCR::new(None, None, None, None, None, Some(s.to_string()));
+        let tcrs = vec![cdr3("CASSLF"), cdr3("CASSLY"), cdr3("CAVRDGGNKLTF")];
+        let matrix = pairwise_distances(&tcrs, MissingPolicy::Skip);
+
+        assert_eq!(matrix.len(), 9);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_eq!(matrix[i * 3 + j], tcrdist_with_policy(&tcrs[i], &tcrs[j], MissingPolicy::Skip));
+                assert_eq!(matrix[i * 3 + j], matrix[j * 3 + i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_residues() {
+        use crate::sequence::AmbiguousResiduePolicy;
+
+        let cdr3 = |s: &str| TCR::new(None, None, None, None, None, Some(s.to_string()));
+        let tcrs = vec![cdr3("CASSLGF"), cdr3("CASSXGF"), cdr3("CASS.LGF")];
+        let residues = |policy| ResidueHandling::new(policy, ".-");
+
+        // Gaps are stripped; X costs like an unknown residue by default
+        let penalize = pairwise_distances_with(&tcrs, MissingPolicy::Na, &residues(AmbiguousResiduePolicy::Penalize), ChainWeights::default()).unwrap();
+        assert_eq!(penalize[2], Some(0.0));
+        assert_eq!(penalize[1], Some(3.0 * 8.0));
+
+        let neutral = pairwise_distances_with(&tcrs, MissingPolicy::Na, &residues(AmbiguousResiduePolicy::Neutral), ChainWeights::default()).unwrap();
+        assert_eq!(neutral[1], Some(0.0));
+
+        // A skipped CDR is missing, so `MissingPolicy` decides
+        let skip = pairwise_distances_with(&tcrs, MissingPolicy::Na, &residues(AmbiguousResiduePolicy::Skip), ChainWeights::default()).unwrap();
+        assert_eq!(skip[1], None);
+        assert_eq!(skip[2], Some(0.0));
+
+        let err = pairwise_distances_with(&tcrs, MissingPolicy::Na, &residues(AmbiguousResiduePolicy::Error), ChainWeights::default()).unwrap_err();
+        assert!(err.starts_with("TCR 2:"));
+    }
+
+    #[test]
+    fn test_chain_weights() {
+        let alpha_only = TCR::new(None, None, Some("CAVRDF".into()), None, None, None);
+        let paired = |b: &str| TCR::new(None, None, Some("CAVRDF".into()), None, None, Some(b.to_string()));
+        let (t1, t2) = (paired("CASSLF"), paired("CASSLY"));
+        let full = tcrdist_with_policy(&t1, &t2, MissingPolicy::Skip).unwrap();
+        assert!(full > 0.0);
+
+        assert_eq!(tcrdist_with_weights(&t1, &t2, MissingPolicy::Skip, ChainWeights::default()), Some(full));
+        let half_beta = ChainWeights::new(1.0, 0.5).unwrap();
+        assert_eq!(tcrdist_with_weights(&t1, &t2, MissingPolicy::Skip, half_beta), Some(full / 2.0));
+        assert_eq!(tcrdist_with_weights(&t1, &t2, MissingPolicy::Skip, ChainWeights::ALPHA_ONLY), Some(0.0));
+
+        // A zero-weight chain is ignored even when unpaired under the NA policy
+        assert_eq!(tcrdist_with_weights(&alpha_only, &t1, MissingPolicy::Na, ChainWeights::default()), None);
+        assert_eq!(tcrdist_with_weights(&alpha_only, &t1, MissingPolicy::Na, ChainWeights::ALPHA_ONLY), Some(0.0));
+
+        assert_eq!(ChainWeights::parse("beta_only").unwrap(), ChainWeights::BETA_ONLY);
+        assert!(ChainWeights::new(0.0, 0.0).is_err());
+        assert!(ChainWeights::new(-1.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_background_distances() {
+        let cdr3 = |s: &str| EncodedTCR::new(&TCR::new(None, None, None, None, None, Some(s.to_string())));
+        let tcrs = vec![cdr3("CASSLF"), cdr3("CASSLY"), cdr3("CAVRDGGNKLTF"), cdr3("CASSLF")];
+
+        // Few TCRs: every pair, exactly once
+        let (all, exhaustive) = background_distances(&tcrs, 100, MissingPolicy::Skip, ChainWeights::default(), &mut Rng::new(1));
+        assert!(exhaustive);
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[0], 0.0);
+        assert!(all.windows(2).all(|w| w[0] <= w[1]));
+
+        let (sampled, exhaustive) = background_distances(&tcrs, 3, MissingPolicy::Skip, ChainWeights::default(), &mut Rng::new(1));
+        assert!(!exhaustive);
+        assert_eq!(sampled.len(), 3);
+        assert!(sampled.iter().all(|d| all.contains(d)));
+
+        assert_eq!(sorted_quantile(&[1.0, 2.0, 3.0, 4.0], 0.5), Some(2.5));
+        assert_eq!(sorted_quantile(&[1.0, 2.0, 3.0, 4.0], 0.0), Some(1.0));
+        assert_eq!(sorted_quantile(&[], 0.5), None);
+
+        let (breaks, counts) = histogram(&[0.0, 1.0, 2.0, 4.0], 2);
+        assert_eq!(breaks, [0.0, 2.0, 4.0]);
+        assert_eq!(counts, [3, 1]);
+    }
+
+    #[test]
+    fn test_cdr3_only_fast_path() {
+        let tcr = |a: Option<&str>, b: Option<&str>| TCR::new(None, None, a.map(Into::into), None, None, b.map(Into::into));
+        let tcrs = vec![
+            tcr(Some("CAVRDF"), Some("CASSLF")),
+            tcr(None, Some("CASSLYEQYF")),
+            tcr(Some("CAVSDF"), None),
+            tcr(Some("CAVRDF"), Some("CASSLF")),
+        ];
+        let encoded: Vec<EncodedTCR> = tcrs.iter().map(EncodedTCR::new).collect();
+        assert!(Cdr3Pack::new(&encoded).is_some());
+
+        for policy in [MissingPolicy::Skip, MissingPolicy::Penalize(24), MissingPolicy::Na] {
+            for weights in [ChainWeights::default(), ChainWeights::new(0.5, 2.0).unwrap(), ChainWeights::BETA_ONLY] {
+                let fast = encoded_pairwise_distances(&encoded, policy, weights);
+                for i in 0..4 {
+                    for j in 0..4 {
+                        let general = encoded_tcrdist_weighted(&encoded[i], &encoded[j], policy, weights);
+                        assert_eq!(fast[i * 4 + j], general, "{:?} {:?} ({}, {})", policy, weights, i, j);
+                    }
+                }
+            }
+        }
+
+        // Any CDR1/2 falls back to the general kernel
+        let mut with_cdr1 = tcrs.clone();
+        with_cdr1[0].cdr1_a_aa = Some("DRGSQS".into());
+        let encoded: Vec<EncodedTCR> = with_cdr1.iter().map(EncodedTCR::new).collect();
+        assert!(Cdr3Pack::new(&encoded).is_none());
+    }
+
+    #[test]
+    fn test_distance_precision() {
+        let tcr = |b: Option<&str>| TCR::new(None, None, Some("CAVRDF".into()), None, None, b.map(Into::into));
+        let tcrs = vec![tcr(Some("CASSLF")), tcr(Some("CASSLYEQYF")), tcr(None)];
+        let residues = ResidueHandling::default();
+        let distances = |precision| {
+            pairwise_distances_as(&tcrs, MissingPolicy::Na, &residues, ChainWeights::default(), precision).unwrap()
+        };
+        let double = pairwise_distances_with(&tcrs, MissingPolicy::Na, &residues, ChainWeights::default()).unwrap();
+        assert_eq!(distances(DistancePrecision::Double), Distances::Double(double.clone()));
+
+        let expected: Vec<f64> = double.iter().map(|d| d.unwrap_or(f64::NAN)).collect();
+        for precision in [DistancePrecision::Float, DistancePrecision::Integer] {
+            let compact = distances(precision).to_f64();
+            assert_eq!(compact.len(), 9);
+            for (a, b) in compact.iter().zip(&expected) {
+                assert!(a == b || a.is_nan() && b.is_nan(), "{:?}: {} != {}", precision, a, b);
+            }
+        }
+        assert_eq!(float_bits(None), FLOAT_NA_BITS);
+        assert_eq!(from_float_bits(float_bits(Some(0.0))), Some(0.0));
+        assert_eq!(DistancePrecision::parse("f32"), Ok(DistancePrecision::Float));
+        assert!(DistancePrecision::parse("half").is_err());
+        assert!(!ChainWeights::new(0.5, 1.0).unwrap().is_integral());
+    }
+
+    /// Random TCR over the 20 amino acids in either case; CDR1/2 are missing
+    /// in about half of them (exercising the CDR3-only path) and any CDR is missing
+    /// one time in five
+    fn random_tcr(rng: &mut crate::random::Rng) -> TCR {
+        let alphabet = b"ACDEFGHIKLMNPQRSTVWYacdefghiklmnpqrstvwy";
+        let cdr3_only = rng.below(2) == 0;
+        let mut cdr = |max_len: usize, cdr3: bool| {
+            (rng.below(5) > 0 && (cdr3 || !cdr3_only)).then(|| {
+                let len = 1 + rng.below(max_len);
+                (0..len).map(|_| alphabet[rng.below(alphabet.len())] as char).collect::<String>()
+            })
+        };
+        let (cdr1_a, cdr2_a, cdr3_a) = (cdr(6, false), cdr(6, false), cdr(16, true));
+        let (cdr1_b, cdr2_b, cdr3_b) = (cdr(6, false), cdr(6, false), cdr(16, true));
+        TCR::new(cdr1_a, cdr2_a, cdr3_a, cdr1_b, cdr2_b, cdr3_b)
+    }
+
+    #[test]
+    fn test_tcrdist_self_and_symmetry() {
+        let mut rng = crate::random::Rng::new(5);
+        let policies = [MissingPolicy::Skip, MissingPolicy::Penalize(24), MissingPolicy::Na];
+        let tcrs: Vec<TCR> = (0..60).map(|_| random_tcr(&mut rng)).collect();
+        for policy in policies {
+            let matrix = pairwise_distances(&tcrs, policy);
+            for (i, a) in tcrs.iter().enumerate() {
+                assert_eq!(tcrdist_with_policy(a, a, policy), Some(0.0), "{:?}", a);
+                for (j, b) in tcrs.iter().enumerate() {
+                    let ab = tcrdist_with_policy(a, b, policy);
+                    assert_eq!(ab, tcrdist_with_policy(b, a, policy), "{:?} {:?}", a, b);
+                    assert_eq!(matrix[i * tcrs.len() + j], ab);
+                }
+            }
+        }
+        for tcr in &tcrs {
+            let cdr3 = tcr.cdr3_b_aa.clone().unwrap_or_default();
+            assert_eq!(cdr3_distance(&cdr3, &cdr3.to_uppercase()), 0);
+            assert_eq!(tcrdist(tcr, tcr), 0.0);
+        }
+    }
 }
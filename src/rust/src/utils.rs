@@ -1,8 +1,6 @@
 use crate::error::{Result, VdjMatchError};
 use crate::sequence::Clonotype;
-use csv::ReaderBuilder;
-use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, Write};
 use std::path::Path;
 
 /// Sample format types
@@ -34,9 +32,8 @@ pub fn load_sample<P: AsRef<Path>>(
     path: P,
     format: SampleFormat,
 ) -> Result<Vec<Clonotype>> {
-    let file = File::open(path.as_ref())?;
-    let reader = BufReader::new(file);
-    
+    let reader = open_reader(path)?;
+
     match format {
         SampleFormat::VdjTools => load_vdjtools_sample(reader),
         SampleFormat::Mitcr => load_mitcr_sample(reader),
@@ -46,9 +43,7 @@ pub fn load_sample<P: AsRef<Path>>(
 }
 
 fn load_vdjtools_sample<R: std::io::Read>(reader: R) -> Result<Vec<Clonotype>> {
-    let mut csv_reader = ReaderBuilder::new()
-        .delimiter(b'\t')
-        .from_reader(reader);
+    let mut csv_reader = crate::io::tsv_reader_builder().from_reader(reader);
     
     let mut clonotypes = Vec::new();
     
@@ -64,7 +59,7 @@ fn load_vdjtools_sample<R: std::io::Read>(reader: R) -> Result<Vec<Clonotype>> {
         let cdr3_nt = record.get(2).map(|s| s.to_string());
         let cdr3_aa = record.get(3).unwrap_or("").to_string();
         let v_segment = record.get(4).unwrap_or("").to_string();
-        let d_segment = record.get(5).map(|s| s.to_string());
+        let d_segment = record.get(5).and_then(crate::sequence::parse_d_segment);
         let j_segment = record.get(6).unwrap_or("").to_string();
         
         if cdr3_aa.is_empty() || v_segment.is_empty() || j_segment.is_empty() {
@@ -104,10 +99,7 @@ fn load_immunoseq_sample<R: std::io::Read>(_reader: R) -> Result<Vec<Clonotype>>
 
 /// Load metadata file
 pub fn load_metadata<P: AsRef<Path>>(path: P) -> Result<Vec<(String, String)>> {
-    let file = File::open(path)?;
-    let mut reader = ReaderBuilder::new()
-        .delimiter(b'\t')
-        .from_reader(file);
+    let mut reader = crate::io::tsv_reader_builder().from_reader(open_reader(path)?);
     
     let mut samples = Vec::new();
     
@@ -122,3 +114,85 @@ pub fn load_metadata<P: AsRef<Path>>(path: P) -> Result<Vec<(String, String)>> {
     
     Ok(samples)
 }
+
+/// Whether `path` has a ".gz" extension
+pub fn is_gz_path(path: &Path) -> bool {
+    crate::io::Compression::from_path(path) == crate::io::Compression::Gzip
+}
+
+pub use crate::io::{open_reader, write_to_path};
+
+/// Version of the files written by `Database::save` and `BatchMatches::save`
+const SAVE_FORMAT_VERSION: &str = "1";
+
+/// Write the header line of a vdjmatchR save file: `#vdjmatchR-<kind>`, format version, extra fields
+pub fn write_save_header(out: &mut dyn Write, kind: &str, extra: &[&str]) -> Result<()> {
+    write!(out, "#vdjmatchR-{}\t{}", kind, SAVE_FORMAT_VERSION)?;
+    for field in extra {
+        write!(out, "\t{}", field)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Read and check a header written by `write_save_header`, returning its extra fields
+pub fn read_save_header(reader: &mut dyn BufRead, kind: &str) -> Result<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut fields = line.trim_end_matches(['\r', '\n']).split('\t');
+
+    if fields.next() != Some(format!("#vdjmatchR-{}", kind).as_str()) {
+        return Err(VdjMatchError::InvalidFormat(format!(
+            "not a saved vdjmatchR {} file",
+            kind
+        )));
+    }
+    match fields.next() {
+        Some(SAVE_FORMAT_VERSION) => Ok(fields.map(|f| f.to_string()).collect()),
+        other => Err(VdjMatchError::InvalidFormat(format!(
+            "unsupported {} file version {}",
+            kind,
+            other.unwrap_or("(missing)")
+        ))),
+    }
+}
+
+/// Current UTC time as an ISO 8601 timestamp ("2024-06-13T09:30:00Z")
+pub fn utc_timestamp() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_utc(secs)
+}
+
+/// Format seconds since the Unix epoch as an ISO 8601 UTC timestamp
+fn format_utc(secs: u64) -> String {
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(1_718_271_000), "2024-06-13T09:30:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+    }
+}
@@ -0,0 +1,318 @@
+use crate::error::{Result, VdjMatchError};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Identity of a hit: (query CDR3, database CDR3, epitope), compared case-insensitively
+pub type HitKey = (String, String, String);
+
+/// Build a normalized hit key
+pub fn hit_key(query_cdr3: &str, db_cdr3: &str, epitope: &str) -> HitKey {
+    (
+        query_cdr3.trim().to_uppercase(),
+        db_cdr3.trim().to_uppercase(),
+        epitope.trim().to_uppercase(),
+    )
+}
+
+/// Accepted column names in reference outputs (vdjmatch, tcrdist3 and our own)
+const QUERY_CDR3_COLUMNS: &[&str] = &["query_cdr3", "cdr3aa", "cdr3_b_aa", "cdr3"];
+const DB_CDR3_COLUMNS: &[&str] = &["cdr3_db", "match.cdr3", "cdr3.db", "db.cdr3", "cdr3_b_aa_db"];
+const EPITOPE_COLUMNS: &[&str] = &["antigen_epitope", "antigen.epitope", "epitope"];
+const SCORE_COLUMNS: &[&str] = &["score", "vdjmatch.score", "distance"];
+
+/// Load hits from a reference TSV (optionally gzipped), keyed by `hit_key`
+/// The score column is optional; duplicate hits keep their highest score.
+pub fn load_reference_hits<P: AsRef<Path>>(path: P) -> Result<HashMap<HitKey, Option<f64>>> {
+    let reader = crate::utils::open_reader(path)?;
+    let mut reader = crate::io::tsv_reader_builder().flexible(true).from_reader(reader);
+
+    let headers: Vec<String> = reader.headers()?.iter().map(|h| h.trim().to_lowercase()).collect();
+    let find = |names: &[&str]| names.iter().find_map(|n| headers.iter().position(|h| h == n));
+    let missing = |what: &str, names: &[&str]| {
+        VdjMatchError::InvalidFormat(format!("reference has no {} column (expected one of: {})", what, names.join(", ")))
+    };
+
+    let query_idx = find(QUERY_CDR3_COLUMNS).ok_or_else(|| missing("query CDR3", QUERY_CDR3_COLUMNS))?;
+    let db_idx = find(DB_CDR3_COLUMNS).ok_or_else(|| missing("database CDR3", DB_CDR3_COLUMNS))?;
+    let epitope_idx = find(EPITOPE_COLUMNS).ok_or_else(|| missing("epitope", EPITOPE_COLUMNS))?;
+    let score_idx = find(SCORE_COLUMNS);
+
+    let mut hits = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |i: usize| record.get(i).unwrap_or("");
+        let key = hit_key(field(query_idx), field(db_idx), field(epitope_idx));
+        let score = score_idx.and_then(|i| field(i).trim().parse::<f64>().ok());
+        insert_hit(&mut hits, key, score);
+    }
+    Ok(hits)
+}
+
+/// Insert a hit, keeping the highest score seen for duplicate keys
+pub fn insert_hit(hits: &mut HashMap<HitKey, Option<f64>>, key: HitKey, score: Option<f64>) {
+    let slot = hits.entry(key).or_insert(score);
+    if let (Some(old), Some(new)) = (*slot, score) {
+        *slot = Some(old.max(new));
+    } else if slot.is_none() {
+        *slot = score;
+    }
+}
+
+/// Concordance between our hits and a reference run
+#[derive(Debug, Clone)]
+pub struct Concordance {
+    pub n_ours: usize,
+    pub n_reference: usize,
+    pub n_shared: usize,
+    /// Shared hits with a score on both sides
+    pub n_scored: usize,
+    /// Pearson correlation of scores over `n_scored` hits (None if fewer than 2 or constant)
+    pub score_correlation: Option<f64>,
+    pub max_score_difference: Option<f64>,
+    pub only_ours: Vec<HitKey>,
+    pub only_reference: Vec<HitKey>,
+}
+
+impl Concordance {
+    /// Fraction of our hits present in the reference
+    pub fn precision(&self) -> f64 {
+        if self.n_ours == 0 { 1.0 } else { self.n_shared as f64 / self.n_ours as f64 }
+    }
+
+    /// Fraction of reference hits we reproduce
+    pub fn recall(&self) -> f64 {
+        if self.n_reference == 0 { 1.0 } else { self.n_shared as f64 / self.n_reference as f64 }
+    }
+
+    /// Shared hits over the union of both hit sets
+    pub fn jaccard(&self) -> f64 {
+        let union = self.n_ours + self.n_reference - self.n_shared;
+        if union == 0 { 1.0 } else { self.n_shared as f64 / union as f64 }
+    }
+}
+
+/// Compare hit sets and, for shared hits, their scores
+pub fn compare_hits(
+    ours: &HashMap<HitKey, Option<f64>>,
+    reference: &HashMap<HitKey, Option<f64>>,
+) -> Concordance {
+    let mut pairs = Vec::new();
+    let mut only_ours = Vec::new();
+    for (key, score) in ours {
+        match reference.get(key) {
+            Some(ref_score) => {
+                if let (Some(a), Some(b)) = (score, ref_score) {
+                    pairs.push((*a, *b));
+                }
+            }
+            None => only_ours.push(key.clone()),
+        }
+    }
+    let mut only_reference: Vec<HitKey> = reference
+        .keys()
+        .filter(|k| !ours.contains_key(*k))
+        .cloned()
+        .collect();
+    only_ours.sort();
+    only_reference.sort();
+
+    Concordance {
+        n_ours: ours.len(),
+        n_reference: reference.len(),
+        n_shared: ours.len() - only_ours.len(),
+        n_scored: pairs.len(),
+        score_correlation: pearson(&pairs),
+        max_score_difference: pairs.iter().map(|(a, b)| (a - b).abs()).reduce(f64::max),
+        only_ours,
+        only_reference,
+    }
+}
+
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let (mean_a, mean_b) = pairs.iter().fold((0.0, 0.0), |(sa, sb), (a, b)| (sa + a / n, sb + b / n));
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (a, b) in pairs {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a).powi(2);
+        var_b += (b - mean_b).powi(2);
+    }
+    (var_a > 0.0 && var_b > 0.0).then(|| cov / (var_a.sqrt() * var_b.sqrt()))
+}
+
+/// Epitope named by a dextramer/antigen-capture feature such as
+/// "A0201_GILGFVFTL_Flu-MP_Influenza_binder": the first token that looks like a
+/// peptide (8+ amino acid letters), or the whole name
+pub fn dextramer_epitope(feature: &str) -> String {
+    feature
+        .split(['_', '-', '.', ' '])
+        .find(|t| t.len() >= 8 && t.bytes().all(|b| b"ACDEFGHIKLMNPQRSTVWY".contains(&b)))
+        .unwrap_or(feature)
+        .to_string()
+}
+
+/// Dextramer call per cell from a cells x features UMI matrix (column-major)
+/// A cell is called for its top feature when that has at least `min_umi` UMIs
+/// and `min_ratio` times the UMIs of the runner-up; otherwise it has no call.
+pub fn dextramer_calls(counts: &[f64], n_cells: usize, min_umi: f64, min_ratio: f64) -> Vec<Option<usize>> {
+    let n_features = counts.len().checked_div(n_cells).unwrap_or(0);
+    (0..n_cells)
+        .map(|cell| {
+            let umi = |f: usize| counts[f * n_cells + cell].max(0.0);
+            let mut order: Vec<usize> = (0..n_features).filter(|&f| !counts[f * n_cells + cell].is_nan()).collect();
+            order.sort_by(|&a, &b| umi(b).total_cmp(&umi(a)));
+            let top = *order.first()?;
+            let runner_up = order.get(1).map_or(0.0, |&f| umi(f));
+            (umi(top) >= min_umi && umi(top) >= min_ratio * runner_up).then_some(top)
+        })
+        .collect()
+}
+
+/// Agreement of predicted and ground-truth labels for one epitope
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpitopeAgreement {
+    pub epitope: String,
+    /// Cells with this ground-truth label
+    pub n_truth: usize,
+    /// Cells with a ground-truth label predicted as this epitope
+    pub n_predicted: usize,
+    pub n_true_positive: usize,
+}
+
+impl EpitopeAgreement {
+    pub fn precision(&self) -> Option<f64> {
+        (self.n_predicted > 0).then(|| self.n_true_positive as f64 / self.n_predicted as f64)
+    }
+
+    pub fn recall(&self) -> Option<f64> {
+        (self.n_truth > 0).then(|| self.n_true_positive as f64 / self.n_truth as f64)
+    }
+}
+
+/// Cell-level comparison of in-silico annotation with ground truth (e.g. dextramers)
+#[derive(Debug, Clone)]
+pub struct LabelComparison {
+    pub n_cells: usize,
+    pub n_truth: usize,
+    pub n_predicted: usize,
+    /// Cells with both a prediction and a ground-truth label
+    pub n_both: usize,
+    pub n_agree: usize,
+    /// Per epitope, over cells with a ground-truth label, sorted by epitope
+    pub per_epitope: Vec<EpitopeAgreement>,
+    /// (predicted, truth, cells) over all cells, None meaning no label
+    pub confusion: Vec<(Option<String>, Option<String>, usize)>,
+}
+
+impl LabelComparison {
+    /// Fraction of cells with both labels whose labels agree
+    pub fn concordance(&self) -> Option<f64> {
+        (self.n_both > 0).then(|| self.n_agree as f64 / self.n_both as f64)
+    }
+}
+
+fn agreement<'a>(map: &'a mut HashMap<String, EpitopeAgreement>, epitope: &str) -> &'a mut EpitopeAgreement {
+    map.entry(epitope.to_string()).or_insert_with(|| EpitopeAgreement {
+        epitope: epitope.to_string(),
+        n_truth: 0,
+        n_predicted: 0,
+        n_true_positive: 0,
+    })
+}
+
+/// Compare predicted with ground-truth epitope labels per cell (case-insensitive)
+pub fn compare_labels(predicted: &[Option<String>], truth: &[Option<String>]) -> LabelComparison {
+    let norm = |l: &Option<String>| {
+        l.as_deref().map(|s| s.trim().to_uppercase()).filter(|s| !s.is_empty())
+    };
+    let mut per_epitope: HashMap<String, EpitopeAgreement> = HashMap::new();
+    let mut confusion: HashMap<(Option<String>, Option<String>), usize> = HashMap::new();
+    let (mut n_truth, mut n_predicted, mut n_both, mut n_agree) = (0, 0, 0, 0);
+
+    for (p, t) in predicted.iter().zip(truth) {
+        let (p, t) = (norm(p), norm(t));
+        n_predicted += p.is_some() as usize;
+        *confusion.entry((p.clone(), t.clone())).or_insert(0) += 1;
+        let Some(t) = t else { continue };
+        n_truth += 1;
+        agreement(&mut per_epitope, &t).n_truth += 1;
+        if let Some(p) = p {
+            n_both += 1;
+            agreement(&mut per_epitope, &p).n_predicted += 1;
+            if p == t {
+                n_agree += 1;
+                agreement(&mut per_epitope, &t).n_true_positive += 1;
+            }
+        }
+    }
+
+    let mut per_epitope: Vec<EpitopeAgreement> = per_epitope.into_values().collect();
+    per_epitope.sort_by(|a, b| a.epitope.cmp(&b.epitope));
+    let mut confusion: Vec<(Option<String>, Option<String>, usize)> =
+        confusion.into_iter().map(|((p, t), n)| (p, t, n)).collect();
+    confusion.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1))));
+
+    LabelComparison {
+        n_cells: predicted.len().min(truth.len()),
+        n_truth,
+        n_predicted,
+        n_both,
+        n_agree,
+        per_epitope,
+        confusion,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_hits() {
+        let mut ours = HashMap::new();
+        insert_hit(&mut ours, hit_key("CASSLF", "CASSLF", "GLCTLVAML"), Some(1.0));
+        insert_hit(&mut ours, hit_key("cassly", "CASSLF", "GLCTLVAML"), Some(0.8));
+        insert_hit(&mut ours, hit_key("CAVRDF", "CAVRDF", "NLVPMVATV"), Some(1.0));
+
+        let mut reference = HashMap::new();
+        insert_hit(&mut reference, hit_key("CASSLF", "CASSLF", "GLCTLVAML"), Some(0.9));
+        insert_hit(&mut reference, hit_key("CASSLY", "CASSLF", "glctlvaml"), Some(0.7));
+        insert_hit(&mut reference, hit_key("CASSLY", "CASSLF", "glctlvaml"), Some(0.5));
+        insert_hit(&mut reference, hit_key("CSARDF", "CSARDF", "YVLDHLIVV"), None);
+
+        let c = compare_hits(&ours, &reference);
+        assert_eq!((c.n_ours, c.n_reference, c.n_shared, c.n_scored), (3, 3, 2, 2));
+        assert!((c.precision() - 2.0 / 3.0).abs() < 1e-12);
+        assert!((c.jaccard() - 0.5).abs() < 1e-12);
+        assert!((c.score_correlation.unwrap() - 1.0).abs() < 1e-12);
+        assert!((c.max_score_difference.unwrap() - 0.1).abs() < 1e-12);
+        assert_eq!(c.only_ours, vec![hit_key("CAVRDF", "CAVRDF", "NLVPMVATV")]);
+        assert_eq!(c.only_reference.len(), 1);
+    }
+
+    #[test]
+    fn test_dextramer_comparison() {
+        assert_eq!(dextramer_epitope("A0201_GILGFVFTL_Flu-MP_Influenza_binder"), "GILGFVFTL");
+        assert_eq!(dextramer_epitope("negative_control"), "negative_control");
+
+        // 3 cells x 2 features, column-major
+        let counts = vec![50.0, 12.0, 3.0, 2.0, 10.0, 1.0];
+        assert_eq!(dextramer_calls(&counts, 3, 10.0, 2.0), vec![Some(0), None, None]);
+
+        let label = |s: &str| Some(s.to_string());
+        let predicted = vec![label("GILGFVFTL"), label("NLVPMVATV"), None, label("GILGFVFTL")];
+        let truth = vec![label("gilgfvftl"), label("GILGFVFTL"), label("GILGFVFTL"), None];
+        let c = compare_labels(&predicted, &truth);
+        assert_eq!((c.n_cells, c.n_truth, c.n_predicted, c.n_both, c.n_agree), (4, 3, 3, 2, 1));
+        assert_eq!(c.concordance(), Some(0.5));
+        let gil = &c.per_epitope[0];
+        assert_eq!((gil.n_truth, gil.n_predicted, gil.n_true_positive), (3, 1, 1));
+        assert_eq!(gil.precision(), Some(1.0));
+        assert_eq!(c.per_epitope[1].precision(), Some(0.0));
+        assert_eq!(c.per_epitope[1].recall(), None);
+        assert_eq!(c.confusion.len(), 4);
+    }
+}